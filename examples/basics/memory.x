@@ -1,6 +1,7 @@
+/* The second half of the flash is reserved for data, see `pgb1::flash` */
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 1024K - 0x100
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
@@ -18,12 +18,22 @@ use embedded_graphics::{
     text::Text,
 };
 
+pgb1::firmware_info!();
+
 #[entry]
 fn main() -> ! {
     info!("Program start");
     let mut periph = pgb1::Peripherals::take().unwrap();
+
+    pgb1::ui::draw_boot_screen(&mut periph.display, pgb1::firmware_info()).unwrap();
+    periph.display.flush().unwrap();
+    periph.delay.delay_ms(1000);
+    periph.display.clear(BinaryColor::Off).unwrap();
+
     let mut small_rng = SmallRng::seed_from_u64(42);
-    let mut brightness : u8 = 20;
+
+    // Brightness is kept across power cycles
+    let mut brightness : u8 = periph.settings.get("brightness").unwrap().unwrap_or(20);
 
     let yoffset = 20;
 
@@ -108,6 +118,10 @@ fn main() -> ! {
             }
         }
 
+        if periph.keyboard.raising(Keys::UP) || periph.keyboard.raising(Keys::DOWN) {
+            periph.settings.set("brightness", &brightness).unwrap();
+        }
+
         // Update LEDs
         periph.leds.write(smart_leds::brightness(colors.iter().copied(), brightness)).unwrap();
         
@@ -17,10 +17,17 @@ use embedded_graphics::{
 
 mod snake;
 
+pgb1::firmware_info!();
+
 #[entry]
 fn main() -> ! {
     info!("Program start");
     let mut periph = pgb1::Peripherals::take().unwrap();
+
+    pgb1::ui::draw_boot_screen(&mut periph.display, pgb1::firmware_info()).unwrap();
+    periph.display.flush().unwrap();
+    periph.delay.delay_ms(1000);
+
     let small_rng = SmallRng::seed_from_u64(42);
 
     let mut game = 
@@ -0,0 +1,130 @@
+//! Audio block types shared by the sound generation modules
+
+/// Output sample rate in Hz
+pub const SAMPLE_RATE: u32 = 44_100;
+
+/// Number of frames rendered per audio callback
+pub const BLOCK_SIZE: usize = 64;
+
+/// A pair of left and right samples
+pub type StereoFrame = [i16; 2];
+
+pub type StereoBlock = [StereoFrame; BLOCK_SIZE];
+
+pub const SILENCE: StereoBlock = [[0, 0]; BLOCK_SIZE];
+
+/// Clamp a 32-bit intermediate value to the 16-bit sample range
+#[inline]
+pub fn saturate(value: i32) -> i16 {
+    value.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+/// Add `frame` to `out` with saturation
+#[inline]
+pub fn mix(out: &mut StereoFrame, frame: StereoFrame) {
+    out[0] = out[0].saturating_add(frame[0]);
+    out[1] = out[1].saturating_add(frame[1]);
+}
+
+/// Return the left and right gains (0..=0x8000) for a pan position in
+/// -64 (left) ..= 63 (right), using a linear law
+pub fn pan_gains(pan: i8) -> (u16, u16) {
+    let pan = pan.clamp(-64, 63) as i32 + 64; // 0..=127
+    let right = (pan * 0x8000 / 127) as u16;
+    (0x8000 - right, right)
+}
+
+/// Multiply two Q15 fixed point values
+#[inline]
+pub fn mul_q15(a: i32, b: i32) -> i32 {
+    (a * b) >> 15
+}
+
+const SMOOTH_FRAC_BITS: u32 = 12;
+
+/// A parameter value ramping linearly to its target, used to avoid zipper
+/// noise when parameters change
+#[derive(Copy, Clone, Debug)]
+pub struct Smoothed {
+    current: i32,
+    target: i32,
+    step: i32,
+    remaining: u32,
+    ramp: u32,
+}
+
+impl Smoothed {
+    /// `ramp` is the number of frames it takes to reach a new target.
+    /// Values must fit in 19 bits.
+    pub const fn new(value: i32, ramp: u32) -> Self {
+        Smoothed {
+            current: value << SMOOTH_FRAC_BITS,
+            target: value,
+            step: 0,
+            remaining: 0,
+            ramp,
+        }
+    }
+
+    pub fn set(&mut self, target: i32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        if self.ramp == 0 {
+            self.jump(target);
+            return;
+        }
+        self.step = ((target << SMOOTH_FRAC_BITS) - self.current) / self.ramp as i32;
+        self.remaining = self.ramp;
+    }
+
+    /// Set the value without ramping
+    pub fn jump(&mut self, value: i32) {
+        self.target = value;
+        self.current = value << SMOOTH_FRAC_BITS;
+        self.remaining = 0;
+    }
+
+    pub fn set_ramp(&mut self, ramp: u32) {
+        self.ramp = ramp;
+    }
+
+    pub fn target(&self) -> i32 {
+        self.target
+    }
+
+    pub fn value(&self) -> i32 {
+        self.current >> SMOOTH_FRAC_BITS
+    }
+
+    pub fn is_ramping(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Advance by `frames` frames and return the new value
+    pub fn advance(&mut self, frames: u32) -> i32 {
+        if frames >= self.remaining {
+            self.remaining = 0;
+            self.current = self.target << SMOOTH_FRAC_BITS;
+        } else {
+            self.remaining -= frames;
+            self.current += self.step * frames as i32;
+        }
+        self.value()
+    }
+
+    /// Advance by one frame and return the new value
+    #[inline]
+    pub fn next_value(&mut self) -> i32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.current = self.target << SMOOTH_FRAC_BITS;
+            } else {
+                self.current += self.step;
+            }
+        }
+        self.value()
+    }
+}
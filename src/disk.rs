@@ -0,0 +1,821 @@
+//! Block storage of the USB disk
+//!
+//! A region of the flash (`flash::DISK_OFFSET`) holds a small FAT12
+//! volume. In USB disk mode it is handed to `usb::msc::MscClass` and the
+//! host reads and writes it like a thumb drive. Once the host ejects it,
+//! the application takes it back and reads the files with `Volume`:
+//!
+//! ```ignore
+//! let mut disk = Some(periph.disk);
+//!
+//! // Enter USB disk mode
+//! periph.usb.msc.attach(disk.take().unwrap());
+//!
+//! // Main loop
+//! if periph.usb.msc.ejected() {
+//!     disk = periph.usb.msc.detach();
+//!     let disk = disk.as_mut().unwrap();
+//!     let volume = Volume::mount(disk)?;
+//!     if let Some(entry) = volume.find(disk, "KICK.WAV")? {
+//!         volume.read(disk, &entry, 0, &mut buffer)?;
+//!     }
+//! }
+//! ```
+//!
+//! Or copies the files where the rest of the firmware looks for them with
+//! `import`: `SAMPLES.BIN`, a bank made with `pgb1-tool pack-samples`, to
+//! the sample bank and the other files to `IMPORT_DIR` of the filesystem:
+//!
+//! ```ignore
+//! if periph.usb.msc.ejected() {
+//!     disk = periph.usb.msc.detach();
+//!     // The bank is rewritten, no sample of it may be playing
+//!     drop(bank);
+//!     let mut flash = FlashRegion::new(SAMPLE_BANK_OFFSET, SAMPLE_BANK_SIZE);
+//!     disk::import(disk.as_mut().unwrap(), &mut periph.fs, &mut flash)?;
+//!     bank = SampleBank::from_flash()?;
+//! }
+//! ```
+//!
+//! The disk is formatted on first use by `Peripherals::take`, when it is
+//! blank. A volume the firmware cannot read is not formatted again, see
+//! `StorageErrors::disk`. `Volume` only reads the root directory, files in
+//! sub-directories are not visible to the firmware.
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+use heapless::String;
+
+use crate::flash::{FlashRegion, SECTOR_SIZE};
+use crate::fs::{Fs, FsError};
+use crate::sampler::{BankError, BankHeader, HEADER_SIZE};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Label of the volume created on first use
+pub const LABEL: &str = "PGB-1";
+
+/// File of the root directory that `import` writes to the sample bank
+pub const SAMPLE_BANK_FILE: &str = "SAMPLES.BIN";
+
+/// Directory of the filesystem receiving the files imported by `import`
+pub const IMPORT_DIR: &str = "/disk";
+
+pub type Block = [u8; BLOCK_SIZE];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DiskError {
+    OutOfRange,
+    Flash(NorFlashErrorKind),
+    /// No FAT12 volume on the disk
+    NotFormatted,
+}
+
+impl<E: NorFlashError> From<E> for DiskError {
+    fn from(err: E) -> Self {
+        DiskError::Flash(err.kind())
+    }
+}
+
+/// Storage made of `BLOCK_SIZE` bytes blocks
+pub trait BlockDevice {
+    fn block_count(&self) -> u32;
+    fn read_block(&mut self, lba: u32, block: &mut Block) -> Result<(), DiskError>;
+    fn write_block(&mut self, lba: u32, block: &Block) -> Result<(), DiskError>;
+    /// Write pending changes to the storage
+    fn flush(&mut self) -> Result<(), DiskError>;
+}
+
+const BLOCKS_PER_SECTOR: u32 = SECTOR_SIZE / BLOCK_SIZE as u32;
+
+/// Block device on a `NorFlash`
+///
+/// Writes go through a cache of one erase sector, so that the blocks of a
+/// sector written in sequence only cost one erase. Call `flush` before
+/// handing the flash to other code.
+pub struct FlashDisk<F> {
+    flash: F,
+    cache: [u8; SECTOR_SIZE as usize],
+    cached: Option<u32>,
+    dirty: bool,
+}
+
+/// The disk in the on-board flash
+pub type Disk = FlashDisk<FlashRegion>;
+
+impl<F: NorFlash> FlashDisk<F> {
+    pub fn new(flash: F) -> Self {
+        assert!((SECTOR_SIZE as usize).is_multiple_of(F::ERASE_SIZE));
+        FlashDisk {
+            flash,
+            cache: [0; SECTOR_SIZE as usize],
+            cached: None,
+            dirty: false,
+        }
+    }
+
+    /// Flush and return the flash
+    pub fn into_inner(mut self) -> Result<F, DiskError> {
+        self.flush()?;
+        Ok(self.flash)
+    }
+
+    fn check(&self, lba: u32) -> Result<(), DiskError> {
+        if lba < self.block_count() {
+            Ok(())
+        } else {
+            Err(DiskError::OutOfRange)
+        }
+    }
+}
+
+impl<F: NorFlash> BlockDevice for FlashDisk<F> {
+    fn block_count(&self) -> u32 {
+        (self.flash.capacity() / BLOCK_SIZE) as u32
+    }
+
+    fn read_block(&mut self, lba: u32, block: &mut Block) -> Result<(), DiskError> {
+        self.check(lba)?;
+        let sector = lba / BLOCKS_PER_SECTOR;
+        let start = (lba % BLOCKS_PER_SECTOR) as usize * BLOCK_SIZE;
+        if self.cached == Some(sector) {
+            block.copy_from_slice(&self.cache[start..start + BLOCK_SIZE]);
+        } else {
+            self.flash.read(lba * BLOCK_SIZE as u32, block)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u32, block: &Block) -> Result<(), DiskError> {
+        self.check(lba)?;
+        let sector = lba / BLOCKS_PER_SECTOR;
+        if self.cached != Some(sector) {
+            self.flush()?;
+            self.flash.read(sector * SECTOR_SIZE, &mut self.cache)?;
+            self.cached = Some(sector);
+        }
+        let start = (lba % BLOCKS_PER_SECTOR) as usize * BLOCK_SIZE;
+        let dst = &mut self.cache[start..start + BLOCK_SIZE];
+        if dst != block {
+            dst.copy_from_slice(block);
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), DiskError> {
+        if let (Some(sector), true) = (self.cached, self.dirty) {
+            let offset = sector * SECTOR_SIZE;
+            self.flash.erase(offset, offset + SECTOR_SIZE)?;
+            self.flash.write(offset, &self.cache)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const DIR_ENTRY_SIZE: usize = 32;
+const ROOT_ENTRIES: u16 = 64;
+/// FAT12 volumes have less clusters than this
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT12_END_OF_CHAIN: u16 = 0xFF8;
+const MEDIA_FIXED: u8 = 0xF8;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Long file name entries have all these attributes
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ENTRY_FREE: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+
+/// Create an empty FAT12 volume on the whole device
+pub fn format<D: BlockDevice>(device: &mut D, label: &str) -> Result<(), DiskError> {
+    let total = device.block_count();
+    let root_blocks = (ROOT_ENTRIES as u32 * DIR_ENTRY_SIZE as u32).div_ceil(BLOCK_SIZE as u32);
+
+    let mut cluster_blocks = 1;
+    while total / cluster_blocks >= FAT12_MAX_CLUSTERS {
+        cluster_blocks *= 2;
+    }
+    let clusters = (total - 1 - root_blocks) / cluster_blocks;
+    let fat_blocks = ((clusters + 2) * 3 / 2 + 1).div_ceil(BLOCK_SIZE as u32);
+
+    let mut block = [0; BLOCK_SIZE];
+    block[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    block[3..11].copy_from_slice(b"PGB-1   ");
+    block[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    block[13] = cluster_blocks as u8;
+    block[14..16].copy_from_slice(&1u16.to_le_bytes()); // Reserved blocks
+    block[16] = 1; // FAT copies
+    block[17..19].copy_from_slice(&ROOT_ENTRIES.to_le_bytes());
+    block[19..21].copy_from_slice(&(total as u16).to_le_bytes());
+    block[21] = MEDIA_FIXED;
+    block[22..24].copy_from_slice(&(fat_blocks as u16).to_le_bytes());
+    block[24..26].copy_from_slice(&1u16.to_le_bytes()); // Blocks per track
+    block[26..28].copy_from_slice(&1u16.to_le_bytes()); // Heads
+    block[36] = 0x80; // Drive number
+    block[38] = 0x29; // Extended boot signature
+    block[39..43].copy_from_slice(&crate::crc::crc32(label.as_bytes()).to_le_bytes());
+    block[43..54].copy_from_slice(&short_label(label));
+    block[54..62].copy_from_slice(b"FAT12   ");
+    block[510..].copy_from_slice(&BOOT_SIGNATURE);
+    device.write_block(0, &block)?;
+
+    for lba in 1..1 + fat_blocks + root_blocks {
+        block.fill(0);
+        if lba == 1 {
+            block[..3].copy_from_slice(&[MEDIA_FIXED, 0xFF, 0xFF]);
+        }
+        if lba == 1 + fat_blocks {
+            block[..11].copy_from_slice(&short_label(label));
+            block[11] = ATTR_VOLUME_ID;
+        }
+        device.write_block(lba, &block)?;
+    }
+    device.flush()
+}
+
+fn short_label(label: &str) -> [u8; 11] {
+    let mut out = [b' '; 11];
+    for (dst, src) in out.iter_mut().zip(label.bytes()) {
+        *dst = src.to_ascii_uppercase();
+    }
+    out
+}
+
+/// A file or directory of the root directory
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DirEntry {
+    /// Short name, `NAME.EXT`
+    pub name: String<12>,
+    pub size: u32,
+    pub is_dir: bool,
+    first_cluster: u16,
+}
+
+impl DirEntry {
+    fn decode(raw: &[u8]) -> Self {
+        let mut name = String::new();
+        let base = core::str::from_utf8(&raw[..8]).unwrap_or("").trim_end();
+        let ext = core::str::from_utf8(&raw[8..11]).unwrap_or("").trim_end();
+        let _ = name.push_str(base);
+        if !ext.is_empty() {
+            let _ = name.push('.');
+            let _ = name.push_str(ext);
+        }
+        DirEntry {
+            name,
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+            is_dir: raw[11] & ATTR_DIRECTORY != 0,
+            first_cluster: u16::from_le_bytes([raw[26], raw[27]]),
+        }
+    }
+}
+
+/// Read access to a FAT12 volume
+#[derive(Copy, Clone, Debug)]
+pub struct Volume {
+    cluster_blocks: u32,
+    fat_start: u32,
+    root_start: u32,
+    root_entries: u32,
+    data_start: u32,
+    clusters: u32,
+}
+
+fn le16(data: &[u8], pos: usize) -> u32 {
+    u16::from_le_bytes([data[pos], data[pos + 1]]) as u32
+}
+
+impl Volume {
+    /// Mount the volume, formatting the device first if it is blank: its
+    /// first block has no boot signature, e.g. erased flash
+    ///
+    /// A volume that `mount` does not support, like one the host formatted
+    /// with another layout, is left as is and `NotFormatted` is returned:
+    /// only `format` erases the files of the user.
+    pub fn mount_or_format<D: BlockDevice>(device: &mut D, label: &str) -> Result<Self, DiskError> {
+        let result = Self::mount(device);
+        if !matches!(result, Err(DiskError::NotFormatted)) {
+            return result;
+        }
+        let mut block = [0; BLOCK_SIZE];
+        device.read_block(0, &mut block)?;
+        if block[510..] == BOOT_SIGNATURE {
+            return result;
+        }
+        format(device, label)?;
+        Self::mount(device)
+    }
+
+    pub fn mount<D: BlockDevice>(device: &mut D) -> Result<Self, DiskError> {
+        let mut block = [0; BLOCK_SIZE];
+        device.read_block(0, &mut block)?;
+
+        let cluster_blocks = block[13] as u32;
+        let reserved = le16(&block, 14);
+        let fats = block[16] as u32;
+        let root_entries = le16(&block, 17);
+        let total = match le16(&block, 19) {
+            0 => u32::from_le_bytes([block[32], block[33], block[34], block[35]]),
+            total => total,
+        };
+        let fat_blocks = le16(&block, 22);
+        if block[510..] != BOOT_SIGNATURE
+            || le16(&block, 11) != BLOCK_SIZE as u32
+            || !cluster_blocks.is_power_of_two()
+            || fats == 0
+            || total > device.block_count()
+        {
+            return Err(DiskError::NotFormatted);
+        }
+
+        let fat_start = reserved;
+        let root_start = fat_start + fats * fat_blocks;
+        let data_start =
+            root_start + (root_entries * DIR_ENTRY_SIZE as u32).div_ceil(BLOCK_SIZE as u32);
+        let clusters = total.saturating_sub(data_start) / cluster_blocks;
+        if data_start >= total || clusters >= FAT12_MAX_CLUSTERS {
+            return Err(DiskError::NotFormatted);
+        }
+
+        Ok(Volume {
+            cluster_blocks,
+            fat_start,
+            root_start,
+            root_entries,
+            data_start,
+            clusters,
+        })
+    }
+
+    /// Call `f` for each file and directory of the root directory
+    pub fn for_each<D: BlockDevice>(
+        &self,
+        device: &mut D,
+        mut f: impl FnMut(&DirEntry),
+    ) -> Result<(), DiskError> {
+        let mut block = [0; BLOCK_SIZE];
+        for index in 0..self.root_entries as usize {
+            let pos = index * DIR_ENTRY_SIZE % BLOCK_SIZE;
+            if pos == 0 {
+                let lba = self.root_start + (index * DIR_ENTRY_SIZE / BLOCK_SIZE) as u32;
+                device.read_block(lba, &mut block)?;
+            }
+            let raw = &block[pos..pos + DIR_ENTRY_SIZE];
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => continue,
+                _ if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME => continue,
+                _ if raw[11] & ATTR_VOLUME_ID != 0 => continue,
+                _ => f(&DirEntry::decode(raw)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Find an entry of the root directory by its short name, ignoring case
+    pub fn find<D: BlockDevice>(
+        &self,
+        device: &mut D,
+        name: &str,
+    ) -> Result<Option<DirEntry>, DiskError> {
+        let mut found = None;
+        self.for_each(device, |entry| {
+            if found.is_none() && entry.name.eq_ignore_ascii_case(name) {
+                found = Some(entry.clone());
+            }
+        })?;
+        Ok(found)
+    }
+
+    fn next_cluster<D: BlockDevice>(&self, device: &mut D, cluster: u16) -> Result<u16, DiskError> {
+        let pos = cluster as u32 * 3 / 2;
+        let mut bytes = [0; 2];
+        let mut block = [0; BLOCK_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let pos = pos + i as u32;
+            device.read_block(self.fat_start + pos / BLOCK_SIZE as u32, &mut block)?;
+            *byte = block[(pos % BLOCK_SIZE as u32) as usize];
+        }
+        let value = u16::from_le_bytes(bytes);
+        Ok(if cluster & 1 == 0 {
+            value & 0xFFF
+        } else {
+            value >> 4
+        })
+    }
+
+    /// Read file data from `offset`, return the number of bytes read
+    pub fn read<D: BlockDevice>(
+        &self,
+        device: &mut D,
+        entry: &DirEntry,
+        offset: u32,
+        buffer: &mut [u8],
+    ) -> Result<usize, DiskError> {
+        let cluster_size = self.cluster_blocks * BLOCK_SIZE as u32;
+        let len = (entry.size.saturating_sub(offset) as usize).min(buffer.len());
+
+        let mut cluster = entry.first_cluster;
+        for _ in 0..offset / cluster_size {
+            cluster = self.next_cluster(device, cluster)?;
+        }
+
+        let mut block = [0; BLOCK_SIZE];
+        let mut pos = offset;
+        let mut done = 0;
+        while done < len {
+            if !(2..2 + self.clusters as u16).contains(&cluster) {
+                return Err(DiskError::OutOfRange);
+            }
+            let in_cluster = pos % cluster_size;
+            let lba = self.data_start
+                + (cluster as u32 - 2) * self.cluster_blocks
+                + in_cluster / BLOCK_SIZE as u32;
+            device.read_block(lba, &mut block)?;
+
+            let start = (pos % BLOCK_SIZE as u32) as usize;
+            let count = (BLOCK_SIZE - start).min(len - done);
+            buffer[done..done + count].copy_from_slice(&block[start..start + count]);
+            done += count;
+            pos += count as u32;
+
+            if pos.is_multiple_of(cluster_size) && done < len {
+                cluster = self.next_cluster(device, cluster)?;
+                if cluster >= FAT12_END_OF_CHAIN {
+                    return Err(DiskError::OutOfRange);
+                }
+            }
+        }
+        Ok(done)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImportError {
+    Disk(DiskError),
+    Fs(FsError),
+    /// `SAMPLE_BANK_FILE` is not a sample bank
+    Bank(BankError),
+    /// `SAMPLE_BANK_FILE` does not fit in the sample bank
+    BankTooLarge,
+}
+
+impl From<DiskError> for ImportError {
+    fn from(err: DiskError) -> Self {
+        ImportError::Disk(err)
+    }
+}
+
+impl From<FsError> for ImportError {
+    fn from(err: FsError) -> Self {
+        ImportError::Fs(err)
+    }
+}
+
+/// What `import` copied
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Imported {
+    /// Files copied to `IMPORT_DIR`
+    pub files: usize,
+    /// Directories, and files past the root directory size of the volume
+    /// created by `format`, not copied
+    pub skipped: usize,
+    /// `SAMPLE_BANK_FILE` was written to the sample bank
+    pub bank: bool,
+}
+
+/// Copy the files of the volume on `device`: `SAMPLE_BANK_FILE` to `bank`,
+/// the others to `IMPORT_DIR` of `fs`, replacing files of the same name
+///
+/// The bank header is checked before anything is erased, but a failure
+/// while writing it leaves the bank invalid. No `SampleBank` of the flash
+/// may be alive during the import, reopen it with `SampleBank::from_flash`
+/// afterwards. Files are left on the volume.
+pub fn import<D: BlockDevice, F: NorFlash, S: NorFlash>(
+    device: &mut D,
+    fs: &mut Fs<F>,
+    bank: &mut S,
+) -> Result<Imported, ImportError> {
+    let volume = Volume::mount(device)?;
+    let mut imported = Imported::default();
+
+    // Listed first, `for_each` borrows the device
+    let mut entries: heapless::Vec<DirEntry, { ROOT_ENTRIES as usize }> = heapless::Vec::new();
+    volume.for_each(device, |entry| {
+        if entry.is_dir || entries.push(entry.clone()).is_err() {
+            imported.skipped += 1;
+        }
+    })?;
+
+    let mut block = [0; BLOCK_SIZE];
+    for entry in &entries {
+        if entry.name.eq_ignore_ascii_case(SAMPLE_BANK_FILE) {
+            volume.read(device, entry, 0, &mut block[..HEADER_SIZE])?;
+            let header = BankHeader::decode(&block).map_err(ImportError::Bank)?;
+            let size = header.size;
+            if entry.size < size {
+                return Err(ImportError::Bank(BankError::Truncated));
+            }
+            if size as usize > bank.capacity() {
+                return Err(ImportError::BankTooLarge);
+            }
+            let end = (size as usize).next_multiple_of(S::ERASE_SIZE) as u32;
+            bank.erase(0, end).map_err(DiskError::from)?;
+            for offset in (0..size).step_by(BLOCK_SIZE) {
+                block.fill(0xFF);
+                volume.read(device, entry, offset, &mut block)?;
+                bank.write(offset, &block).map_err(DiskError::from)?;
+            }
+            imported.bank = true;
+            continue;
+        }
+
+        if !fs.exists(IMPORT_DIR) {
+            fs.create_dir(IMPORT_DIR)?;
+        }
+        let mut path: String<{ IMPORT_DIR.len() + 13 }> = String::new();
+        let _ = path.push_str(IMPORT_DIR);
+        let _ = path.push('/');
+        let _ = path.push_str(&entry.name);
+        let mut writer = fs.create(&path)?;
+        for offset in (0..entry.size).step_by(BLOCK_SIZE) {
+            let len = volume.read(device, entry, offset, &mut block)?;
+            fs.write(&mut writer, &block[..len])?;
+        }
+        fs.commit(writer)?;
+        imported.files += 1;
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{RamFlash, DISK_SIZE};
+    use std::vec::Vec;
+
+    type TestDisk = FlashDisk<RamFlash<{ DISK_SIZE as usize }>>;
+
+    fn disk() -> Box<TestDisk> {
+        Box::new(FlashDisk::new(RamFlash::new()))
+    }
+
+    fn pattern(lba: u32) -> Block {
+        core::array::from_fn(|i| (i as u32 ^ lba.wrapping_mul(31)) as u8)
+    }
+
+    #[test]
+    fn write_back_cache() {
+        let mut disk = disk();
+        assert_eq!(disk.block_count(), DISK_SIZE / BLOCK_SIZE as u32);
+
+        // A whole sector written in sequence costs one erase
+        for lba in 8..16 {
+            disk.write_block(lba, &pattern(lba)).unwrap();
+        }
+        assert_eq!(disk.flash.erase_count(), 0);
+        let mut block = [0; BLOCK_SIZE];
+        disk.read_block(9, &mut block).unwrap();
+        assert_eq!(block, pattern(9));
+
+        disk.write_block(3, &pattern(3)).unwrap();
+        assert_eq!(disk.flash.erase_count(), 1);
+        disk.flush().unwrap();
+        disk.flush().unwrap();
+        assert_eq!(disk.flash.erase_count(), 2);
+
+        // Rewriting the same data does not erase
+        disk.write_block(3, &pattern(3)).unwrap();
+        disk.flush().unwrap();
+        assert_eq!(disk.flash.erase_count(), 2);
+
+        for lba in (3..4).chain(8..16) {
+            disk.read_block(lba, &mut block).unwrap();
+            assert_eq!(block, pattern(lba));
+        }
+        disk.read_block(4, &mut block).unwrap();
+        assert_eq!(block, [0xFF; BLOCK_SIZE]);
+
+        let last = disk.block_count();
+        assert_eq!(
+            disk.read_block(last, &mut block),
+            Err(DiskError::OutOfRange)
+        );
+        assert_eq!(disk.write_block(last, &block), Err(DiskError::OutOfRange));
+
+        let flash = disk.into_inner().unwrap();
+        assert_eq!(
+            &flash.as_bytes()[4 * BLOCK_SIZE..5 * BLOCK_SIZE],
+            &[0xFF; BLOCK_SIZE]
+        );
+        assert_eq!(
+            &flash.as_bytes()[3 * BLOCK_SIZE..4 * BLOCK_SIZE],
+            &pattern(3)
+        );
+    }
+
+    /// Add a file the way a host would: directory entry, FAT chain, data
+    fn add_file(disk: &mut TestDisk, volume: &Volume, name: &[u8; 11], first: u16, data: &[u8]) {
+        let cluster_size = (volume.cluster_blocks as usize) * BLOCK_SIZE;
+        let clusters = data.len().div_ceil(cluster_size);
+        // Allocated in reverse order to test the chain
+        let chain: Vec<u16> = (0..clusters)
+            .map(|i| first + (clusters - 1 - i) as u16)
+            .collect();
+
+        let mut fat = [0; BLOCK_SIZE];
+        disk.read_block(volume.fat_start, &mut fat).unwrap();
+        for (i, &cluster) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(0xFFF);
+            let pos = cluster as usize * 3 / 2;
+            if cluster & 1 == 0 {
+                fat[pos] = next as u8;
+                fat[pos + 1] = fat[pos + 1] & 0xF0 | (next >> 8) as u8;
+            } else {
+                fat[pos] = fat[pos] & 0x0F | (next << 4) as u8;
+                fat[pos + 1] = (next >> 4) as u8;
+            }
+        }
+        disk.write_block(volume.fat_start, &fat).unwrap();
+
+        for (&cluster, chunk) in chain.iter().zip(data.chunks(cluster_size)) {
+            for (i, part) in chunk.chunks(BLOCK_SIZE).enumerate() {
+                let mut block = [0; BLOCK_SIZE];
+                block[..part.len()].copy_from_slice(part);
+                let lba =
+                    volume.data_start + (cluster as u32 - 2) * volume.cluster_blocks + i as u32;
+                disk.write_block(lba, &block).unwrap();
+            }
+        }
+
+        let mut root = [0; BLOCK_SIZE];
+        disk.read_block(volume.root_start, &mut root).unwrap();
+        let free = root
+            .chunks(DIR_ENTRY_SIZE)
+            .position(|e| e[0] == ENTRY_END)
+            .unwrap();
+        let entry = &mut root[free * DIR_ENTRY_SIZE..(free + 1) * DIR_ENTRY_SIZE];
+        entry[..11].copy_from_slice(name);
+        entry[26..28].copy_from_slice(&chain[0].to_le_bytes());
+        entry[28..32].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        disk.write_block(volume.root_start, &root).unwrap();
+        disk.flush().unwrap();
+    }
+
+    #[test]
+    fn format_and_read_files() {
+        let mut disk = disk();
+        assert_eq!(
+            Volume::mount(&mut *disk).err(),
+            Some(DiskError::NotFormatted)
+        );
+
+        format(&mut *disk, "pgb-1").unwrap();
+        let volume = Volume::mount(&mut *disk).unwrap();
+        assert_eq!(volume.cluster_blocks, 1);
+        assert!(volume.clusters > 400);
+        let mut count = 0;
+        volume.for_each(&mut *disk, |_| count += 1).unwrap();
+        assert_eq!(count, 0, "the volume label is not listed");
+
+        let data: Vec<u8> = (0..1500u32).map(|i| (i * 7) as u8).collect();
+        add_file(&mut disk, &volume, b"KICK    WAV", 2, &data);
+        add_file(&mut disk, &volume, b"README     ", 10, b"hello");
+
+        let mut names = Vec::new();
+        volume
+            .for_each(&mut *disk, |e| names.push(e.name.clone()))
+            .unwrap();
+        assert_eq!(names, ["KICK.WAV", "README"]);
+
+        let entry = volume.find(&mut *disk, "kick.wav").unwrap().unwrap();
+        assert_eq!(entry.size, 1500);
+        let mut buffer = [0; 2000];
+        assert_eq!(volume.read(&mut *disk, &entry, 0, &mut buffer), Ok(1500));
+        assert_eq!(&buffer[..1500], &data[..]);
+        assert_eq!(
+            volume.read(&mut *disk, &entry, 1000, &mut buffer[..100]),
+            Ok(100)
+        );
+        assert_eq!(&buffer[..100], &data[1000..1100]);
+        assert_eq!(volume.read(&mut *disk, &entry, 1600, &mut buffer), Ok(0));
+        assert_eq!(volume.find(&mut *disk, "SNARE.WAV"), Ok(None));
+    }
+
+    #[test]
+    fn mount_or_format() {
+        let mut disk = disk();
+        Volume::mount_or_format(&mut *disk, LABEL).unwrap();
+        let erases = disk.flash.erase_count();
+        assert!(erases > 0);
+
+        let volume = Volume::mount(&mut *disk).unwrap();
+        add_file(&mut disk, &volume, b"README     ", 2, b"hello");
+        let erases = disk.flash.erase_count();
+
+        // A formatted volume is kept
+        Volume::mount_or_format(&mut *disk, LABEL).unwrap();
+        assert_eq!(disk.flash.erase_count(), erases);
+        assert!(volume.find(&mut *disk, "README").unwrap().is_some());
+
+        // And a volume that cannot be mounted, formatted by the host with
+        // 4096 bytes sectors
+        let mut block = [0; BLOCK_SIZE];
+        disk.read_block(0, &mut block).unwrap();
+        block[11..13].copy_from_slice(&4096u16.to_le_bytes());
+        disk.write_block(0, &block).unwrap();
+        disk.flush().unwrap();
+        let erases = disk.flash.erase_count();
+        assert_eq!(
+            Volume::mount_or_format(&mut *disk, LABEL).err(),
+            Some(DiskError::NotFormatted)
+        );
+        assert_eq!(disk.flash.erase_count(), erases);
+        let mut read = [0; BLOCK_SIZE];
+        disk.read_block(0, &mut read).unwrap();
+        assert_eq!(read, block);
+    }
+
+    #[test]
+    fn import_files_and_bank() {
+        use crate::flash::{RamFlash, SECTOR_SIZE};
+        use crate::sampler::{SampleBank, SampleEntry, BANK_VERSION, ENTRY_SIZE, NAME_LEN};
+
+        const BANK_SIZE: usize = 4 * SECTOR_SIZE as usize;
+
+        let mut disk = disk();
+        let mut fs = Box::new(Fs::new(RamFlash::<{ 16 * SECTOR_SIZE as usize }>::new()).unwrap());
+        let mut bank = Box::new(RamFlash::<BANK_SIZE>::new());
+        assert_eq!(
+            import(&mut *disk, &mut *fs, &mut *bank),
+            Err(ImportError::Disk(DiskError::NotFormatted))
+        );
+
+        format(&mut *disk, LABEL).unwrap();
+        let volume = Volume::mount(&mut *disk).unwrap();
+        let data: Vec<u8> = (0..1500u32).map(|i| (i * 7) as u8).collect();
+        add_file(&mut disk, &volume, b"KICK    WAV", 2, &data);
+        add_file(&mut disk, &volume, b"README     ", 10, b"hello");
+
+        // One sample of 600 frames, larger than a block
+        let frames = 600;
+        let size = HEADER_SIZE + ENTRY_SIZE + frames * 2;
+        let mut image = Vec::new();
+        let header = BankHeader {
+            version: BANK_VERSION,
+            count: 1,
+            size: size as u32,
+        };
+        image.extend_from_slice(&header.encode());
+        let mut entry = SampleEntry {
+            name: [0; NAME_LEN],
+            offset: (HEADER_SIZE + ENTRY_SIZE) as u32,
+            length: frames as u32,
+            sample_rate: 44_100,
+            root_note: 60,
+        };
+        entry.name[..5].copy_from_slice(b"snare");
+        image.extend_from_slice(&entry.encode());
+        for i in 0..frames as i16 {
+            image.extend_from_slice(&(i * 50).to_le_bytes());
+        }
+        add_file(&mut disk, &volume, b"SAMPLES BIN", 20, &image);
+
+        // Replaced by the import
+        fs.create_dir(IMPORT_DIR).unwrap();
+        fs.write_file("/disk/README", b"old").unwrap();
+
+        let imported = import(&mut *disk, &mut *fs, &mut *bank).unwrap();
+        assert_eq!(
+            imported,
+            Imported {
+                files: 2,
+                skipped: 0,
+                bank: true
+            }
+        );
+        let mut buffer = [0; 2000];
+        assert_eq!(fs.read("/disk/KICK.WAV", 0, &mut buffer), Ok(1500));
+        assert_eq!(&buffer[..1500], &data[..]);
+        assert_eq!(fs.read("/disk/README", 0, &mut buffer), Ok(5));
+        assert_eq!(&buffer[..5], b"hello");
+        assert!(!fs.exists("/disk/SAMPLES.BIN"));
+
+        assert_eq!(&bank.as_bytes()[..size], &image[..]);
+        let leaked: &'static [u8] = Vec::leak(bank.as_bytes().to_vec());
+        let samples = SampleBank::new(leaked).unwrap();
+        let sample = samples.find("snare").unwrap();
+        assert_eq!(sample.len(), frames as u32);
+        assert_eq!(sample.frame(599), 599 * 50);
+
+        // A bank larger than the region is refused before erasing
+        let mut small = RamFlash::<1024>::new();
+        assert_eq!(
+            import(&mut *disk, &mut *fs, &mut small),
+            Err(ImportError::BankTooLarge)
+        );
+        assert_eq!(small.erase_count(), 0);
+    }
+}
@@ -0,0 +1,781 @@
+//! Fixed point audio effects
+//!
+//! Effects process stereo blocks in place. They can be used on their own or
+//! combined in an `EffectChain` with insert and send buses.
+//!
+//! Gains, amounts and mix levels are Q15 values where `UNITY` (0x8000) is
+//! 1.0. Continuous parameters are ramped over `SMOOTHING_FRAMES` to avoid
+//! zipper noise.
+//!
+//! Memory usage: `Reverb` uses about 14 KiB, `Delay` uses the buffer
+//! provided by the application (4 bytes per frame of delay), the other
+//! effects only a few bytes.
+
+use heapless::Vec;
+
+use crate::audio::{mul_q15, saturate, Smoothed, StereoFrame, BLOCK_SIZE, SAMPLE_RATE};
+
+pub const UNITY: i32 = 0x8000;
+
+/// Number of frames used to ramp parameter changes, about 6 ms
+pub const SMOOTHING_FRAMES: u32 = 256;
+
+pub trait Effect {
+    fn process(&mut self, block: &mut [StereoFrame]);
+
+    /// Clear the internal state, e.g. the content of delay lines
+    fn reset(&mut self) {}
+}
+
+/// Mix of `dry` and `wet`, the wet signal is saturated first so that the
+/// difference times `mix` fits in 32 bits
+#[inline]
+fn crossfade(dry: i16, wet: i32, mix: i32) -> i16 {
+    let dry = dry as i32;
+    let wet = saturate(wet) as i32;
+    saturate(dry + mul_q15(wet - dry, mix))
+}
+
+/// Note lengths for tempo synced effects
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum NoteDivision {
+    Whole,
+    Half,
+    DottedQuarter,
+    Quarter,
+    TripletQuarter,
+    DottedEighth,
+    Eighth,
+    TripletEighth,
+    Sixteenth,
+    ThirtySecond,
+}
+
+impl NoteDivision {
+    /// Length in MIDI clock ticks (24 per quarter note)
+    pub fn ticks(&self) -> u32 {
+        match *self {
+            NoteDivision::Whole => 96,
+            NoteDivision::Half => 48,
+            NoteDivision::DottedQuarter => 36,
+            NoteDivision::Quarter => 24,
+            NoteDivision::TripletQuarter => 16,
+            NoteDivision::DottedEighth => 18,
+            NoteDivision::Eighth => 12,
+            NoteDivision::TripletEighth => 8,
+            NoteDivision::Sixteenth => 6,
+            NoteDivision::ThirtySecond => 3,
+        }
+    }
+
+    /// Length in frames at the given tempo
+    pub fn frames(&self, bpm: u16) -> u32 {
+        let bpm = bpm.max(1) as u32;
+        SAMPLE_RATE * 60 * self.ticks() / (24 * bpm)
+    }
+}
+
+/// Stereo feedback delay
+pub struct Delay<'a> {
+    buffer: &'a mut [StereoFrame],
+    write: usize,
+    length: Smoothed,
+    feedback: Smoothed,
+    mix: Smoothed,
+    ping_pong: bool,
+}
+
+impl<'a> Delay<'a> {
+    /// The maximum delay time is the length of `buffer` minus one frame
+    pub fn new(buffer: &'a mut [StereoFrame]) -> Self {
+        buffer.fill([0, 0]);
+        let length = (buffer.len() / 2) as i32;
+        Delay {
+            buffer,
+            write: 0,
+            length: Smoothed::new(length, SMOOTHING_FRAMES * 4),
+            feedback: Smoothed::new(UNITY / 2, SMOOTHING_FRAMES),
+            mix: Smoothed::new(UNITY / 3, SMOOTHING_FRAMES),
+            ping_pong: false,
+        }
+    }
+
+    pub fn max_frames(&self) -> u32 {
+        self.buffer.len().saturating_sub(1) as u32
+    }
+
+    pub fn set_time_frames(&mut self, frames: u32) {
+        let frames = frames.clamp(1, self.max_frames().max(1));
+        self.length.set(frames as i32);
+    }
+
+    pub fn set_time_ms(&mut self, ms: u32) {
+        self.set_time_frames((ms as u64 * SAMPLE_RATE as u64 / 1000) as u32);
+    }
+
+    /// Set the delay time to a note length at the given tempo
+    pub fn set_time_synced(&mut self, bpm: u16, division: NoteDivision) {
+        self.set_time_frames(division.frames(bpm));
+    }
+
+    /// Q15 feedback amount, clamped below unity
+    pub fn set_feedback(&mut self, feedback: i32) {
+        self.feedback.set(feedback.clamp(0, UNITY - 1024));
+    }
+
+    /// Q15 wet/dry balance
+    pub fn set_mix(&mut self, mix: i32) {
+        self.mix.set(mix.clamp(0, UNITY));
+    }
+
+    /// Bounce the echoes between the left and right channels
+    pub fn set_ping_pong(&mut self, ping_pong: bool) {
+        self.ping_pong = ping_pong;
+    }
+}
+
+impl Effect for Delay<'_> {
+    fn process(&mut self, block: &mut [StereoFrame]) {
+        let size = self.buffer.len();
+        if size < 2 {
+            return;
+        }
+
+        for frame in block.iter_mut() {
+            let length = (self.length.next_value() as usize).clamp(1, size - 1);
+            let feedback = self.feedback.next_value();
+            let mix = self.mix.next_value();
+
+            let read = (self.write + size - length) % size;
+            let delayed = self.buffer[read];
+            let (l, r) = (delayed[0] as i32, delayed[1] as i32);
+
+            self.buffer[self.write] = if self.ping_pong {
+                let input = (frame[0] as i32 + frame[1] as i32) / 2;
+                [
+                    saturate(input + mul_q15(r, feedback)),
+                    saturate(mul_q15(l, feedback)),
+                ]
+            } else {
+                [
+                    saturate(frame[0] as i32 + mul_q15(l, feedback)),
+                    saturate(frame[1] as i32 + mul_q15(r, feedback)),
+                ]
+            };
+            self.write = (self.write + 1) % size;
+
+            frame[0] = crossfade(frame[0], l, mix);
+            frame[1] = crossfade(frame[1], r, mix);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill([0, 0]);
+    }
+}
+
+struct Comb<const N: usize> {
+    buffer: [i16; N],
+    index: usize,
+    filter: i32,
+}
+
+impl<const N: usize> Comb<N> {
+    const fn new() -> Self {
+        Comb {
+            buffer: [0; N],
+            index: 0,
+            filter: 0,
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, input: i32, feedback: i32, damp: i32) -> i32 {
+        let output = self.buffer[self.index] as i32;
+        self.filter = output + mul_q15(self.filter - output, damp);
+        self.buffer[self.index] = saturate(input + mul_q15(self.filter, feedback));
+        self.index = (self.index + 1) % N;
+        output
+    }
+
+    fn reset(&mut self) {
+        self.buffer = [0; N];
+        self.filter = 0;
+    }
+}
+
+struct Allpass<const N: usize> {
+    buffer: [i16; N],
+    index: usize,
+}
+
+impl<const N: usize> Allpass<N> {
+    const fn new() -> Self {
+        Allpass {
+            buffer: [0; N],
+            index: 0,
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, input: i32) -> i32 {
+        let delayed = self.buffer[self.index] as i32;
+        self.buffer[self.index] = saturate(input + delayed / 2);
+        self.index = (self.index + 1) % N;
+        delayed - input
+    }
+
+    fn reset(&mut self) {
+        self.buffer = [0; N];
+    }
+}
+
+/// Small footprint reverb based on the Freeverb structure
+///
+/// A mono tank of four damped comb filters feeds two series of all-pass
+/// filters with slightly different lengths for the left and right outputs.
+pub struct Reverb {
+    comb1: Comb<1116>,
+    comb2: Comb<1188>,
+    comb3: Comb<1277>,
+    comb4: Comb<1356>,
+    left1: Allpass<556>,
+    left2: Allpass<441>,
+    right1: Allpass<579>,
+    right2: Allpass<464>,
+
+    size: Smoothed,
+    damping: Smoothed,
+    mix: Smoothed,
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reverb {
+    pub const fn new() -> Self {
+        Reverb {
+            comb1: Comb::new(),
+            comb2: Comb::new(),
+            comb3: Comb::new(),
+            comb4: Comb::new(),
+            left1: Allpass::new(),
+            left2: Allpass::new(),
+            right1: Allpass::new(),
+            right2: Allpass::new(),
+            size: Smoothed::new(UNITY / 2, SMOOTHING_FRAMES),
+            damping: Smoothed::new(UNITY / 2, SMOOTHING_FRAMES),
+            mix: Smoothed::new(UNITY / 4, SMOOTHING_FRAMES),
+        }
+    }
+
+    /// Q15 room size, longer decay for larger values
+    pub fn set_size(&mut self, size: i32) {
+        self.size.set(size.clamp(0, UNITY));
+    }
+
+    /// Q15 high frequency damping
+    pub fn set_damping(&mut self, damping: i32) {
+        self.damping.set(damping.clamp(0, UNITY));
+    }
+
+    /// Q15 wet/dry balance
+    pub fn set_mix(&mut self, mix: i32) {
+        self.mix.set(mix.clamp(0, UNITY));
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, block: &mut [StereoFrame]) {
+        for frame in block.iter_mut() {
+            // Same scaling as Freeverb: feedback 0.7..0.98, damping 0..0.4
+            let feedback = 22938 + mul_q15(self.size.next_value(), 9175);
+            let damp = mul_q15(self.damping.next_value(), 13107);
+            let mix = self.mix.next_value();
+
+            let input = mul_q15(frame[0] as i32 + frame[1] as i32, 983);
+
+            let tank = saturate(
+                self.comb1.process(input, feedback, damp)
+                    + self.comb2.process(input, feedback, damp)
+                    + self.comb3.process(input, feedback, damp)
+                    + self.comb4.process(input, feedback, damp),
+            ) as i32;
+
+            let left = saturate(self.left1.process(tank)) as i32;
+            let left = saturate(self.left2.process(left)) as i32;
+            let right = saturate(self.right1.process(tank)) as i32;
+            let right = saturate(self.right2.process(right)) as i32;
+
+            frame[0] = crossfade(frame[0], left * 2, mix);
+            frame[1] = crossfade(frame[1], right * 2, mix);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.comb1.reset();
+        self.comb2.reset();
+        self.comb3.reset();
+        self.comb4.reset();
+        self.left1.reset();
+        self.left2.reset();
+        self.right1.reset();
+        self.right2.reset();
+    }
+}
+
+/// Bit depth and sample rate reducer
+///
+/// The bit depth and rate reduction are discrete settings, only the mix is
+/// smoothed.
+pub struct Bitcrusher {
+    bits: u8,
+    downsample: u16,
+    counter: u16,
+    held: StereoFrame,
+    mix: Smoothed,
+}
+
+impl Default for Bitcrusher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bitcrusher {
+    pub const fn new() -> Self {
+        Bitcrusher {
+            bits: 8,
+            downsample: 4,
+            counter: 0,
+            held: [0, 0],
+            mix: Smoothed::new(UNITY, SMOOTHING_FRAMES),
+        }
+    }
+
+    /// Output resolution, from 1 to 16 bits
+    pub fn set_bits(&mut self, bits: u8) {
+        self.bits = bits.clamp(1, 16);
+    }
+
+    /// Keep one frame out of `factor`, 1 disables the rate reduction
+    pub fn set_downsample(&mut self, factor: u16) {
+        self.downsample = factor.max(1);
+    }
+
+    /// Q15 wet/dry balance
+    pub fn set_mix(&mut self, mix: i32) {
+        self.mix.set(mix.clamp(0, UNITY));
+    }
+}
+
+impl Effect for Bitcrusher {
+    fn process(&mut self, block: &mut [StereoFrame]) {
+        let mask = !((1i32 << (16 - self.bits)) - 1);
+
+        for frame in block.iter_mut() {
+            if self.counter == 0 {
+                self.held = [
+                    (frame[0] as i32 & mask) as i16,
+                    (frame[1] as i32 & mask) as i16,
+                ];
+            }
+            self.counter = (self.counter + 1) % self.downsample;
+
+            let mix = self.mix.next_value();
+            frame[0] = crossfade(frame[0], self.held[0] as i32, mix);
+            frame[1] = crossfade(frame[1], self.held[1] as i32, mix);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.counter = 0;
+        self.held = [0, 0];
+    }
+}
+
+/// Waveshaper distortion with a cubic soft clipping curve
+pub struct Overdrive {
+    /// Input gain in 8.8 fixed point
+    drive: Smoothed,
+    level: Smoothed,
+    mix: Smoothed,
+}
+
+impl Default for Overdrive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Overdrive {
+    pub const fn new() -> Self {
+        Overdrive {
+            drive: Smoothed::new(4 << 8, SMOOTHING_FRAMES),
+            level: Smoothed::new(UNITY / 2, SMOOTHING_FRAMES),
+            mix: Smoothed::new(UNITY, SMOOTHING_FRAMES),
+        }
+    }
+
+    /// Input gain in 8.8 fixed point, from 1.0 (256) to 64.0
+    pub fn set_drive(&mut self, drive: i32) {
+        self.drive.set(drive.clamp(1 << 8, 64 << 8));
+    }
+
+    /// Q15 output level
+    pub fn set_level(&mut self, level: i32) {
+        self.level.set(level.clamp(0, UNITY));
+    }
+
+    /// Q15 wet/dry balance
+    pub fn set_mix(&mut self, mix: i32) {
+        self.mix.set(mix.clamp(0, UNITY));
+    }
+
+    #[inline]
+    fn shape(sample: i16, drive: i32) -> i32 {
+        // y = 1.5x - 0.5x^3 for x in [-1, 1]
+        let x = ((sample as i32 * drive) >> 8).clamp(-32767, 32767);
+        let x3 = mul_q15(mul_q15(x, x), x);
+        (3 * x - x3) / 2
+    }
+}
+
+impl Effect for Overdrive {
+    fn process(&mut self, block: &mut [StereoFrame]) {
+        for frame in block.iter_mut() {
+            let drive = self.drive.next_value();
+            let level = self.level.next_value();
+            let mix = self.mix.next_value();
+
+            for sample in frame.iter_mut() {
+                let wet = mul_q15(Self::shape(*sample, drive), level);
+                *sample = crossfade(*sample, wet, mix);
+            }
+        }
+    }
+}
+
+pub const MAX_INSERTS: usize = 4;
+pub const MAX_SENDS: usize = 2;
+
+/// Returned when adding an effect to a full `EffectChain`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ChainFull;
+
+struct SendBus<'a> {
+    effect: &'a mut dyn Effect,
+    level: Smoothed,
+}
+
+/// Insert effects applied in series, followed by send effects applied in
+/// parallel and mixed back into the output
+///
+/// Effects on send buses should have their mix set to 100% wet.
+pub struct EffectChain<'a> {
+    inserts: Vec<&'a mut dyn Effect, MAX_INSERTS>,
+    sends: Vec<SendBus<'a>, MAX_SENDS>,
+}
+
+impl Default for EffectChain<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> EffectChain<'a> {
+    pub const fn new() -> Self {
+        EffectChain {
+            inserts: Vec::new(),
+            sends: Vec::new(),
+        }
+    }
+
+    /// Add an effect at the end of the insert chain, return its index
+    pub fn add_insert(&mut self, effect: &'a mut dyn Effect) -> Result<usize, ChainFull> {
+        self.inserts.push(effect).map_err(|_| ChainFull)?;
+        Ok(self.inserts.len() - 1)
+    }
+
+    /// Add a send bus with a Q15 send level, return its index
+    pub fn add_send(&mut self, effect: &'a mut dyn Effect, level: i32) -> Result<usize, ChainFull> {
+        let bus = SendBus {
+            effect,
+            level: Smoothed::new(level.clamp(0, UNITY), SMOOTHING_FRAMES),
+        };
+        self.sends.push(bus).map_err(|_| ChainFull)?;
+        Ok(self.sends.len() - 1)
+    }
+
+    pub fn set_send_level(&mut self, index: usize, level: i32) {
+        if let Some(bus) = self.sends.get_mut(index) {
+            bus.level.set(level.clamp(0, UNITY));
+        }
+    }
+
+    /// Remove all effects from the chain
+    pub fn clear(&mut self) {
+        self.inserts.clear();
+        self.sends.clear();
+    }
+}
+
+impl Effect for EffectChain<'_> {
+    fn process(&mut self, block: &mut [StereoFrame]) {
+        for effect in self.inserts.iter_mut() {
+            effect.process(block);
+        }
+
+        if self.sends.is_empty() {
+            return;
+        }
+
+        for chunk in block.chunks_mut(BLOCK_SIZE) {
+            let mut dry = [[0i16; 2]; BLOCK_SIZE];
+            dry[..chunk.len()].copy_from_slice(chunk);
+
+            for bus in self.sends.iter_mut() {
+                let mut send = [[0i16; 2]; BLOCK_SIZE];
+                for (out, input) in send.iter_mut().zip(dry.iter()).take(chunk.len()) {
+                    let level = bus.level.next_value();
+                    out[0] = mul_q15(input[0] as i32, level) as i16;
+                    out[1] = mul_q15(input[1] as i32, level) as i16;
+                }
+
+                bus.effect.process(&mut send[..chunk.len()]);
+
+                for (out, wet) in chunk.iter_mut().zip(send.iter()) {
+                    out[0] = out[0].saturating_add(wet[0]);
+                    out[1] = out[1].saturating_add(wet[1]);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for effect in self.inserts.iter_mut() {
+            effect.reset();
+        }
+        for bus in self.sends.iter_mut() {
+            bus.effect.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Output of `effect` for an impulse on the first frame, once reset and
+    /// the parameters settled on silence
+    fn impulse(effect: &mut dyn Effect, impulse: StereoFrame, frames: usize) -> Vec<StereoFrame> {
+        effect.reset();
+        let mut block = [[0, 0]; BLOCK_SIZE];
+        for _ in 0..=4 * SMOOTHING_FRAMES as usize / BLOCK_SIZE {
+            effect.process(&mut block);
+        }
+        let mut out = std::vec![[0, 0]; frames];
+        out[0] = impulse;
+        for chunk in out.chunks_mut(BLOCK_SIZE) {
+            effect.process(chunk);
+        }
+        out
+    }
+
+    /// Frames that are not silent, with their index
+    fn echoes(out: &[StereoFrame]) -> Vec<(usize, StereoFrame)> {
+        out.iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, frame)| frame != [0, 0])
+            .collect()
+    }
+
+    #[test]
+    fn crossfades() {
+        assert_eq!(crossfade(1000, 3000, 0), 1000);
+        assert_eq!(crossfade(1000, 3000, UNITY / 2), 2000);
+        assert_eq!(crossfade(1000, 3000, UNITY), 3000);
+        // Wet signals beyond the sample range
+        assert_eq!(crossfade(i16::MIN, 400_000, UNITY), i16::MAX);
+        assert_eq!(crossfade(i16::MAX, -400_000, UNITY), i16::MIN);
+        assert_eq!(crossfade(0, 400_000, UNITY / 2), 16383);
+    }
+
+    #[test]
+    fn delay_times() {
+        let mut buffer = std::vec![[0, 0]; 8000];
+        let mut delay = Delay::new(&mut buffer);
+        delay.set_feedback(0);
+        delay.set_mix(UNITY);
+
+        delay.set_time_ms(10);
+        let out = impulse(&mut delay, [1000, -1000], 2000);
+        assert_eq!(echoes(&out), [(441, [1000, -1000])]);
+
+        // A sixteenth at 120 BPM lasts 125 ms
+        delay.set_time_synced(120, NoteDivision::Sixteenth);
+        let synced = echoes(&impulse(&mut delay, [1000, 1000], 6000));
+        assert_eq!(synced, [(5512, [1000, 1000])]);
+        delay.set_time_ms(125);
+        assert_eq!(echoes(&impulse(&mut delay, [1000, 1000], 6000)), synced);
+
+        // Limited by the buffer
+        delay.set_time_ms(1000);
+        let out = impulse(&mut delay, [1000, 1000], 8000);
+        assert_eq!(echoes(&out), [(7999, [1000, 1000])]);
+    }
+
+    #[test]
+    fn delay_feedback() {
+        let mut buffer = std::vec![[0, 0]; 2000];
+        let mut delay = Delay::new(&mut buffer);
+        delay.set_time_ms(10);
+        delay.set_feedback(UNITY / 2);
+        delay.set_mix(UNITY);
+        let out = impulse(&mut delay, [16000, -16000], 1400);
+        assert_eq!(
+            echoes(&out),
+            [
+                (441, [16000, -16000]),
+                (882, [8000, -8000]),
+                (1323, [4000, -4000])
+            ]
+        );
+
+        // The echoes of the mono input alternate between the channels
+        delay.set_ping_pong(true);
+        let out = impulse(&mut delay, [16000, 0], 1400);
+        assert_eq!(
+            echoes(&out),
+            [(441, [8000, 0]), (882, [0, 4000]), (1323, [2000, 0])]
+        );
+
+        // Half dry, half wet
+        delay.set_ping_pong(false);
+        delay.set_feedback(0);
+        delay.set_mix(UNITY / 2);
+        let out = impulse(&mut delay, [16000, 16000], 1400);
+        assert_eq!(echoes(&out), [(0, [8000, 8000]), (441, [8000, 8000])]);
+    }
+
+    #[test]
+    fn bitcrusher() {
+        let mut crusher = Bitcrusher::new();
+        crusher.set_bits(4);
+        crusher.set_downsample(3);
+        let mut block: Vec<StereoFrame> = (0..9).map(|i| [i * 0xF00, -i * 0xF00]).collect();
+        crusher.process(&mut block);
+        // Rounded down to 4 bits, held for 3 frames
+        let expected: Vec<StereoFrame> = [[0, 0], [0x2000, -0x3000], [0x5000, -0x6000]]
+            .iter()
+            .flat_map(|&frame| [frame; 3])
+            .collect();
+        assert_eq!(block, expected);
+
+        crusher.reset();
+        crusher.set_bits(16);
+        crusher.set_downsample(1);
+        let input: Vec<StereoFrame> = (0..9).map(|i| [i * 1001, -i * 999]).collect();
+        let mut block = input.clone();
+        crusher.process(&mut block);
+        assert_eq!(block, input);
+    }
+
+    #[test]
+    fn overdrive() {
+        let mut overdrive = Overdrive::new();
+        overdrive.set_drive(1 << 8);
+        overdrive.set_level(UNITY);
+        // 1.5x - 0.5x^3, linear near 0
+        assert_eq!(
+            impulse(&mut overdrive, [16384, -16384], 1)[0],
+            [22528, -22528]
+        );
+        assert_eq!(impulse(&mut overdrive, [100, -100], 1)[0], [150, -150]);
+
+        // Clipped at full scale
+        overdrive.set_drive(64 << 8);
+        let [l, r] = impulse(&mut overdrive, [1000, -1000], 1)[0];
+        assert_eq!(l, i16::MAX);
+        assert!(r <= -i16::MAX, "{}", r);
+
+        overdrive.set_drive(1 << 8);
+        overdrive.set_level(UNITY / 2);
+        assert_eq!(impulse(&mut overdrive, [16384, 100], 1)[0], [11264, 75]);
+        overdrive.set_mix(UNITY / 2);
+        assert_eq!(impulse(&mut overdrive, [16384, 100], 1)[0], [13824, 87]);
+    }
+
+    /// Q15 gain, easy to follow through a chain
+    struct Gain(i32);
+
+    impl Effect for Gain {
+        fn process(&mut self, block: &mut [StereoFrame]) {
+            for sample in block.iter_mut().flatten() {
+                *sample = mul_q15(*sample as i32, self.0) as i16;
+            }
+        }
+    }
+
+    #[test]
+    fn chain_sends() {
+        let (mut insert, mut send, mut quiet) = (Gain(UNITY / 2), Gain(UNITY), Gain(UNITY));
+        let (mut extra, mut loud) = (Gain(UNITY), Gain(UNITY));
+        let mut chain = EffectChain::new();
+        assert_eq!(chain.add_insert(&mut insert), Ok(0));
+        assert_eq!(chain.add_send(&mut send, UNITY / 2), Ok(0));
+        assert_eq!(chain.add_send(&mut quiet, UNITY / 4), Ok(1));
+        assert_eq!(chain.add_send(&mut extra, UNITY), Err(ChainFull));
+
+        // The sends get the output of the inserts, in parallel, and are
+        // mixed with it
+        let mut block = [[16000, -16000]; 3 * BLOCK_SIZE];
+        chain.process(&mut block);
+        assert!(block.iter().all(|&frame| frame == [14000, -14000]));
+
+        chain.set_send_level(0, 0);
+        let mut block = [[16000, -16000]; SMOOTHING_FRAMES as usize + BLOCK_SIZE];
+        chain.process(&mut block);
+        assert_eq!(block[block.len() - 1], [10000, -10000]);
+
+        // Saturated when mixed back
+        chain.clear();
+        chain.add_send(&mut loud, UNITY).unwrap();
+        let mut block = [[30000, -30000]; BLOCK_SIZE];
+        chain.process(&mut block);
+        assert_eq!(block[0], [i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn full_scale_reverb() {
+        let mut reverb = Reverb::new();
+        reverb.set_size(UNITY);
+        reverb.set_damping(0);
+        reverb.set_mix(UNITY);
+
+        let mut peak = 0;
+        for n in 0..400 {
+            // Full scale square wave, 4 blocks per period
+            let level = if n % 4 < 2 { i16::MAX } else { i16::MIN };
+            let mut block = [[level, level]; BLOCK_SIZE];
+            reverb.process(&mut block);
+            peak = block.iter().fold(peak, |peak, f| peak.max(f[0].unsigned_abs()));
+        }
+        assert_eq!(peak, 32768);
+
+        // The tail decays in about 10 s, without wrapping into noise. The
+        // rounding of the feedback leaves a small residue.
+        let mut peak = 0;
+        for _ in 0..7000 {
+            let mut block = [[0, 0]; BLOCK_SIZE];
+            reverb.process(&mut block);
+            peak = block.iter().map(|f| f[0].unsigned_abs()).max().unwrap();
+        }
+        assert!(peak < 1000, "{}", peak);
+    }
+}
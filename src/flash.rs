@@ -0,0 +1,401 @@
+//! On-board QSPI flash layout
+//!
+//! The 2 MiB flash is XIP-mapped at `XIP_BASE`. The program image only uses
+//! the first `PROGRAM_SIZE` bytes (see `memory.x` in the examples), the rest
+//! is split in regions reserved for the crate's storage features.
+//!
+//! Regions are written through `FlashRegion`, an `embedded_storage`
+//! `NorFlash`. `RamFlash` is a stand-in with the same behavior, to test
+//! storage code on the host.
+
+use embedded_storage::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use rp2040_hal::rom_data;
+
+/// Address of the flash in the XIP (execute in place) address space
+pub const XIP_BASE: u32 = 0x1000_0000;
+
+/// Total size of the on-board flash
+pub const FLASH_SIZE: u32 = 2048 * 1024;
+
+/// Space reserved for the program image, including the boot2 block
+pub const PROGRAM_SIZE: u32 = 1024 * 1024;
+
+/// Offset of the sample bank image in flash (see `crate::sampler`)
+pub const SAMPLE_BANK_OFFSET: u32 = PROGRAM_SIZE;
+pub const SAMPLE_BANK_SIZE: u32 = 512 * 1024;
+
+/// Return a slice over a region of the XIP-mapped flash
+///
+/// # Safety
+///
+/// The region must not be erased or programmed while the slice is alive.
+pub unsafe fn xip_slice(offset: u32, size: u32) -> &'static [u8] {
+    debug_assert!(offset + size <= FLASH_SIZE);
+    core::slice::from_raw_parts((XIP_BASE + offset) as *const u8, size as usize)
+}
+
+/// Offset of the USB disk region (see `crate::disk`)
+pub const DISK_OFFSET: u32 = SAMPLE_BANK_OFFSET + SAMPLE_BANK_SIZE;
+pub const DISK_SIZE: u32 = 256 * 1024;
+
+/// Offset of the settings store (see `crate::settings`)
+pub const SETTINGS_OFFSET: u32 = DISK_OFFSET + DISK_SIZE;
+pub const SETTINGS_SIZE: u32 = 64 * 1024;
+
+/// Offset of the filesystem, the rest of the flash (see `crate::fs`)
+pub const FS_OFFSET: u32 = SETTINGS_OFFSET + SETTINGS_SIZE;
+pub const FS_SIZE: u32 = FLASH_SIZE - FS_OFFSET;
+
+/// Smallest erasable unit of the flash
+pub const SECTOR_SIZE: u32 = 4096;
+/// Largest unit programmed at once
+pub const PAGE_SIZE: u32 = 256;
+
+/// 64 KiB block erase command, used by the ROM for aligned ranges
+const BLOCK_ERASE_SIZE: u32 = 65536;
+const BLOCK_ERASE_CMD: u8 = 0xD8;
+
+/// Read unique ID command, followed by 4 dummy bytes
+const READ_UNIQUE_ID_CMD: u8 = 0x4B;
+const READ_UNIQUE_ID_DUMMY: usize = 4;
+
+/// Size of the flash unique ID
+pub const UNIQUE_ID_SIZE: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FlashError {
+    NotAligned,
+    OutOfBounds,
+}
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            FlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+        }
+    }
+}
+
+fn check(offset: u32, len: usize, capacity: usize, align: u32) -> Result<(), FlashError> {
+    if !offset.is_multiple_of(align) || !(len as u32).is_multiple_of(align) {
+        Err(FlashError::NotAligned)
+    } else if offset as usize + len > capacity {
+        Err(FlashError::OutOfBounds)
+    } else {
+        Ok(())
+    }
+}
+
+/// A region of the on-board flash, as a `NorFlash` storage
+///
+/// Flash operations stop the XIP access to the flash, so erase and program
+/// run from RAM with interrupts disabled on the calling core, and with
+/// core1 parked in RAM if it runs a task (see `multicore`). Write from
+/// core0 only.
+pub struct FlashRegion {
+    offset: u32,
+    size: u32,
+}
+
+impl FlashRegion {
+    /// # Safety
+    ///
+    /// Only one `FlashRegion` may cover a given range of the flash, and the
+    /// range must not be read through `xip_slice` while it is written.
+    pub const unsafe fn new(offset: u32, size: u32) -> Self {
+        assert!(offset.is_multiple_of(SECTOR_SIZE) && size.is_multiple_of(SECTOR_SIZE));
+        assert!(offset >= PROGRAM_SIZE && offset + size <= FLASH_SIZE);
+        FlashRegion { offset, size }
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
+impl ErrorType for FlashRegion {
+    type Error = FlashError;
+}
+
+impl ReadNorFlash for FlashRegion {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        check(offset, bytes.len(), self.capacity(), 1)?;
+        let src = unsafe { xip_slice(self.offset + offset, bytes.len() as u32) };
+        bytes.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl NorFlash for FlashRegion {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        let len = to.checked_sub(from).ok_or(FlashError::OutOfBounds)?;
+        check(from, len as usize, self.capacity(), SECTOR_SIZE)?;
+        if len > 0 {
+            unsafe { write_flash(self.offset + from, None, len as usize) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        check(offset, bytes.len(), self.capacity(), 1)?;
+
+        // The ROM programs whole pages: the bytes around the data are left
+        // to 0xFF, which does not change the flash content.
+        let mut offset = self.offset + offset;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let start = (offset % PAGE_SIZE) as usize;
+            let len = bytes.len().min(PAGE_SIZE as usize - start);
+            let mut page = [0xFF; PAGE_SIZE as usize];
+            page[start..start + len].copy_from_slice(&bytes[..len]);
+            unsafe { write_flash(offset - start as u32, Some(&page), page.len()) };
+            offset += len as u32;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+}
+
+/// Bits can be programmed from 1 to 0 several times between erases
+impl MultiwriteNorFlash for FlashRegion {}
+
+/// Pointers to the ROM functions, looked up before the flash is
+/// disconnected
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+    enter_xip: unsafe extern "C" fn(),
+}
+
+/// Copy of the boot2 block, called to restore the fast XIP mode
+static mut BOOT2_COPY: [u32; 64] = [0; 64];
+
+/// Look up the ROM functions and save boot2, while the flash is mapped
+unsafe fn rom_functions() -> RomFunctions {
+    use rom_data::*;
+
+    let boot2 = core::ptr::addr_of_mut!(BOOT2_COPY);
+    core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2 as *mut u32, 64);
+    RomFunctions {
+        connect_internal_flash: connect_internal_flash::ptr(),
+        flash_exit_xip: flash_exit_xip::ptr(),
+        flash_range_erase: flash_range_erase::ptr(),
+        flash_range_program: flash_range_program::ptr(),
+        flash_flush_cache: flash_flush_cache::ptr(),
+        // Thumb code, bit 0 set
+        enter_xip: core::mem::transmute::<usize, unsafe extern "C" fn()>(boot2 as usize + 1),
+    }
+}
+
+/// Erase `len` bytes at `offset`, or program them with `data`
+unsafe fn write_flash(offset: u32, data: Option<&[u8; PAGE_SIZE as usize]>, len: usize) {
+    let rom = rom_functions();
+    let data = data.map_or(core::ptr::null(), |page| page.as_ptr());
+
+    crate::multicore::lockout(|| {
+        cortex_m::interrupt::free(|_| {
+            core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+            write_flash_in_ram(&rom, offset, data, len);
+        })
+    });
+}
+
+/// Must not touch the flash, nor call code that is in flash
+#[inline(never)]
+#[cfg_attr(target_os = "none", link_section = ".data.ram_func")]
+unsafe fn write_flash_in_ram(rom: &RomFunctions, offset: u32, data: *const u8, len: usize) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if data.is_null() {
+        (rom.flash_range_erase)(offset, len, BLOCK_ERASE_SIZE, BLOCK_ERASE_CMD);
+    } else {
+        (rom.flash_range_program)(offset, data, len);
+    }
+    (rom.flash_flush_cache)();
+    (rom.enter_xip)();
+}
+
+/// Read the 64-bit unique ID of the flash chip, which also identifies the
+/// unit since the RP2040 has none. Like `FlashRegion` writes, it parks
+/// core1 and must be called from core0.
+pub fn unique_id() -> [u8; UNIQUE_ID_SIZE] {
+    let mut buf = [0; 1 + READ_UNIQUE_ID_DUMMY + UNIQUE_ID_SIZE];
+    buf[0] = READ_UNIQUE_ID_CMD;
+    unsafe {
+        let rom = rom_functions();
+        crate::multicore::lockout(|| {
+            cortex_m::interrupt::free(|_| {
+                core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+                flash_cmd_in_ram(&rom, buf.as_mut_ptr(), buf.len());
+            })
+        });
+    }
+    let mut id = [0; UNIQUE_ID_SIZE];
+    id.copy_from_slice(&buf[1 + READ_UNIQUE_ID_DUMMY..]);
+    id
+}
+
+/// SSI and QSPI chip select registers, accessed directly as the PAC code
+/// lives in flash
+const SSI_SR: *mut u32 = 0x1800_0028 as *mut u32;
+const SSI_DR0: *mut u32 = 0x1800_0060 as *mut u32;
+const SSI_SR_TFNF: u32 = 1 << 1;
+const SSI_SR_RFNE: u32 = 1 << 3;
+const QSPI_SS_CTRL: *mut u32 = 0x4001_800C as *mut u32;
+const QSPI_SS_OUTOVER_MASK: u32 = 3 << 8;
+const QSPI_SS_OUTOVER_LOW: u32 = 2 << 8;
+const QSPI_SS_OUTOVER_HIGH: u32 = 3 << 8;
+/// Bytes in flight, below the 16 entries of the RX FIFO
+const SSI_MAX_IN_FLIGHT: usize = 14;
+
+/// Send a raw command, `buf` is replaced by the bytes read back. Must not
+/// touch the flash, like `write_flash_in_ram`.
+#[inline(never)]
+#[cfg_attr(target_os = "none", link_section = ".data.ram_func")]
+unsafe fn flash_cmd_in_ram(rom: &RomFunctions, buf: *mut u8, len: usize) {
+    use core::ptr::{read_volatile, write_volatile};
+
+    let chip_select = |outover: u32| {
+        let ctrl = read_volatile(QSPI_SS_CTRL) & !QSPI_SS_OUTOVER_MASK;
+        write_volatile(QSPI_SS_CTRL, ctrl | outover);
+    };
+
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    chip_select(QSPI_SS_OUTOVER_LOW);
+    let (mut sent, mut received) = (0, 0);
+    while received < len {
+        let status = read_volatile(SSI_SR);
+        if status & SSI_SR_TFNF != 0 && sent < len && sent - received < SSI_MAX_IN_FLIGHT {
+            write_volatile(SSI_DR0, *buf.add(sent) as u32);
+            sent += 1;
+        }
+        if status & SSI_SR_RFNE != 0 {
+            *buf.add(received) = read_volatile(SSI_DR0) as u8;
+            received += 1;
+        }
+    }
+    chip_select(QSPI_SS_OUTOVER_HIGH);
+    (rom.flash_flush_cache)();
+    (rom.enter_xip)();
+    // Back to the SSI control of the chip select
+    chip_select(0);
+}
+
+/// Flash stand-in in RAM, for tests and host tools. Like the real flash,
+/// programming can only clear bits.
+pub struct RamFlash<const SIZE: usize> {
+    data: [u8; SIZE],
+    erase_count: u32,
+    power_budget: Option<u32>,
+}
+
+impl<const SIZE: usize> Default for RamFlash<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> RamFlash<SIZE> {
+    /// An erased flash
+    pub const fn new() -> Self {
+        RamFlash {
+            data: [0xFF; SIZE],
+            erase_count: 0,
+            power_budget: None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Number of sectors erased so far
+    pub fn erase_count(&self) -> u32 {
+        self.erase_count
+    }
+
+    /// Simulate a power loss after `count` more bytes programmed or
+    /// sectors erased: later operations succeed but leave the flash
+    /// unchanged. `None` restores the power.
+    pub fn power_loss_after(&mut self, count: Option<u32>) {
+        self.power_budget = count;
+    }
+
+    /// Take one unit of the power budget, false once the power is lost
+    fn powered(&mut self) -> bool {
+        match &mut self.power_budget {
+            None => true,
+            Some(0) => false,
+            Some(budget) => {
+                *budget -= 1;
+                true
+            }
+        }
+    }
+}
+
+impl<const SIZE: usize> ErrorType for RamFlash<SIZE> {
+    type Error = FlashError;
+}
+
+impl<const SIZE: usize> ReadNorFlash for RamFlash<SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        check(offset, bytes.len(), SIZE, 1)?;
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.data[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for RamFlash<SIZE> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        let len = to.checked_sub(from).ok_or(FlashError::OutOfBounds)?;
+        check(from, len as usize, SIZE, SECTOR_SIZE)?;
+        for sector in (from..to).step_by(SECTOR_SIZE as usize) {
+            if self.powered() {
+                let start = sector as usize;
+                self.data[start..start + SECTOR_SIZE as usize].fill(0xFF);
+                self.erase_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        check(offset, bytes.len(), SIZE, 1)?;
+        let start = offset as usize;
+        for (i, &byte) in bytes.iter().enumerate() {
+            if self.powered() {
+                self.data[start + i] &= byte;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const SIZE: usize> MultiwriteNorFlash for RamFlash<SIZE> {}
@@ -0,0 +1,1100 @@
+//! Flash filesystem
+//!
+//! Files and directories for projects, patterns, kits and samples, in the
+//! last region of the flash (`flash::FS_OFFSET`):
+//!
+//! ```ignore
+//! let fs = &mut periph.fs;
+//! fs.create_dir("/projects")?;
+//! fs.write_file("/projects/song.prj", &data)?;
+//!
+//! let len = fs.read("/projects/song.prj", 0, &mut buffer)?;
+//! fs.read_dir("/projects", |entry| info!("{} {}", entry.name.as_str(), entry.size))?;
+//! ```
+//!
+//! The filesystem is a log of records spread over the erase sectors of a
+//! `NorFlash`: file data in chunks of `CHUNK_SIZE` bytes, and entries that
+//! give the name, parent directory and size of a file or directory. Changes
+//! append records to the newest sector. When the flash is full, the live
+//! records of the oldest sector are copied to the newest one and the oldest
+//! sector is erased, so the sectors are written in turn.
+//!
+//! A file is replaced atomically: the new data chunks carry a new
+//! generation number and only become the content of the file when its entry
+//! is written, after the data. On a power loss, the file keeps its previous
+//! content.
+//!
+//! The directory tree is kept in RAM, up to `MAX_FILES` files and
+//! directories. Reading a file scans the record headers of the log, so
+//! read large files with large buffers.
+//!
+//! `Fs` works on any `NorFlash`, e.g. `flash::RamFlash` in tests. On the
+//! PGB-1 it uses a `flash::FlashRegion`, which erases and programs the
+//! flash from RAM.
+//!
+//! A record interrupted by a power loss is cleared to zeros when the
+//! filesystem is mounted, zero bytes are skipped by 4 as padding.
+//!
+//! Sector layout, little endian:
+//!
+//! | Offset | Size | Field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | `MAGIC`, written last                         |
+//! | 4      | 4    | sequence number, order of the sectors in the log |
+//! | 8      |      | records                                       |
+//!
+//! Record layout, padded with 0xFF to a multiple of 4 bytes:
+//!
+//! | Offset | Size | Field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 1    | kind: 1 directory, 2 file, 3 removed, 4 data  |
+//! | 1      | 1    | 0xFF                                          |
+//! | 2      | 2    | payload length                                |
+//! | 4      | 4    | CRC-32 of the record, with 0xFF in this field |
+//! | 8      | 2    | file id                                       |
+//! | 10     | 2    | entries: parent id, data: chunk index         |
+//! | 12     | 4    | generation of the file data                   |
+//! | 16     |      | entries: file size (4 bytes) and name, data: chunk |
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+use heapless::{String, Vec};
+
+use crate::crc::crc32;
+use crate::flash::FlashRegion;
+
+pub const MAGIC: [u8; 4] = *b"PGFS";
+
+pub const MAX_NAME_SIZE: usize = 32;
+/// Files and directories, the root directory excluded
+pub const MAX_FILES: usize = 128;
+/// Data bytes per record
+pub const CHUNK_SIZE: usize = 256;
+
+const MAX_SECTORS: usize = 64;
+/// Free sectors kept to move the live records of the oldest sector
+const RESERVED_SECTORS: usize = 1;
+
+const SECTOR_HEADER_SIZE: u32 = 8;
+const RECORD_HEADER_SIZE: usize = 16;
+const MAX_RECORD_SIZE: usize = RECORD_HEADER_SIZE + CHUNK_SIZE;
+
+const KIND_PADDING: u8 = 0;
+const KIND_DIR: u8 = 1;
+const KIND_FILE: u8 = 2;
+const KIND_REMOVED: u8 = 3;
+const KIND_DATA: u8 = 4;
+
+const ROOT: u16 = 0;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FsError {
+    Flash(NorFlashErrorKind),
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// Empty path, name longer than `MAX_NAME_SIZE`, or a directory moved
+    /// inside itself
+    InvalidPath,
+    /// More than `MAX_FILES` files and directories
+    TooManyFiles,
+    /// No space left on the flash
+    Full,
+    /// The file write failed or another file is being written, the file
+    /// is not changed
+    Interrupted,
+}
+
+impl<E: NorFlashError> From<E> for FsError {
+    fn from(err: E) -> Self {
+        FsError::Flash(err.kind())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DirEntry {
+    pub name: String<MAX_NAME_SIZE>,
+    pub is_dir: bool,
+    /// File size in bytes, 0 for directories
+    pub size: u32,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    id: u16,
+    parent: u16,
+    is_dir: bool,
+    generation: u32,
+    size: u32,
+    name: String<MAX_NAME_SIZE>,
+    /// Flash address of the entry record
+    address: u32,
+}
+
+impl Node {
+    fn entry(&self) -> DirEntry {
+        DirEntry {
+            name: self.name.clone(),
+            is_dir: self.is_dir,
+            size: self.size,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Header {
+    kind: u8,
+    len: usize,
+    id: u16,
+    /// Parent id of entries, chunk index of data
+    arg: u16,
+    generation: u32,
+}
+
+impl Header {
+    fn decode(buf: &[u8]) -> Self {
+        Header {
+            kind: buf[0],
+            len: u16::from_le_bytes([buf[2], buf[3]]) as usize,
+            id: u16::from_le_bytes([buf[8], buf[9]]),
+            arg: u16::from_le_bytes([buf[10], buf[11]]),
+            generation: u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]),
+        }
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.kind;
+        buf[1] = 0xFF;
+        buf[2..4].copy_from_slice(&(self.len as u16).to_le_bytes());
+        buf[4..8].fill(0xFF);
+        buf[8..10].copy_from_slice(&self.id.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.arg.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.generation.to_le_bytes());
+    }
+
+    /// Size in flash, with the padding
+    fn size(&self) -> u32 {
+        record_size(self.len)
+    }
+}
+
+fn record_size(payload: usize) -> u32 {
+    (RECORD_HEADER_SIZE + payload).next_multiple_of(4) as u32
+}
+
+/// A file being written, see `Fs::create`
+pub struct FileWriter {
+    id: u16,
+    parent: u16,
+    name: String<MAX_NAME_SIZE>,
+    generation: u32,
+    size: u32,
+    chunk: [u8; CHUNK_SIZE],
+    chunk_len: usize,
+}
+
+/// Filesystem on a `NorFlash` of at least three erase sectors
+pub struct Fs<F> {
+    flash: F,
+    sectors: u32,
+    /// Sectors of the log, oldest first, the last one is written
+    log: Vec<u32, MAX_SECTORS>,
+    /// Sequence number of the newest sector
+    sequence: u32,
+    /// End of the valid records of each sector
+    ends: [u32; MAX_SECTORS],
+    nodes: Vec<Node, MAX_FILES>,
+    last_id: u16,
+    last_generation: u32,
+    /// Data of the file being written, live although not in `nodes`
+    pending: Option<(u16, u32)>,
+    collecting: bool,
+}
+
+/// The filesystem in the on-board flash
+pub type FlashFs = Fs<FlashRegion>;
+
+impl<F: NorFlash> Fs<F> {
+    /// Mount the filesystem, formatting the flash if it does not hold one
+    pub fn new(flash: F) -> Result<Self, FsError> {
+        let mut fs = Self::unmounted(flash);
+        let mut found: Vec<(u32, u32), MAX_SECTORS> = Vec::new();
+        for sector in 0..fs.sectors {
+            let mut header = [0; SECTOR_HEADER_SIZE as usize];
+            fs.flash.read(fs.address(sector, 0), &mut header)?;
+            if header[..4] == MAGIC {
+                let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+                let _ = found.push((sequence, sector));
+            }
+        }
+        if found.is_empty() {
+            fs.format()?;
+            return Ok(fs);
+        }
+
+        found.sort_unstable();
+        for &(sequence, sector) in &found {
+            let _ = fs.log.push(sector);
+            fs.sequence = sequence;
+            fs.ends[sector as usize] = fs.check_sector(sector)?;
+        }
+        fs.replay()?;
+        Ok(fs)
+    }
+
+    /// Erase the flash and mount an empty filesystem, to start again when
+    /// `new` fails
+    pub fn formatted(flash: F) -> Result<Self, FsError> {
+        let mut fs = Self::unmounted(flash);
+        fs.format()?;
+        Ok(fs)
+    }
+
+    fn unmounted(flash: F) -> Self {
+        assert!(4 % F::WRITE_SIZE == 0 && F::ERASE_SIZE >= 2 * MAX_RECORD_SIZE);
+        let sectors = (flash.capacity() / F::ERASE_SIZE) as u32;
+        assert!(sectors >= 3 && sectors as usize <= MAX_SECTORS);
+
+        Fs {
+            flash,
+            sectors,
+            log: Vec::new(),
+            sequence: 0,
+            ends: [0; MAX_SECTORS],
+            nodes: Vec::new(),
+            last_id: ROOT,
+            last_generation: 0,
+            pending: None,
+            collecting: false,
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Erase all the files
+    pub fn format(&mut self) -> Result<(), FsError> {
+        for sector in 0..self.sectors {
+            if !self.is_erased(sector)? {
+                let start = self.address(sector, 0);
+                self.flash.erase(start, start + self.sector_size())?;
+            }
+        }
+        self.log.clear();
+        self.nodes.clear();
+        self.sequence = 0;
+        self.last_id = ROOT;
+        self.last_generation = 0;
+        self.pending = None;
+        self.open_sector(0, 0)
+    }
+
+    /// Size of the filesystem, in bytes
+    pub fn capacity(&self) -> u32 {
+        self.slots() * CHUNK_SIZE as u32
+    }
+
+    /// Space left for the data of a new file, in bytes
+    pub fn free_space(&self) -> u32 {
+        // Each record uses at most one slot, plus the entry of the new file
+        let used: u32 = self
+            .nodes
+            .iter()
+            .map(|node| 1 + node.size.div_ceil(CHUNK_SIZE as u32))
+            .sum();
+        self.slots().saturating_sub(used + 1) * CHUNK_SIZE as u32
+    }
+
+    /// Number of records of the largest size that fit in the flash, not
+    /// counting the reserved sectors
+    fn slots(&self) -> u32 {
+        let per_sector = (self.sector_size() - SECTOR_HEADER_SIZE) / record_size(CHUNK_SIZE);
+        (self.sectors - RESERVED_SECTORS as u32) * per_sector
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.resolve(path).is_ok()
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<DirEntry, FsError> {
+        match self.resolve(path)? {
+            ROOT => Ok(DirEntry {
+                name: String::new(),
+                is_dir: true,
+                size: 0,
+            }),
+            id => Ok(self.node(id).ok_or(FsError::NotFound)?.entry()),
+        }
+    }
+
+    /// Call `f` for each entry of a directory
+    pub fn read_dir(&self, path: &str, mut f: impl FnMut(&DirEntry)) -> Result<(), FsError> {
+        let id = self.resolve_dir(path)?;
+        for node in self.nodes.iter().filter(|node| node.parent == id) {
+            f(&node.entry());
+        }
+        Ok(())
+    }
+
+    pub fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if self.child(parent, name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        if self.nodes.is_full() {
+            return Err(FsError::TooManyFiles);
+        }
+        let node = Node {
+            id: self.new_id(),
+            parent,
+            is_dir: true,
+            generation: 0,
+            size: 0,
+            name: String::try_from(name).map_err(|_| FsError::InvalidPath)?,
+            address: 0,
+        };
+        self.write_entry(node)
+    }
+
+    /// Remove a file or an empty directory
+    pub fn remove(&mut self, path: &str) -> Result<(), FsError> {
+        let id = self.resolve(path)?;
+        if id == ROOT {
+            return Err(FsError::InvalidPath);
+        }
+        if self.nodes.iter().any(|node| node.parent == id) {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        let header = Header {
+            kind: KIND_REMOVED,
+            len: 0,
+            id,
+            arg: 0,
+            generation: 0,
+        };
+        self.append(&header, &[], &[])?;
+        self.nodes.retain(|node| node.id != id);
+        Ok(())
+    }
+
+    /// Move or rename a file or directory, `to` must not exist
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let id = self.resolve(from)?;
+        let (parent, name) = self.resolve_parent(to)?;
+        if id == ROOT {
+            return Err(FsError::InvalidPath);
+        }
+        if self.child(parent, name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        // A directory cannot move inside itself
+        let mut ancestor = parent;
+        while ancestor != ROOT {
+            if ancestor == id {
+                return Err(FsError::InvalidPath);
+            }
+            ancestor = self.node(ancestor).map_or(ROOT, |node| node.parent);
+        }
+
+        let mut node = self.node(id).ok_or(FsError::NotFound)?.clone();
+        node.parent = parent;
+        node.name = String::try_from(name).map_err(|_| FsError::InvalidPath)?;
+        self.write_entry(node)
+    }
+
+    /// Read from `offset` of a file, return the number of bytes read
+    pub fn read(&mut self, path: &str, offset: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        let id = self.resolve(path)?;
+        let node = self.node(id).ok_or(FsError::IsADirectory)?;
+        if node.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        let (generation, size) = (node.generation, node.size);
+        let end = size.min(offset.saturating_add(buf.len() as u32));
+        if offset >= end {
+            return Ok(0);
+        }
+
+        for i in 0..self.log.len() {
+            let sector = self.log[i];
+            let mut offset_in_sector = SECTOR_HEADER_SIZE;
+            while let Some((address, header)) = self.next_record(sector, &mut offset_in_sector)? {
+                if header.kind != KIND_DATA || header.id != id || header.generation != generation {
+                    continue;
+                }
+
+                // Overlap of the chunk and the requested range
+                let chunk_start = header.arg as u32 * CHUNK_SIZE as u32;
+                let from = chunk_start.max(offset);
+                let to = (chunk_start + header.len as u32).min(end);
+                if from < to {
+                    let src = address + RECORD_HEADER_SIZE as u32 + (from - chunk_start);
+                    let dst = (from - offset) as usize..(to - offset) as usize;
+                    self.flash.read(src, &mut buf[dst])?;
+                }
+            }
+        }
+        Ok((end - offset) as usize)
+    }
+
+    /// Create or replace a file with `data`
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let mut writer = self.create(path)?;
+        self.write(&mut writer, data)?;
+        self.commit(writer)
+    }
+
+    /// Start writing a file, the data is written with `write` and the file
+    /// created or replaced by `commit`. Until then the file keeps its
+    /// previous content. Only one file can be written at a time, and a
+    /// write that failed cannot be committed.
+    pub fn create(&mut self, path: &str) -> Result<FileWriter, FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let id = match self.child(parent, name) {
+            Some(node) if node.is_dir => return Err(FsError::IsADirectory),
+            Some(node) => node.id,
+            None if self.nodes.is_full() => return Err(FsError::TooManyFiles),
+            None => self.new_id(),
+        };
+        self.last_generation += 1;
+        self.pending = Some((id, self.last_generation));
+        Ok(FileWriter {
+            id,
+            parent,
+            name: String::try_from(name).map_err(|_| FsError::InvalidPath)?,
+            generation: self.last_generation,
+            size: 0,
+            chunk: [0; CHUNK_SIZE],
+            chunk_len: 0,
+        })
+    }
+
+    pub fn write(&mut self, writer: &mut FileWriter, mut data: &[u8]) -> Result<(), FsError> {
+        if self.pending != Some((writer.id, writer.generation)) {
+            return Err(FsError::Interrupted);
+        }
+        while !data.is_empty() {
+            let len = data.len().min(CHUNK_SIZE - writer.chunk_len);
+            writer.chunk[writer.chunk_len..writer.chunk_len + len].copy_from_slice(&data[..len]);
+            writer.chunk_len += len;
+            data = &data[len..];
+            if writer.chunk_len == CHUNK_SIZE {
+                self.write_chunk(writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn commit(&mut self, mut writer: FileWriter) -> Result<(), FsError> {
+        if self.pending != Some((writer.id, writer.generation)) {
+            return Err(FsError::Interrupted);
+        }
+        if writer.chunk_len > 0 {
+            self.write_chunk(&mut writer)?;
+        }
+        if writer.parent != ROOT && self.node(writer.parent).is_none() {
+            return Err(FsError::NotFound);
+        }
+        let exists = self.node(writer.id).is_some();
+        if !exists && self.nodes.is_full() {
+            return Err(FsError::TooManyFiles);
+        }
+
+        let node = Node {
+            id: writer.id,
+            parent: writer.parent,
+            is_dir: false,
+            generation: writer.generation,
+            size: writer.size,
+            name: writer.name,
+            address: 0,
+        };
+        self.pending = None;
+        self.write_entry(node)
+    }
+
+    fn write_chunk(&mut self, writer: &mut FileWriter) -> Result<(), FsError> {
+        let header = Header {
+            kind: KIND_DATA,
+            len: writer.chunk_len,
+            id: writer.id,
+            arg: (writer.size / CHUNK_SIZE as u32) as u16,
+            generation: writer.generation,
+        };
+        if let Err(err) = self.append(&header, &writer.chunk[..writer.chunk_len], &[]) {
+            self.pending = None;
+            return Err(err);
+        }
+        writer.size += writer.chunk_len as u32;
+        writer.chunk_len = 0;
+        Ok(())
+    }
+
+    /// Write the entry of a file or directory and update the tree
+    fn write_entry(&mut self, mut node: Node) -> Result<(), FsError> {
+        let header = Header {
+            kind: if node.is_dir { KIND_DIR } else { KIND_FILE },
+            len: 4 + node.name.len(),
+            id: node.id,
+            arg: node.parent,
+            generation: node.generation,
+        };
+        node.address = self.append(&header, &node.size.to_le_bytes(), node.name.as_bytes())?;
+        self.set_node(node);
+        Ok(())
+    }
+
+    fn set_node(&mut self, node: Node) {
+        match self.nodes.iter_mut().find(|n| n.id == node.id) {
+            Some(n) => *n = node,
+            None => {
+                let _ = self.nodes.push(node);
+            }
+        }
+    }
+
+    fn node(&self, id: u16) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    fn child(&self, parent: u16, name: &str) -> Option<&Node> {
+        self.nodes
+            .iter()
+            .find(|node| node.parent == parent && node.name == name)
+    }
+
+    fn new_id(&mut self) -> u16 {
+        if self.last_id < u16::MAX {
+            self.last_id += 1;
+            return self.last_id;
+        }
+        // Ids of removed files can be used again, their data is never
+        // read as it has an older generation
+        (1..u16::MAX)
+            .find(|&id| self.node(id).is_none() && self.pending.is_none_or(|(p, _)| p != id))
+            .unwrap_or(u16::MAX)
+    }
+
+    /// Id of the file or directory at `path`
+    fn resolve(&self, path: &str) -> Result<u16, FsError> {
+        let mut id = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if id != ROOT && !self.node(id).is_some_and(|node| node.is_dir) {
+                return Err(FsError::NotADirectory);
+            }
+            id = self.child(id, name).ok_or(FsError::NotFound)?.id;
+        }
+        Ok(id)
+    }
+
+    fn resolve_dir(&self, path: &str) -> Result<u16, FsError> {
+        let id = self.resolve(path)?;
+        if id != ROOT && !self.node(id).is_some_and(|node| node.is_dir) {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(id)
+    }
+
+    /// Parent directory and name of the last component of `path`
+    fn resolve_parent<'a>(&self, path: &'a str) -> Result<(u16, &'a str), FsError> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name.len() > MAX_NAME_SIZE {
+            return Err(FsError::InvalidPath);
+        }
+        Ok((self.resolve_dir(dir)?, name))
+    }
+
+    fn sector_size(&self) -> u32 {
+        F::ERASE_SIZE as u32
+    }
+
+    fn address(&self, sector: u32, offset: u32) -> u32 {
+        sector * self.sector_size() + offset
+    }
+
+    fn head(&self) -> u32 {
+        self.log[self.log.len() - 1]
+    }
+
+    fn is_erased(&mut self, sector: u32) -> Result<bool, FsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let mut offset = 0;
+        while offset < self.sector_size() {
+            let len = (self.sector_size() - offset).min(buf.len() as u32);
+            self.flash
+                .read(self.address(sector, offset), &mut buf[..len as usize])?;
+            if buf[..len as usize].iter().any(|&b| b != 0xFF) {
+                return Ok(false);
+            }
+            offset += len;
+        }
+        Ok(true)
+    }
+
+    /// Add an erased sector at the end of the log. The magic is written
+    /// after the sequence number, so that the sector is only valid once its
+    /// header is complete.
+    fn open_sector(&mut self, sector: u32, sequence: u32) -> Result<(), FsError> {
+        self.flash
+            .write(self.address(sector, 4), &sequence.to_le_bytes())?;
+        self.flash.write(self.address(sector, 0), &MAGIC)?;
+        let _ = self.log.push(sector);
+        self.sequence = sequence;
+        self.ends[sector as usize] = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    /// End of the records of a sector. The data of a write interrupted by
+    /// a power loss is cleared to zeros, so that records can be appended
+    /// after it.
+    fn check_sector(&mut self, sector: u32) -> Result<u32, FsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let mut offset = SECTOR_HEADER_SIZE;
+        while offset < self.sector_size() {
+            if offset + RECORD_HEADER_SIZE as u32 <= self.sector_size() {
+                let address = self.address(sector, offset);
+                self.flash.read(address, &mut buf[..RECORD_HEADER_SIZE])?;
+                let header = Header::decode(&buf);
+                if header.kind == KIND_PADDING {
+                    offset += 4;
+                    continue;
+                }
+                let valid_kind = (KIND_DIR..=KIND_DATA).contains(&header.kind);
+                if valid_kind
+                    && header.len <= CHUNK_SIZE
+                    && offset + header.size() <= self.sector_size()
+                {
+                    let len = RECORD_HEADER_SIZE + header.len;
+                    self.flash.read(
+                        address + RECORD_HEADER_SIZE as u32,
+                        &mut buf[RECORD_HEADER_SIZE..len],
+                    )?;
+                    let crc = [buf[4], buf[5], buf[6], buf[7]];
+                    buf[4..8].fill(0xFF);
+                    if crc32(&buf[..len]).to_le_bytes() == crc {
+                        offset += header.size();
+                        continue;
+                    }
+                }
+            }
+
+            // Erased up to the end of the sector, or interrupted write
+            match self.programmed_end(sector, offset)? {
+                None => break,
+                Some(end) => {
+                    self.clear(sector, offset, end)?;
+                    offset = end;
+                }
+            }
+        }
+        Ok(offset)
+    }
+
+    /// End of the last programmed byte after `offset`, rounded up to 4
+    fn programmed_end(&mut self, sector: u32, offset: u32) -> Result<Option<u32>, FsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let mut end = None;
+        let mut from = offset;
+        while from < self.sector_size() {
+            let len = (self.sector_size() - from).min(buf.len() as u32);
+            self.flash
+                .read(self.address(sector, from), &mut buf[..len as usize])?;
+            if let Some(last) = buf[..len as usize].iter().rposition(|&b| b != 0xFF) {
+                end = Some((from + last as u32 + 1).next_multiple_of(4));
+            }
+            from += len;
+        }
+        Ok(end)
+    }
+
+    /// Program zeros from `offset` to `end`
+    fn clear(&mut self, sector: u32, mut offset: u32, end: u32) -> Result<(), FsError> {
+        let zeros = [0; MAX_RECORD_SIZE];
+        while offset < end {
+            let len = (end - offset).min(zeros.len() as u32);
+            self.flash
+                .write(self.address(sector, offset), &zeros[..len as usize])?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Next record of a sector from `offset`, skipping the padding
+    fn next_record(
+        &mut self,
+        sector: u32,
+        offset: &mut u32,
+    ) -> Result<Option<(u32, Header)>, FsError> {
+        while *offset < self.ends[sector as usize]
+            && *offset + RECORD_HEADER_SIZE as u32 <= self.sector_size()
+        {
+            let address = self.address(sector, *offset);
+            let header = self.read_header(address)?;
+            if header.kind == KIND_PADDING {
+                *offset += 4;
+                continue;
+            }
+            *offset += header.size();
+            return Ok(Some((address, header)));
+        }
+        Ok(None)
+    }
+
+    /// Whether a data record of `sector` is also in another sector of the
+    /// log
+    fn is_copied(&mut self, sector: u32, data: &Header) -> Result<bool, FsError> {
+        for i in 0..self.log.len() {
+            let other = self.log[i];
+            if other == sector {
+                continue;
+            }
+            let mut offset = SECTOR_HEADER_SIZE;
+            while let Some((_, header)) = self.next_record(other, &mut offset)? {
+                if header.kind == KIND_DATA
+                    && header.id == data.id
+                    && header.generation == data.generation
+                    && header.arg == data.arg
+                {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn read_header(&mut self, address: u32) -> Result<Header, FsError> {
+        let mut buf = [0; RECORD_HEADER_SIZE];
+        self.flash.read(address, &mut buf)?;
+        Ok(Header::decode(&buf))
+    }
+
+    /// Rebuild the tree from the log
+    fn replay(&mut self) -> Result<(), FsError> {
+        for i in 0..self.log.len() {
+            let sector = self.log[i];
+            let mut offset = SECTOR_HEADER_SIZE;
+            while let Some((address, header)) = self.next_record(sector, &mut offset)? {
+                self.last_id = self.last_id.max(header.id);
+                self.last_generation = self.last_generation.max(header.generation);
+
+                match header.kind {
+                    KIND_DIR | KIND_FILE => {
+                        let mut payload = [0; 4 + MAX_NAME_SIZE];
+                        let len = header.len.min(payload.len());
+                        self.flash
+                            .read(address + RECORD_HEADER_SIZE as u32, &mut payload[..len])?;
+                        let name = core::str::from_utf8(&payload[4..len]).unwrap_or("?");
+                        let node = Node {
+                            id: header.id,
+                            parent: header.arg,
+                            is_dir: header.kind == KIND_DIR,
+                            generation: header.generation,
+                            size: u32::from_le_bytes([
+                                payload[0], payload[1], payload[2], payload[3],
+                            ]),
+                            name: String::try_from(name).unwrap_or_default(),
+                            address,
+                        };
+                        if self.node(node.id).is_none() && self.nodes.is_full() {
+                            return Err(FsError::TooManyFiles);
+                        }
+                        self.set_node(node);
+                    }
+                    KIND_REMOVED => self.nodes.retain(|node| node.id != header.id),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Append a record with `payload` then `tail` as payload, return its
+    /// address
+    fn append(&mut self, header: &Header, payload: &[u8], tail: &[u8]) -> Result<u32, FsError> {
+        let size = header.size();
+        let mut collections = 0;
+        while self.ends[self.head() as usize] + size > self.sector_size() {
+            if self.log.len() + RESERVED_SECTORS < self.sectors as usize || self.collecting {
+                self.next_sector()?;
+            } else if collections < self.sectors {
+                self.collect()?;
+                collections += 1;
+            } else {
+                return Err(FsError::Full);
+            }
+        }
+
+        let mut buf = [0xFF; MAX_RECORD_SIZE];
+        header.encode(&mut buf);
+        let len = RECORD_HEADER_SIZE + payload.len() + tail.len();
+        buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload.len()].copy_from_slice(payload);
+        buf[RECORD_HEADER_SIZE + payload.len()..len].copy_from_slice(tail);
+        let crc = crc32(&buf[..len]);
+        buf[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        let head = self.head();
+        let address = self.address(head, self.ends[head as usize]);
+        self.flash.write(address, &buf[..size as usize])?;
+        self.ends[head as usize] += size;
+        Ok(address)
+    }
+
+    /// Start a new sector after the head, the next free one
+    fn next_sector(&mut self) -> Result<(), FsError> {
+        let head = self.head();
+        let sector = (1..self.sectors)
+            .map(|i| (head + i) % self.sectors)
+            .find(|sector| !self.log.contains(sector))
+            .ok_or(FsError::Full)?;
+        if !self.is_erased(sector)? {
+            let start = self.address(sector, 0);
+            self.flash.erase(start, start + self.sector_size())?;
+        }
+        self.open_sector(sector, self.sequence.wrapping_add(1))
+    }
+
+    /// Move the live records of the oldest sector to the head and erase it
+    fn collect(&mut self) -> Result<(), FsError> {
+        let oldest = self.log[0];
+        if oldest == self.head() {
+            return Err(FsError::Full);
+        }
+
+        self.collecting = true;
+        let result = self.move_live_records(oldest);
+        self.collecting = false;
+        result?;
+
+        self.log.remove(0);
+        let start = self.address(oldest, 0);
+        self.flash.erase(start, start + self.sector_size())?;
+        Ok(())
+    }
+
+    fn move_live_records(&mut self, sector: u32) -> Result<(), FsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let mut offset = SECTOR_HEADER_SIZE;
+        while let Some((address, header)) = self.next_record(sector, &mut offset)? {
+            let live = match header.kind {
+                KIND_DIR | KIND_FILE => self.node(header.id).is_some_and(|n| n.address == address),
+                KIND_DATA => {
+                    let committed = self
+                        .node(header.id)
+                        .is_some_and(|n| !n.is_dir && n.generation == header.generation);
+                    let pending = self.pending == Some((header.id, header.generation));
+                    // Already copied by a collection interrupted by a power loss
+                    (committed || pending) && !self.is_copied(sector, &header)?
+                }
+                // All the older records are in this sector
+                _ => false,
+            };
+            if !live {
+                continue;
+            }
+
+            let len = RECORD_HEADER_SIZE + header.len;
+            self.flash.read(address, &mut buf[..len])?;
+            let new_address = self.append(&header, &buf[RECORD_HEADER_SIZE..len], &[])?;
+            if let Some(node) = self.nodes.iter_mut().find(|n| n.address == address) {
+                node.address = new_address;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{RamFlash, SECTOR_SIZE};
+
+    const SIZE: usize = 16 * SECTOR_SIZE as usize;
+
+    type TestFs = Fs<RamFlash<SIZE>>;
+
+    fn new_fs() -> Box<TestFs> {
+        Box::new(Fs::new(RamFlash::new()).unwrap())
+    }
+
+    fn remount(fs: Box<TestFs>) -> Box<TestFs> {
+        let mut flash = fs.into_inner();
+        flash.power_loss_after(None);
+        Box::new(Fs::new(flash).unwrap())
+    }
+
+    fn data(len: usize, seed: u8) -> std::vec::Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    fn read_all(fs: &mut TestFs, path: &str) -> std::vec::Vec<u8> {
+        let size = fs.metadata(path).unwrap().size as usize;
+        let mut buf = vec![0; size];
+        assert_eq!(fs.read(path, 0, &mut buf).unwrap(), size);
+        buf
+    }
+
+    fn list(fs: &TestFs, path: &str) -> std::vec::Vec<std::string::String> {
+        let mut names = std::vec::Vec::new();
+        fs.read_dir(path, |entry| names.push(entry.name.as_str().into()))
+            .unwrap();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn files_and_directories() {
+        let mut fs = new_fs();
+        fs.create_dir("/projects").unwrap();
+        fs.create_dir("/samples").unwrap();
+        fs.create_dir("/samples/kits").unwrap();
+        fs.write_file("/projects/song.prj", &data(1000, 1)).unwrap();
+        fs.write_file("/samples/kits/kick.raw", &data(5000, 2))
+            .unwrap();
+        fs.write_file("/empty", &[]).unwrap();
+
+        assert_eq!(fs.create_dir("/projects"), Err(FsError::AlreadyExists));
+        assert_eq!(fs.create_dir("/nothing/here"), Err(FsError::NotFound));
+        assert_eq!(
+            fs.write_file("/empty/file", &[]),
+            Err(FsError::NotADirectory)
+        );
+        assert_eq!(fs.write_file("/projects", &[]), Err(FsError::IsADirectory));
+        assert_eq!(fs.remove("/samples"), Err(FsError::DirectoryNotEmpty));
+        assert_eq!(
+            fs.rename("/samples", "/samples/kits/x"),
+            Err(FsError::InvalidPath)
+        );
+        assert_eq!(
+            fs.create_dir("/a-very-long-directory-name-that-does-not-fit"),
+            Err(FsError::InvalidPath)
+        );
+
+        let mut fs = remount(fs);
+        assert_eq!(list(&fs, "/"), ["empty", "projects", "samples"]);
+        assert_eq!(list(&fs, "samples"), ["kits"]);
+        assert_eq!(read_all(&mut fs, "/projects/song.prj"), data(1000, 1));
+        assert_eq!(read_all(&mut fs, "samples/kits/kick.raw"), data(5000, 2));
+        assert_eq!(fs.metadata("/empty").unwrap().size, 0);
+
+        // Partial reads
+        let mut buf = [0; 300];
+        assert_eq!(
+            fs.read("/samples/kits/kick.raw", 250, &mut buf).unwrap(),
+            300
+        );
+        assert_eq!(buf[..], data(5000, 2)[250..550]);
+        assert_eq!(
+            fs.read("/samples/kits/kick.raw", 4900, &mut buf).unwrap(),
+            100
+        );
+        assert_eq!(
+            fs.read("/samples/kits/kick.raw", 6000, &mut buf).unwrap(),
+            0
+        );
+
+        fs.rename("/samples/kits/kick.raw", "/projects/kick")
+            .unwrap();
+        fs.rename("/samples/kits", "/kits").unwrap();
+        fs.remove("/samples").unwrap();
+        fs.remove("/empty").unwrap();
+
+        let mut fs = remount(fs);
+        assert_eq!(list(&fs, "/"), ["kits", "projects"]);
+        assert_eq!(list(&fs, "/projects"), ["kick", "song.prj"]);
+        assert_eq!(read_all(&mut fs, "/projects/kick"), data(5000, 2));
+        assert!(!fs.exists("/samples/kits/kick.raw"));
+    }
+
+    #[test]
+    fn garbage_collection() {
+        let mut fs = new_fs();
+        let capacity = fs.capacity();
+        assert_eq!(fs.free_space(), capacity - CHUNK_SIZE as u32);
+
+        fs.write_file("/static", &data(10_000, 3)).unwrap();
+        // Rewrite far more than the capacity of the flash
+        for i in 0..200 {
+            fs.write_file("/changing", &data(3000 + i, i as u8))
+                .unwrap();
+        }
+        // 40 + 13 chunks and 2 entries, plus the entry of a new file
+        assert_eq!(fs.free_space(), capacity - 56 * CHUNK_SIZE as u32);
+
+        let mut fs = remount(fs);
+        assert_eq!(read_all(&mut fs, "/static"), data(10_000, 3));
+        assert_eq!(read_all(&mut fs, "/changing"), data(3199, 199));
+
+        // Sectors are used in turn
+        let flash = fs.into_inner();
+        assert!(flash.erase_count() > 100);
+
+        let mut fs = Box::new(Fs::new(flash).unwrap());
+        let big = data(fs.free_space() as usize + 4 * CHUNK_SIZE, 4);
+        assert_eq!(fs.write_file("/big", &big), Err(FsError::Full));
+        // The failed write did not damage the files
+        let mut fs = remount(fs);
+        assert!(!fs.exists("/big"));
+        assert_eq!(read_all(&mut fs, "/static"), data(10_000, 3));
+        let free = fs.free_space() as usize;
+        fs.write_file("/big", &big[..free]).unwrap();
+        assert_eq!(fs.free_space(), 0);
+    }
+
+    #[test]
+    fn formatted() {
+        let mut fs = new_fs();
+        fs.create_dir("/projects").unwrap();
+        for i in 0..40 {
+            fs.write_file("/projects/song.prj", &data(3000, i)).unwrap();
+        }
+
+        let mut fs = Box::new(Fs::formatted(fs.into_inner()).unwrap());
+        assert_eq!(list(&fs, "/"), [""; 0]);
+        fs.write_file("/new", &data(100, 5)).unwrap();
+
+        let mut fs = remount(fs);
+        assert_eq!(list(&fs, "/"), ["new"]);
+        assert_eq!(read_all(&mut fs, "/new"), data(100, 5));
+        assert_eq!(fs.free_space(), fs.capacity() - 3 * CHUNK_SIZE as u32);
+    }
+
+    #[test]
+    fn atomic_replace() {
+        let mut fs = new_fs();
+        fs.create_dir("/projects").unwrap();
+        fs.write_file("/projects/song.prj", &data(2000, 1)).unwrap();
+        // Fill the flash so that some writes move records
+        for i in 0..13 {
+            fs.write_file("/filler", &data(4000, i)).unwrap();
+        }
+        let image = fs.into_inner().as_bytes().to_vec();
+
+        for count in (0..6000).step_by(37) {
+            let mut flash = RamFlash::<SIZE>::new();
+            flash.write(0, &image).unwrap();
+            flash.power_loss_after(Some(count));
+            let mut fs = Box::new(Fs::new(flash).unwrap());
+            let _ = fs.write_file("/projects/song.prj", &data(2500, 2));
+            let _ = fs.write_file("/filler", &data(4000, 100));
+
+            let mut fs = remount(fs);
+            let song = read_all(&mut fs, "/projects/song.prj");
+            assert!(song == data(2000, 1) || song == data(2500, 2), "{}", count);
+            let filler = read_all(&mut fs, "/filler");
+            assert!(
+                filler == data(4000, 12) || filler == data(4000, 100),
+                "{}",
+                count
+            );
+
+            // Still usable after the power loss
+            fs.write_file("/projects/song.prj", &data(100, 3)).unwrap();
+            let mut fs = remount(fs);
+            assert_eq!(read_all(&mut fs, "/projects/song.prj"), data(100, 3));
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Pattern generators
+//!
+//! A `Generator` fills the steps of a track:
+//!
+//! - `Euclid`: `hits` spread as evenly as possible over `steps`, shifted
+//!   later by `rotation` steps. The track gets `steps` steps.
+//! - `Random`: each step plays with a `density` percent chance.
+//! - `Arpeggio`: a note every `rate` steps, walking the notes of a scale
+//!   over `octaves` from `root`.
+//!
+//! The rhythm generators keep the notes of the steps. Random choices come
+//! from an `Rng` seeded with `seed`, the same settings always give the same
+//! steps.
+//!
+//! The settings are parameters of `PARAMS`, edited from a menu with
+//! `ui::GeneratorMenu` which previews the steps before they replace the
+//! track.
+
+use crate::param::{Format, ParamId, ParamInfo, ParamSet};
+use crate::project::{Sequence, MAX_STEPS};
+use crate::rng::Rng;
+
+pub const KIND: ParamId = 0;
+pub const HITS: ParamId = 1;
+pub const STEPS: ParamId = 2;
+pub const ROTATION: ParamId = 3;
+pub const DENSITY: ParamId = 4;
+pub const SCALE: ParamId = 5;
+pub const ROOT: ParamId = 6;
+pub const OCTAVES: ParamId = 7;
+pub const ARP_MODE: ParamId = 8;
+pub const RATE: ParamId = 9;
+pub const SEED: ParamId = 10;
+pub const PARAM_COUNT: usize = 11;
+
+pub static PARAMS: [ParamInfo; PARAM_COUNT] = [
+    ParamInfo::new("Generator", 0, 2, 0).format(Format::Choice(&["Euclid", "Random", "Arp"])),
+    ParamInfo::new("Hits", 0, MAX_STEPS as i16, 4),
+    ParamInfo::new("Steps", 1, MAX_STEPS as i16, 16),
+    ParamInfo::new("Rotation", 0, MAX_STEPS as i16 - 1, 0),
+    ParamInfo::new("Density", 0, 100, 50).format(Format::Percent),
+    ParamInfo::new("Scale", 0, 5, 0).format(Format::Choice(&[
+        "Major",
+        "Minor",
+        "Dorian",
+        "Penta",
+        "Min penta",
+        "Chromatic",
+    ])),
+    ParamInfo::new("Root", 0, 127, 48).format(Format::Note),
+    ParamInfo::new("Octaves", 1, 4, 1),
+    ParamInfo::new("Arp mode", 0, 3, 0)
+        .format(Format::Choice(&["Up", "Down", "Up/down", "Random"])),
+    ParamInfo::new("Rate", 1, 16, 1),
+    ParamInfo::new("Seed", 1, 9999, 1),
+];
+
+/// Parameters of each kind of generator, to show in a `ui::ParamMenu`
+pub static EUCLID_ITEMS: [ParamId; 4] = [KIND, HITS, STEPS, ROTATION];
+pub static RANDOM_ITEMS: [ParamId; 3] = [KIND, DENSITY, SEED];
+pub static ARPEGGIO_ITEMS: [ParamId; 7] = [KIND, SCALE, ROOT, OCTAVES, ARP_MODE, RATE, SEED];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Scale {
+    Major,
+    Minor,
+    Dorian,
+    Pentatonic,
+    MinorPentatonic,
+    Chromatic,
+}
+
+impl Scale {
+    /// Semitones from the root
+    pub fn intervals(self) -> &'static [u8] {
+        match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Pentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// Up then down, without repeating the highest and lowest notes
+    UpDown,
+    Random,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Generator {
+    Euclid {
+        hits: u8,
+        steps: u8,
+        rotation: u8,
+    },
+    Random {
+        /// Percent of steps played
+        density: u8,
+        seed: u32,
+    },
+    Arpeggio {
+        scale: Scale,
+        root: u8,
+        octaves: u8,
+        mode: ArpMode,
+        /// Steps between the notes
+        rate: u8,
+        seed: u32,
+    },
+}
+
+impl Generator {
+    /// Generator set by the values of `PARAMS`
+    pub fn from_params<const N: usize>(params: &ParamSet<N>) -> Self {
+        let value = |id| params.get(id).max(0) as u8;
+        match params.get(KIND) {
+            0 => Generator::Euclid {
+                hits: value(HITS),
+                steps: value(STEPS),
+                rotation: value(ROTATION),
+            },
+            1 => Generator::Random {
+                density: value(DENSITY),
+                seed: params.get(SEED) as u32,
+            },
+            _ => Generator::Arpeggio {
+                scale: match params.get(SCALE) {
+                    0 => Scale::Major,
+                    1 => Scale::Minor,
+                    2 => Scale::Dorian,
+                    3 => Scale::Pentatonic,
+                    4 => Scale::MinorPentatonic,
+                    _ => Scale::Chromatic,
+                },
+                root: value(ROOT),
+                octaves: value(OCTAVES),
+                mode: match params.get(ARP_MODE) {
+                    0 => ArpMode::Up,
+                    1 => ArpMode::Down,
+                    2 => ArpMode::UpDown,
+                    _ => ArpMode::Random,
+                },
+                rate: value(RATE),
+                seed: params.get(SEED) as u32,
+            },
+        }
+    }
+
+    /// Parameters of the generator kind, see `EUCLID_ITEMS`
+    pub fn items(&self) -> &'static [ParamId] {
+        match self {
+            Generator::Euclid { .. } => &EUCLID_ITEMS,
+            Generator::Random { .. } => &RANDOM_ITEMS,
+            Generator::Arpeggio { .. } => &ARPEGGIO_ITEMS,
+        }
+    }
+
+    /// Replace the steps of a sequence
+    pub fn fill(&self, sequence: &mut Sequence) {
+        match *self {
+            Generator::Euclid {
+                hits,
+                steps,
+                rotation,
+            } => {
+                let steps = (steps as usize).clamp(1, MAX_STEPS);
+                let hits = (hits as usize).min(steps);
+                let rotation = rotation as usize % steps;
+                sequence.length = steps as u8;
+                for (i, step) in sequence.steps.iter_mut().enumerate() {
+                    let j = (i + steps - rotation) % steps;
+                    step.active = i < steps && (j * hits) % steps < hits;
+                }
+            }
+            Generator::Random { density, seed } => {
+                let mut rng = Rng::new(seed);
+                let length = sequence.length as usize;
+                for (i, step) in sequence.steps.iter_mut().enumerate() {
+                    step.active = i < length && rng.chance(density);
+                }
+            }
+            Generator::Arpeggio {
+                scale,
+                root,
+                octaves,
+                mode,
+                rate,
+                seed,
+            } => {
+                let intervals = scale.intervals();
+                let count = intervals.len() * octaves.max(1) as usize;
+                let note = |k: usize| {
+                    let octave = (k / intervals.len()) as u32;
+                    let note = root as u32 + 12 * octave + intervals[k % intervals.len()] as u32;
+                    note.min(127) as u8
+                };
+                let mut rng = Rng::new(seed);
+                let rate = rate.max(1) as usize;
+                let length = sequence.length as usize;
+                for (i, step) in sequence.steps.iter_mut().enumerate() {
+                    step.active = i < length && i.is_multiple_of(rate);
+                    if !step.active {
+                        continue;
+                    }
+                    let k = i / rate;
+                    step.note = note(match mode {
+                        ArpMode::Up => k % count,
+                        ArpMode::Down => count - 1 - k % count,
+                        ArpMode::UpDown if count < 2 => 0,
+                        ArpMode::UpDown => {
+                            let k = k % (2 * count - 2);
+                            if k < count {
+                                k
+                            } else {
+                                2 * count - 2 - k
+                            }
+                        }
+                        ArpMode::Random => rng.below(count as u32) as usize,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn rhythm(sequence: &Sequence) -> std::string::String {
+        sequence.steps[..sequence.length as usize]
+            .iter()
+            .map(|step| if step.active { 'x' } else { '.' })
+            .collect()
+    }
+
+    fn euclid(hits: u8, steps: u8, rotation: u8) -> std::string::String {
+        let mut sequence = Sequence::new();
+        sequence.steps[40].active = true;
+        Generator::Euclid {
+            hits,
+            steps,
+            rotation,
+        }
+        .fill(&mut sequence);
+        assert!(!sequence.steps[sequence.length as usize..]
+            .iter()
+            .any(|s| s.active));
+        rhythm(&sequence)
+    }
+
+    #[test]
+    fn euclidean() {
+        assert_eq!(euclid(3, 8, 0), "x..x..x.");
+        assert_eq!(euclid(5, 8, 0), "x.x.xx.x");
+        assert_eq!(euclid(4, 16, 0), "x...x...x...x...");
+        assert_eq!(euclid(3, 8, 2), "x.x..x..");
+        assert_eq!(euclid(3, 8, 10), euclid(3, 8, 2));
+        assert_eq!(euclid(0, 4, 0), "....");
+        assert_eq!(euclid(9, 4, 0), "xxxx");
+        assert_eq!(euclid(1, 0, 0), "x");
+    }
+
+    #[test]
+    fn random_density() {
+        let mut sequence = Sequence::new();
+        sequence.length = 64;
+        sequence.steps[0].note = 36;
+        let generator = Generator::Random {
+            density: 25,
+            seed: 7,
+        };
+        generator.fill(&mut sequence);
+        let first = rhythm(&sequence);
+        let hits = first.matches('x').count();
+        assert!((8..=24).contains(&hits), "{}", first);
+        assert_eq!(sequence.steps[0].note, 36);
+
+        generator.fill(&mut sequence);
+        assert_eq!(rhythm(&sequence), first);
+        Generator::Random {
+            density: 25,
+            seed: 8,
+        }
+        .fill(&mut sequence);
+        assert_ne!(rhythm(&sequence), first);
+
+        for (density, expected) in [(0, 0), (100, 64)] {
+            Generator::Random { density, seed: 1 }.fill(&mut sequence);
+            assert_eq!(rhythm(&sequence).matches('x').count(), expected);
+        }
+    }
+
+    fn arpeggio(scale: Scale, octaves: u8, mode: ArpMode, rate: u8) -> Vec<u8> {
+        let mut sequence = Sequence::new();
+        sequence.length = 12;
+        Generator::Arpeggio {
+            scale,
+            root: 60,
+            octaves,
+            mode,
+            rate,
+            seed: 3,
+        }
+        .fill(&mut sequence);
+        sequence.steps[..12]
+            .iter()
+            .filter(|step| step.active)
+            .map(|step| step.note)
+            .collect()
+    }
+
+    #[test]
+    fn arpeggios() {
+        assert_eq!(
+            arpeggio(Scale::Pentatonic, 2, ArpMode::Up, 1),
+            [60, 62, 64, 67, 69, 72, 74, 76, 79, 81, 60, 62]
+        );
+        assert_eq!(
+            arpeggio(Scale::MinorPentatonic, 1, ArpMode::Down, 2),
+            [70, 67, 65, 63, 60, 70]
+        );
+        assert_eq!(
+            arpeggio(Scale::Major, 1, ArpMode::UpDown, 1),
+            [60, 62, 64, 65, 67, 69, 71, 69, 67, 65, 64, 62]
+        );
+        let random = arpeggio(Scale::Minor, 1, ArpMode::Random, 3);
+        assert_eq!(random.len(), 4);
+        assert!(random
+            .iter()
+            .all(|note| Scale::Minor.intervals().contains(&(note - 60))));
+        assert_eq!(arpeggio(Scale::Minor, 1, ArpMode::Random, 3), random);
+    }
+
+    #[test]
+    fn parameters() {
+        let mut params = ParamSet::new(&PARAMS);
+        assert_eq!(
+            Generator::from_params(&params),
+            Generator::Euclid {
+                hits: 4,
+                steps: 16,
+                rotation: 0
+            }
+        );
+        params.set(KIND, 2);
+        params.set(SCALE, 3);
+        params.set(ARP_MODE, 2);
+        let generator = Generator::from_params(&params);
+        assert_eq!(
+            generator,
+            Generator::Arpeggio {
+                scale: Scale::Pentatonic,
+                root: 48,
+                octaves: 1,
+                mode: ArpMode::UpDown,
+                rate: 1,
+                seed: 1
+            }
+        );
+        assert_eq!(generator.items(), ARPEGGIO_ITEMS);
+    }
+}
@@ -0,0 +1,845 @@
+//! Undo and redo of the edits of a project
+//!
+//! `History` changes the project and records each edit as a delta: where
+//! the value is and the value it replaced. Undoing swaps the recorded value
+//! with the one of the project, the same delta then redoes the edit.
+//!
+//! Deltas take 4 to 13 bytes in a buffer of `N` bytes, the memory budget of
+//! the history: when it is full the oldest edits are forgotten. Edits of
+//! several values, like `set_sequence`, are undone at once. An edit larger
+//! than the whole buffer, up to about 850 bytes for a sequence, cannot be
+//! undone and clears the history.
+//!
+//! The song arrangement, tempo and swing are not recorded. Changes made to
+//! the project without `History` are not recorded either, they stay in
+//! place when other edits are undone. An edit that would put back more
+//! locks than its pattern has room for, after locks were added that way,
+//! is not undone.
+
+use heapless::Vec;
+
+use crate::param::ParamId;
+use crate::project::{
+    Condition, LocksFull, Project, Sequence, Step, MAX_SOUND_PARAMS, MAX_STEPS, PATTERNS,
+};
+
+/// Kinds of delta, saved at both ends of the delta to read the buffer in
+/// both directions
+const STEP: u8 = 1;
+const LENGTH: u8 = 2;
+const LOCK: u8 = 3;
+const SOUND: u8 = 4;
+/// Flag of the kind, the delta belongs to the same edit as the previous one
+const CONTINUED: u8 = 0x80;
+/// Flag of the lock parameter, the step was locked
+const LOCKED: u8 = 0x80;
+
+/// Size of the largest delta, a step
+pub const MAX_DELTA_SIZE: usize = 13;
+
+fn delta_size(kind: u8) -> usize {
+    match kind & !CONTINUED {
+        STEP => MAX_DELTA_SIZE,
+        LENGTH => 4,
+        LOCK => 7,
+        _ => 6,
+    }
+}
+
+/// Previous value at a place of the project
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Delta {
+    Step {
+        pattern: u8,
+        track: u8,
+        index: u8,
+        step: Step,
+    },
+    Length {
+        pattern: u8,
+        track: u8,
+        length: u8,
+    },
+    Lock {
+        pattern: u8,
+        track: u8,
+        step: u8,
+        param: u8,
+        value: Option<i16>,
+    },
+    Sound {
+        track: u8,
+        param: u8,
+        value: i16,
+    },
+}
+
+impl Delta {
+    fn encode(&self, continued: bool) -> ([u8; MAX_DELTA_SIZE], usize) {
+        // Pattern and track in one byte, for up to 32 patterns of 8 tracks
+        let place = |pattern: u8, track: u8| (pattern << 3) | track;
+        let mut bytes = [0; MAX_DELTA_SIZE];
+        let kind = match *self {
+            Delta::Step {
+                pattern,
+                track,
+                index,
+                step,
+            } => {
+                let [condition, arg] = step.condition.encode();
+                bytes[1..12].copy_from_slice(&[
+                    place(pattern, track),
+                    index,
+                    step.active as u8,
+                    step.note,
+                    step.velocity,
+                    step.gate,
+                    step.offset as u8,
+                    condition,
+                    arg,
+                    step.retrigs,
+                    step.decay,
+                ]);
+                STEP
+            }
+            Delta::Length {
+                pattern,
+                track,
+                length,
+            } => {
+                bytes[1..3].copy_from_slice(&[place(pattern, track), length]);
+                LENGTH
+            }
+            Delta::Lock {
+                pattern,
+                track,
+                step,
+                param,
+                value,
+            } => {
+                let [lo, hi] = value.unwrap_or(0).to_le_bytes();
+                let flag = if value.is_some() { LOCKED } else { 0 };
+                bytes[1..6].copy_from_slice(&[place(pattern, track), step, param | flag, lo, hi]);
+                LOCK
+            }
+            Delta::Sound {
+                track,
+                param,
+                value,
+            } => {
+                let [lo, hi] = value.to_le_bytes();
+                bytes[1..5].copy_from_slice(&[track, param, lo, hi]);
+                SOUND
+            }
+        };
+        let kind = if continued { kind | CONTINUED } else { kind };
+        let size = delta_size(kind);
+        bytes[0] = kind;
+        bytes[size - 1] = kind;
+        (bytes, size)
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let (pattern, track) = (bytes[1] >> 3, bytes[1] & 0x7);
+        match bytes[0] & !CONTINUED {
+            STEP => Delta::Step {
+                pattern,
+                track,
+                index: bytes[2],
+                step: Step {
+                    active: bytes[3] != 0,
+                    note: bytes[4],
+                    velocity: bytes[5],
+                    gate: bytes[6],
+                    offset: bytes[7] as i8,
+                    // Always valid, encoded from a step
+                    condition: Condition::decode([bytes[8], bytes[9]]).unwrap_or(Condition::Always),
+                    retrigs: bytes[10],
+                    decay: bytes[11],
+                },
+            },
+            LENGTH => Delta::Length {
+                pattern,
+                track,
+                length: bytes[2],
+            },
+            LOCK => Delta::Lock {
+                pattern,
+                track,
+                step: bytes[2],
+                param: bytes[3] & !LOCKED,
+                value: (bytes[3] & LOCKED != 0).then(|| i16::from_le_bytes([bytes[4], bytes[5]])),
+            },
+            _ => Delta::Sound {
+                track: bytes[1],
+                param: bytes[2],
+                value: i16::from_le_bytes([bytes[3], bytes[4]]),
+            },
+        }
+    }
+
+    /// Same place of the project, whatever the values
+    fn same_place(&self, other: &Delta) -> bool {
+        match (*self, *other) {
+            (
+                Delta::Length { pattern, track, .. },
+                Delta::Length {
+                    pattern: p,
+                    track: t,
+                    ..
+                },
+            ) => (pattern, track) == (p, t),
+            (
+                Delta::Lock {
+                    pattern,
+                    track,
+                    step,
+                    param,
+                    ..
+                },
+                Delta::Lock {
+                    pattern: p,
+                    track: t,
+                    step: s,
+                    param: q,
+                    ..
+                },
+            ) => (pattern, track, step, param) == (p, t, s, q),
+            (
+                Delta::Sound { track, param, .. },
+                Delta::Sound {
+                    track: t, param: q, ..
+                },
+            ) => (track, param) == (t, q),
+            _ => false,
+        }
+    }
+
+    /// Put the value in the project, returns the delta that puts back the
+    /// value it replaced
+    fn apply(self, project: &mut Project) -> Self {
+        match self {
+            Delta::Step {
+                pattern,
+                track,
+                index,
+                step,
+            } => {
+                let sequence = &mut project.patterns[pattern as usize].tracks[track as usize];
+                let step = core::mem::replace(&mut sequence.steps[index as usize], step);
+                Delta::Step {
+                    pattern,
+                    track,
+                    index,
+                    step,
+                }
+            }
+            Delta::Length {
+                pattern,
+                track,
+                length,
+            } => {
+                let sequence = &mut project.patterns[pattern as usize].tracks[track as usize];
+                let length = core::mem::replace(&mut sequence.length, length);
+                Delta::Length {
+                    pattern,
+                    track,
+                    length,
+                }
+            }
+            Delta::Lock {
+                pattern,
+                track,
+                step,
+                param,
+                value,
+            } => {
+                let (t, s) = (track as usize, step as usize);
+                let p = &mut project.patterns[pattern as usize];
+                let previous = p.lock(t, s, param);
+                match value {
+                    Some(value) => {
+                        let added = p.set_lock(t, s, param, value);
+                        debug_assert!(added.is_ok(), "room for the lock checked by History");
+                    }
+                    None => p.clear_lock(t, s, Some(param)),
+                }
+                Delta::Lock {
+                    pattern,
+                    track,
+                    step,
+                    param,
+                    value: previous,
+                }
+            }
+            Delta::Sound {
+                track,
+                param,
+                value,
+            } => {
+                let sound = &mut project.tracks[track as usize].sound;
+                let value = match sound.get_mut(param as usize) {
+                    Some(current) => core::mem::replace(current, value),
+                    None => value,
+                };
+                Delta::Sound {
+                    track,
+                    param,
+                    value,
+                }
+            }
+        }
+    }
+}
+
+/// Bounded undo/redo history of a project, using `N` bytes
+pub struct History<const N: usize> {
+    buf: [u8; N],
+    /// Bytes of the recorded deltas
+    len: usize,
+    /// End of the edits that can be undone, those after it can be redone
+    cursor: usize,
+    /// Nesting of `begin`
+    depth: u8,
+    /// Start of the edit in progress between `begin` and `end`
+    group: usize,
+    /// The edit in progress did not fit in the buffer
+    lost: bool,
+    /// The last delta was a single edit, that absorbs new values of the same
+    /// parameter
+    mergeable: bool,
+}
+
+impl<const N: usize> Default for History<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> History<N> {
+    pub const fn new() -> Self {
+        History {
+            buf: [0; N],
+            len: 0,
+            cursor: 0,
+            depth: 0,
+            group: 0,
+            lost: false,
+            mergeable: false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.cursor = 0;
+        self.group = 0;
+        self.mergeable = false;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.cursor > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.cursor < self.len
+    }
+
+    /// Bytes used by the recorded edits, out of `N`
+    pub fn used(&self) -> usize {
+        self.len
+    }
+
+    /// Start an edit made of several changes, undone at once, until the
+    /// matching `end`
+    pub fn begin(&mut self) {
+        if self.depth == 0 {
+            self.group = self.cursor;
+            self.lost = false;
+        }
+        self.depth += 1;
+        self.mergeable = false;
+    }
+
+    pub fn end(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    /// Undo the last edit, returns false if there is none or if its
+    /// pattern has no room for the locks it puts back
+    pub fn undo(&mut self, project: &mut Project) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        let mut start = self.cursor;
+        loop {
+            let kind = self.buf[start - 1];
+            start -= delta_size(kind);
+            if kind & CONTINUED == 0 {
+                break;
+            }
+        }
+        if !self.has_room(start, self.cursor, project) {
+            return false;
+        }
+        self.mergeable = false;
+        while self.cursor > start {
+            self.cursor -= delta_size(self.buf[self.cursor - 1]);
+            self.swap(self.cursor, project);
+        }
+        true
+    }
+
+    /// Redo the last undone edit, returns false if there is none or if its
+    /// pattern has no room for the locks it puts back
+    pub fn redo(&mut self, project: &mut Project) -> bool {
+        if self.cursor == self.len {
+            return false;
+        }
+        let mut end = self.cursor + delta_size(self.buf[self.cursor]);
+        while end < self.len && self.buf[end] & CONTINUED != 0 {
+            end += delta_size(self.buf[end]);
+        }
+        if !self.has_room(self.cursor, end, project) {
+            return false;
+        }
+        self.mergeable = false;
+        while self.cursor < end {
+            self.swap(self.cursor, project);
+            self.cursor += delta_size(self.buf[self.cursor]);
+        }
+        true
+    }
+
+    /// Replace a step of a track
+    pub fn set_step(
+        &mut self,
+        project: &mut Project,
+        pattern: usize,
+        track: usize,
+        index: usize,
+        step: Step,
+    ) {
+        let current = &mut project.patterns[pattern].tracks[track].steps[index];
+        if *current != step {
+            let step = core::mem::replace(current, step);
+            self.record(Delta::Step {
+                pattern: pattern as u8,
+                track: track as u8,
+                index: index as u8,
+                step,
+            });
+        }
+    }
+
+    /// Change the number of steps of a track, 1..=`MAX_STEPS`
+    pub fn set_length(&mut self, project: &mut Project, pattern: usize, track: usize, length: u8) {
+        let length = length.clamp(1, MAX_STEPS as u8);
+        let current = &mut project.patterns[pattern].tracks[track].length;
+        if *current != length {
+            let length = core::mem::replace(current, length);
+            self.record(Delta::Length {
+                pattern: pattern as u8,
+                track: track as u8,
+                length,
+            });
+        }
+    }
+
+    /// Replace the steps of a track, recording only the steps that change
+    pub fn set_sequence(
+        &mut self,
+        project: &mut Project,
+        pattern: usize,
+        track: usize,
+        sequence: &Sequence,
+    ) {
+        self.begin();
+        self.set_length(project, pattern, track, sequence.length);
+        for (index, &step) in sequence.steps.iter().enumerate() {
+            self.set_step(project, pattern, track, index, step);
+        }
+        self.end();
+    }
+
+    /// Set or remove (`None`) the lock of a parameter for a step, see
+    /// `Pattern::set_lock`
+    pub fn set_lock(
+        &mut self,
+        project: &mut Project,
+        pattern: usize,
+        track: usize,
+        step: usize,
+        param: u8,
+        value: Option<i16>,
+    ) -> Result<(), LocksFull> {
+        let p = &mut project.patterns[pattern];
+        let previous = p.lock(track, step, param);
+        match value {
+            Some(value) => p.set_lock(track, step, param, value)?,
+            None => p.clear_lock(track, step, Some(param)),
+        }
+        if p.lock(track, step, param) != previous {
+            self.record(Delta::Lock {
+                pattern: pattern as u8,
+                track: track as u8,
+                step: step as u8,
+                param,
+                value: previous,
+            });
+        }
+        Ok(())
+    }
+
+    /// Remove all the locks of a step
+    pub fn clear_locks(
+        &mut self,
+        project: &mut Project,
+        pattern: usize,
+        track: usize,
+        step: usize,
+    ) {
+        let params: Vec<u8, MAX_SOUND_PARAMS> = project.patterns[pattern]
+            .step_locks(track, step)
+            .map(|(param, _)| param)
+            .collect();
+        self.begin();
+        for param in params {
+            // Removing a lock cannot fail
+            let _ = self.set_lock(project, pattern, track, step, param, None);
+        }
+        self.end();
+    }
+
+    /// Change a sound parameter of a track, parameters beyond
+    /// `Track::sound` are ignored
+    pub fn set_sound(&mut self, project: &mut Project, track: usize, param: ParamId, value: i16) {
+        let Some(current) = project.tracks[track].sound.get_mut(param as usize) else {
+            return;
+        };
+        if *current != value {
+            let value = core::mem::replace(current, value);
+            self.record(Delta::Sound {
+                track: track as u8,
+                param: param as u8,
+                value,
+            });
+        }
+    }
+
+    /// Whether the patterns have room for the locks that the deltas between
+    /// `start` and `end` put back
+    fn has_room(&self, start: usize, end: usize, project: &Project) -> bool {
+        let mut added = [0; PATTERNS];
+        let mut at = start;
+        while at < end {
+            let size = delta_size(self.buf[at]);
+            if let Delta::Lock {
+                pattern,
+                track,
+                step,
+                param,
+                value: Some(_),
+            } = Delta::decode(&self.buf[at..at + size])
+            {
+                let p = &project.patterns[pattern as usize];
+                if p.lock(track as usize, step as usize, param).is_none() {
+                    added[pattern as usize] += 1;
+                }
+            }
+            at += size;
+        }
+        added
+            .iter()
+            .zip(&project.patterns)
+            .all(|(&added, p)| added <= p.free_locks())
+    }
+
+    fn swap(&mut self, start: usize, project: &mut Project) {
+        let kind = self.buf[start];
+        let size = delta_size(kind);
+        let delta = Delta::decode(&self.buf[start..start + size]).apply(project);
+        let (bytes, _) = delta.encode(kind & CONTINUED != 0);
+        self.buf[start..start + size].copy_from_slice(&bytes[..size]);
+    }
+
+    fn record(&mut self, delta: Delta) {
+        // A new edit replaces the undone ones
+        self.len = self.cursor;
+
+        let grouped = self.depth > 0;
+        if grouped && self.lost {
+            return;
+        }
+        // New values of the same length, lock or sound parameter are the
+        // same edit, the delta keeps the value from before the first one
+        if self.mergeable && !grouped && self.cursor > 0 {
+            let size = delta_size(self.buf[self.cursor - 1]);
+            let last = Delta::decode(&self.buf[self.cursor - size..self.cursor]);
+            if last.same_place(&delta) {
+                return;
+            }
+        }
+
+        let continued = grouped && self.len > self.group;
+        let (bytes, size) = delta.encode(continued);
+        while self.len + size > N {
+            if self.len == 0 || (continued && self.group == 0) {
+                // The edit does not fit: it cannot be undone, nor the older ones
+                self.clear();
+                self.lost = grouped;
+                return;
+            }
+            self.drop_oldest();
+        }
+        self.buf[self.len..self.len + size].copy_from_slice(&bytes[..size]);
+        self.len += size;
+        self.cursor = self.len;
+        self.mergeable = !grouped && !matches!(delta, Delta::Step { .. });
+    }
+
+    fn drop_oldest(&mut self) {
+        let mut end = delta_size(self.buf[0]);
+        while end < self.len && self.buf[end] & CONTINUED != 0 {
+            end += delta_size(self.buf[end]);
+        }
+        self.buf.copy_within(end..self.len, 0);
+        self.len -= end;
+        self.cursor -= end;
+        self.group = self.group.saturating_sub(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::Generator;
+
+    fn active(project: &Project, track: usize) -> usize {
+        let sequence = &project.patterns[0].tracks[track];
+        sequence.steps.iter().filter(|step| step.active).count()
+    }
+
+    fn on(note: u8) -> Step {
+        Step {
+            active: true,
+            note,
+            condition: Condition::Cycle { n: 2, m: 4 },
+            offset: -5,
+            ..Step::new()
+        }
+    }
+
+    #[test]
+    fn steps_and_lengths() {
+        let mut project = Project::new();
+        let mut history = History::<256>::new();
+        assert!(!history.undo(&mut project));
+
+        history.set_step(&mut project, 0, 1, 3, on(64));
+        history.set_step(&mut project, 0, 1, 3, on(65));
+        history.set_length(&mut project, 0, 1, 12);
+        history.set_length(&mut project, 0, 1, 12);
+        assert_eq!(history.used(), 2 * MAX_DELTA_SIZE + 4);
+        let edited = project.clone();
+
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[0].tracks[1].length, 16);
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[0].tracks[1].steps[3], on(64));
+        assert!(history.undo(&mut project));
+        assert_eq!(project, Project::new());
+        assert!(!history.undo(&mut project));
+
+        while history.redo(&mut project) {}
+        assert_eq!(project, edited);
+
+        history.undo(&mut project);
+        history.undo(&mut project);
+        history.set_step(&mut project, 0, 2, 0, on(36));
+        assert!(!history.can_redo());
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert_eq!(project, Project::new());
+    }
+
+    #[test]
+    fn merged_parameters() {
+        let mut project = Project::new();
+        project.tracks[0].sound.extend([10, 20, 30]);
+        let original = project.clone();
+        let mut history = History::<64>::new();
+
+        for value in 11..=20 {
+            history.set_sound(&mut project, 0, 1, value);
+        }
+        history.set_sound(&mut project, 0, 2, 5);
+        history.set_sound(&mut project, 0, 1, 21);
+        history.set_sound(&mut project, 0, 7, 1);
+        assert_eq!(history.used(), 3 * 6);
+
+        assert!(history.undo(&mut project));
+        assert_eq!(project.tracks[0].sound, [10, 20, 5]);
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert_eq!(project, original);
+        assert!(history.redo(&mut project));
+        assert_eq!(project.tracks[0].sound, [10, 20, 30]);
+
+        // No merge after an undo or redo
+        history.set_sound(&mut project, 0, 1, 0);
+        history.undo(&mut project);
+        assert_eq!(project.tracks[0].sound, [10, 20, 30]);
+    }
+
+    #[test]
+    fn locks() {
+        let mut project = Project::new();
+        let mut history = History::<256>::new();
+
+        history
+            .set_lock(&mut project, 3, 2, 5, 1, Some(-100))
+            .unwrap();
+        history
+            .set_lock(&mut project, 3, 2, 5, 1, Some(200))
+            .unwrap();
+        history.set_lock(&mut project, 3, 2, 5, 4, Some(7)).unwrap();
+        history.clear_locks(&mut project, 3, 2, 5);
+        assert!(!project.patterns[3].is_locked(2, 5));
+
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[3].lock(2, 5, 1), Some(200));
+        assert_eq!(project.patterns[3].lock(2, 5, 4), Some(7));
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert_eq!(project, Project::new());
+        while history.redo(&mut project) {}
+        assert!(!project.patterns[3].is_locked(2, 5));
+        history.undo(&mut project);
+
+        for step in 0..MAX_STEPS {
+            for param in 0..2 {
+                let _ = project.patterns[0].set_lock(0, step, param, 0);
+            }
+        }
+        assert_eq!(
+            history.set_lock(&mut project, 0, 1, 0, 0, Some(1)),
+            Err(LocksFull)
+        );
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[3].locks().len(), 1);
+    }
+
+    #[test]
+    fn full_patterns() {
+        let mut project = Project::new();
+        let mut history = History::<256>::new();
+        history.set_lock(&mut project, 0, 0, 0, 1, Some(5)).unwrap();
+        history.set_lock(&mut project, 0, 0, 0, 2, Some(6)).unwrap();
+        history.clear_locks(&mut project, 0, 0, 0);
+
+        // Filled without the history
+        for step in 0..MAX_STEPS {
+            for param in 0..2 {
+                project.patterns[0].set_lock(1, step, param, 0).unwrap();
+            }
+        }
+        project.patterns[0].clear_lock(1, 0, None);
+        assert_eq!(project.patterns[0].free_locks(), 2);
+        project.patterns[0].set_lock(1, 0, 0, 0).unwrap();
+        let full = project.clone();
+        assert!(!history.undo(&mut project));
+        assert_eq!(project, full);
+
+        project.patterns[0].clear_lock(1, 0, None);
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[0].lock(0, 0, 1), Some(5));
+        assert_eq!(project.patterns[0].lock(0, 0, 2), Some(6));
+
+        // Same for redo
+        assert!(history.undo(&mut project));
+        project.patterns[0].set_lock(1, 0, 0, 0).unwrap();
+        assert!(!history.redo(&mut project));
+        assert_eq!(project.patterns[0].lock(0, 0, 2), None);
+        project.patterns[0].clear_lock(1, 0, None);
+        assert!(history.redo(&mut project));
+        assert_eq!(project.patterns[0].lock(0, 0, 2), Some(6));
+    }
+
+    #[test]
+    fn sequences() {
+        let mut project = Project::new();
+        let mut history = History::<1024>::new();
+        let mut sequence = project.patterns[0].tracks[4].clone();
+        Generator::Euclid {
+            hits: 3,
+            steps: 8,
+            rotation: 0,
+        }
+        .fill(&mut sequence);
+
+        history.set_sequence(&mut project, 0, 4, &sequence);
+        assert_eq!(history.used(), 3 * MAX_DELTA_SIZE + 4);
+        assert_eq!(project.patterns[0].tracks[4], sequence);
+        history.set_step(&mut project, 0, 5, 0, on(40));
+
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert_eq!(project, Project::new());
+        assert!(history.redo(&mut project));
+        assert_eq!(project.patterns[0].tracks[4], sequence);
+        assert_eq!(active(&project, 5), 0);
+
+        // Groups inside a group
+        history.begin();
+        history.set_sequence(&mut project, 0, 4, &Sequence::new());
+        history.set_step(&mut project, 0, 6, 0, on(40));
+        history.end();
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[0].tracks[4], sequence);
+        assert_eq!(active(&project, 6), 0);
+    }
+
+    #[test]
+    fn budget() {
+        let mut project = Project::new();
+        let mut history = History::<{ 3 * MAX_DELTA_SIZE + 2 }>::new();
+        for index in 0..5 {
+            history.set_step(&mut project, 0, 0, index, on(60));
+        }
+        assert_eq!(history.used(), 3 * MAX_DELTA_SIZE);
+        for _ in 0..3 {
+            assert!(history.undo(&mut project));
+        }
+        assert!(!history.undo(&mut project));
+        assert_eq!(active(&project, 0), 2);
+
+        // Whole edits are forgotten
+        history.clear();
+        history.set_step(&mut project, 1, 0, 0, on(60));
+        history.begin();
+        history.set_step(&mut project, 1, 0, 1, on(60));
+        history.set_step(&mut project, 1, 0, 2, on(60));
+        history.end();
+        history.set_step(&mut project, 1, 0, 3, on(60));
+        assert_eq!(history.used(), 3 * MAX_DELTA_SIZE);
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert!(!history.undo(&mut project));
+        assert_eq!(active(&project, 0), 2);
+        assert!(project.patterns[1].tracks[0].steps[0].active);
+        assert!(!project.patterns[1].tracks[0].steps[1].active);
+
+        // An edit larger than the budget clears the history
+        history.set_step(&mut project, 1, 1, 0, on(60));
+        history.begin();
+        for index in 0..4 {
+            history.set_step(&mut project, 1, 2, index, on(60));
+        }
+        history.end();
+        assert_eq!(history.used(), 0);
+        assert!(!history.undo(&mut project));
+        history.set_step(&mut project, 1, 3, 0, on(60));
+        assert!(history.undo(&mut project));
+        assert!(!history.undo(&mut project));
+    }
+}
@@ -0,0 +1,227 @@
+//! Firmware information block
+//!
+//! Applications embed a `FirmwareInfo` with the `firmware_info!` macro, it
+//! is then available at runtime with `crate::firmware_info` and can be
+//! shown with `ui::draw_boot_screen`. The block is stored as is in the
+//! flash image, host tools find it by scanning a binary, ELF or UF2 file for
+//! `MAGIC`.
+//!
+//! Layout, format 1 (strings are UTF-8, padded with zeros):
+//!
+//! | Offset | Size | Field                       |
+//! |--------|------|-----------------------------|
+//! | 0      | 8    | `MAGIC`                     |
+//! | 8      | 2    | format, little endian       |
+//! | 10     | 1    | board revision              |
+//! | 11     | 1    | reserved, 0                 |
+//! | 12     | 32   | name                        |
+//! | 44     | 16   | version                     |
+//! | 60     | 16   | git hash                    |
+//! | 76     | 16   | build date, `YYYY-MM-DD`    |
+//!
+//! Later formats only add fields at the end.
+
+pub const MAGIC: [u8; 8] = *b"PGB1INFO";
+pub const FORMAT: u16 = 1;
+pub const SIZE: usize = 92;
+
+pub const NAME_SIZE: usize = 32;
+pub const FIELD_SIZE: usize = 16;
+
+#[repr(C, align(4))]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FirmwareInfo {
+    magic: [u8; 8],
+    format: [u8; 2],
+    board_revision: u8,
+    reserved: u8,
+    name: [u8; NAME_SIZE],
+    version: [u8; FIELD_SIZE],
+    git_hash: [u8; FIELD_SIZE],
+    build_date: [u8; FIELD_SIZE],
+}
+
+const _: () = assert!(core::mem::size_of::<FirmwareInfo>() == SIZE);
+
+/// Copy `s` in a zero padded field, truncated if too long
+const fn field<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < bytes.len() && i < N {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+fn as_str(field: &[u8]) -> &str {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    match core::str::from_utf8(&field[..len]) {
+        Ok(s) => s,
+        // Truncated in the middle of a character
+        Err(e) => core::str::from_utf8(&field[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+impl FirmwareInfo {
+    pub const fn new(
+        name: &str,
+        version: &str,
+        git_hash: &str,
+        build_date: &str,
+        board_revision: u8,
+    ) -> Self {
+        FirmwareInfo {
+            magic: MAGIC,
+            format: FORMAT.to_le_bytes(),
+            board_revision,
+            reserved: 0,
+            name: field(name),
+            version: field(version),
+            git_hash: field(git_hash),
+            build_date: field(build_date),
+        }
+    }
+
+    pub fn format(&self) -> u16 {
+        u16::from_le_bytes(self.format)
+    }
+
+    pub fn name(&self) -> &str {
+        as_str(&self.name)
+    }
+
+    pub fn version(&self) -> &str {
+        as_str(&self.version)
+    }
+
+    /// Short git commit hash, empty if unknown
+    pub fn git_hash(&self) -> &str {
+        as_str(&self.git_hash)
+    }
+
+    /// `YYYY-MM-DD`, empty if unknown
+    pub fn build_date(&self) -> &str {
+        as_str(&self.build_date)
+    }
+
+    pub fn board_revision(&self) -> u8 {
+        self.board_revision
+    }
+
+    pub fn to_bytes(&self) -> [u8; SIZE] {
+        let mut out = [0; SIZE];
+        out[0..8].copy_from_slice(&self.magic);
+        out[8..10].copy_from_slice(&self.format);
+        out[10] = self.board_revision;
+        out[11] = self.reserved;
+        out[12..44].copy_from_slice(&self.name);
+        out[44..60].copy_from_slice(&self.version);
+        out[60..76].copy_from_slice(&self.git_hash);
+        out[76..92].copy_from_slice(&self.build_date);
+        out
+    }
+
+    /// Decode a block, `None` if `bytes` does not start with one
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..SIZE)?;
+        if bytes[0..8] != MAGIC {
+            return None;
+        }
+        let mut info = FirmwareInfo::new("", "", "", "", bytes[10]);
+        info.format.copy_from_slice(&bytes[8..10]);
+        info.reserved = bytes[11];
+        info.name.copy_from_slice(&bytes[12..44]);
+        info.version.copy_from_slice(&bytes[44..60]);
+        info.git_hash.copy_from_slice(&bytes[60..76]);
+        info.build_date.copy_from_slice(&bytes[76..92]);
+        if info.format() == 0 {
+            return None;
+        }
+        Some(info)
+    }
+
+    /// Find the block in a flash image, it is always 4 bytes aligned
+    pub fn find(image: &[u8]) -> Option<Self> {
+        (0..image.len())
+            .step_by(4)
+            .find_map(|offset| Self::from_bytes(&image[offset..]))
+    }
+}
+
+/// Define the firmware information of the application, read with
+/// `pgb1::firmware_info()`
+///
+/// Use once in the application crate, `pgb1::firmware_info()` does not
+/// link without it. Name and version default to the package ones, the git
+/// hash and build date come from the `PGB1_GIT_HASH` and `PGB1_BUILD_DATE`
+/// environment variables at build time, as set by
+/// `pgb1_build::firmware_info` (`tools/pgb1-build`).
+#[macro_export]
+macro_rules! firmware_info {
+    () => {
+        $crate::firmware_info!(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    };
+    ($name:expr, $version:expr) => {
+        #[no_mangle]
+        #[used]
+        static PGB1_FIRMWARE_INFO: $crate::info::FirmwareInfo = $crate::info::FirmwareInfo::new(
+            $name,
+            $version,
+            match option_env!("PGB1_GIT_HASH") {
+                Some(hash) => hash,
+                None => "",
+            },
+            match option_env!("PGB1_BUILD_DATE") {
+                Some(date) => date,
+                None => "",
+            },
+            $crate::BOARD_REVISION,
+        );
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let info = FirmwareInfo::new("basics", "0.1.0", "abc1234", "2026-10-19", 1);
+        let bytes = info.to_bytes();
+        assert_eq!(&bytes[0..8], b"PGB1INFO");
+        assert_eq!(&bytes[8..12], &[1, 0, 1, 0]);
+        assert_eq!(&bytes[12..19], b"basics\0");
+        assert_eq!(&bytes[44..50], b"0.1.0\0");
+        assert_eq!(&bytes[60..68], b"abc1234\0");
+        assert_eq!(&bytes[76..86], b"2026-10-19");
+
+        // The in-memory layout is the file format
+        let raw: [u8; SIZE] = unsafe { core::mem::transmute(info) };
+        assert_eq!(raw, bytes);
+    }
+
+    #[test]
+    fn find_in_image() {
+        let info = FirmwareInfo::new(
+            "a very long application name, truncated",
+            "1.2.3",
+            "",
+            "",
+            2,
+        );
+        assert_eq!(info.name(), "a very long application name, tr");
+        assert_eq!(info.git_hash(), "");
+
+        let mut image = vec![0xFF; 1000];
+        image[3..11].copy_from_slice(&MAGIC); // Not aligned
+        image[400..400 + SIZE].copy_from_slice(&info.to_bytes());
+        let found = FirmwareInfo::find(&image).unwrap();
+        assert_eq!(found, info);
+        assert_eq!(found.version(), "1.2.3");
+        assert_eq!(found.board_revision(), 2);
+
+        assert_eq!(FirmwareInfo::find(&image[..400]), None);
+    }
+}
@@ -1,8 +1,8 @@
 //! Blinks the LED on a Pico board
 //!
 //! This will blink an LED attached to GP25, which is the pin the Pico uses for the on-board LED.
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 pub extern crate rp2040_hal as hal;
 
@@ -24,6 +24,34 @@ pub use hal::pac;
 
 pub const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
 
+/// Hardware revision of the PGB-1 supported by this crate
+pub const BOARD_REVISION: u8 = 1;
+
+pub mod audio;
+pub mod crc;
+pub mod disk;
+pub mod effects;
+pub mod flash;
+pub mod fs;
+pub mod generate;
+pub mod history;
+pub mod info;
+pub mod midi;
+pub mod multicore;
+pub mod param;
+pub mod project;
+pub mod record;
+pub mod rng;
+pub mod sampler;
+pub mod sequencer;
+pub mod settings;
+pub mod shell;
+pub mod spsc;
+pub mod sysex;
+pub mod ui;
+pub mod usb;
+pub mod voice;
+
 use display_interface_spi::SPIInterfaceNoCS;
 use fugit::*;
 use ws2812_pio::Ws2812Direct;
@@ -42,6 +70,8 @@ use rp2040_hal::{
     watchdog::Watchdog,
 };
 
+#[cfg(feature = "critical-section-impl")]
+#[allow(clippy::single_component_path_imports)]
 use critical_section;
 
 #[derive(Copy, Clone)]
@@ -111,6 +141,25 @@ impl Keys {
                                   Keys::K16,
                                 ];
 
+    /// Step keys, in order
+    pub const STEPS: [Self; 16] = [Keys::K1,
+                                   Keys::K2,
+                                   Keys::K3,
+                                   Keys::K4,
+                                   Keys::K5,
+                                   Keys::K6,
+                                   Keys::K7,
+                                   Keys::K8,
+                                   Keys::K9,
+                                   Keys::K10,
+                                   Keys::K11,
+                                   Keys::K12,
+                                   Keys::K13,
+                                   Keys::K14,
+                                   Keys::K15,
+                                   Keys::K16,
+                                 ];
+
     pub fn mask(&self) -> u32 {
         match *self {
             Keys::TRACK => 0x10000000,
@@ -180,7 +229,89 @@ impl Keys {
             Keys::K16   => 22,
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Keys::TRACK => "TRACK",
+            Keys::STEP  => "STEP",
+            Keys::PLAY  => "PLAY",
+            Keys::REC   => "REC",
+            Keys::ALT   => "ALT",
+            Keys::PATT  => "PATT",
+            Keys::SONG  => "SONG",
+            Keys::MENU  => "MENU",
+            Keys::UP    => "UP",
+            Keys::DOWN  => "DOWN",
+            Keys::RIGHT => "RIGHT",
+            Keys::LEFT  => "LEFT",
+            Keys::A     => "A",
+            Keys::B     => "B",
+            Keys::K1    => "K1",
+            Keys::K2    => "K2",
+            Keys::K3    => "K3",
+            Keys::K4    => "K4",
+            Keys::K5    => "K5",
+            Keys::K6    => "K6",
+            Keys::K7    => "K7",
+            Keys::K8    => "K8",
+            Keys::K9    => "K9",
+            Keys::K10   => "K10",
+            Keys::K11   => "K11",
+            Keys::K12   => "K12",
+            Keys::K13   => "K13",
+            Keys::K14   => "K14",
+            Keys::K15   => "K15",
+            Keys::K16   => "K16",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Self> {
+        Keys::LIST.iter().copied().find(|k| k.name().eq_ignore_ascii_case(name))
+    }
+}
+/// State of the keys at a keyboard scan, as read by the `ui` widgets
+///
+/// `KeyboardMatrix::keys` gives the state of the last scan, tests build
+/// their own with `scan`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct KeyState {
+    state : u32,
+    prev_state : u32,
 }
+
+impl KeyState {
+
+    /// Key masks (`Keys::mask`) held at this scan and at the previous one
+    pub const fn new(state : u32, prev_state : u32) -> Self
+    {
+        KeyState { state, prev_state }
+    }
+
+    /// The next scan, with `keys` held
+    pub fn scan(&self, keys : &[Keys]) -> Self
+    {
+        let state = keys.iter().fold(0, |state, k| state | k.mask());
+        KeyState::new(state, self.state)
+    }
+
+    pub fn pressed(&self, k : Keys) -> bool
+    {
+        (self.state & k.mask()) != 0
+    }
+
+    /// Pressed at this scan
+    pub fn falling(&self, k : Keys) -> bool
+    {
+        (self.state & !self.prev_state & k.mask()) != 0
+    }
+
+    /// Released at this scan
+    pub fn raising(&self, k : Keys) -> bool
+    {
+        (!self.state & self.prev_state & k.mask()) != 0
+    }
+}
+
 pub struct KeyboardMatrix {
     col1 : Pin<rp2040_hal::gpio::bank0::Gpio18, FunctionSio<SioOutput>, PullDown>,
     col2 : Pin<rp2040_hal::gpio::bank0::Gpio19, FunctionSio<SioOutput>, PullDown>,
@@ -197,10 +328,22 @@ pub struct KeyboardMatrix {
 
     state : u32,
     prev_state : u32,
+
+    /// Keys pressed remotely (SysEx, shell)
+    remote : u32,
+    /// Keys pressed remotely for a single scan
+    remote_taps : u32,
 }
 
+#[allow(clippy::needless_return)]
 impl KeyboardMatrix {
 
+    /// State of the last scan
+    pub fn keys(&self) -> KeyState
+    {
+        return KeyState::new(self.state, self.prev_state);
+    }
+
     pub fn pressed(&self, k : Keys) -> bool
     {
         return (self.state & k.mask()) != 0;
@@ -252,11 +395,42 @@ impl KeyboardMatrix {
         }
 
         self.prev_state = self.state;
-        self.state = new_state;
+        self.state = new_state | self.remote | self.remote_taps;
+        self.remote_taps = 0;
+    }
+
+    /// Press or release a key remotely, applied at the next scan
+    pub fn set_remote(&mut self, k : Keys, pressed : bool)
+    {
+        if pressed {
+            self.remote |= k.mask();
+        } else {
+            self.remote &= !k.mask();
+        }
     }
 
+    /// Press a key remotely for the next scan only
+    pub fn tap_remote(&mut self, k : Keys)
+    {
+        self.remote_taps |= k.mask();
+    }
+
+}
+
+/// Storage that `Peripherals::take` could not open
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct StorageErrors {
+    /// The USB disk holds a volume `disk::Volume` cannot mount, e.g. the
+    /// host formatted it with another layout. It is left as is for the
+    /// host, `disk::format` erases it once the user agrees.
+    pub disk : Option<disk::DiskError>,
+    /// The settings could not be read, they were erased
+    pub settings : Option<settings::SettingsError>,
+    /// The filesystem could not be mounted, all its files were erased
+    pub fs : Option<fs::FsError>,
 }
 
+#[allow(clippy::type_complexity)]
 pub struct Peripherals {
     pub keyboard : KeyboardMatrix,
     pub display : Ssd1306<SPIInterfaceNoCS<rp2040_hal::spi::Spi<rp2040_hal::spi::Enabled, crate::pac::SPI1,
@@ -269,10 +443,57 @@ pub struct Peripherals {
                             rp2040_hal::pio::SM0,
                             Pin<rp2040_hal::gpio::bank0::Gpio5, rp2040_hal::gpio::FunctionPio0, PullDown>>,
     pub delay : Delay,
+    /// Microsecond timer, `get_counter_low` gives `midi::clock::Instant`s
+    pub timer : rp2040_hal::Timer,
+    pub core1 : multicore::Core1,
+    /// MIDI on the DIN/TRS jacks, its interrupt stays masked unless the
+    /// `midi-uart-irq` feature is enabled, see `midi::uart`
+    pub midi : midi::uart::MidiUart,
+    pub usb : usb::Usb,
+    /// Flash region shared with the host in USB disk mode, see `usb::msc`
+    pub disk : disk::Disk,
+    /// Settings kept across power cycles
+    pub settings : settings::FlashSettings,
+    /// Files and directories, see `fs`
+    pub fs : fs::FlashFs,
+    /// What went wrong opening `disk`, `settings` and `fs`, to report to
+    /// the user
+    pub storage_errors : StorageErrors,
 }
 
 static mut DEVICE_PERIPHERALS: bool = false;
 
+/// Keys held during `Peripherals::take` to enter the USB bootloader
+pub const BOOTLOADER_KEYS : (Keys, Keys) = (Keys::MENU, Keys::ALT);
+
+/// Information block of the application, defined with the `firmware_info!`
+/// macro
+///
+/// There is no default block: without a `firmware_info!` in the application
+/// the link fails with an undefined `PGB1_FIRMWARE_INFO` symbol.
+pub fn firmware_info() -> &'static info::FirmwareInfo {
+    extern "Rust" {
+        static PGB1_FIRMWARE_INFO : info::FirmwareInfo;
+    }
+    unsafe { &PGB1_FIRMWARE_INFO }
+}
+
+/// Restart the application
+pub fn reboot() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Restart in the RP2040 ROM USB bootloader (BOOTSEL mode): the PGB-1
+/// shows up as a drive and a new application is installed by copying a
+/// UF2 file to it.
+pub fn reboot_to_bootloader() -> ! {
+    // No activity LED, both the mass storage and PICOBOOT interfaces
+    hal::rom_data::reset_to_usb_boot(0, 0);
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
 impl Peripherals {
     #[cfg(feature = "critical-section-impl")]
     #[inline]
@@ -285,12 +506,18 @@ impl Peripherals {
         })
     }
 
+    /// # Safety
+    ///
+    /// Must be called at most once, and not after a successful call to
+    /// `take`, as it takes ownership of the underlying `pac` peripherals.
+    #[allow(clippy::redundant_field_names)]
     pub unsafe fn steal() -> Self {
         DEVICE_PERIPHERALS = true;
         let mut pac = pac::Peripherals::take().unwrap();
         let core = pac::CorePeripherals::take().unwrap();
         let mut watchdog = Watchdog::new(pac.WATCHDOG);
         let sio = Sio::new(pac.SIO);
+        let core1 = multicore::Core1::new(pac.PSM, pac.PPB, sio.fifo);
     
         let clocks = init_clocks_and_plls(
             XOSC_CRYSTAL_FREQ,
@@ -314,7 +541,7 @@ impl Peripherals {
         );
     
         // Keyboard
-        let keys = KeyboardMatrix {        
+        let mut keys = KeyboardMatrix {        
             col1 : pins.gpio18.into_push_pull_output(),
             col2 : pins.gpio19.into_push_pull_output(),
             col3 : pins.gpio26.into_push_pull_output(),
@@ -328,7 +555,18 @@ impl Peripherals {
             row6 : pins.gpio27.into_pull_down_input(),
             state : 0,
             prev_state : 0,
+            remote : 0,
+            remote_taps : 0,
         };
+
+        // Holding MENU and ALT at boot enters the USB bootloader, a way to
+        // update applications that do not call `reboot_to_bootloader`
+        keys.scan(&mut delay);
+        if keys.pressed(BOOTLOADER_KEYS.0) && keys.pressed(BOOTLOADER_KEYS.1) {
+            reboot_to_bootloader();
+        }
+        keys.state = 0;
+        keys.prev_state = 0;
    
         // These are implicitly used by the spi driver if they are in the correct mode
         let spi_sclk = pins.gpio10.into_function::<FunctionSpi>(); // scl
@@ -362,12 +600,60 @@ impl Peripherals {
             sm0,
             clocks.peripheral_clock.freq(),
         );
-    
+
+        // MIDI
+        let midi = midi::uart::MidiUart::new(
+            pac.UART0,
+            (pins.gpio0.reconfigure(), pins.gpio1.reconfigure()),
+            &mut pac.RESETS,
+            clocks.peripheral_clock.freq(),
+        );
+
+        let timer = rp2040_hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+
+        // USB
+        let usb = usb::Usb::new(
+            pac.USBCTRL_REGS,
+            pac.USBCTRL_DPRAM,
+            clocks.usb_clock,
+            &mut pac.RESETS,
+        );
+
+        let mut disk = disk::FlashDisk::new(flash::FlashRegion::new(flash::DISK_OFFSET, flash::DISK_SIZE));
+        let disk_error = disk::Volume::mount_or_format(&mut disk, disk::LABEL).err();
+
+        // Damaged settings or filesystem are formatted rather than leaving
+        // the PGB-1 unable to start. Formatting only fails on accesses out
+        // of the region, which the constant offsets and sizes rule out.
+        let settings_region = || flash::FlashRegion::new(flash::SETTINGS_OFFSET, flash::SETTINGS_SIZE);
+        let (settings, settings_error) = match settings::Settings::new(settings_region()) {
+            Ok(settings) => (settings, None),
+            Err(err) => (settings::Settings::formatted(settings_region()).expect("settings region"), Some(err)),
+        };
+        let fs_region = || flash::FlashRegion::new(flash::FS_OFFSET, flash::FS_SIZE);
+        let (fs, fs_error) = match fs::Fs::new(fs_region()) {
+            Ok(fs) => (fs, None),
+            Err(err) => (fs::Fs::formatted(fs_region()).expect("fs region"), Some(err)),
+        };
+        let storage_errors = StorageErrors {
+            disk : disk_error,
+            settings : settings_error,
+            fs : fs_error,
+        };
+
         Peripherals {
             keyboard: keys,
             display: display,
             leds : ws,
             delay: delay,
+            timer,
+            core1,
+            midi,
+            usb,
+            disk,
+            settings,
+            fs,
+            storage_errors,
         }
     }
 }
@@ -0,0 +1,519 @@
+//! MIDI 1.0 messages and stream parser
+//!
+//! The types in this module are shared by all the MIDI ports of the PGB-1
+//! (see `uart` for the DIN/TRS jacks and `usb` for the USB port).
+
+pub mod clock;
+pub mod learn;
+pub mod routing;
+pub mod uart;
+pub mod usb;
+
+use heapless::Vec;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Message {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// 14-bit value, 0x2000 is the center
+    PitchBend { channel: u8, value: u16 },
+
+    TimeCodeQuarterFrame(u8),
+    /// Position in MIDI beats (sixteenth notes)
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+impl Message {
+    /// Channel of a channel voice message
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            Message::NoteOff { channel, .. }
+            | Message::NoteOn { channel, .. }
+            | Message::PolyPressure { channel, .. }
+            | Message::ControlChange { channel, .. }
+            | Message::ProgramChange { channel, .. }
+            | Message::ChannelPressure { channel, .. }
+            | Message::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    pub fn is_realtime(&self) -> bool {
+        matches!(
+            self,
+            Message::Clock
+                | Message::Start
+                | Message::Continue
+                | Message::Stop
+                | Message::ActiveSensing
+                | Message::Reset
+        )
+    }
+
+    /// Status byte of the message
+    pub fn status(&self) -> u8 {
+        match *self {
+            Message::NoteOff { channel, .. } => 0x80 | (channel & 0xF),
+            Message::NoteOn { channel, .. } => 0x90 | (channel & 0xF),
+            Message::PolyPressure { channel, .. } => 0xA0 | (channel & 0xF),
+            Message::ControlChange { channel, .. } => 0xB0 | (channel & 0xF),
+            Message::ProgramChange { channel, .. } => 0xC0 | (channel & 0xF),
+            Message::ChannelPressure { channel, .. } => 0xD0 | (channel & 0xF),
+            Message::PitchBend { channel, .. } => 0xE0 | (channel & 0xF),
+            Message::TimeCodeQuarterFrame(_) => 0xF1,
+            Message::SongPosition(_) => 0xF2,
+            Message::SongSelect(_) => 0xF3,
+            Message::TuneRequest => 0xF6,
+            Message::Clock => 0xF8,
+            Message::Start => 0xFA,
+            Message::Continue => 0xFB,
+            Message::Stop => 0xFC,
+            Message::ActiveSensing => 0xFE,
+            Message::Reset => 0xFF,
+        }
+    }
+
+    /// Encode the message, return the buffer and the number of bytes used
+    pub fn to_bytes(&self) -> ([u8; 3], usize) {
+        let status = self.status();
+        match *self {
+            Message::NoteOff { note, velocity, .. } | Message::NoteOn { note, velocity, .. } => {
+                ([status, note & 0x7F, velocity & 0x7F], 3)
+            }
+            Message::PolyPressure { note, pressure, .. } => {
+                ([status, note & 0x7F, pressure & 0x7F], 3)
+            }
+            Message::ControlChange { control, value, .. } => {
+                ([status, control & 0x7F, value & 0x7F], 3)
+            }
+            Message::ProgramChange { program: data, .. }
+            | Message::ChannelPressure { pressure: data, .. }
+            | Message::TimeCodeQuarterFrame(data)
+            | Message::SongSelect(data) => ([status, data & 0x7F, 0], 2),
+            Message::PitchBend { value, .. } | Message::SongPosition(value) => {
+                ([status, (value & 0x7F) as u8, ((value >> 7) & 0x7F) as u8], 3)
+            }
+            _ => ([status, 0, 0], 1),
+        }
+    }
+
+    /// Decode a complete message from its status and data bytes
+    fn decode(status: u8, data: &[u8]) -> Option<Self> {
+        let channel = status & 0x0F;
+        let d0 = data.first().copied().unwrap_or(0);
+        let d1 = data.get(1).copied().unwrap_or(0);
+        let msg = match status & 0xF0 {
+            0x80 => Message::NoteOff {
+                channel,
+                note: d0,
+                velocity: d1,
+            },
+            0x90 => Message::NoteOn {
+                channel,
+                note: d0,
+                velocity: d1,
+            },
+            0xA0 => Message::PolyPressure {
+                channel,
+                note: d0,
+                pressure: d1,
+            },
+            0xB0 => Message::ControlChange {
+                channel,
+                control: d0,
+                value: d1,
+            },
+            0xC0 => Message::ProgramChange {
+                channel,
+                program: d0,
+            },
+            0xD0 => Message::ChannelPressure {
+                channel,
+                pressure: d0,
+            },
+            0xE0 => Message::PitchBend {
+                channel,
+                value: d0 as u16 | (d1 as u16) << 7,
+            },
+            _ => match status {
+                0xF1 => Message::TimeCodeQuarterFrame(d0),
+                0xF2 => Message::SongPosition(d0 as u16 | (d1 as u16) << 7),
+                0xF3 => Message::SongSelect(d0),
+                0xF6 => Message::TuneRequest,
+                0xF8 => Message::Clock,
+                0xFA => Message::Start,
+                0xFB => Message::Continue,
+                0xFC => Message::Stop,
+                0xFE => Message::ActiveSensing,
+                0xFF => Message::Reset,
+                _ => return None,
+            },
+        };
+        Some(msg)
+    }
+}
+
+/// Number of data bytes following a status byte, `None` for undefined or
+/// SysEx status bytes
+fn data_length(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => Some(2),
+        0xC0..=0xDF | 0xF1 | 0xF3 => Some(1),
+        0xF6 | 0xF8 | 0xFA..=0xFC | 0xFE | 0xFF => Some(0),
+        _ => None,
+    }
+}
+
+/// Output of the `Parser`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Event<'a> {
+    Message(Message),
+    /// Content of a complete SysEx message, without the 0xF0 and 0xF7
+    /// delimiters
+    SysEx(&'a [u8]),
+}
+
+/// A MIDI port
+pub trait Interface {
+    /// Return the next complete message received, if any
+    fn poll(&mut self) -> Option<Event<'_>>;
+
+    /// Queue a message, return false if there is no room for it
+    fn send(&mut self, msg: &Message) -> bool;
+
+    /// Queue a SysEx message, `data` excludes the 0xF0 and 0xF7 delimiters.
+    /// Return false without sending anything if a byte of `data` is not a
+    /// data byte (< 0x80).
+    fn send_sysex(&mut self, data: &[u8]) -> bool;
+
+    fn send_event(&mut self, event: &Event) -> bool {
+        match event {
+            Event::Message(msg) => self.send(msg),
+            Event::SysEx(data) => self.send_sysex(data),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub(crate) enum Parsed {
+    Message(Message),
+    /// The SysEx buffer of the parser holds a complete message
+    SysEx,
+}
+
+/// Default SysEx buffer size of the parsers used by the MIDI ports
+pub const SYSEX_BUFFER_SIZE: usize = 256;
+
+/// Streaming MIDI 1.0 parser
+///
+/// Handles running status, real-time messages interleaved anywhere in the
+/// stream (including in the middle of other messages and SysEx), and SysEx
+/// messages up to `SYSEX` bytes. Longer SysEx messages, SysEx interrupted
+/// by another status byte and data bytes without status are dropped.
+pub struct Parser<const SYSEX: usize = SYSEX_BUFFER_SIZE> {
+    /// Running status, 0 when none
+    status: u8,
+    data: [u8; 2],
+    len: usize,
+
+    in_sysex: bool,
+    sysex_overflow: bool,
+    sysex: Vec<u8, SYSEX>,
+}
+
+impl<const SYSEX: usize> Default for Parser<SYSEX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SYSEX: usize> Parser<SYSEX> {
+    pub const fn new() -> Self {
+        Parser {
+            status: 0,
+            data: [0; 2],
+            len: 0,
+            in_sysex: false,
+            sysex_overflow: false,
+            sysex: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.status = 0;
+        self.len = 0;
+        self.in_sysex = false;
+        self.sysex.clear();
+    }
+
+    /// Feed one byte, return an event when a message is complete
+    pub fn feed(&mut self, byte: u8) -> Option<Event<'_>> {
+        self.parse(byte).map(|parsed| self.event(parsed))
+    }
+
+    /// Same as `feed`, but the returned value does not borrow the parser,
+    /// use `event` to get the SysEx data
+    pub(crate) fn parse(&mut self, byte: u8) -> Option<Parsed> {
+        if byte >= 0xF8 {
+            // Real-time, does not interfere with anything else
+            return Message::decode(byte, &[]).map(Parsed::Message);
+        }
+
+        if byte & 0x80 == 0 {
+            return self.data_byte(byte);
+        }
+
+        if byte == 0xF7 {
+            let complete = self.in_sysex && !self.sysex_overflow;
+            self.in_sysex = false;
+            self.status = 0;
+            return complete.then_some(Parsed::SysEx);
+        }
+
+        self.in_sysex = false;
+        self.len = 0;
+
+        if byte == 0xF0 {
+            self.status = 0;
+            self.in_sysex = true;
+            self.sysex_overflow = false;
+            self.sysex.clear();
+            return None;
+        }
+
+        match data_length(byte) {
+            Some(0) => {
+                // System common messages cancel the running status
+                self.status = 0;
+                Message::decode(byte, &[]).map(Parsed::Message)
+            }
+            Some(_) => {
+                self.status = byte;
+                None
+            }
+            None => {
+                self.status = 0;
+                None
+            }
+        }
+    }
+
+    pub(crate) fn event(&self, parsed: Parsed) -> Event<'_> {
+        match parsed {
+            Parsed::Message(msg) => Event::Message(msg),
+            Parsed::SysEx => Event::SysEx(&self.sysex),
+        }
+    }
+
+    fn data_byte(&mut self, byte: u8) -> Option<Parsed> {
+        if self.in_sysex {
+            if self.sysex.push(byte).is_err() {
+                self.sysex_overflow = true;
+            }
+            return None;
+        }
+
+        let expected = data_length(self.status)?;
+        self.data[self.len] = byte;
+        self.len += 1;
+        if self.len < expected {
+            return None;
+        }
+
+        self.len = 0;
+        let status = self.status;
+        if status >= 0xF0 {
+            // No running status for system common messages
+            self.status = 0;
+        }
+        Message::decode(status, &self.data[..expected]).map(Parsed::Message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[derive(Debug, PartialEq)]
+    enum Out {
+        Msg(Message),
+        SysEx(Vec<u8>),
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<Out> {
+        let mut parser: Parser<8> = Parser::new();
+        let mut out = Vec::new();
+        for &b in bytes {
+            match parser.feed(b) {
+                Some(Event::Message(m)) => out.push(Out::Msg(m)),
+                Some(Event::SysEx(data)) => out.push(Out::SysEx(data.to_vec())),
+                None => {}
+            }
+        }
+        out
+    }
+
+    fn note_on(note: u8, velocity: u8) -> Out {
+        Out::Msg(Message::NoteOn {
+            channel: 1,
+            note,
+            velocity,
+        })
+    }
+
+    #[test]
+    fn channel_messages() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 0xB2, 7, 127, 0xC3, 5, 0xE4, 0x00, 0x40]),
+            [
+                note_on(60, 100),
+                Out::Msg(Message::ControlChange {
+                    channel: 2,
+                    control: 7,
+                    value: 127
+                }),
+                Out::Msg(Message::ProgramChange {
+                    channel: 3,
+                    program: 5
+                }),
+                Out::Msg(Message::PitchBend {
+                    channel: 4,
+                    value: 0x2000
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn running_status() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 62, 101, 64, 0]),
+            [note_on(60, 100), note_on(62, 101), note_on(64, 0)]
+        );
+        assert_eq!(
+            parse(&[0xC0, 1, 2, 3]).len(),
+            3,
+            "running status with one data byte"
+        );
+    }
+
+    #[test]
+    fn realtime_interleaved() {
+        assert_eq!(
+            parse(&[0x91, 0xF8, 60, 0xFA, 100, 62, 0xFE, 101]),
+            [
+                Out::Msg(Message::Clock),
+                Out::Msg(Message::Start),
+                note_on(60, 100),
+                Out::Msg(Message::ActiveSensing),
+                note_on(62, 101),
+            ]
+        );
+    }
+
+    #[test]
+    fn system_common_cancels_running_status() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 0xF3, 4, 62, 101, 0xF6, 1, 2]),
+            [
+                note_on(60, 100),
+                Out::Msg(Message::SongSelect(4)),
+                Out::Msg(Message::TuneRequest),
+            ]
+        );
+        assert_eq!(
+            parse(&[0xF2, 0x10, 0x01]),
+            [Out::Msg(Message::SongPosition(0x90))]
+        );
+    }
+
+    #[test]
+    fn data_without_status_is_ignored() {
+        assert_eq!(parse(&[1, 2, 3, 0x91, 60, 100]), [note_on(60, 100)]);
+        assert_eq!(parse(&[0xF4, 1, 2, 0xFD, 3]), []);
+    }
+
+    #[test]
+    fn sysex() {
+        assert_eq!(
+            parse(&[0xF0, 0x7D, 1, 2, 0xF7, 0x91, 60, 100]),
+            [Out::SysEx(std::vec![0x7D, 1, 2]), note_on(60, 100)]
+        );
+    }
+
+    #[test]
+    fn sysex_with_realtime() {
+        assert_eq!(
+            parse(&[0xF0, 0x7D, 0xF8, 1, 0xF7]),
+            [Out::Msg(Message::Clock), Out::SysEx(std::vec![0x7D, 1])]
+        );
+    }
+
+    #[test]
+    fn sysex_cancels_running_status() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 0xF0, 1, 0xF7, 62, 101]),
+            [note_on(60, 100), Out::SysEx(std::vec![1])]
+        );
+    }
+
+    #[test]
+    fn sysex_overflow_and_abort() {
+        // Longer than the 8 bytes buffer
+        assert_eq!(parse(&[0xF0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0xF7]), []);
+        // Interrupted by a status byte
+        assert_eq!(
+            parse(&[0xF0, 1, 2, 0x91, 60, 100, 0xF7]),
+            [note_on(60, 100)]
+        );
+        // Stray end of exclusive
+        assert_eq!(parse(&[0xF7, 0xF0, 1, 0xF7]), [Out::SysEx(std::vec![1])]);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let messages = [
+            Message::NoteOff {
+                channel: 15,
+                note: 127,
+                velocity: 64,
+            },
+            Message::PolyPressure {
+                channel: 0,
+                note: 1,
+                pressure: 2,
+            },
+            Message::ChannelPressure {
+                channel: 3,
+                pressure: 4,
+            },
+            Message::PitchBend {
+                channel: 9,
+                value: 0x3FFF,
+            },
+            Message::TimeCodeQuarterFrame(0x35),
+            Message::SongPosition(1234),
+            Message::Continue,
+            Message::Stop,
+            Message::Reset,
+        ];
+        for msg in messages {
+            let (bytes, len) = msg.to_bytes();
+            assert_eq!(parse(&bytes[..len]), [Out::Msg(msg)]);
+        }
+    }
+}
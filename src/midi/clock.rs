@@ -0,0 +1,611 @@
+//! MIDI clock synchronization
+//!
+//! The sequencer is driven by clock ticks at 24 pulses per quarter note,
+//! either from the internal `ClockMaster` or from an external clock followed
+//! by `ClockSlave`. Both keep a `Transport` that tells the sequencer if it
+//! is playing and the current tick.
+//!
+//! Timestamps are in microseconds and may wrap around.
+
+use super::Message;
+
+/// Timestamp in microseconds
+pub type Instant = u32;
+
+/// Clock ticks per quarter note
+pub const PPQN: u32 = 24;
+
+/// Clock ticks per sixteenth note (a sequencer step, or a MIDI beat)
+pub const TICKS_PER_STEP: u32 = PPQN / 4;
+
+pub const MIN_BPM: u16 = 20;
+pub const MAX_BPM: u16 = 300;
+
+/// Tick periods are in microseconds with 8 fractional bits
+const PERIOD_SHIFT: u32 = 8;
+
+fn bpm_to_period(bpm: u16) -> u32 {
+    let bpm = bpm.clamp(MIN_BPM, MAX_BPM) as u64;
+    ((60_000_000u64 << PERIOD_SHIFT) / (bpm * PPQN as u64)) as u32
+}
+
+fn period_to_bpm(period: u32) -> u16 {
+    if period == 0 {
+        return 0;
+    }
+    let ticks_per_minute = (60_000_000u64 << PERIOD_SHIFT) / period as u64;
+    ((ticks_per_minute + PPQN as u64 / 2) / PPQN as u64).min(u16::MAX as u64) as u16
+}
+
+/// Time from `earlier` to `later`, negative if `later` is before `earlier`
+fn elapsed(earlier: Instant, later: Instant) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ClockEvent {
+    Tick,
+    Start,
+    Continue,
+    Stop,
+    /// Position in MIDI beats (sixteenth notes)
+    SongPosition(u16),
+}
+
+impl ClockEvent {
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        match *msg {
+            Message::Clock => Some(ClockEvent::Tick),
+            Message::Start => Some(ClockEvent::Start),
+            Message::Continue => Some(ClockEvent::Continue),
+            Message::Stop => Some(ClockEvent::Stop),
+            Message::SongPosition(beats) => Some(ClockEvent::SongPosition(beats)),
+            _ => None,
+        }
+    }
+
+    pub fn to_message(self) -> Message {
+        match self {
+            ClockEvent::Tick => Message::Clock,
+            ClockEvent::Start => Message::Start,
+            ClockEvent::Continue => Message::Continue,
+            ClockEvent::Stop => Message::Stop,
+            ClockEvent::SongPosition(beats) => Message::SongPosition(beats),
+        }
+    }
+}
+
+/// Play state and position
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Transport {
+    playing: bool,
+    /// Position of the next tick to play
+    next: u32,
+}
+
+impl Transport {
+    pub const fn new() -> Self {
+        Transport {
+            playing: false,
+            next: 0,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Last tick played since the start, 0 until the first tick
+    pub fn tick(&self) -> u32 {
+        self.next.saturating_sub(1)
+    }
+
+    /// Sequencer step of the last tick
+    pub fn step(&self) -> u32 {
+        self.tick() / TICKS_PER_STEP
+    }
+
+    /// Position of the last tick within its step
+    pub fn tick_in_step(&self) -> u32 {
+        self.tick() % TICKS_PER_STEP
+    }
+
+    /// Update the transport, return true if a tick must be played
+    pub fn apply(&mut self, event: ClockEvent) -> bool {
+        match event {
+            ClockEvent::Tick => {
+                if self.playing {
+                    self.next += 1;
+                }
+                return self.playing;
+            }
+            ClockEvent::Start => {
+                self.playing = true;
+                self.next = 0;
+            }
+            ClockEvent::Continue => self.playing = true,
+            ClockEvent::Stop => self.playing = false,
+            ClockEvent::SongPosition(beats) => {
+                // Only valid while stopped
+                if !self.playing {
+                    self.next = beats as u32 * TICKS_PER_STEP;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Internal clock, the `ClockEvent`s it returns are also meant to be sent
+/// out as MIDI messages.
+///
+/// The clock keeps running while the transport is stopped so that slaves can
+/// follow the tempo.
+pub struct ClockMaster {
+    transport: Transport,
+    period: u32,
+    /// Time of the first tick of the current tempo
+    origin: Instant,
+    /// Ticks emitted since `origin`
+    count: u32,
+    running: bool,
+    pending: Option<ClockEvent>,
+}
+
+impl ClockMaster {
+    pub fn new(bpm: u16) -> Self {
+        ClockMaster {
+            transport: Transport::new(),
+            period: bpm_to_period(bpm),
+            origin: 0,
+            count: 0,
+            running: false,
+            pending: None,
+        }
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    pub fn bpm(&self) -> u16 {
+        period_to_bpm(self.period)
+    }
+
+    /// Change the tempo from the next tick on
+    pub fn set_bpm(&mut self, bpm: u16) {
+        if self.running && self.count > 0 {
+            self.origin = self.tick_time(self.count - 1);
+            self.count = 1;
+        }
+        self.period = bpm_to_period(bpm);
+    }
+
+    fn tick_time(&self, count: u32) -> Instant {
+        let offset = (count as u64 * self.period as u64) >> PERIOD_SHIFT;
+        self.origin.wrapping_add(offset as u32)
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.running = true;
+        self.origin = now;
+        self.count = 0;
+    }
+
+    /// Start from the beginning, the first tick is played right away
+    pub fn start(&mut self, now: Instant) {
+        self.restart(now);
+        self.pending = Some(ClockEvent::Start);
+    }
+
+    /// Resume from the current position
+    pub fn resume(&mut self, now: Instant) {
+        self.restart(now);
+        self.pending = Some(ClockEvent::Continue);
+    }
+
+    pub fn stop(&mut self) {
+        self.pending = Some(ClockEvent::Stop);
+    }
+
+    /// Move the position, only while stopped
+    pub fn set_song_position(&mut self, beats: u16) {
+        if !self.transport.is_playing() {
+            self.pending = Some(ClockEvent::SongPosition(beats));
+        }
+    }
+
+    /// Return the next event due at `now`, call until it returns `None`
+    pub fn poll(&mut self, now: Instant) -> Option<ClockEvent> {
+        if let Some(event) = self.pending.take() {
+            self.transport.apply(event);
+            return Some(event);
+        }
+
+        if !self.running {
+            self.restart(now);
+        }
+        if elapsed(self.tick_time(self.count), now) < 0 {
+            return None;
+        }
+        self.count += 1;
+        self.transport.apply(ClockEvent::Tick);
+        Some(ClockEvent::Tick)
+    }
+}
+
+/// Follows an external clock
+///
+/// The tick period and phase are tracked with an alpha-beta filter, so that
+/// jitter on the incoming clock does not move the tempo estimate much.
+/// Clocks far from the prediction (lost or doubled messages) only resync the
+/// phase, unless they keep coming that way.
+pub struct ClockSlave {
+    transport: Transport,
+    /// Filtered tick period, 0 until two clocks are received
+    period: u32,
+    /// Filtered time of the last tick
+    phase: Instant,
+    last: Option<Instant>,
+    /// Clocks received since the estimate was reset
+    count: u32,
+    /// Consecutive clocks rejected as outliers
+    outliers: u32,
+}
+
+impl Default for ClockSlave {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSlave {
+    /// Clocks needed to consider the tempo estimate reliable
+    pub const LOCK_CLOCKS: u32 = PPQN;
+
+    /// The clock is lost after this many tick periods without a message
+    pub const TIMEOUT_TICKS: u32 = 8;
+
+    /// Outliers in a row that are taken as a tempo jump
+    const MAX_OUTLIERS: u32 = 3;
+
+    pub const fn new() -> Self {
+        ClockSlave {
+            transport: Transport::new(),
+            period: 0,
+            phase: 0,
+            last: None,
+            count: 0,
+            outliers: 0,
+        }
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Estimated tempo, 0 when unknown
+    pub fn bpm(&self) -> u16 {
+        period_to_bpm(self.period)
+    }
+
+    /// Estimated tick period in microseconds, 0 when unknown
+    pub fn period_us(&self) -> u32 {
+        self.period >> PERIOD_SHIFT
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.count >= Self::LOCK_CLOCKS
+    }
+
+    /// Predicted time of the next tick
+    pub fn next_tick(&self) -> Option<Instant> {
+        (self.period != 0).then(|| self.phase.wrapping_add(self.period_us()))
+    }
+
+    /// Position between the last tick and the next one, 0..=0xFFFF
+    pub fn phase(&self, now: Instant) -> u16 {
+        if self.period == 0 {
+            return 0;
+        }
+        let since = elapsed(self.phase, now).max(0) as u64;
+        ((since << (16 + PERIOD_SHIFT)) / self.period as u64).min(0xFFFF) as u16
+    }
+
+    /// Forget the estimate, the position is kept
+    pub fn reset(&mut self) {
+        self.period = 0;
+        self.last = None;
+        self.count = 0;
+        self.outliers = 0;
+    }
+
+    /// Check for a lost clock, should be called regularly
+    pub fn update(&mut self, now: Instant) {
+        let Some(last) = self.last else {
+            return;
+        };
+        let timeout = if self.period == 0 {
+            // Slower than the minimum tempo
+            bpm_to_period(MIN_BPM) >> PERIOD_SHIFT
+        } else {
+            self.period_us() * Self::TIMEOUT_TICKS
+        };
+        if elapsed(last, now) > timeout as i32 {
+            self.reset();
+        }
+    }
+
+    /// Handle an incoming message, return the clock event it carries
+    pub fn receive(&mut self, msg: &Message, now: Instant) -> Option<ClockEvent> {
+        let event = ClockEvent::from_message(msg)?;
+        if event == ClockEvent::Tick {
+            self.clock(now);
+        }
+        self.transport.apply(event);
+        Some(event)
+    }
+
+    fn clock(&mut self, now: Instant) {
+        let Some(last) = self.last.replace(now) else {
+            self.phase = now;
+            self.count = 1;
+            return;
+        };
+
+        let interval = elapsed(last, now).max(1) as u32;
+        if self.period == 0 {
+            self.period = interval << PERIOD_SHIFT;
+            self.phase = now;
+            self.count = 2;
+            return;
+        }
+
+        let expected = self.phase.wrapping_add(self.period_us());
+        let error = elapsed(expected, now);
+
+        if error.unsigned_abs() > self.period_us() / 2 {
+            self.outliers += 1;
+            if self.outliers >= Self::MAX_OUTLIERS {
+                // Not a glitch, the tempo changed a lot
+                self.period = interval << PERIOD_SHIFT;
+                self.count = 2;
+                self.outliers = 0;
+            }
+            self.phase = now;
+            return;
+        }
+        self.outliers = 0;
+
+        // Faster convergence until locked, then smoother
+        let (alpha, beta) = if self.is_locked() { (3, 7) } else { (2, 5) };
+        self.phase = expected.wrapping_add_signed(error >> alpha);
+        let period = self.period as i32 + ((error << PERIOD_SHIFT) >> beta);
+        self.period = period.max(1) as u32;
+        self.count = self.count.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Tick period at 120 BPM
+    const PERIOD_120: u32 = 20_833;
+
+    /// Deterministic pseudo random jitter in -amplitude..=amplitude
+    struct Jitter(u32);
+
+    impl Jitter {
+        fn next(&mut self, amplitude: i32) -> i32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (self.0 >> 8) as i32 % (amplitude + 1) * if self.0 & 1 == 0 { 1 } else { -1 }
+        }
+    }
+
+    fn clocks(slave: &mut ClockSlave, start: Instant, period: u32, count: u32, jitter: i32) {
+        let mut rng = Jitter(42);
+        for i in 0..count {
+            let t = start.wrapping_add(i * period).wrapping_add_signed(rng.next(jitter));
+            slave.receive(&Message::Clock, t);
+        }
+    }
+
+    #[test]
+    fn master_ticks() {
+        let mut master = ClockMaster::new(120);
+        master.start(1000);
+        assert_eq!(master.poll(1000), Some(ClockEvent::Start));
+
+        let mut ticks = Vec::new();
+        for now in (1000..1_001_000).step_by(100) {
+            while let Some(event) = master.poll(now) {
+                assert_eq!(event, ClockEvent::Tick);
+                ticks.push(now);
+            }
+        }
+        // One second at 120 BPM
+        assert_eq!(ticks.len(), 48);
+        assert_eq!(ticks[0], 1000);
+        assert!(ticks[47].abs_diff(1000 + 47 * PERIOD_120) < 100);
+        assert_eq!(master.transport().tick(), 47);
+        assert_eq!(master.transport().step(), 7);
+        assert_eq!(master.transport().tick_in_step(), 5);
+    }
+
+    #[test]
+    fn master_does_not_drift() {
+        let mut master = ClockMaster::new(133);
+        master.start(u32::MAX - 1000);
+        let mut now = u32::MAX - 1000;
+        let mut count = 0u32;
+        // Ten minutes, across the timestamp wrap around
+        for _ in 0..600_000 {
+            while let Some(event) = master.poll(now) {
+                if event == ClockEvent::Tick {
+                    count += 1;
+                }
+            }
+            now = now.wrapping_add(1000);
+        }
+        assert_eq!(count, 133 * 24 * 10);
+    }
+
+    #[test]
+    fn master_transport() {
+        let mut master = ClockMaster::new(120);
+        assert!(!master.transport().is_playing());
+        master.start(0);
+        while master.poll(0).is_some() {}
+        assert!(master.transport().is_playing());
+
+        master.stop();
+        assert_eq!(master.poll(10), Some(ClockEvent::Stop));
+        assert!(!master.transport().is_playing());
+
+        master.set_song_position(4);
+        assert_eq!(master.poll(10), Some(ClockEvent::SongPosition(4)));
+        master.resume(100_000);
+        assert_eq!(master.poll(100_000), Some(ClockEvent::Continue));
+        assert_eq!(master.poll(100_000), Some(ClockEvent::Tick));
+        assert_eq!(master.transport().tick(), 24);
+        assert_eq!(master.transport().step(), 4);
+    }
+
+    #[test]
+    fn master_tempo_change_keeps_phase() {
+        let mut master = ClockMaster::new(120);
+        master.start(0);
+        while master.poll(0).is_some() {}
+        assert_eq!(master.poll(PERIOD_120), Some(ClockEvent::Tick));
+
+        master.set_bpm(60);
+        assert_eq!(master.bpm(), 60);
+        assert_eq!(master.poll(2 * PERIOD_120), None);
+        assert_eq!(master.poll(3 * PERIOD_120 + 10), Some(ClockEvent::Tick));
+    }
+
+    #[test]
+    fn slave_tempo_without_jitter() {
+        let mut slave = ClockSlave::new();
+        clocks(&mut slave, 0, PERIOD_120, 48, 0);
+        assert!(slave.is_locked());
+        assert_eq!(slave.bpm(), 120);
+        assert!(slave.period_us().abs_diff(PERIOD_120) <= 1);
+    }
+
+    #[test]
+    fn slave_jitter_tolerance() {
+        let mut slave = ClockSlave::new();
+        // +/- 2 ms of jitter on each clock, about 10% of the period
+        clocks(&mut slave, 5000, PERIOD_120, 24 * 16, 2000);
+        assert!(slave.is_locked());
+        assert!(slave.bpm().abs_diff(120) <= 1, "{}", slave.bpm());
+
+        // The estimate stays stable after lock
+        let mut rng = Jitter(7);
+        let mut min = u16::MAX;
+        let mut max = 0;
+        for i in 24 * 16..24 * 64 {
+            let t = 5000 + i * PERIOD_120;
+            slave.receive(&Message::Clock, t.wrapping_add_signed(rng.next(2000)));
+            min = min.min(slave.bpm());
+            max = max.max(slave.bpm());
+        }
+        assert!(min >= 119 && max <= 121, "{min} {max}");
+    }
+
+    #[test]
+    fn slave_phase_lock() {
+        let mut slave = ClockSlave::new();
+        clocks(&mut slave, 0, PERIOD_120, 24 * 8, 1000);
+
+        // The predicted tick is close to the real one despite the jitter
+        let next = slave.next_tick().unwrap();
+        assert!(next.abs_diff(24 * 8 * PERIOD_120) < 1000, "{next}");
+
+        let last = next - slave.period_us();
+        assert_eq!(slave.phase(last), 0);
+        assert!(slave.phase(last + PERIOD_120 / 2).abs_diff(0x8000) < 0x400);
+        assert_eq!(slave.phase(last + 2 * PERIOD_120), 0xFFFF);
+    }
+
+    #[test]
+    fn slave_follows_tempo_change() {
+        let mut slave = ClockSlave::new();
+        clocks(&mut slave, 0, PERIOD_120, 48, 500);
+        assert_eq!(slave.bpm(), 120);
+
+        // 125 BPM
+        let period = 20_000;
+        clocks(&mut slave, 48 * PERIOD_120, period, 24 * 8, 500);
+        assert!(slave.bpm().abs_diff(125) <= 1, "{}", slave.bpm());
+
+        // A large jump is taken after a few clocks
+        let start = 48 * PERIOD_120 + 24 * 8 * period;
+        clocks(&mut slave, start, 2 * PERIOD_120, 24 * 4, 0);
+        assert_eq!(slave.bpm(), 60);
+    }
+
+    #[test]
+    fn slave_ignores_glitches() {
+        let mut slave = ClockSlave::new();
+        clocks(&mut slave, 0, PERIOD_120, 48, 0);
+
+        // A lost clock, and a doubled one
+        let t = 48 * PERIOD_120;
+        slave.receive(&Message::Clock, t + PERIOD_120);
+        slave.receive(&Message::Clock, t + 2 * PERIOD_120);
+        slave.receive(&Message::Clock, t + 2 * PERIOD_120 + 50);
+        slave.receive(&Message::Clock, t + 3 * PERIOD_120);
+        assert_eq!(slave.bpm(), 120);
+        assert!(slave.is_locked());
+    }
+
+    #[test]
+    fn slave_timeout() {
+        let mut slave = ClockSlave::new();
+        clocks(&mut slave, 0, PERIOD_120, 48, 0);
+        let last = 47 * PERIOD_120;
+
+        slave.update(last + 4 * PERIOD_120);
+        assert!(slave.is_locked());
+        slave.update(last + 9 * PERIOD_120);
+        assert!(!slave.is_locked());
+        assert_eq!(slave.bpm(), 0);
+        assert_eq!(slave.next_tick(), None);
+    }
+
+    #[test]
+    fn slave_transport() {
+        let mut slave = ClockSlave::new();
+        assert_eq!(
+            slave.receive(&Message::SongPosition(2), 0),
+            Some(ClockEvent::SongPosition(2))
+        );
+        assert_eq!(slave.receive(&Message::Continue, 0), Some(ClockEvent::Continue));
+        slave.receive(&Message::Clock, 10);
+        assert_eq!(slave.transport().tick(), 12);
+        assert_eq!(slave.transport().step(), 2);
+
+        slave.receive(&Message::Stop, 20);
+        slave.receive(&Message::Clock, 30);
+        assert_eq!(slave.transport().tick(), 12);
+
+        slave.receive(&Message::Start, 40);
+        for t in 0..7 {
+            slave.receive(&Message::Clock, 50 + t);
+        }
+        assert_eq!(slave.transport().tick(), 6);
+        assert_eq!(slave.transport().step(), 1);
+        assert_eq!(slave.transport().tick_in_step(), 0);
+
+        let note = Message::NoteOn {
+            channel: 0,
+            note: 60,
+            velocity: 100,
+        };
+        assert_eq!(slave.receive(&note, 100), None);
+    }
+}
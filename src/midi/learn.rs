@@ -0,0 +1,599 @@
+//! MIDI learn: control parameters with incoming CCs
+//!
+//! Arm learning on a parameter, typically the one selected in a
+//! `ui::ParamMenu`, and the next CC received is mapped to it:
+//!
+//! ```ignore
+//! if keyboard.falling(Keys::MENU) {
+//!     if let Some(id) = menu.selected() {
+//!         learn.start(id);
+//!     }
+//! }
+//! routing.process(&mut ports, |_port, event| {
+//!     if let Event::Message(msg) = event {
+//!         learn.handle(msg, &mut params);
+//!     }
+//! });
+//! ```
+//!
+//! The mapping list is kept with the other settings, see `MidiLearn::load`
+//! and `MidiLearn::save`.
+
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+
+use super::Message;
+use crate::param::{ParamId, ParamSet};
+use crate::settings::{Settings, SettingsError, MAX_VALUE_SIZE};
+
+pub const MAX_MAPPINGS: usize = 32;
+
+/// Returned when adding a mapping to a full `MidiLearn`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LearnFull;
+
+/// Response of the parameter to the controller position
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Curve {
+    Linear,
+    /// Fine control at the bottom of the range
+    Exponential,
+    /// Fine control at the top of the range
+    Logarithmic,
+}
+
+/// What happens when the controller and the parameter values differ
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Takeover {
+    /// The parameter jumps to the controller value
+    Jump,
+    /// The parameter only follows once the controller reaches its value
+    Pickup,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Mapping {
+    pub channel: u8,
+    pub control: u8,
+    pub param: ParamId,
+    /// Parameter values at CC 0 and CC 127, `min` can be above `max` to
+    /// invert the control
+    pub min: i16,
+    pub max: i16,
+    pub curve: Curve,
+    pub takeover: Takeover,
+}
+
+/// Result of a CC handled by `MidiLearn`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LearnEvent {
+    /// A new mapping was created for the parameter being learned
+    Learned(ParamId),
+    /// A parameter value changed
+    Changed(ParamId, i16),
+}
+
+const CC_MAX: i32 = 127;
+
+impl Mapping {
+    /// Map a controller to the full range of a parameter
+    pub fn new<const N: usize>(
+        channel: u8,
+        control: u8,
+        param: ParamId,
+        params: &ParamSet<N>,
+    ) -> Self {
+        let (min, max) = params
+            .info(param)
+            .map_or((0, 0), |info| (info.min, info.max));
+        Mapping {
+            channel,
+            control,
+            param,
+            min,
+            max,
+            curve: Curve::Linear,
+            takeover: Takeover::Jump,
+        }
+    }
+
+    /// Parameter value for a CC value
+    pub fn value(&self, cc: u8) -> i16 {
+        let cc = (cc as i32).min(CC_MAX);
+        let full = CC_MAX * CC_MAX;
+        let position = match self.curve {
+            Curve::Linear => cc * CC_MAX,
+            Curve::Exponential => cc * cc,
+            Curve::Logarithmic => full - (CC_MAX - cc) * (CC_MAX - cc),
+        };
+        let span = self.max as i32 - self.min as i32;
+        (self.min as i32 + span * position / full) as i16
+    }
+
+    fn encode(&self) -> [u8; MAPPING_SIZE] {
+        let [param_lo, param_hi] = self.param.to_le_bytes();
+        let [min_lo, min_hi] = self.min.to_le_bytes();
+        let [max_lo, max_hi] = self.max.to_le_bytes();
+        let curve = match self.curve {
+            Curve::Linear => 0,
+            Curve::Exponential => 1,
+            Curve::Logarithmic => 2,
+        };
+        let takeover = match self.takeover {
+            Takeover::Jump => 0,
+            Takeover::Pickup => 1,
+        };
+        [
+            self.channel,
+            self.control,
+            param_lo,
+            param_hi,
+            min_lo,
+            min_hi,
+            max_lo,
+            max_hi,
+            curve | takeover << 4,
+        ]
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let curve = match data[8] & 0xF {
+            0 => Curve::Linear,
+            1 => Curve::Exponential,
+            2 => Curve::Logarithmic,
+            _ => return None,
+        };
+        let takeover = match data[8] >> 4 {
+            0 => Takeover::Jump,
+            1 => Takeover::Pickup,
+            _ => return None,
+        };
+        Some(Mapping {
+            channel: data[0] & 0xF,
+            control: data[1] & 0x7F,
+            param: u16::from_le_bytes([data[2], data[3]]),
+            min: i16::from_le_bytes([data[4], data[5]]),
+            max: i16::from_le_bytes([data[6], data[7]]),
+            curve,
+            takeover,
+        })
+    }
+}
+
+const MAPPING_SIZE: usize = 9;
+const FORMAT_VERSION: u8 = 1;
+
+/// Prefix of the settings keys of the list, which is too large for a single
+/// setting: it is split in chunks saved as `midi.learn.0`, `midi.learn.1`...
+pub const SETTINGS_KEY: &str = "midi.learn";
+const CHUNK_COUNT: usize = MidiLearn::MAX_ENCODED_SIZE.div_ceil(MAX_VALUE_SIZE);
+
+fn chunk_key(index: usize) -> String<16> {
+    let mut key = String::new();
+    let _ = core::fmt::write(&mut key, format_args!("{SETTINGS_KEY}.{index}"));
+    key
+}
+
+/// Pickup tracking of a mapping
+#[derive(Copy, Clone, Default)]
+struct Pickup {
+    /// The controller has reached the parameter value
+    caught: bool,
+    /// Value set by the controller once caught, last controller value
+    /// before that
+    last: Option<i16>,
+}
+
+pub struct MidiLearn {
+    mappings: Vec<Mapping, MAX_MAPPINGS>,
+    pickups: Vec<Pickup, MAX_MAPPINGS>,
+    learning: Option<ParamId>,
+}
+
+impl Default for MidiLearn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiLearn {
+    /// Size of the `encode`d list with all the mappings
+    pub const MAX_ENCODED_SIZE: usize = 2 + MAX_MAPPINGS * MAPPING_SIZE;
+
+    pub const fn new() -> Self {
+        MidiLearn {
+            mappings: Vec::new(),
+            pickups: Vec::new(),
+            learning: None,
+        }
+    }
+
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// Change the range, curve or takeover of a mapping
+    pub fn mapping_mut(&mut self, index: usize) -> Option<&mut Mapping> {
+        // The last controller position is on the old scale
+        *self.pickups.get_mut(index)? = Pickup::default();
+        self.mappings.get_mut(index)
+    }
+
+    /// Map the next CC received to `param`
+    pub fn start(&mut self, param: ParamId) {
+        self.learning = Some(param);
+    }
+
+    pub fn cancel(&mut self) {
+        self.learning = None;
+    }
+
+    /// Parameter waiting for a CC
+    pub fn learning(&self) -> Option<ParamId> {
+        self.learning
+    }
+
+    /// Add a mapping, or replace the one of the same controller
+    pub fn map(&mut self, mapping: Mapping) -> Result<(), LearnFull> {
+        let existing = self
+            .mappings
+            .iter()
+            .position(|m| m.channel == mapping.channel && m.control == mapping.control);
+        if let Some(index) = existing {
+            self.mappings[index] = mapping;
+            self.pickups[index] = Pickup::default();
+            return Ok(());
+        }
+        self.mappings.push(mapping).map_err(|_| LearnFull)?;
+        let _ = self.pickups.push(Pickup::default());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < self.mappings.len() {
+            self.mappings.remove(index);
+            self.pickups.remove(index);
+        }
+    }
+
+    /// Remove all the mappings of a parameter
+    pub fn unmap(&mut self, param: ParamId) {
+        while let Some(index) = self.mappings.iter().position(|m| m.param == param) {
+            self.remove(index);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.mappings.clear();
+        self.pickups.clear();
+    }
+
+    /// Handle an incoming message, only CCs are used
+    pub fn handle<const N: usize>(
+        &mut self,
+        msg: &Message,
+        params: &mut ParamSet<N>,
+    ) -> Option<LearnEvent> {
+        let Message::ControlChange {
+            channel,
+            control,
+            value,
+        } = *msg
+        else {
+            return None;
+        };
+
+        if let Some(param) = self.learning {
+            if params.info(param).is_some() {
+                // Learning ends even if the list is full
+                self.learning = None;
+                let mapping = Mapping::new(channel, control, param, params);
+                return self.map(mapping).ok().map(|_| LearnEvent::Learned(param));
+            }
+        }
+
+        let mut event = None;
+        for (mapping, pickup) in self.mappings.iter().zip(self.pickups.iter_mut()) {
+            if mapping.channel != channel || mapping.control != control {
+                continue;
+            }
+
+            let target = mapping.value(value);
+            let current = params.get(mapping.param);
+            if pickup.caught && pickup.last != Some(current) {
+                // Changed by other means since, from the menu or a preset
+                *pickup = Pickup::default();
+            }
+
+            if mapping.takeover == Takeover::Pickup && !pickup.caught {
+                // Caught when the controller reaches or crosses the
+                // parameter value
+                pickup.caught = target == current
+                    || pickup
+                        .last
+                        .is_some_and(|last| (last < current) != (target < current));
+                pickup.last = Some(target);
+                if !pickup.caught {
+                    continue;
+                }
+            }
+
+            params.set(mapping.param, target as i32);
+            let value = params.get(mapping.param);
+            pickup.last = Some(value);
+            event = Some(LearnEvent::Changed(mapping.param, value));
+        }
+        event
+    }
+
+    /// Write the mapping list to `buffer`, return the encoded length
+    pub fn encode(&self, buffer: &mut [u8]) -> Option<usize> {
+        let len = 2 + self.mappings.len() * MAPPING_SIZE;
+        let out = buffer.get_mut(..len)?;
+        out[0] = FORMAT_VERSION;
+        out[1] = self.mappings.len() as u8;
+        for (mapping, chunk) in self
+            .mappings
+            .iter()
+            .zip(out[2..].chunks_exact_mut(MAPPING_SIZE))
+        {
+            chunk.copy_from_slice(&mapping.encode());
+        }
+        Some(len)
+    }
+
+    /// Replace the mapping list with the one saved in the settings. Return
+    /// false, with the list unchanged, if none was saved or it is not valid.
+    pub fn load<F: NorFlash>(&mut self, settings: &mut Settings<F>) -> Result<bool, SettingsError> {
+        let mut data = [0; Self::MAX_ENCODED_SIZE];
+        let mut len = 0;
+        for index in 0..CHUNK_COUNT {
+            let chunk = &mut data[len..(len + MAX_VALUE_SIZE).min(Self::MAX_ENCODED_SIZE)];
+            match settings.get_bytes(&chunk_key(index), chunk)? {
+                Some(chunk_len) if chunk_len <= chunk.len() => len += chunk_len,
+                _ => return Ok(false),
+            }
+            // The header gives the encoded length
+            if len >= 2 && len >= 2 + data[1] as usize * MAPPING_SIZE {
+                break;
+            }
+        }
+        Ok(self.decode(&data[..len]))
+    }
+
+    pub fn save<F: NorFlash>(&self, settings: &mut Settings<F>) -> Result<(), SettingsError> {
+        let mut data = [0; Self::MAX_ENCODED_SIZE];
+        let len = self.encode(&mut data).unwrap_or(0);
+        let mut chunks = data[..len].chunks(MAX_VALUE_SIZE);
+        for index in 0..CHUNK_COUNT {
+            match chunks.next() {
+                Some(chunk) => settings.set_bytes(&chunk_key(index), chunk)?,
+                None => settings.remove(&chunk_key(index))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the mapping list with an `encode`d one
+    pub fn decode(&mut self, data: &[u8]) -> bool {
+        let [version, count, rest @ ..] = data else {
+            return false;
+        };
+        let count = *count as usize;
+        if *version != FORMAT_VERSION || count > MAX_MAPPINGS || rest.len() != count * MAPPING_SIZE
+        {
+            return false;
+        }
+
+        let mut mappings: Vec<Mapping, MAX_MAPPINGS> = Vec::new();
+        for chunk in rest.chunks_exact(MAPPING_SIZE) {
+            let Some(mapping) = Mapping::decode(chunk) else {
+                return false;
+            };
+            let _ = mappings.push(mapping);
+        }
+
+        self.clear();
+        for mapping in mappings {
+            let _ = self.map(mapping);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{RamFlash, SECTOR_SIZE};
+    use crate::param::ParamInfo;
+
+    static PARAMS: [ParamInfo; 2] = [
+        ParamInfo::new("Level", 0, 127, 64),
+        ParamInfo::new("Tune", -1200, 1200, 0),
+    ];
+    const LEVEL: ParamId = 0;
+    const TUNE: ParamId = 1;
+
+    fn cc(control: u8, value: u8) -> Message {
+        Message::ControlChange {
+            channel: 3,
+            control,
+            value,
+        }
+    }
+
+    #[test]
+    fn curves() {
+        let params = ParamSet::new(&PARAMS);
+        let mut mapping = Mapping::new(0, 1, TUNE, &params);
+        assert_eq!((mapping.min, mapping.max), (-1200, 1200));
+        mapping.min = 0;
+        mapping.max = 127 * 127;
+        for (curve, mid) in [
+            (Curve::Linear, 8128),
+            (Curve::Exponential, 4096),
+            (Curve::Logarithmic, 16129 - 63 * 63),
+        ] {
+            mapping.curve = curve;
+            assert_eq!(mapping.value(0), 0);
+            assert_eq!(mapping.value(64), mid);
+            assert_eq!(mapping.value(127), 16129);
+            assert_eq!(mapping.value(200), 16129);
+        }
+
+        // Inverted
+        mapping.curve = Curve::Linear;
+        mapping.min = 100;
+        mapping.max = -100;
+        assert_eq!(mapping.value(0), 100);
+        assert_eq!(mapping.value(127), -100);
+        mapping.min = i16::MIN;
+        mapping.max = i16::MAX;
+        assert_eq!(mapping.value(0), i16::MIN);
+        assert_eq!(mapping.value(127), i16::MAX);
+    }
+
+    #[test]
+    fn learning() {
+        let mut params = ParamSet::new(&PARAMS);
+        let mut learn = MidiLearn::new();
+        assert_eq!(learn.handle(&cc(7, 10), &mut params), None);
+
+        learn.start(LEVEL);
+        assert_eq!(learn.learning(), Some(LEVEL));
+        assert_eq!(learn.handle(&Message::Clock, &mut params), None);
+        assert_eq!(
+            learn.handle(&cc(7, 10), &mut params),
+            Some(LearnEvent::Learned(LEVEL))
+        );
+        assert_eq!(learn.learning(), None);
+        assert_eq!(learn.mappings()[0], Mapping::new(3, 7, LEVEL, &params));
+        assert_eq!(
+            learn.handle(&cc(7, 10), &mut params),
+            Some(LearnEvent::Changed(LEVEL, 10))
+        );
+        assert_eq!(params.get(LEVEL), 10);
+        // Other channel
+        let msg = Message::ControlChange {
+            channel: 4,
+            control: 7,
+            value: 0,
+        };
+        assert_eq!(learn.handle(&msg, &mut params), None);
+
+        // Learning the same controller again replaces its mapping
+        learn.start(TUNE);
+        learn.handle(&cc(7, 10), &mut params);
+        assert_eq!(learn.mappings().len(), 1);
+        assert_eq!(
+            learn.handle(&cc(7, 127), &mut params),
+            Some(LearnEvent::Changed(TUNE, 1200))
+        );
+        learn.start(LEVEL);
+        learn.cancel();
+        assert_eq!(learn.learning(), None);
+
+        for control in 0..MAX_MAPPINGS as u8 - 1 {
+            learn.map(Mapping::new(0, control, LEVEL, &params)).unwrap();
+        }
+        assert_eq!(
+            learn.map(Mapping::new(1, 0, LEVEL, &params)),
+            Err(LearnFull)
+        );
+        // Learning ends on a full list
+        learn.start(TUNE);
+        assert_eq!(learn.handle(&cc(100, 0), &mut params), None);
+        assert_eq!(learn.learning(), None);
+
+        learn.unmap(LEVEL);
+        assert_eq!(learn.mappings().len(), 1);
+        learn.remove(0);
+        learn.remove(5);
+        assert!(learn.mappings().is_empty());
+    }
+
+    #[test]
+    fn pickup() {
+        let mut params = ParamSet::new(&PARAMS);
+        let mut learn = MidiLearn::new();
+        let mut mapping = Mapping::new(3, 1, LEVEL, &params);
+        mapping.takeover = Takeover::Pickup;
+        learn.map(mapping).unwrap();
+
+        // Below the value of the parameter, 64
+        assert_eq!(learn.handle(&cc(1, 10), &mut params), None);
+        assert_eq!(learn.handle(&cc(1, 30), &mut params), None);
+        assert_eq!(params.get(LEVEL), 64);
+        // Crossing it
+        assert_eq!(
+            learn.handle(&cc(1, 70), &mut params),
+            Some(LearnEvent::Changed(LEVEL, 70))
+        );
+        assert_eq!(
+            learn.handle(&cc(1, 20), &mut params),
+            Some(LearnEvent::Changed(LEVEL, 20))
+        );
+
+        // Changed from the menu, picked up again when reached
+        params.set(LEVEL, 100);
+        assert_eq!(learn.handle(&cc(1, 21), &mut params), None);
+        assert_eq!(
+            learn.handle(&cc(1, 100), &mut params),
+            Some(LearnEvent::Changed(LEVEL, 100))
+        );
+
+        // Changing the mapping releases it
+        learn.mapping_mut(0).unwrap().max = 63;
+        assert_eq!(learn.handle(&cc(1, 127), &mut params), None);
+        assert_eq!(learn.handle(&cc(1, 126), &mut params), None);
+
+        // Jump follows the controller right away
+        learn.mapping_mut(0).unwrap().takeover = Takeover::Jump;
+        assert_eq!(
+            learn.handle(&cc(1, 0), &mut params),
+            Some(LearnEvent::Changed(LEVEL, 0))
+        );
+    }
+
+    #[test]
+    fn load_and_save() {
+        let params = ParamSet::new(&PARAMS);
+        let mut settings = Settings::new(RamFlash::<{ 2 * SECTOR_SIZE as usize }>::new()).unwrap();
+        let mut learn = MidiLearn::new();
+        assert_eq!(learn.load(&mut settings), Ok(false));
+
+        // Spread over all the chunks
+        for control in 0..MAX_MAPPINGS as u8 {
+            let mut mapping = Mapping::new(control % 16, control, TUNE, &params);
+            mapping.min = control as i16 * 10;
+            mapping.curve = Curve::Logarithmic;
+            mapping.takeover = Takeover::Pickup;
+            learn.map(mapping).unwrap();
+        }
+        learn.save(&mut settings).unwrap();
+        let mut settings = Settings::new(settings.into_inner()).unwrap();
+        let mut loaded = MidiLearn::new();
+        assert_eq!(loaded.load(&mut settings), Ok(true));
+        assert_eq!(loaded.mappings(), learn.mappings());
+
+        // Fewer mappings, the extra chunks are removed
+        learn.clear();
+        learn.map(Mapping::new(0, 1, LEVEL, &params)).unwrap();
+        learn.save(&mut settings).unwrap();
+        assert_eq!(settings.get_bytes("midi.learn.1", &mut []), Ok(None));
+        assert_eq!(loaded.load(&mut settings), Ok(true));
+        assert_eq!(loaded.mappings(), learn.mappings());
+
+        // An invalid list leaves the mappings unchanged
+        settings
+            .set_bytes("midi.learn.0", &[FORMAT_VERSION, 2])
+            .unwrap();
+        assert_eq!(loaded.load(&mut settings), Ok(false));
+        assert_eq!(loaded.mappings(), learn.mappings());
+
+        learn.clear();
+        learn.save(&mut settings).unwrap();
+        assert_eq!(loaded.load(&mut settings), Ok(true));
+        assert!(loaded.mappings().is_empty());
+    }
+}
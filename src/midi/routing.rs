@@ -0,0 +1,460 @@
+//! MIDI routing between the ports
+//!
+//! Each input port has a set of destination ports (DIN in to DIN out is the
+//! soft thru), a filter applied to what it receives and a filter applied to
+//! what is sent on it. The application sees the messages that pass the input
+//! filter of their port.
+//!
+//! ```ignore
+//! periph.usb.poll();
+//! routing.process(&mut [&mut periph.midi, &mut periph.usb.midi], |port, event| {
+//!     // handle the event
+//! });
+//! ```
+//!
+//! The table is kept with the other settings, see `Routing::load` and
+//! `Routing::save`.
+
+use embedded_storage::nor_flash::NorFlash;
+
+use super::{Event, Interface, Message, SYSEX_BUFFER_SIZE};
+use crate::settings::{Settings, SettingsError};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Port {
+    /// DIN/TRS jacks
+    Din,
+    Usb,
+}
+
+impl Port {
+    pub const COUNT: usize = 2;
+    pub const LIST: [Port; Port::COUNT] = [Port::Din, Port::Usb];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Messages let through a port
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Filter {
+    /// One bit per channel, bit 0 for channel 0
+    pub channels: u16,
+    /// Drop clock, start, stop, continue and song position
+    pub drop_clock: bool,
+    pub drop_sysex: bool,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl Filter {
+    /// Let everything through
+    pub const ALL: Filter = Filter {
+        channels: 0xFFFF,
+        drop_clock: false,
+        drop_sysex: false,
+    };
+
+    pub fn channel_enabled(&self, channel: u8) -> bool {
+        self.channels & (1 << (channel & 0xF)) != 0
+    }
+
+    pub fn set_channel(&mut self, channel: u8, enabled: bool) {
+        let bit = 1 << (channel & 0xF);
+        if enabled {
+            self.channels |= bit;
+        } else {
+            self.channels &= !bit;
+        }
+    }
+
+    pub fn accepts(&self, event: &Event) -> bool {
+        match event {
+            Event::SysEx(_) => !self.drop_sysex,
+            Event::Message(msg) => match msg {
+                Message::Clock
+                | Message::Start
+                | Message::Continue
+                | Message::Stop
+                | Message::SongPosition(_) => !self.drop_clock,
+                _ => msg.channel().is_none_or(|ch| self.channel_enabled(ch)),
+            },
+        }
+    }
+
+    fn encode(&self) -> [u8; 3] {
+        let [lo, hi] = self.channels.to_le_bytes();
+        let flags = self.drop_clock as u8 | (self.drop_sysex as u8) << 1;
+        [lo, hi, flags]
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        Filter {
+            channels: u16::from_le_bytes([data[0], data[1]]),
+            drop_clock: data[2] & 1 != 0,
+            drop_sysex: data[2] & 2 != 0,
+        }
+    }
+}
+
+/// Routing table of the MIDI ports
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Routing {
+    /// Destinations of each input, one bit per port
+    routes: [u8; Port::COUNT],
+    input_filters: [Filter; Port::COUNT],
+    output_filters: [Filter; Port::COUNT],
+}
+
+impl Default for Routing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const FORMAT_VERSION: u8 = 1;
+
+/// Settings key of the table
+pub const SETTINGS_KEY: &str = "midi.routing";
+
+impl Routing {
+    /// Size of the `encode`d table
+    pub const ENCODED_SIZE: usize = 1 + Port::COUNT * 7;
+
+    /// No forwarding, no filtering
+    pub const fn new() -> Self {
+        Routing {
+            routes: [0; Port::COUNT],
+            input_filters: [Filter::ALL; Port::COUNT],
+            output_filters: [Filter::ALL; Port::COUNT],
+        }
+    }
+
+    pub fn route(&self, from: Port, to: Port) -> bool {
+        self.routes[from.index()] & to.mask() != 0
+    }
+
+    pub fn set_route(&mut self, from: Port, to: Port, enabled: bool) {
+        if enabled {
+            self.routes[from.index()] |= to.mask();
+        } else {
+            self.routes[from.index()] &= !to.mask();
+        }
+    }
+
+    /// DIN in to DIN out
+    pub fn soft_thru(&self) -> bool {
+        self.route(Port::Din, Port::Din)
+    }
+
+    pub fn set_soft_thru(&mut self, enabled: bool) {
+        self.set_route(Port::Din, Port::Din, enabled);
+    }
+
+    /// Filter of the messages received on `port`
+    pub fn input_filter(&self, port: Port) -> &Filter {
+        &self.input_filters[port.index()]
+    }
+
+    pub fn input_filter_mut(&mut self, port: Port) -> &mut Filter {
+        &mut self.input_filters[port.index()]
+    }
+
+    /// Filter of the messages sent on `port`
+    pub fn output_filter(&self, port: Port) -> &Filter {
+        &self.output_filters[port.index()]
+    }
+
+    pub fn output_filter_mut(&mut self, port: Port) -> &mut Filter {
+        &mut self.output_filters[port.index()]
+    }
+
+    /// True if an event received on `from` goes to `to`
+    pub fn forwards(&self, from: Port, to: Port, event: &Event) -> bool {
+        self.route(from, to)
+            && self.input_filter(from).accepts(event)
+            && self.output_filter(to).accepts(event)
+    }
+
+    /// Read the events received on all the `ports`, indexed by `Port`, and
+    /// forward them to their destinations. `handler` gets the events that
+    /// the application should handle.
+    ///
+    /// Events are dropped on destinations that are full.
+    pub fn process<F>(&self, ports: &mut [&mut dyn Interface; Port::COUNT], mut handler: F)
+    where
+        F: FnMut(Port, &Event),
+    {
+        // Events borrow their port, SysEx data is copied so that it can be
+        // sent back on the same port.
+        let mut sysex = [0u8; SYSEX_BUFFER_SIZE];
+
+        for from in Port::LIST {
+            loop {
+                let event = match ports[from.index()].poll() {
+                    None => break,
+                    Some(Event::Message(msg)) => Event::Message(msg),
+                    Some(Event::SysEx(data)) => {
+                        let len = data.len().min(sysex.len());
+                        sysex[..len].copy_from_slice(&data[..len]);
+                        Event::SysEx(&sysex[..len])
+                    }
+                };
+
+                for to in Port::LIST {
+                    if self.forwards(from, to, &event) {
+                        ports[to.index()].send_event(&event);
+                    }
+                }
+                if self.input_filter(from).accepts(&event) {
+                    handler(from, &event);
+                }
+            }
+        }
+    }
+
+    /// Send an event generated by the application to `port`, if its output
+    /// filter lets it through
+    pub fn send(&self, port: Port, event: &Event, output: &mut dyn Interface) -> bool {
+        self.output_filter(port).accepts(event) && output.send_event(event)
+    }
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_SIZE] {
+        let mut data = [0; Self::ENCODED_SIZE];
+        data[0] = FORMAT_VERSION;
+        for (port, chunk) in data[1..].chunks_exact_mut(7).enumerate() {
+            chunk[0] = self.routes[port];
+            chunk[1..4].copy_from_slice(&self.input_filters[port].encode());
+            chunk[4..7].copy_from_slice(&self.output_filters[port].encode());
+        }
+        data
+    }
+
+    /// Read the table from the settings, `None` if it was never saved or
+    /// is not valid
+    pub fn load<F: NorFlash>(settings: &mut Settings<F>) -> Result<Option<Self>, SettingsError> {
+        let mut data = [0; Self::ENCODED_SIZE];
+        Ok(match settings.get_bytes(SETTINGS_KEY, &mut data)? {
+            Some(Self::ENCODED_SIZE) => Self::decode(&data),
+            _ => None,
+        })
+    }
+
+    pub fn save<F: NorFlash>(&self, settings: &mut Settings<F>) -> Result<(), SettingsError> {
+        settings.set_bytes(SETTINGS_KEY, &self.encode())
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != Self::ENCODED_SIZE || data[0] != FORMAT_VERSION {
+            return None;
+        }
+        let mut routing = Routing::new();
+        for (port, chunk) in data[1..].chunks_exact(7).enumerate() {
+            routing.routes[port] = chunk[0] & ((1 << Port::COUNT) - 1);
+            routing.input_filters[port] = Filter::decode(&chunk[1..4]);
+            routing.output_filters[port] = Filter::decode(&chunk[4..7]);
+        }
+        Some(routing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{RamFlash, SECTOR_SIZE};
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// Owned copy of an `Event`
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    enum Owned {
+        Message(Message),
+        SysEx(Vec<u8>),
+    }
+
+    impl Owned {
+        fn new(event: &Event) -> Self {
+            match event {
+                Event::Message(msg) => Owned::Message(*msg),
+                Event::SysEx(data) => Owned::SysEx(data.to_vec()),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct TestPort {
+        received: VecDeque<Owned>,
+        current: Option<Owned>,
+        sent: Vec<Owned>,
+    }
+
+    impl Interface for TestPort {
+        fn poll(&mut self) -> Option<Event<'_>> {
+            self.current = self.received.pop_front();
+            match self.current.as_ref()? {
+                Owned::Message(msg) => Some(Event::Message(*msg)),
+                Owned::SysEx(data) => Some(Event::SysEx(data)),
+            }
+        }
+
+        fn send(&mut self, msg: &Message) -> bool {
+            self.sent.push(Owned::Message(*msg));
+            true
+        }
+
+        fn send_sysex(&mut self, data: &[u8]) -> bool {
+            self.sent.push(Owned::SysEx(data.to_vec()));
+            true
+        }
+    }
+
+    const NOTE: Message = Message::NoteOn {
+        channel: 2,
+        note: 60,
+        velocity: 100,
+    };
+    const CC: Message = Message::ControlChange {
+        channel: 9,
+        control: 1,
+        value: 64,
+    };
+
+    #[test]
+    fn filters() {
+        let mut filter = Filter::ALL;
+        assert!(filter.accepts(&Event::Message(NOTE)));
+        filter.set_channel(2, false);
+        assert!(!filter.channel_enabled(2));
+        assert!(!filter.accepts(&Event::Message(NOTE)));
+        assert!(filter.accepts(&Event::Message(CC)));
+        // System messages have no channel
+        assert!(filter.accepts(&Event::Message(Message::TuneRequest)));
+        filter.set_channel(2, true);
+        assert_eq!(filter, Filter::ALL);
+
+        filter.drop_clock = true;
+        for msg in [
+            Message::Clock,
+            Message::Start,
+            Message::Continue,
+            Message::Stop,
+            Message::SongPosition(4),
+        ] {
+            assert!(!filter.accepts(&Event::Message(msg)));
+        }
+        assert!(filter.accepts(&Event::Message(Message::ActiveSensing)));
+        assert!(filter.accepts(&Event::SysEx(&[1, 2])));
+        filter.drop_sysex = true;
+        assert!(!filter.accepts(&Event::SysEx(&[1, 2])));
+    }
+
+    #[test]
+    fn routes() {
+        let mut routing = Routing::new();
+        let note = Event::Message(NOTE);
+        for from in Port::LIST {
+            for to in Port::LIST {
+                assert!(!routing.forwards(from, to, &note));
+            }
+        }
+
+        routing.set_soft_thru(true);
+        routing.set_route(Port::Usb, Port::Din, true);
+        assert!(routing.soft_thru());
+        assert!(routing.forwards(Port::Din, Port::Din, &note));
+        assert!(routing.forwards(Port::Usb, Port::Din, &note));
+        assert!(!routing.forwards(Port::Din, Port::Usb, &note));
+
+        // Input and output filters both apply
+        routing.input_filter_mut(Port::Usb).set_channel(2, false);
+        routing.output_filter_mut(Port::Din).set_channel(9, false);
+        assert!(!routing.forwards(Port::Usb, Port::Din, &note));
+        assert!(routing.forwards(Port::Din, Port::Din, &note));
+        assert!(!routing.forwards(Port::Din, Port::Din, &Event::Message(CC)));
+
+        routing.set_route(Port::Usb, Port::Din, false);
+        assert!(!routing.route(Port::Usb, Port::Din));
+        assert!(routing.route(Port::Din, Port::Din));
+    }
+
+    #[test]
+    fn process() {
+        let mut routing = Routing::new();
+        routing.set_route(Port::Din, Port::Usb, true);
+        routing.set_soft_thru(true);
+        routing.output_filter_mut(Port::Usb).drop_clock = true;
+        routing.input_filter_mut(Port::Din).set_channel(9, false);
+
+        let mut din = TestPort::default();
+        let mut usb = TestPort::default();
+        din.received.extend([
+            Owned::Message(NOTE),
+            Owned::Message(Message::Clock),
+            Owned::Message(CC),
+            Owned::SysEx(std::vec![0x7D, 1]),
+        ]);
+        usb.received.push_back(Owned::Message(Message::Stop));
+
+        let mut handled = Vec::new();
+        routing.process(&mut [&mut din, &mut usb], |port, event| {
+            handled.push((port, Owned::new(event)));
+        });
+        assert_eq!(
+            din.sent,
+            [
+                Owned::Message(NOTE),
+                Owned::Message(Message::Clock),
+                Owned::SysEx(std::vec![0x7D, 1]),
+            ]
+        );
+        assert_eq!(
+            usb.sent,
+            [Owned::Message(NOTE), Owned::SysEx(std::vec![0x7D, 1])]
+        );
+        assert_eq!(
+            handled,
+            [
+                (Port::Din, Owned::Message(NOTE)),
+                (Port::Din, Owned::Message(Message::Clock)),
+                (Port::Din, Owned::SysEx(std::vec![0x7D, 1])),
+                (Port::Usb, Owned::Message(Message::Stop)),
+            ]
+        );
+
+        assert!(!routing.send(Port::Usb, &Event::Message(Message::Start), &mut usb));
+        assert!(routing.send(Port::Din, &Event::Message(Message::Start), &mut din));
+    }
+
+    #[test]
+    fn load_and_save() {
+        let mut settings = Settings::new(RamFlash::<{ 2 * SECTOR_SIZE as usize }>::new()).unwrap();
+        assert_eq!(Routing::load(&mut settings), Ok(None));
+
+        let mut routing = Routing::new();
+        routing.set_route(Port::Usb, Port::Din, true);
+        routing.input_filter_mut(Port::Din).set_channel(15, false);
+        routing.output_filter_mut(Port::Usb).drop_sysex = true;
+        routing.output_filter_mut(Port::Din).drop_clock = true;
+        routing.save(&mut settings).unwrap();
+
+        let mut settings = Settings::new(settings.into_inner()).unwrap();
+        assert_eq!(Routing::load(&mut settings), Ok(Some(routing)));
+
+        // Unknown format
+        let mut data = routing.encode();
+        data[0] = 2;
+        settings.set_bytes(SETTINGS_KEY, &data).unwrap();
+        assert_eq!(Routing::load(&mut settings), Ok(None));
+        settings.set_bytes(SETTINGS_KEY, &data[..4]).unwrap();
+        assert_eq!(Routing::load(&mut settings), Ok(None));
+    }
+}
@@ -0,0 +1,257 @@
+//! MIDI on the DIN/TRS jacks
+//!
+//! UART0 runs at the MIDI rate of 31250 baud. The `UART0_IRQ` handler moves
+//! received bytes to a ring buffer and feeds the transmit FIFO from a queue,
+//! so the main loop never waits on the serial line.
+//!
+//! The interrupt is only unmasked when there is a handler for it: with the
+//! `midi-uart-irq` feature the crate defines the handler and unmasks the
+//! interrupt. Otherwise the application defines its own handler, which
+//! calls `on_interrupt`, then calls `MidiUart::enable_interrupt`:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn UART0_IRQ() {
+//!     pgb1::midi::uart::on_interrupt();
+//! }
+//!
+//! periph.midi.enable_interrupt();
+//! ```
+//!
+//! Without a handler the application can call `on_interrupt` from its main
+//! loop instead. The receive FIFO holds 32 bytes, about 10 ms of MIDI.
+
+use core::cell::RefCell;
+use core::ptr::addr_of_mut;
+
+use cortex_m::interrupt::Mutex;
+use fugit::HertzU32;
+use rp2040_hal::gpio::bank0::{Gpio0, Gpio1};
+use rp2040_hal::gpio::{FunctionUart, Pin, PullNone, PullUp};
+use rp2040_hal::uart::{DataBits, Enabled, StopBits, UartConfig, UartPeripheral};
+
+use super::{Event, Interface, Message, Parser};
+use crate::pac;
+#[cfg(feature = "midi-uart-irq")]
+use crate::pac::interrupt;
+use crate::spsc::{Consumer, Producer, Queue};
+
+pub const BAUDRATE: u32 = 31_250;
+
+pub const RX_BUFFER_SIZE: usize = 256;
+pub const TX_BUFFER_SIZE: usize = 256;
+
+pub type TxPin = Pin<Gpio0, FunctionUart, PullNone>;
+/// The opto-isolator output is open collector
+pub type RxPin = Pin<Gpio1, FunctionUart, PullUp>;
+
+type Uart = UartPeripheral<Enabled, pac::UART0, (TxPin, RxPin)>;
+
+static mut RX_QUEUE: Queue<u8, RX_BUFFER_SIZE> = Queue::new();
+static mut TX_QUEUE: Queue<u8, TX_BUFFER_SIZE> = Queue::new();
+
+/// What the interrupt handler works with
+struct IrqState {
+    uart: Uart,
+    rx: Producer<'static, u8, RX_BUFFER_SIZE>,
+    tx: Consumer<'static, u8, TX_BUFFER_SIZE>,
+    /// Bytes lost because the RX buffer was full or the line was noisy
+    dropped: u32,
+}
+
+static IRQ_STATE: Mutex<RefCell<Option<IrqState>>> = Mutex::new(RefCell::new(None));
+
+impl IrqState {
+    fn receive(&mut self) {
+        let mut buffer = [0u8; 16];
+        loop {
+            match self.uart.read_raw(&mut buffer) {
+                Ok(len) => {
+                    for &byte in &buffer[..len] {
+                        if self.rx.enqueue(byte).is_err() {
+                            self.dropped = self.dropped.wrapping_add(1);
+                        }
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(err)) => {
+                    // Framing, parity or overrun error, the parser resyncs
+                    // on the next status byte.
+                    self.dropped = self.dropped.wrapping_add(err.discarded.len() as u32 + 1);
+                }
+            }
+        }
+    }
+
+    /// Fill the TX FIFO, the TX interrupt stays enabled while there are
+    /// bytes left in the queue.
+    fn transmit(&mut self) {
+        while self.uart.uart_is_writable() {
+            match self.tx.dequeue() {
+                Some(byte) => {
+                    let _ = self.uart.write_raw(&[byte]);
+                }
+                None => {
+                    self.uart.disable_tx_interrupt();
+                    return;
+                }
+            }
+        }
+        self.uart.enable_tx_interrupt();
+    }
+}
+
+/// Service the MIDI UART, must be called from the `UART0_IRQ` handler
+pub fn on_interrupt() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(state) = IRQ_STATE.borrow(cs).borrow_mut().as_mut() {
+            state.receive();
+            state.transmit();
+        }
+    });
+}
+
+#[cfg(feature = "midi-uart-irq")]
+#[interrupt]
+fn UART0_IRQ() {
+    on_interrupt();
+}
+
+/// MIDI port of the DIN/TRS jacks
+pub struct MidiUart {
+    rx: Consumer<'static, u8, RX_BUFFER_SIZE>,
+    tx: Producer<'static, u8, TX_BUFFER_SIZE>,
+    parser: Parser,
+}
+
+impl MidiUart {
+    /// Only called once, from `Peripherals::steal`
+    pub(crate) fn new(
+        device: pac::UART0,
+        pins: (TxPin, RxPin),
+        resets: &mut pac::RESETS,
+        peripheral_clock: HertzU32,
+    ) -> Self {
+        let config = UartConfig::new(
+            HertzU32::from_raw(BAUDRATE),
+            DataBits::Eight,
+            None,
+            StopBits::One,
+        );
+        let mut uart = UartPeripheral::new(device, pins, resets)
+            .enable(config, peripheral_clock)
+            .unwrap();
+        uart.enable_rx_interrupt();
+
+        // The queues are only split here
+        let (rx_producer, rx_consumer) = unsafe { (*addr_of_mut!(RX_QUEUE)).split() };
+        let (tx_producer, tx_consumer) = unsafe { (*addr_of_mut!(TX_QUEUE)).split() };
+
+        cortex_m::interrupt::free(|cs| {
+            IRQ_STATE.borrow(cs).replace(Some(IrqState {
+                uart,
+                rx: rx_producer,
+                tx: tx_consumer,
+                dropped: 0,
+            }));
+        });
+        let midi = MidiUart {
+            rx: rx_consumer,
+            tx: tx_producer,
+            parser: Parser::new(),
+        };
+        #[cfg(feature = "midi-uart-irq")]
+        midi.enable_interrupt();
+        midi
+    }
+
+    /// Unmask `UART0_IRQ`, once the application defined its handler. Done
+    /// by `Peripherals` with the `midi-uart-irq` feature.
+    ///
+    /// Without a handler, the first interrupt would end in the default
+    /// handler of `cortex-m-rt`, which never returns.
+    pub fn enable_interrupt(&self) {
+        unsafe { pac::NVIC::unmask(pac::Interrupt::UART0_IRQ) };
+    }
+
+    /// Next received raw byte
+    pub fn read_byte(&mut self) -> Option<u8> {
+        self.rx.dequeue()
+    }
+
+    /// Parse the received bytes until a message is complete
+    pub fn poll(&mut self) -> Option<Event<'_>> {
+        loop {
+            let byte = self.rx.dequeue()?;
+            if let Some(parsed) = self.parser.parse(byte) {
+                return Some(self.parser.event(parsed));
+            }
+        }
+    }
+
+    /// Number of received bytes lost so far
+    pub fn dropped_bytes(&self) -> u32 {
+        cortex_m::interrupt::free(|cs| {
+            IRQ_STATE
+                .borrow(cs)
+                .borrow()
+                .as_ref()
+                .map_or(0, |state| state.dropped)
+        })
+    }
+
+    /// Free space in the transmit queue
+    pub fn tx_space(&self) -> usize {
+        TX_BUFFER_SIZE - self.tx.len()
+    }
+
+    /// Queue raw bytes, nothing is sent if they do not all fit
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> bool {
+        if bytes.len() > self.tx_space() {
+            return false;
+        }
+        for &byte in bytes {
+            let _ = self.tx.enqueue(byte);
+        }
+        self.kick();
+        true
+    }
+
+    /// Queue a message, return false if the transmit queue is full
+    pub fn send(&mut self, msg: &Message) -> bool {
+        let (bytes, len) = msg.to_bytes();
+        self.write_bytes(&bytes[..len])
+    }
+
+    /// Queue a SysEx message, `data` excludes the F0/F7 framing bytes and
+    /// must only contain data bytes (< 0x80)
+    pub fn send_sysex(&mut self, data: &[u8]) -> bool {
+        if data.iter().any(|&b| b & 0x80 != 0) || data.len() + 2 > self.tx_space() {
+            return false;
+        }
+        self.write_bytes(&[0xF0]) && self.write_bytes(data) && self.write_bytes(&[0xF7])
+    }
+
+    /// Start the transmission if the UART was idle
+    fn kick(&mut self) {
+        cortex_m::interrupt::free(|cs| {
+            if let Some(state) = IRQ_STATE.borrow(cs).borrow_mut().as_mut() {
+                state.transmit();
+            }
+        });
+    }
+}
+
+impl Interface for MidiUart {
+    fn poll(&mut self) -> Option<Event<'_>> {
+        MidiUart::poll(self)
+    }
+
+    fn send(&mut self, msg: &Message) -> bool {
+        MidiUart::send(self, msg)
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) -> bool {
+        MidiUart::send_sysex(self, data)
+    }
+}
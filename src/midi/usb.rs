@@ -0,0 +1,404 @@
+//! USB MIDI 1.0 class
+//!
+//! A class compliant MIDI streaming interface with one cable in each
+//! direction, so the PGB-1 shows up as a MIDI port without a driver. Like
+//! the DIN port it deals in `Message`s and `Event`s, and never blocks:
+//! messages are queued and sent when the host polls the IN endpoint.
+
+use heapless::Deque;
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+use super::{Event, Interface, Message, Parser};
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const USB_SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const USB_SUBCLASS_MIDISTREAMING: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+const HEADER: u8 = 0x01;
+const MS_HEADER: u8 = 0x01;
+const MS_GENERAL: u8 = 0x01;
+const MIDI_IN_JACK: u8 = 0x02;
+const MIDI_OUT_JACK: u8 = 0x03;
+const EMBEDDED: u8 = 0x01;
+const EXTERNAL: u8 = 0x02;
+
+/// Jack IDs: the host sends to the embedded IN jack and receives from the
+/// embedded OUT jack.
+const JACK_IN_EMBEDDED: u8 = 1;
+const JACK_IN_EXTERNAL: u8 = 2;
+const JACK_OUT_EMBEDDED: u8 = 3;
+const JACK_OUT_EXTERNAL: u8 = 4;
+
+/// Class specific interface and endpoint descriptors of the MIDI streaming
+/// interface: header, 4 jacks, 2 standard + 2 class endpoints.
+const MS_TOTAL_LENGTH: u16 = 7 + 6 + 6 + 9 + 9 + 9 + 5 + 9 + 5;
+
+pub const MAX_PACKET_SIZE: u16 = 64;
+
+/// Number of 4 bytes event packets waiting to be sent
+pub const TX_QUEUE_SIZE: usize = 128;
+
+/// A USB-MIDI event packet
+pub type Packet = [u8; 4];
+
+/// Number of MIDI bytes in a packet, from its Code Index Number
+fn packet_length(cin: u8) -> usize {
+    match cin & 0xF {
+        0x5 | 0xF => 1,
+        0x2 | 0x6 | 0xC | 0xD => 2,
+        0x3 | 0x4 | 0x7 | 0x8 | 0x9 | 0xA | 0xB | 0xE => 3,
+        // Reserved
+        _ => 0,
+    }
+}
+
+/// Bytes of MIDI stream carried by a packet
+pub fn packet_bytes(packet: &Packet) -> &[u8] {
+    &packet[1..1 + packet_length(packet[0])]
+}
+
+/// Build the packet of a message, on cable 0
+pub fn encode_message(msg: &Message) -> Packet {
+    let (bytes, len) = msg.to_bytes();
+    let status = bytes[0];
+    let cin = if status < 0xF0 {
+        status >> 4
+    } else if msg.is_realtime() {
+        0xF
+    } else {
+        match len {
+            1 => 0x5,
+            2 => 0x2,
+            _ => 0x3,
+        }
+    };
+    [cin, bytes[0], bytes[1], bytes[2]]
+}
+
+/// Split a SysEx message in packets, `data` excludes the F0/F7 framing
+/// bytes
+pub fn encode_sysex(data: &[u8]) -> impl Iterator<Item = Packet> + '_ {
+    let total = data.len() + 2;
+    (0..total.div_ceil(3)).map(move |index| {
+        let byte = |pos: usize| match pos {
+            0 => 0xF0,
+            _ if pos == total - 1 => 0xF7,
+            _ => data[pos - 1],
+        };
+
+        let start = index * 3;
+        let len = (total - start).min(3);
+        let cin = if start + len < total { 0x4 } else { 0x4 + len as u8 };
+
+        let mut packet = [cin, 0, 0, 0];
+        for i in 0..len {
+            packet[1 + i] = byte(start + i);
+        }
+        packet
+    })
+}
+
+pub struct MidiClass<'a, B: UsbBus> {
+    audio_control: InterfaceNumber,
+    midi_streaming: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+
+    rx: [u8; MAX_PACKET_SIZE as usize],
+    rx_len: usize,
+    rx_pos: usize,
+    parser: Parser,
+
+    tx: Deque<Packet, TX_QUEUE_SIZE>,
+}
+
+impl<'a, B: UsbBus> MidiClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        MidiClass {
+            audio_control: alloc.interface(),
+            midi_streaming: alloc.interface(),
+            ep_out: alloc.bulk(MAX_PACKET_SIZE),
+            ep_in: alloc.bulk(MAX_PACKET_SIZE),
+            rx: [0; MAX_PACKET_SIZE as usize],
+            rx_len: 0,
+            rx_pos: 0,
+            parser: Parser::new(),
+            tx: Deque::new(),
+        }
+    }
+
+    /// Next packet received from the host
+    pub fn read_packet(&mut self) -> Option<Packet> {
+        if self.rx_pos >= self.rx_len {
+            self.rx_pos = 0;
+            self.rx_len = self.ep_out.read(&mut self.rx).unwrap_or(0);
+        }
+        if self.rx_pos + 4 > self.rx_len {
+            return None;
+        }
+        let mut packet = [0; 4];
+        packet.copy_from_slice(&self.rx[self.rx_pos..self.rx_pos + 4]);
+        self.rx_pos += 4;
+        Some(packet)
+    }
+
+    /// Return the next complete message received from the host, if any
+    pub fn poll(&mut self) -> Option<Event<'_>> {
+        loop {
+            let packet = self.read_packet()?;
+            // Only one cable, the parser also puts SysEx packets back
+            // together.
+            let mut parsed = None;
+            for &byte in packet_bytes(&packet) {
+                parsed = parsed.or(self.parser.parse(byte));
+            }
+            if let Some(parsed) = parsed {
+                return Some(self.parser.event(parsed));
+            }
+        }
+    }
+
+    /// Free space in the transmit queue, in packets
+    pub fn tx_space(&self) -> usize {
+        self.tx.capacity() - self.tx.len()
+    }
+
+    /// Queue a packet, return false if the transmit queue is full
+    pub fn write_packet(&mut self, packet: Packet) -> bool {
+        let queued = self.tx.push_back(packet).is_ok();
+        self.flush();
+        queued
+    }
+
+    /// Queue a message, return false if the transmit queue is full
+    pub fn send(&mut self, msg: &Message) -> bool {
+        self.write_packet(encode_message(msg))
+    }
+
+    /// Queue a SysEx message, `data` excludes the F0/F7 framing bytes.
+    /// Nothing is sent if the whole message does not fit in the queue or
+    /// `data` contains a status byte.
+    pub fn send_sysex(&mut self, data: &[u8]) -> bool {
+        if data.iter().any(|&b| b & 0x80 != 0) || (data.len() + 2).div_ceil(3) > self.tx_space() {
+            return false;
+        }
+        for packet in encode_sysex(data) {
+            let _ = self.tx.push_back(packet);
+        }
+        self.flush();
+        true
+    }
+
+    /// Hand the queued packets to the IN endpoint
+    fn flush(&mut self) {
+        if self.tx.is_empty() {
+            return;
+        }
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE as usize];
+        let mut len = 0;
+        for packet in self.tx.iter().take(buffer.len() / 4) {
+            buffer[len..len + 4].copy_from_slice(packet);
+            len += 4;
+        }
+
+        // The endpoint is busy until the host reads the previous packet
+        if self.ep_in.write(&buffer[..len]).is_ok() {
+            for _ in 0..len / 4 {
+                self.tx.pop_front();
+            }
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.audio_control, USB_CLASS_AUDIO, USB_SUBCLASS_AUDIOCONTROL, 0)?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                HEADER,
+                0x00, // bcdADC 1.00
+                0x01,
+                0x09, // wTotalLength
+                0x00,
+                0x01, // bInCollection
+                self.midi_streaming.into(),
+            ],
+        )?;
+
+        writer.interface(self.midi_streaming, USB_CLASS_AUDIO, USB_SUBCLASS_MIDISTREAMING, 0)?;
+        let [total_lo, total_hi] = MS_TOTAL_LENGTH.to_le_bytes();
+        writer.write(CS_INTERFACE, &[MS_HEADER, 0x00, 0x01, total_lo, total_hi])?;
+
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, EMBEDDED, JACK_IN_EMBEDDED, 0])?;
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, EXTERNAL, JACK_IN_EXTERNAL, 0])?;
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_OUT_JACK, EMBEDDED, JACK_OUT_EMBEDDED, 1, JACK_IN_EXTERNAL, 1, 0],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_OUT_JACK, EXTERNAL, JACK_OUT_EXTERNAL, 1, JACK_IN_EMBEDDED, 1, 0],
+        )?;
+
+        // Audio class endpoints have 2 extra bytes: bRefresh, bSynchAddress
+        writer.endpoint_ex(&self.ep_out, |extra| {
+            extra[..2].fill(0);
+            Ok(2)
+        })?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, JACK_IN_EMBEDDED])?;
+
+        writer.endpoint_ex(&self.ep_in, |extra| {
+            extra[..2].fill(0);
+            Ok(2)
+        })?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, JACK_OUT_EMBEDDED])?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.rx_len = 0;
+        self.rx_pos = 0;
+        self.parser.reset();
+        self.tx.clear();
+    }
+
+    fn poll(&mut self) {
+        self.flush();
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.flush();
+        }
+    }
+}
+
+impl<B: UsbBus> Interface for MidiClass<'_, B> {
+    fn poll(&mut self) -> Option<Event<'_>> {
+        MidiClass::poll(self)
+    }
+
+    fn send(&mut self, msg: &Message) -> bool {
+        MidiClass::send(self, msg)
+    }
+
+    fn send_sysex(&mut self, data: &[u8]) -> bool {
+        MidiClass::send_sysex(self, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Events of the MIDI stream carried by packets
+    fn parse(packets: impl IntoIterator<Item = Packet>) -> Vec<(Option<Message>, Vec<u8>)> {
+        let mut parser: Parser<64> = Parser::new();
+        let mut out = Vec::new();
+        for packet in packets {
+            for &byte in packet_bytes(&packet) {
+                match parser.feed(byte) {
+                    Some(Event::Message(msg)) => out.push((Some(msg), Vec::new())),
+                    Some(Event::SysEx(data)) => out.push((None, data.to_vec())),
+                    None => {}
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn messages() {
+        let cases = [
+            (
+                Message::NoteOn {
+                    channel: 1,
+                    note: 60,
+                    velocity: 100,
+                },
+                [0x9, 0x91, 60, 100],
+            ),
+            (
+                Message::NoteOff {
+                    channel: 15,
+                    note: 0,
+                    velocity: 0,
+                },
+                [0x8, 0x8F, 0, 0],
+            ),
+            (
+                Message::ProgramChange {
+                    channel: 3,
+                    program: 5,
+                },
+                [0xC, 0xC3, 5, 0],
+            ),
+            (
+                Message::ChannelPressure {
+                    channel: 0,
+                    pressure: 64,
+                },
+                [0xD, 0xD0, 64, 0],
+            ),
+            (
+                Message::PitchBend {
+                    channel: 4,
+                    value: 0x2000,
+                },
+                [0xE, 0xE4, 0x00, 0x40],
+            ),
+            // System common, by length
+            (Message::TimeCodeQuarterFrame(0x35), [0x2, 0xF1, 0x35, 0]),
+            (Message::SongPosition(200), [0x3, 0xF2, 72, 1]),
+            (Message::SongSelect(3), [0x2, 0xF3, 3, 0]),
+            (Message::TuneRequest, [0x5, 0xF6, 0, 0]),
+            // Real-time
+            (Message::Clock, [0xF, 0xF8, 0, 0]),
+            (Message::Start, [0xF, 0xFA, 0, 0]),
+            (Message::Stop, [0xF, 0xFC, 0, 0]),
+            (Message::Reset, [0xF, 0xFF, 0, 0]),
+        ];
+        for (msg, packet) in cases {
+            assert_eq!(encode_message(&msg), packet, "{:?}", msg);
+            assert_eq!(parse([packet]), [(Some(msg), Vec::new())]);
+        }
+    }
+
+    #[test]
+    fn packet_lengths() {
+        let packet = |cin| packet_bytes(&[cin, 0xF0, 1, 2]).len();
+        let lengths: Vec<usize> = (0..16).map(packet).collect();
+        assert_eq!(lengths, [0, 0, 2, 3, 3, 1, 2, 3, 3, 3, 3, 3, 2, 2, 3, 1]);
+        // The cable number is ignored
+        assert_eq!(packet_bytes(&[0x19, 0x90, 60, 100]), [0x90, 60, 100]);
+    }
+
+    #[test]
+    fn sysex() {
+        let cases: [(&[u8], &[Packet]); 5] = [
+            (&[], &[[0x6, 0xF0, 0xF7, 0]]),
+            (&[1], &[[0x7, 0xF0, 1, 0xF7]]),
+            (&[1, 2], &[[0x4, 0xF0, 1, 2], [0x5, 0xF7, 0, 0]]),
+            (&[1, 2, 3], &[[0x4, 0xF0, 1, 2], [0x6, 3, 0xF7, 0]]),
+            (&[1, 2, 3, 4], &[[0x4, 0xF0, 1, 2], [0x7, 3, 4, 0xF7]]),
+        ];
+        for (data, packets) in cases {
+            let encoded: Vec<Packet> = encode_sysex(data).collect();
+            assert_eq!(encoded, packets);
+            assert_eq!(parse(encoded), [(None, data.to_vec())]);
+        }
+
+        let data: Vec<u8> = (0..40).collect();
+        let encoded: Vec<Packet> = encode_sysex(&data).collect();
+        assert_eq!(encoded.len(), 14);
+        assert_eq!(encoded[13], [0x7, 38, 39, 0xF7]);
+        assert_eq!(parse(encoded), [(None, data)]);
+    }
+}
@@ -0,0 +1,349 @@
+//! Second core support
+//!
+//! `Peripherals::core1` launches a task on the second core of the RP2040.
+//! The intended architecture is the UI (keyboard, screen, LEDs) on core0 and
+//! audio rendering on core1. The cores talk through the SIO FIFOs with
+//! `Message`s; larger or more frequent data can be exchanged with a
+//! `crate::spsc::Queue` shared between the two tasks.
+//!
+//! Core1 gets its own stack, which must be a `'static`:
+//!
+//! ```ignore
+//! static mut CORE1_STACK: pgb1::multicore::Stack<4096> = pgb1::multicore::Stack::new();
+//!
+//! let stack = unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK) };
+//! periph.core1.spawn(stack, |mut fifo| loop {
+//!     while let Some(msg) = fifo.try_recv() {
+//!         // ...
+//!     }
+//! }).unwrap();
+//! ```
+//!
+//! Flash writes (`flash::FlashRegion`, `flash::unique_id`) disconnect the
+//! flash, so they park core1 in RAM first, like `multicore_lockout` of the
+//! pico-sdk: core0 sends a lockout word through the FIFO and core1 parks
+//! when its task reads it with `Fifo::try_recv` or `Fifo::recv_blocking`,
+//! or waits in `Fifo::send_blocking`. The core1 task must read its FIFO
+//! regularly, a flash write waits until it does. Flash writes are done from
+//! core0.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use rp2040_hal::multicore::Multicore;
+use rp2040_hal::sio::{CoreId, Sio, SioFifo};
+
+use crate::pac;
+
+pub use rp2040_hal::multicore::{Error, Stack};
+
+/// Messages exchanged between the cores, packed in a single FIFO word
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Message {
+    /// Channel is 0..=15, note and velocity 0..=127
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    /// Parameter id is 0..=4095
+    Param { id: u16, value: i16 },
+    /// Application defined, 28 bits of payload
+    User(u32),
+}
+
+const TAG_NOTE_ON: u32 = 1;
+const TAG_NOTE_OFF: u32 = 2;
+const TAG_PARAM: u32 = 3;
+const TAG_USER: u32 = 4;
+
+impl Message {
+    pub fn encode(&self) -> u32 {
+        match *self {
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                TAG_NOTE_ON << 28
+                    | (channel as u32 & 0xF) << 16
+                    | (note as u32 & 0x7F) << 8
+                    | (velocity as u32 & 0x7F)
+            }
+            Message::NoteOff { channel, note } => {
+                TAG_NOTE_OFF << 28 | (channel as u32 & 0xF) << 16 | (note as u32 & 0x7F) << 8
+            }
+            Message::Param { id, value } => {
+                TAG_PARAM << 28 | (id as u32 & 0xFFF) << 16 | value as u16 as u32
+            }
+            Message::User(payload) => TAG_USER << 28 | (payload & 0x0FFF_FFFF),
+        }
+    }
+
+    pub fn decode(word: u32) -> Option<Self> {
+        match word >> 28 {
+            TAG_NOTE_ON => Some(Message::NoteOn {
+                channel: (word >> 16) as u8 & 0xF,
+                note: (word >> 8) as u8 & 0x7F,
+                velocity: word as u8 & 0x7F,
+            }),
+            TAG_NOTE_OFF => Some(Message::NoteOff {
+                channel: (word >> 16) as u8 & 0xF,
+                note: (word >> 8) as u8 & 0x7F,
+            }),
+            TAG_PARAM => Some(Message::Param {
+                id: (word >> 16) as u16 & 0xFFF,
+                value: word as u16 as i16,
+            }),
+            TAG_USER => Some(Message::User(word & 0x0FFF_FFFF)),
+            _ => None,
+        }
+    }
+}
+
+/// One end of the inter-core FIFOs
+///
+/// Each core has its own end: what is sent from one core is received on the
+/// other. The hardware FIFOs hold 8 words in each direction.
+pub struct Fifo {
+    inner: SioFifo,
+}
+
+impl Fifo {
+    /// Send a message if there is room in the FIFO, return false otherwise
+    pub fn send(&mut self, msg: Message) -> bool {
+        if !self.inner.is_write_ready() {
+            return false;
+        }
+        self.inner.write(msg.encode());
+        true
+    }
+
+    /// Wait for room in the FIFO and send a message. On core1 a flash
+    /// lockout parks the core while it waits, core0 does not read its FIFO
+    /// until the lockout ends.
+    pub fn send_blocking(&mut self, msg: Message) {
+        while !self.inner.is_write_ready() {
+            if LOCKOUT_REQUESTED.load(Ordering::Acquire) && Sio::core() == CoreId::Core1 {
+                park();
+            }
+            core::hint::spin_loop();
+        }
+        self.inner.write(msg.encode());
+    }
+
+    /// Return the next message, if any. Words that do not decode as a
+    /// `Message` are dropped, on core1 a flash lockout parks the core.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        while let Some(word) = self.inner.read() {
+            if let Some(msg) = receive(word) {
+                return Some(msg);
+            }
+        }
+        None
+    }
+
+    pub fn recv_blocking(&mut self) -> Message {
+        loop {
+            if let Some(msg) = receive(self.inner.read_blocking()) {
+                return msg;
+            }
+        }
+    }
+}
+
+fn receive(word: u32) -> Option<Message> {
+    if word == LOCKOUT_WORD {
+        park();
+        return None;
+    }
+    Message::decode(word)
+}
+
+/// Sent to core1 to park it, does not decode as a `Message`
+const LOCKOUT_WORD: u32 = 0xF10C_4007;
+
+/// A task was spawned on core1 and has not returned
+static CORE1_RUNNING: AtomicBool = AtomicBool::new(false);
+/// Set by core0 for the time core1 must stay parked
+static LOCKOUT_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set by core1 while it is parked
+static LOCKOUT_PARKED: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` while core1 is parked in RAM with its interrupts disabled, so
+/// that `f` can disconnect the flash
+///
+/// Runs `f` right away when core1 runs no task, or when called from core1.
+/// A task that returns stops in RAM, the lockout does not wait for it.
+pub(crate) fn lockout<R>(f: impl FnOnce() -> R) -> R {
+    if !CORE1_RUNNING.load(Ordering::Acquire) || Sio::core() != CoreId::Core0 {
+        return f();
+    }
+
+    LOCKOUT_REQUESTED.store(true, Ordering::Release);
+    let sio = unsafe { &*pac::SIO::ptr() };
+    while sio.fifo_st().read().rdy().bit_is_clear() {
+        core::hint::spin_loop();
+    }
+    sio.fifo_wr().write(|w| unsafe { w.bits(LOCKOUT_WORD) });
+    cortex_m::asm::sev();
+    while !LOCKOUT_PARKED.load(Ordering::Acquire) && CORE1_RUNNING.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+
+    let result = f();
+
+    LOCKOUT_REQUESTED.store(false, Ordering::Release);
+    while LOCKOUT_PARKED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    result
+}
+
+/// Core1 side of `lockout`
+fn park() {
+    cortex_m::interrupt::free(|_| park_in_ram());
+}
+
+/// Must not touch the flash, nor call code that is in flash
+///
+/// Lockout words left in the FIFO, e.g. after `Fifo::send_blocking` parked
+/// the core, are ignored: the core only parks while core0 requests it.
+#[inline(never)]
+#[cfg_attr(target_os = "none", link_section = ".data.ram_func")]
+fn park_in_ram() {
+    if !LOCKOUT_REQUESTED.load(Ordering::Acquire) {
+        return;
+    }
+    LOCKOUT_PARKED.store(true, Ordering::Release);
+    while LOCKOUT_REQUESTED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    LOCKOUT_PARKED.store(false, Ordering::Release);
+}
+
+/// Core1 after its task returned, until it is reset by `Core1::spawn`
+fn stop() -> ! {
+    cortex_m::interrupt::disable();
+    stop_in_ram()
+}
+
+/// Must not touch the flash, nor call code that is in flash
+#[inline(never)]
+#[cfg_attr(target_os = "none", link_section = ".data.ram_func")]
+fn stop_in_ram() -> ! {
+    CORE1_RUNNING.store(false, Ordering::Release);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Control of the second core
+pub struct Core1 {
+    psm: pac::PSM,
+    ppb: pac::PPB,
+    fifo: Fifo,
+}
+
+impl Core1 {
+    pub(crate) fn new(psm: pac::PSM, ppb: pac::PPB, fifo: SioFifo) -> Self {
+        Core1 {
+            psm,
+            ppb,
+            fifo: Fifo { inner: fifo },
+        }
+    }
+
+    /// Start `entry` on core1 with the given stack. The task receives the
+    /// core1 end of the FIFOs.
+    ///
+    /// Core1 is reset first, spawning a second task replaces the first one.
+    ///
+    /// Flash writes on core0 wait for core1 to park, see `multicore`: the
+    /// task must call `Fifo::try_recv`, `Fifo::recv_blocking` or
+    /// `Fifo::send_blocking` regularly while it runs, or a flash write
+    /// never returns. A task that returns releases the flash writes.
+    pub fn spawn<F, const N: usize>(
+        &mut self,
+        stack: &'static mut Stack<N>,
+        entry: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(Fifo) + Send + 'static,
+    {
+        let mut mc = Multicore::new(&mut self.psm, &mut self.ppb, &mut self.fifo.inner);
+        let cores = mc.cores();
+        // Set before the task starts, it may return right away
+        CORE1_RUNNING.store(true, Ordering::Release);
+        let result = cores[1].spawn(&mut stack.mem, move || {
+            // Each core only sees its own end of the FIFOs through the SIO
+            // registers, so this does not alias core0's handle.
+            let sio = Sio::new(unsafe { pac::Peripherals::steal() }.SIO);
+            entry(Fifo { inner: sio.fifo });
+            stop()
+        });
+        if result.is_err() {
+            CORE1_RUNNING.store(false, Ordering::Release);
+        }
+        result
+    }
+
+    /// Core0 end of the FIFOs
+    pub fn fifo(&mut self) -> &mut Fifo {
+        &mut self.fifo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let messages = [
+            Message::NoteOn {
+                channel: 15,
+                note: 127,
+                velocity: 1,
+            },
+            Message::NoteOn {
+                channel: 0,
+                note: 0,
+                velocity: 127,
+            },
+            Message::NoteOff {
+                channel: 9,
+                note: 36,
+            },
+            Message::Param {
+                id: 4095,
+                value: -32768,
+            },
+            Message::Param { id: 0, value: 32767 },
+            Message::Param { id: 12, value: -1 },
+            Message::User(0),
+            Message::User(0x0FFF_FFFF),
+        ];
+        for msg in messages {
+            assert_eq!(Message::decode(msg.encode()), Some(msg));
+        }
+
+        // Values out of range are cut to their bits
+        let msg = Message::NoteOn {
+            channel: 17,
+            note: 200,
+            velocity: 130,
+        };
+        assert_eq!(
+            Message::decode(msg.encode()),
+            Some(Message::NoteOn {
+                channel: 1,
+                note: 72,
+                velocity: 2
+            })
+        );
+        assert_eq!(
+            Message::decode(Message::User(u32::MAX).encode()),
+            Some(Message::User(0x0FFF_FFFF))
+        );
+        assert_eq!(Message::decode(0), None);
+        assert_eq!(Message::decode(LOCKOUT_WORD), None);
+        assert_eq!(Message::decode(0xF000_0000), None);
+    }
+}
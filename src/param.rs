@@ -0,0 +1,824 @@
+//! Sound parameters and modulation
+//!
+//! Applications describe their parameters with a static table of
+//! `ParamInfo`. A `ParamSet` holds the values edited from the UI and glides
+//! towards them with the smoothing time of each parameter. A `ModMatrix`
+//! then routes modulation sources (LFOs, envelopes, velocity, key held) to
+//! any parameter.
+//!
+//! A parameter is identified by its index in the table (`ParamId`).
+
+use core::fmt::{self, Write};
+
+use heapless::Vec;
+
+use crate::audio::{Smoothed, SAMPLE_RATE};
+
+pub type ParamId = u16;
+
+/// How a parameter value is displayed
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// Plain number
+    Integer,
+    /// Value is a percentage
+    Percent,
+    /// Value is a time in milliseconds
+    Millis,
+    /// Value is a frequency in Hertz
+    Hertz,
+    /// Value is a MIDI note number, displayed as note name and octave
+    Note,
+    /// Value is a pan position, displayed as L<n>, C or R<n>
+    Pan,
+    /// Value is 0 or 1
+    OnOff,
+    /// Value is an index in the list of labels
+    Choice(&'static [&'static str]),
+}
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Static description of a parameter
+#[derive(Copy, Clone, Debug)]
+pub struct ParamInfo {
+    /// Short name, about 10 characters fit on the menu screen
+    pub name: &'static str,
+    pub min: i16,
+    pub max: i16,
+    pub default: i16,
+    /// Time to glide to a new value, 0 to apply changes immediately
+    pub smoothing_ms: u16,
+    pub format: Format,
+}
+
+impl ParamInfo {
+    pub const fn new(name: &'static str, min: i16, max: i16, default: i16) -> Self {
+        ParamInfo {
+            name,
+            min,
+            max,
+            default,
+            smoothing_ms: 0,
+            format: Format::Integer,
+        }
+    }
+
+    pub const fn smoothing(mut self, ms: u16) -> Self {
+        self.smoothing_ms = ms;
+        self
+    }
+
+    pub const fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn clamp(&self, value: i32) -> i16 {
+        value.clamp(self.min as i32, self.max as i32) as i16
+    }
+
+    /// Size of the value range
+    pub fn range(&self) -> i32 {
+        self.max as i32 - self.min as i32
+    }
+
+    /// Value scaled to 0..=0x8000
+    pub fn normalized(&self, value: i16) -> i32 {
+        if self.range() == 0 {
+            return 0;
+        }
+        (value as i32 - self.min as i32) * 0x8000 / self.range()
+    }
+
+    /// Inverse of `normalized`
+    pub fn from_normalized(&self, norm: i32) -> i16 {
+        self.clamp(self.min as i32 + (norm.clamp(0, 0x8000) * self.range() + 0x4000) / 0x8000)
+    }
+
+    /// Write the value in the display format of the parameter
+    pub fn write_value<W: Write>(&self, value: i16, w: &mut W) -> fmt::Result {
+        match self.format {
+            Format::Integer => write!(w, "{}", value),
+            Format::Percent => write!(w, "{}%", value),
+            Format::Millis => {
+                if value >= 1000 {
+                    write!(w, "{}.{:02}s", value / 1000, (value % 1000) / 10)
+                } else {
+                    write!(w, "{}ms", value)
+                }
+            }
+            Format::Hertz => write!(w, "{}Hz", value),
+            Format::Note => {
+                let note = value.clamp(0, 127) as usize;
+                write!(w, "{}{}", NOTE_NAMES[note % 12], note as i32 / 12 - 1)
+            }
+            Format::Pan => match value {
+                0 => write!(w, "C"),
+                v if v < 0 => write!(w, "L{}", -v),
+                v => write!(w, "R{}", v),
+            },
+            Format::OnOff => write!(w, "{}", if value != 0 { "On" } else { "Off" }),
+            Format::Choice(labels) => {
+                write!(w, "{}", labels.get(value as usize).copied().unwrap_or("?"))
+            }
+        }
+    }
+}
+
+/// Current values of a table of parameters
+pub struct ParamSet<const N: usize> {
+    infos: &'static [ParamInfo; N],
+    values: [i16; N],
+    smoothed: [Smoothed; N],
+}
+
+impl<const N: usize> ParamSet<N> {
+    /// Create a set with all parameters at their default value
+    pub fn new(infos: &'static [ParamInfo; N]) -> Self {
+        let mut smoothed = [Smoothed::new(0, 0); N];
+        for (s, info) in smoothed.iter_mut().zip(infos.iter()) {
+            let ramp = info.smoothing_ms as u32 * SAMPLE_RATE / 1000;
+            *s = Smoothed::new(info.default as i32, ramp);
+        }
+        ParamSet {
+            infos,
+            values: core::array::from_fn(|i| infos[i].default),
+            smoothed,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    pub fn infos(&self) -> &'static [ParamInfo; N] {
+        self.infos
+    }
+
+    pub fn info(&self, id: ParamId) -> Option<&'static ParamInfo> {
+        self.infos.get(id as usize)
+    }
+
+    /// Value as set by the user
+    pub fn get(&self, id: ParamId) -> i16 {
+        self.values.get(id as usize).copied().unwrap_or(0)
+    }
+
+    /// Set a value, clamped to the parameter range. The current value
+    /// glides to it.
+    pub fn set(&mut self, id: ParamId, value: i32) {
+        let index = id as usize;
+        if index >= N {
+            return;
+        }
+        let value = self.infos[index].clamp(value);
+        self.values[index] = value;
+        self.smoothed[index].set(value as i32);
+    }
+
+    /// Set a value without gliding
+    pub fn jump(&mut self, id: ParamId, value: i32) {
+        let index = id as usize;
+        if index >= N {
+            return;
+        }
+        let value = self.infos[index].clamp(value);
+        self.values[index] = value;
+        self.smoothed[index].jump(value as i32);
+    }
+
+    /// Add `delta` to a value
+    pub fn step(&mut self, id: ParamId, delta: i32) {
+        self.set(id, self.get(id) as i32 + delta);
+    }
+
+    pub fn reset(&mut self, id: ParamId) {
+        if let Some(info) = self.info(id) {
+            self.jump(id, info.default as i32);
+        }
+    }
+
+    /// Value after smoothing
+    pub fn current(&self, id: ParamId) -> i16 {
+        self.smoothed
+            .get(id as usize)
+            .map_or(0, |s| s.value() as i16)
+    }
+
+    /// Advance the glides by `frames` audio frames, typically once per
+    /// rendered block
+    pub fn update(&mut self, frames: u32) {
+        for s in self.smoothed.iter_mut() {
+            s.advance(frames);
+        }
+    }
+
+    pub fn write_value<W: Write>(&self, id: ParamId, w: &mut W) -> fmt::Result {
+        match self.info(id) {
+            Some(info) => info.write_value(self.get(id), w),
+            None => Ok(()),
+        }
+    }
+}
+
+pub const MOD_LFOS: usize = 2;
+pub const MOD_ENVELOPES: usize = 2;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ModSource {
+    Lfo(u8),
+    Envelope(u8),
+    Velocity,
+    KeyHeld,
+}
+
+/// Values of the modulation sources, in Q15. LFOs are bipolar, the other
+/// sources are unipolar.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ModInputs {
+    pub lfo: [i16; MOD_LFOS],
+    pub envelope: [i16; MOD_ENVELOPES],
+    pub velocity: i16,
+    pub key_held: bool,
+}
+
+impl ModInputs {
+    pub fn get(&self, source: ModSource) -> i32 {
+        match source {
+            ModSource::Lfo(i) => self.lfo.get(i as usize).copied().unwrap_or(0) as i32,
+            ModSource::Envelope(i) => self.envelope.get(i as usize).copied().unwrap_or(0) as i32,
+            ModSource::Velocity => self.velocity as i32,
+            ModSource::KeyHeld => {
+                if self.key_held {
+                    i16::MAX as i32
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// Set the velocity source from a MIDI velocity
+    pub fn set_velocity(&mut self, velocity: u8) {
+        self.velocity = (velocity.min(127) as i16) * 258;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ModRoute {
+    pub source: ModSource,
+    pub destination: ParamId,
+    /// Q15 fraction of the destination range, can be negative
+    pub depth: i16,
+}
+
+pub const MAX_MOD_ROUTES: usize = 16;
+
+/// Returned when adding a route to a full `ModMatrix`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MatrixFull;
+
+pub struct ModMatrix {
+    routes: Vec<ModRoute, MAX_MOD_ROUTES>,
+}
+
+impl Default for ModMatrix {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ModMatrix {
+    pub const fn new() -> Self {
+        ModMatrix { routes: Vec::new() }
+    }
+
+    pub fn routes(&self) -> &[ModRoute] {
+        &self.routes
+    }
+
+    /// Add a route, or update the depth if the source is already routed to
+    /// that destination
+    pub fn connect(
+        &mut self,
+        source: ModSource,
+        destination: ParamId,
+        depth: i16,
+    ) -> Result<(), MatrixFull> {
+        if let Some(route) = self
+            .routes
+            .iter_mut()
+            .find(|r| r.source == source && r.destination == destination)
+        {
+            route.depth = depth;
+            return Ok(());
+        }
+        self.routes
+            .push(ModRoute {
+                source,
+                destination,
+                depth,
+            })
+            .map_err(|_| MatrixFull)
+    }
+
+    pub fn disconnect(&mut self, source: ModSource, destination: ParamId) {
+        self.routes
+            .retain(|r| !(r.source == source && r.destination == destination));
+    }
+
+    pub fn clear(&mut self) {
+        self.routes.clear();
+    }
+
+    /// Smoothed value of a parameter with all modulations applied, clamped
+    /// to the parameter range
+    pub fn modulated<const N: usize>(
+        &self,
+        params: &ParamSet<N>,
+        id: ParamId,
+        inputs: &ModInputs,
+    ) -> i16 {
+        let Some(info) = params.info(id) else {
+            return 0;
+        };
+
+        let mut value = params.current(id) as i32;
+        for route in self.routes.iter().filter(|r| r.destination == id) {
+            let amount = (route.depth as i32 * inputs.get(route.source)) >> 15;
+            value += (amount * info.range()) >> 15;
+        }
+        info.clamp(value)
+    }
+
+    /// Compute the modulated value of every parameter
+    pub fn apply<const N: usize>(
+        &self,
+        params: &ParamSet<N>,
+        inputs: &ModInputs,
+        out: &mut [i16; N],
+    ) {
+        for (id, value) in out.iter_mut().enumerate() {
+            *value = self.modulated(params, id as ParamId, inputs);
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LfoShape {
+    Triangle,
+    Sine,
+    SawUp,
+    SawDown,
+    Square,
+    /// A new random value every cycle
+    SampleAndHold,
+}
+
+/// Low frequency oscillator, bipolar Q15 output
+pub struct Lfo {
+    shape: LfoShape,
+    phase: u32,
+    increment: u32,
+    held: i16,
+    seed: u32,
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lfo {
+    pub const fn new() -> Self {
+        Lfo {
+            shape: LfoShape::Triangle,
+            phase: 0,
+            increment: 0,
+            held: 0,
+            seed: 0x1234_5678,
+        }
+    }
+
+    pub fn set_shape(&mut self, shape: LfoShape) {
+        self.shape = shape;
+    }
+
+    /// Rate in millihertz
+    pub fn set_rate(&mut self, millihertz: u32) {
+        self.increment = ((millihertz as u64) << 32)
+            .checked_div(1000 * SAMPLE_RATE as u64)
+            .unwrap_or(0) as u32;
+    }
+
+    /// Restart the cycle, e.g. on note-on
+    pub fn retrigger(&mut self) {
+        self.phase = 0;
+    }
+
+    /// Advance by `frames` audio frames and return the new output
+    pub fn advance(&mut self, frames: u32) -> i16 {
+        let (phase, wrapped) = self
+            .phase
+            .overflowing_add(self.increment.wrapping_mul(frames));
+        if wrapped || frames as u64 * self.increment as u64 > u32::MAX as u64 {
+            // xorshift32
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 17;
+            self.seed ^= self.seed << 5;
+            self.held = (self.seed >> 16) as i16;
+        }
+        self.phase = phase;
+        self.output()
+    }
+
+    pub fn output(&self) -> i16 {
+        // Phase as a 16-bit unsigned value
+        let p = (self.phase >> 16) as i32;
+        match self.shape {
+            LfoShape::Triangle => {
+                let tri = if p < 0x8000 { p } else { 0xFFFF - p };
+                (tri * 2 - 0x8000) as i16
+            }
+            LfoShape::Sine => {
+                // Parabolic approximation: 4x(1 - |x|) on each half cycle
+                let x = (p & 0x7FFF) * 2; // 0..0xFFFF over half a cycle
+                let y = (x * (0xFFFF - x)) >> 16; // 0..0x3FFF
+                let y = (y * 2).min(0x7FFF);
+                if p < 0x8000 {
+                    y as i16
+                } else {
+                    -(y as i16)
+                }
+            }
+            LfoShape::SawUp => (p - 0x8000) as i16,
+            LfoShape::SawDown => (0x7FFF - p) as i16,
+            LfoShape::Square => {
+                if p < 0x8000 {
+                    i16::MAX
+                } else {
+                    -i16::MAX
+                }
+            }
+            LfoShape::SampleAndHold => self.held,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+const ENV_FULL: i32 = (i16::MAX as i32) << 8;
+
+/// Linear ADSR envelope, unipolar Q15 output
+pub struct Envelope {
+    stage: Stage,
+    level: i32,
+    attack: i32,
+    decay: i32,
+    sustain: i32,
+    release: i32,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Envelope {
+    pub const fn new() -> Self {
+        Envelope {
+            stage: Stage::Idle,
+            level: 0,
+            attack: ENV_FULL,
+            decay: ENV_FULL,
+            sustain: ENV_FULL,
+            release: ENV_FULL,
+        }
+    }
+
+    fn rate(ms: u16) -> i32 {
+        let frames = (ms as u32 * SAMPLE_RATE / 1000).max(1);
+        (ENV_FULL as u32).div_ceil(frames) as i32
+    }
+
+    /// Segment times in milliseconds, sustain level in Q15
+    pub fn set_adsr(&mut self, attack_ms: u16, decay_ms: u16, sustain: i16, release_ms: u16) {
+        self.attack = Self::rate(attack_ms);
+        self.decay = Self::rate(decay_ms);
+        self.sustain = (sustain.max(0) as i32) << 8;
+        self.release = Self::rate(release_ms);
+    }
+
+    pub fn gate(&mut self, on: bool) {
+        if on {
+            self.stage = Stage::Attack;
+        } else if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    /// Advance by `frames` audio frames and return the new output
+    pub fn advance(&mut self, frames: u32) -> i16 {
+        let frames = frames as i32;
+        match self.stage {
+            Stage::Idle | Stage::Sustain => {}
+            Stage::Attack => {
+                self.level = self.level.saturating_add(self.attack.saturating_mul(frames));
+                if self.level >= ENV_FULL {
+                    self.level = ENV_FULL;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level = self.level.saturating_sub(self.decay.saturating_mul(frames));
+                if self.level <= self.sustain {
+                    self.level = self.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Release => {
+                self.level = self.level.saturating_sub(self.release.saturating_mul(frames));
+                if self.level <= 0 {
+                    self.level = 0;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+        self.output()
+    }
+
+    pub fn output(&self) -> i16 {
+        (self.level >> 8) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    static PARAMS: [ParamInfo; 4] = [
+        ParamInfo::new("Cutoff", 20, 2000, 1000)
+            .smoothing(10)
+            .format(Format::Hertz),
+        ParamInfo::new("Note", 0, 127, 60).format(Format::Note),
+        ParamInfo::new("Pan", -64, 63, 0).format(Format::Pan),
+        ParamInfo::new("Wide", i16::MIN, i16::MAX, 0),
+    ];
+    const CUTOFF: ParamId = 0;
+    const NOTE: ParamId = 1;
+    const PAN: ParamId = 2;
+    const WIDE: ParamId = 3;
+
+    fn text(info: &ParamInfo, value: i16) -> String {
+        let mut s = String::new();
+        info.write_value(value, &mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn values() {
+        let mut params = ParamSet::new(&PARAMS);
+        assert_eq!(params.get(CUTOFF), 1000);
+        params.set(CUTOFF, 5000);
+        assert_eq!(params.get(CUTOFF), 2000);
+        params.step(CUTOFF, -3000);
+        assert_eq!(params.get(CUTOFF), 20);
+        params.set(9, 1);
+        assert_eq!((params.get(9), params.current(9)), (0, 0));
+        assert!(params.info(9).is_none());
+
+        // 10 ms glide, 441 frames
+        assert_eq!(params.current(CUTOFF), 1000);
+        params.jump(CUTOFF, 100);
+        assert_eq!(params.current(CUTOFF), 100);
+        params.set(CUTOFF, 541);
+        params.update(220);
+        assert!((319..=321).contains(&params.current(CUTOFF)));
+        params.update(221);
+        assert_eq!(params.current(CUTOFF), 541);
+        // Without smoothing
+        params.set(NOTE, 72);
+        assert_eq!(params.current(NOTE), 72);
+        params.reset(CUTOFF);
+        assert_eq!((params.get(CUTOFF), params.current(CUTOFF)), (1000, 1000));
+
+        let mut s = String::new();
+        params.write_value(NOTE, &mut s).unwrap();
+        assert_eq!(s, "C5");
+    }
+
+    #[test]
+    fn formats() {
+        let info = |format| ParamInfo::new("", -100, 5000, 0).format(format);
+        assert_eq!(text(&info(Format::Integer), -5), "-5");
+        assert_eq!(text(&info(Format::Percent), 50), "50%");
+        assert_eq!(text(&info(Format::Millis), 250), "250ms");
+        assert_eq!(text(&info(Format::Millis), 1250), "1.25s");
+        assert_eq!(text(&info(Format::Hertz), 440), "440Hz");
+        assert_eq!(text(&info(Format::Note), 60), "C4");
+        assert_eq!(text(&info(Format::Note), 1), "C#-1");
+        assert_eq!(text(&info(Format::Pan), 0), "C");
+        assert_eq!(text(&info(Format::Pan), -12), "L12");
+        assert_eq!(text(&info(Format::Pan), 7), "R7");
+        assert_eq!(text(&info(Format::OnOff), 1), "On");
+        assert_eq!(text(&info(Format::OnOff), 0), "Off");
+        let choice = info(Format::Choice(&["Sine", "Saw"]));
+        assert_eq!(text(&choice, 1), "Saw");
+        assert_eq!(text(&choice, 2), "?");
+        assert_eq!(text(&choice, -1), "?");
+
+        let info = ParamInfo::new("", -64, 63, 0);
+        assert_eq!(info.normalized(-64), 0);
+        assert_eq!(info.normalized(63), 0x8000);
+        assert_eq!(info.from_normalized(0x4000), 0);
+        assert_eq!(info.from_normalized(0x9000), 63);
+        for value in -64..=63 {
+            assert_eq!(info.from_normalized(info.normalized(value)), value);
+        }
+    }
+
+    #[test]
+    fn modulation() {
+        let mut params = ParamSet::new(&PARAMS);
+        let mut matrix = ModMatrix::new();
+        let mut inputs = ModInputs::default();
+        assert_eq!(matrix.modulated(&params, CUTOFF, &inputs), 1000);
+
+        matrix.connect(ModSource::Lfo(0), CUTOFF, 0x4000).unwrap();
+        matrix.connect(ModSource::Velocity, CUTOFF, 0x2000).unwrap();
+        inputs.lfo[0] = i16::MAX;
+        // Half the range, 1980 / 2, rounded down
+        assert_eq!(matrix.modulated(&params, CUTOFF, &inputs), 1000 + 989);
+        inputs.lfo[0] = -0x4000;
+        assert_eq!(matrix.modulated(&params, CUTOFF, &inputs), 1000 - 495);
+        inputs.set_velocity(127);
+        assert_eq!(matrix.modulated(&params, CUTOFF, &inputs), 1000 - 495 + 494);
+
+        // Same route, new depth
+        matrix.connect(ModSource::Lfo(0), CUTOFF, i16::MAX).unwrap();
+        assert_eq!(matrix.routes().len(), 2);
+        inputs.lfo[0] = i16::MIN;
+        assert_eq!(matrix.modulated(&params, CUTOFF, &inputs), 20);
+        matrix.disconnect(ModSource::Velocity, CUTOFF);
+        matrix.disconnect(ModSource::Lfo(0), CUTOFF);
+        assert!(matrix.routes().is_empty());
+
+        matrix.connect(ModSource::KeyHeld, PAN, -0x4000).unwrap();
+        matrix
+            .connect(ModSource::Envelope(1), NOTE, 0x1000)
+            .unwrap();
+        inputs.key_held = true;
+        inputs.envelope[1] = i16::MAX;
+        params.set(NOTE, 100);
+        let mut out = [0; 4];
+        matrix.apply(&params, &inputs, &mut out);
+        assert_eq!(out, [1000, 115, -64, 0]);
+
+        for i in 2..MAX_MOD_ROUTES {
+            matrix.connect(ModSource::Lfo(1), i as ParamId, 1).unwrap();
+        }
+        assert_eq!(matrix.connect(ModSource::Velocity, 0, 1), Err(MatrixFull));
+        matrix.clear();
+        assert!(matrix.routes().is_empty());
+    }
+
+    #[test]
+    fn modulation_headroom() {
+        // Largest depths and inputs on the widest range do not overflow
+        let mut params = ParamSet::new(&PARAMS);
+        let mut matrix = ModMatrix::new();
+        let mut inputs = ModInputs::default();
+        for (lfo, depth, start, expected) in [
+            (i16::MIN, i16::MIN, i16::MIN, i16::MAX),
+            (i16::MAX, i16::MIN, i16::MAX, i16::MIN + 1),
+            (i16::MAX, i16::MAX, i16::MIN, i16::MAX - 4),
+            (i16::MIN, i16::MAX, i16::MAX, i16::MIN + 1),
+        ] {
+            params.jump(WIDE, start as i32);
+            inputs.lfo = [lfo; MOD_LFOS];
+            matrix.clear();
+            matrix.connect(ModSource::Lfo(0), WIDE, depth).unwrap();
+            assert_eq!(matrix.modulated(&params, WIDE, &inputs), expected);
+            // Routes add up, clamped to the range
+            matrix.connect(ModSource::Lfo(1), WIDE, depth).unwrap();
+            let sign = if expected > 0 { i16::MAX } else { i16::MIN };
+            assert_eq!(matrix.modulated(&params, WIDE, &inputs), sign);
+        }
+    }
+
+    #[test]
+    fn lfos() {
+        let mut lfo = Lfo::new();
+        // 1 Hz, at 1/8, 3/8, 5/8 and 7/8 of the cycle
+        lfo.set_rate(1000);
+        let quarter = SAMPLE_RATE / 4;
+        let mut shape = |shape| {
+            lfo.set_shape(shape);
+            lfo.retrigger();
+            let mut values = [lfo.advance(quarter / 2), 0, 0, 0];
+            for value in values.iter_mut().skip(1) {
+                *value = lfo.advance(quarter);
+            }
+            values
+        };
+        let near = |values: [i16; 4], expected: [i16; 4]| {
+            values
+                .iter()
+                .zip(expected)
+                .all(|(&v, e)| (v as i32 - e as i32).abs() <= 16)
+        };
+        assert!(near(
+            shape(LfoShape::Triangle),
+            [-0x4000, 0x4000, 0x4000, -0x4000]
+        ));
+        assert!(near(
+            shape(LfoShape::Sine),
+            [0x5FFE, 0x5FFE, -0x5FFE, -0x5FFE]
+        ));
+        assert!(near(
+            shape(LfoShape::SawUp),
+            [-0x6000, -0x2000, 0x2000, 0x6000]
+        ));
+        assert!(near(
+            shape(LfoShape::SawDown),
+            [0x6000, 0x2000, -0x2000, -0x6000]
+        ));
+        assert_eq!(
+            shape(LfoShape::Square),
+            [i16::MAX, i16::MAX, -i16::MAX, -i16::MAX]
+        );
+
+        // A new value once per cycle
+        lfo.set_shape(LfoShape::SampleAndHold);
+        lfo.retrigger();
+        let mut held = std::vec::Vec::new();
+        for _ in 0..12 {
+            held.push(lfo.advance(quarter - 1));
+        }
+        let changes = held.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(changes, 2);
+        // Faster than the block rate, still one value per block
+        lfo.set_rate(1_000_000);
+        let a = lfo.advance(64);
+        assert_ne!(lfo.advance(64), a);
+    }
+
+    #[test]
+    fn envelopes() {
+        let mut env = Envelope::new();
+        assert!(!env.is_active());
+        env.gate(false);
+        assert!(!env.is_active());
+
+        // Segment times are for the full scale: 441 frames to rise, 882 to
+        // fall all the way and 1764 to release
+        env.set_adsr(10, 20, 0x4000, 40);
+        env.gate(true);
+        assert!((16000..16500).contains(&env.advance(220)));
+        assert_eq!(env.advance(221), i16::MAX);
+        assert!((24000..25000).contains(&env.advance(220)));
+        assert_eq!(env.advance(300), 0x4000);
+        assert_eq!(env.advance(10_000), 0x4000);
+
+        env.gate(false);
+        assert!((8000..8300).contains(&env.advance(441)));
+        assert!(env.is_active());
+        assert_eq!(env.advance(1000), 0);
+        assert!(!env.is_active());
+
+        // Retrigger during the release, from the current level
+        env.gate(true);
+        env.advance(441);
+        env.gate(false);
+        env.advance(441);
+        env.gate(true);
+        assert!(env.advance(1) > 8000);
+
+        // Instant segments
+        env.set_adsr(0, 0, 0, 0);
+        env.gate(true);
+        assert_eq!(env.advance(1), i16::MAX);
+        assert_eq!(env.advance(1), 0);
+    }
+}
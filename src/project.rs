@@ -0,0 +1,1075 @@
+//! Project files
+//!
+//! A `Project` holds the state of the groovebox that is saved: the
+//! patterns of each track, the song, and the sound and mixer settings of
+//! the tracks. It is saved to a compact binary file, usually in the
+//! flash filesystem. Projects are numbered, `path` gives the file of a
+//! number in `PROJECT_DIR`: `sysex` backs them up and restores them by that
+//! number.
+//!
+//! ```ignore
+//! if !periph.fs.exists(project::PROJECT_DIR) {
+//!     periph.fs.create_dir(project::PROJECT_DIR)?;
+//! }
+//! project.save(&mut periph.fs, &project::path(5))?;
+//! project.load(&mut periph.fs, &project::path(5))?;
+//! ```
+//!
+//! Files carry the format version they were written with. Fields added by
+//! a new version get their default value when an older file is read, so
+//! projects saved with older firmware still load. No version so far changed
+//! the meaning of an existing field; one that does converts the older
+//! values at the end of `Project::decode`. Files of a newer version are
+//! rejected.
+//!
+//! `pgb1-tool project` converts project files to a text format and back,
+//! to compare or share them.
+//!
+//! File layout, little endian:
+//!
+//! | Size | Field                                                      |
+//! |------|------------------------------------------------------------|
+//! | 4    | `MAGIC`                                                    |
+//! | 2    | format version (`VERSION`)                                 |
+//! | 2    | reserved, 0                                                |
+//! | 1+n  | name: length and UTF-8 bytes                               |
+//! | 2    | tempo, tenth of BPM                                        |
+//! | 1    | swing, percent                                             |
+//! | 1    | number of tracks, then for each track:                     |
+//! |      | volume, pan (i8), mute, MIDI channel, number of sound parameters and their values (i16) |
+//! | 1    | number of patterns, then for each pattern that is not empty: |
+//! |      | pattern index, then for each track: length, number of active steps and the steps (index, note, velocity, gate, micro-timing offset (i8) since version 3, condition (kind and argument), retrigs and decay since version 5) |
+//! |      | number of parameter locks and the locks (track, step, parameter, value i16), since version 2 |
+//! | 1    | number of song rows, then for each row: pattern, repeats, and tempo (u16), mutes since version 4 |
+//! | 2    | song loop: first and last row, 255 for no loop, since version 4 |
+//! | 4    | CRC-32 of all the above                                    |
+
+use core::convert::Infallible;
+use core::fmt::Write;
+
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+
+use crate::crc::Crc32;
+use crate::fs::{Fs, FsError};
+
+pub const MAGIC: [u8; 4] = *b"PGBP";
+/// Format version of the files written by this firmware
+pub const VERSION: u16 = 5;
+
+pub const TRACKS: usize = 8;
+pub const PATTERNS: usize = 16;
+/// Steps per page, one per K1..K16 key
+pub const PAGE_STEPS: usize = 16;
+pub const MAX_STEPS: usize = 4 * PAGE_STEPS;
+pub const MAX_NAME_SIZE: usize = 16;
+pub const MAX_SOUND_PARAMS: usize = 32;
+pub const MAX_SONG_ROWS: usize = 64;
+/// Parameter locks per pattern, for all the tracks
+pub const MAX_LOCKS: usize = 128;
+/// Largest micro-timing offset of a step, half a step either way in
+/// `sequencer::TICKS_PER_STEP`
+pub const MAX_OFFSET: i8 = 12;
+/// Extra notes of a step, the step is then divided in up to 8
+pub const MAX_RETRIGS: u8 = 7;
+/// Longest cycle of `Condition::Cycle`
+pub const MAX_CYCLE: u8 = 8;
+
+/// Directory of the numbered project files
+pub const PROJECT_DIR: &str = "/projects";
+/// Project numbers are 0..=`MAX_PROJECT`, the SysEx index range
+pub const MAX_PROJECT: u8 = 127;
+
+/// File of a project number, e.g. `/projects/005`
+pub fn path(index: u8) -> String<16> {
+    let mut path = String::new();
+    let _ = write!(path, "{PROJECT_DIR}/{index:03}");
+    path
+}
+
+const HEADER_SIZE: u32 = 8;
+/// Song loop row of the projects without loop
+const NO_LOOP: u8 = 0xFF;
+const CRC_SIZE: u32 = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProjectError {
+    Fs(FsError),
+    BadMagic,
+    /// Written by a newer firmware
+    UnsupportedVersion(u16),
+    Truncated,
+    BadChecksum,
+    /// Out of range value or count
+    Invalid,
+}
+
+impl From<FsError> for ProjectError {
+    fn from(err: FsError) -> Self {
+        ProjectError::Fs(err)
+    }
+}
+
+impl From<Infallible> for ProjectError {
+    fn from(err: Infallible) -> Self {
+        match err {}
+    }
+}
+
+/// When an active step plays, checked every time it is reached
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Condition {
+    Always,
+    /// Percent chance to play, 0..=100
+    Probability(u8),
+    /// Only while the fill is on
+    Fill,
+    NotFill,
+    /// On loop `n` of every `m` loops of the track, `1 <= n <= m <=
+    /// MAX_CYCLE`
+    Cycle {
+        n: u8,
+        m: u8,
+    },
+    /// On the first loop of the track after the pattern starts
+    First,
+    NotFirst,
+}
+
+impl Condition {
+    /// Kind and argument, as saved in the files
+    pub(crate) fn encode(self) -> [u8; 2] {
+        match self {
+            Condition::Always => [0, 0],
+            Condition::Probability(percent) => [1, percent],
+            Condition::Fill => [2, 0],
+            Condition::NotFill => [3, 0],
+            Condition::Cycle { n, m } => [4, (n << 4) | m],
+            Condition::First => [5, 0],
+            Condition::NotFirst => [6, 0],
+        }
+    }
+
+    pub(crate) fn decode([kind, arg]: [u8; 2]) -> Option<Self> {
+        let condition = match kind {
+            0 => Condition::Always,
+            1 => Condition::Probability(arg),
+            2 => Condition::Fill,
+            3 => Condition::NotFill,
+            4 => Condition::Cycle {
+                n: arg >> 4,
+                m: arg & 0xF,
+            },
+            5 => Condition::First,
+            6 => Condition::NotFirst,
+            _ => return None,
+        };
+        condition.is_valid().then_some(condition)
+    }
+
+    pub fn is_valid(self) -> bool {
+        match self {
+            Condition::Probability(percent) => percent <= 100,
+            Condition::Cycle { n, m } => n >= 1 && n <= m && m <= MAX_CYCLE,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Step {
+    pub active: bool,
+    /// MIDI note number
+    pub note: u8,
+    pub velocity: u8,
+    /// Note length, percent of the step
+    pub gate: u8,
+    /// Micro-timing, ticks before (negative) or after the step,
+    /// -`MAX_OFFSET`..=`MAX_OFFSET`
+    pub offset: i8,
+    pub condition: Condition,
+    /// Extra notes evenly spaced in the step, up to `MAX_RETRIGS`
+    pub retrigs: u8,
+    /// Velocity lost by each retrig, percent
+    pub decay: u8,
+}
+
+impl Step {
+    pub const fn new() -> Self {
+        Step {
+            active: false,
+            note: 60,
+            velocity: 100,
+            gate: 50,
+            offset: 0,
+            condition: Condition::Always,
+            retrigs: 0,
+            decay: 0,
+        }
+    }
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Steps of a track in a pattern
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Sequence {
+    /// Number of steps played, 1..=MAX_STEPS
+    pub length: u8,
+    pub steps: [Step; MAX_STEPS],
+}
+
+impl Sequence {
+    pub const fn new() -> Self {
+        Sequence {
+            length: PAGE_STEPS as u8,
+            steps: [Step::new(); MAX_STEPS],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.length = PAGE_STEPS as u8;
+        self.steps = [Step::new(); MAX_STEPS];
+    }
+
+    /// Default length and no active step
+    pub fn is_empty(&self) -> bool {
+        self.length == PAGE_STEPS as u8 && !self.steps.iter().any(|step| step.active)
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Value of a sound parameter for a single step
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Lock {
+    pub track: u8,
+    pub step: u8,
+    /// Below `MAX_SOUND_PARAMS`
+    pub param: u8,
+    pub value: i16,
+}
+
+/// Returned when setting a lock in a pattern that has `MAX_LOCKS`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LocksFull;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Pattern {
+    pub tracks: [Sequence; TRACKS],
+    /// Parameter locks of all the tracks, by track, step and parameter
+    locks: Vec<Lock, MAX_LOCKS>,
+}
+
+impl Pattern {
+    pub const fn new() -> Self {
+        const EMPTY: Sequence = Sequence::new();
+        Pattern {
+            tracks: [EMPTY; TRACKS],
+            locks: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.iter_mut().for_each(Sequence::clear);
+        self.locks.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.iter().all(Sequence::is_empty) && self.locks.is_empty()
+    }
+
+    pub fn locks(&self) -> &[Lock] {
+        &self.locks
+    }
+
+    /// Locks of a step, as (parameter, value)
+    pub fn step_locks(&self, track: usize, step: usize) -> impl Iterator<Item = (u8, i16)> + '_ {
+        self.locks
+            .iter()
+            .filter(move |l| l.track as usize == track && l.step as usize == step)
+            .map(|l| (l.param, l.value))
+    }
+
+    pub fn is_locked(&self, track: usize, step: usize) -> bool {
+        self.step_locks(track, step).next().is_some()
+    }
+
+    pub fn lock(&self, track: usize, step: usize, param: u8) -> Option<i16> {
+        self.step_locks(track, step)
+            .find(|&(p, _)| p == param)
+            .map(|(_, value)| value)
+    }
+
+    /// Set the value of `param` for a step. Locks of parameters from
+    /// `MAX_SOUND_PARAMS` are ignored.
+    pub fn set_lock(
+        &mut self,
+        track: usize,
+        step: usize,
+        param: u8,
+        value: i16,
+    ) -> Result<(), LocksFull> {
+        if track >= TRACKS || step >= MAX_STEPS || param as usize >= MAX_SOUND_PARAMS {
+            return Ok(());
+        }
+        let lock = Lock {
+            track: track as u8,
+            step: step as u8,
+            param,
+            value,
+        };
+        let key = |l: &Lock| (l.track, l.step, l.param);
+        match self.locks.binary_search_by_key(&key(&lock), key) {
+            Ok(index) => self.locks[index] = lock,
+            Err(index) => self.locks.insert(index, lock).map_err(|_| LocksFull)?,
+        }
+        Ok(())
+    }
+
+    /// Remove the lock of `param`, or all the locks of the step if `None`
+    pub fn clear_lock(&mut self, track: usize, step: usize, param: Option<u8>) {
+        self.locks.retain(|l| {
+            l.track as usize != track
+                || l.step as usize != step
+                || param.is_some_and(|param| l.param != param)
+        });
+    }
+
+    /// Number of locks that can still be set
+    pub fn free_locks(&self) -> usize {
+        MAX_LOCKS - self.locks.len()
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sound and mixer settings of a track
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Track {
+    /// 0..=127
+    pub volume: u8,
+    /// -64 (left) ..= 63 (right)
+    pub pan: i8,
+    pub mute: bool,
+    /// 0..=15
+    pub midi_channel: u8,
+    /// Values of the sound parameters, by `param::ParamId`
+    pub sound: Vec<i16, MAX_SOUND_PARAMS>,
+}
+
+impl Track {
+    pub const fn new() -> Self {
+        Track {
+            volume: 100,
+            pan: 0,
+            mute: false,
+            midi_channel: 0,
+            sound: Vec::new(),
+        }
+    }
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A row of the song arrangement
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SongRow {
+    pub pattern: u8,
+    /// Number of times the pattern is played, at least 1
+    pub repeats: u8,
+    /// Tenth of BPM from this row on, 0 keeps the tempo
+    pub tempo: u16,
+    /// Tracks muted during the row, one bit per track
+    pub mutes: u8,
+}
+
+impl SongRow {
+    pub const fn new(pattern: u8) -> Self {
+        SongRow {
+            pattern,
+            repeats: 1,
+            tempo: 0,
+            mutes: 0,
+        }
+    }
+}
+
+/// Returned when adding a row to a song of `MAX_SONG_ROWS`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SongFull;
+
+/// Rows played again after the last row of the song, `start..=end`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SongLoop {
+    pub start: u8,
+    pub end: u8,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Project {
+    pub name: String<MAX_NAME_SIZE>,
+    /// Tenth of BPM
+    pub tempo: u16,
+    /// Percent, 50 is straight
+    pub swing: u8,
+    pub tracks: [Track; TRACKS],
+    pub patterns: [Pattern; PATTERNS],
+    pub song: Vec<SongRow, MAX_SONG_ROWS>,
+    /// The song stops after the last row without a loop
+    pub song_loop: Option<SongLoop>,
+}
+
+impl Default for Project {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Project {
+    pub const fn new() -> Self {
+        const TRACK: Track = Track::new();
+        const PATTERN: Pattern = Pattern::new();
+        Project {
+            name: String::new(),
+            tempo: 1200,
+            swing: 50,
+            tracks: [TRACK; TRACKS],
+            patterns: [PATTERN; PATTERNS],
+            song: Vec::new(),
+            song_loop: None,
+        }
+    }
+
+    /// Insert a song row before `index`, the loop keeps the same rows and
+    /// grows when the row is inserted in it
+    pub fn insert_row(&mut self, index: usize, row: SongRow) -> Result<(), SongFull> {
+        let index = index.min(self.song.len());
+        self.song.insert(index, row).map_err(|_| SongFull)?;
+        if let Some(song_loop) = &mut self.song_loop {
+            if song_loop.start as usize >= index {
+                song_loop.start += 1;
+            }
+            if song_loop.end as usize >= index {
+                song_loop.end += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a song row, and the loop if it was its only row
+    pub fn remove_row(&mut self, index: usize) {
+        if index >= self.song.len() {
+            return;
+        }
+        self.song.remove(index);
+        if let Some(song_loop) = self.song_loop {
+            let (start, end) = (song_loop.start as usize, song_loop.end as usize);
+            self.song_loop = if start == index && end == index {
+                None
+            } else {
+                Some(SongLoop {
+                    start: song_loop.start - (start > index) as u8,
+                    end: song_loop.end - (end >= index) as u8,
+                })
+            };
+        }
+    }
+
+    /// Write the file content, in small parts, to `write`
+    pub fn encode<E>(&self, write: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        let mut w = Writer {
+            write,
+            buf: [0; 64],
+            len: 0,
+            crc: Crc32::new(),
+        };
+        w.bytes(&MAGIC)?;
+        w.u16(VERSION)?;
+        w.u16(0)?;
+
+        w.u8(self.name.len() as u8)?;
+        w.bytes(self.name.as_bytes())?;
+        w.u16(self.tempo)?;
+        w.u8(self.swing)?;
+
+        w.u8(TRACKS as u8)?;
+        for track in &self.tracks {
+            w.u8(track.volume)?;
+            w.u8(track.pan as u8)?;
+            w.u8(track.mute as u8)?;
+            w.u8(track.midi_channel)?;
+            w.u8(track.sound.len() as u8)?;
+            for &value in &track.sound {
+                w.u16(value as u16)?;
+            }
+        }
+
+        let patterns = self.patterns.iter().filter(|p| !p.is_empty()).count();
+        w.u8(patterns as u8)?;
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            if pattern.is_empty() {
+                continue;
+            }
+            w.u8(index as u8)?;
+            for sequence in &pattern.tracks {
+                w.u8(sequence.length)?;
+                w.u8(sequence.steps.iter().filter(|s| s.active).count() as u8)?;
+                for (index, step) in sequence.steps.iter().enumerate() {
+                    if step.active {
+                        w.bytes(&[index as u8, step.note, step.velocity, step.gate])?;
+                        w.u8(step.offset as u8)?;
+                        w.bytes(&step.condition.encode())?;
+                        w.bytes(&[step.retrigs, step.decay])?;
+                    }
+                }
+            }
+            w.u8(pattern.locks.len() as u8)?;
+            for lock in &pattern.locks {
+                w.bytes(&[lock.track, lock.step, lock.param])?;
+                w.u16(lock.value as u16)?;
+            }
+        }
+
+        w.u8(self.song.len() as u8)?;
+        for row in &self.song {
+            w.bytes(&[row.pattern, row.repeats])?;
+            w.u16(row.tempo)?;
+            w.u8(row.mutes)?;
+        }
+        match self.song_loop {
+            Some(song_loop) => w.bytes(&[song_loop.start, song_loop.end])?,
+            None => w.bytes(&[NO_LOOP, NO_LOOP])?,
+        }
+
+        let crc = w.crc.finish();
+        w.bytes(&crc.to_le_bytes())?;
+        w.flush()
+    }
+
+    /// Replace the project with the content of a file, `read` fills a
+    /// buffer from an offset of the file and returns the number of bytes
+    /// read, 0 at the end of the file.
+    ///
+    /// The file is checked before the project is changed, an `Invalid`
+    /// file may leave it partly loaded.
+    pub fn decode<E: Into<ProjectError>>(
+        &mut self,
+        mut read: impl FnMut(u32, &mut [u8]) -> Result<usize, E>,
+    ) -> Result<(), ProjectError> {
+        let (version, end) = check(&mut read)?;
+        let mut r = Reader {
+            read,
+            offset: HEADER_SIZE,
+            end,
+            buf: [0; 64],
+            pos: 0,
+            len: 0,
+        };
+
+        self.name.clear();
+        let mut name = [0; MAX_NAME_SIZE];
+        let len = r.u8()? as usize;
+        let name = name.get_mut(..len).ok_or(ProjectError::Invalid)?;
+        r.bytes(name)?;
+        let name = core::str::from_utf8(name).map_err(|_| ProjectError::Invalid)?;
+        let _ = self.name.push_str(name);
+        self.tempo = r.u16()?;
+        self.swing = r.u8()?;
+
+        let tracks = r.u8()? as usize;
+        if tracks > TRACKS {
+            return Err(ProjectError::Invalid);
+        }
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            *track = Track::new();
+            if index >= tracks {
+                continue;
+            }
+            track.volume = r.u8()?;
+            track.pan = r.u8()? as i8;
+            track.mute = r.u8()? != 0;
+            track.midi_channel = r.u8()?;
+            for _ in 0..r.u8()? {
+                let value = r.u16()? as i16;
+                track.sound.push(value).map_err(|_| ProjectError::Invalid)?;
+            }
+        }
+
+        self.patterns.iter_mut().for_each(Pattern::clear);
+        for _ in 0..r.u8()? {
+            let index = r.u8()? as usize;
+            let pattern = self.patterns.get_mut(index).ok_or(ProjectError::Invalid)?;
+            for sequence in &mut pattern.tracks[..tracks] {
+                sequence.length = r.u8()?;
+                if sequence.length == 0 || sequence.length as usize > MAX_STEPS {
+                    return Err(ProjectError::Invalid);
+                }
+                for _ in 0..r.u8()? {
+                    let mut fields = [0; 4];
+                    r.bytes(&mut fields)?;
+                    let [index, note, velocity, gate] = fields;
+                    let offset = if version >= 3 { r.u8()? as i8 } else { 0 };
+                    let mut step = Step {
+                        active: true,
+                        note,
+                        velocity,
+                        gate,
+                        offset,
+                        ..Step::new()
+                    };
+                    if version >= 5 {
+                        let mut fields = [0; 4];
+                        r.bytes(&mut fields)?;
+                        let [kind, arg, retrigs, decay] = fields;
+                        step.condition =
+                            Condition::decode([kind, arg]).ok_or(ProjectError::Invalid)?;
+                        step.retrigs = retrigs;
+                        step.decay = decay;
+                    }
+                    if !(-MAX_OFFSET..=MAX_OFFSET).contains(&offset)
+                        || step.retrigs > MAX_RETRIGS
+                        || step.decay > 100
+                    {
+                        return Err(ProjectError::Invalid);
+                    }
+                    *sequence
+                        .steps
+                        .get_mut(index as usize)
+                        .ok_or(ProjectError::Invalid)? = step;
+                }
+            }
+            if version >= 2 {
+                for _ in 0..r.u8()? {
+                    let mut fields = [0; 3];
+                    r.bytes(&mut fields)?;
+                    let [track, step, param] = fields;
+                    let value = r.u16()? as i16;
+                    if track as usize >= tracks
+                        || step as usize >= MAX_STEPS
+                        || param as usize >= MAX_SOUND_PARAMS
+                    {
+                        return Err(ProjectError::Invalid);
+                    }
+                    pattern
+                        .set_lock(track as usize, step as usize, param, value)
+                        .map_err(|_| ProjectError::Invalid)?;
+                }
+            }
+        }
+
+        self.song.clear();
+        for _ in 0..r.u8()? {
+            let mut row = SongRow {
+                pattern: r.u8()?,
+                repeats: r.u8()?,
+                ..SongRow::new(0)
+            };
+            if version >= 4 {
+                row.tempo = r.u16()?;
+                row.mutes = r.u8()?;
+            }
+            if row.pattern as usize >= PATTERNS || row.repeats == 0 {
+                return Err(ProjectError::Invalid);
+            }
+            self.song.push(row).map_err(|_| ProjectError::Invalid)?;
+        }
+        self.song_loop = None;
+        if version >= 4 {
+            let (start, end) = (r.u8()?, r.u8()?);
+            if start != NO_LOOP {
+                if start > end || end as usize >= self.song.len() {
+                    return Err(ProjectError::Invalid);
+                }
+                self.song_loop = Some(SongLoop { start, end });
+            }
+        }
+
+        if r.offset - r.len as u32 + r.pos as u32 != end {
+            return Err(ProjectError::Invalid);
+        }
+        Ok(())
+    }
+
+    /// Replace the project with the content of `data`
+    pub fn decode_bytes(&mut self, data: &[u8]) -> Result<(), ProjectError> {
+        self.decode(|offset, buf| {
+            let rest = data.get(offset as usize..).unwrap_or(&[]);
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            Ok::<_, Infallible>(len)
+        })
+    }
+
+    /// Save to a file, replaced atomically
+    pub fn save<F: NorFlash>(&self, fs: &mut Fs<F>, path: &str) -> Result<(), FsError> {
+        let mut writer = fs.create(path)?;
+        self.encode(|data| fs.write(&mut writer, data))?;
+        fs.commit(writer)
+    }
+
+    pub fn load<F: NorFlash>(&mut self, fs: &mut Fs<F>, path: &str) -> Result<(), ProjectError> {
+        self.decode(|offset, buf| fs.read(path, offset, buf))
+    }
+}
+
+/// Check the header and checksum of a file, return its version and the
+/// offset of the checksum
+fn check<E: Into<ProjectError>>(
+    read: &mut impl FnMut(u32, &mut [u8]) -> Result<usize, E>,
+) -> Result<(u16, u32), ProjectError> {
+    let mut header = [0; HEADER_SIZE as usize];
+    let len = read(0, &mut header).map_err(Into::into)?;
+    if len < header.len() {
+        return Err(ProjectError::Truncated);
+    }
+    if header[..4] != MAGIC {
+        return Err(ProjectError::BadMagic);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version == 0 || version > VERSION {
+        return Err(ProjectError::UnsupportedVersion(version));
+    }
+
+    // The last 4 bytes are the checksum of the others
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    let mut tail = [0; CRC_SIZE as usize];
+    let mut tail_len = 0;
+    let mut offset = HEADER_SIZE;
+    let mut buf = [0; 64];
+    loop {
+        let len = read(offset, &mut buf).map_err(Into::into)?;
+        if len == 0 {
+            break;
+        }
+        for &byte in &buf[..len] {
+            if tail_len == tail.len() {
+                crc.update(&tail[..1]);
+                tail.copy_within(1.., 0);
+                tail[tail.len() - 1] = byte;
+            } else {
+                tail[tail_len] = byte;
+                tail_len += 1;
+            }
+        }
+        offset += len as u32;
+    }
+    if tail_len < tail.len() {
+        return Err(ProjectError::Truncated);
+    }
+    if crc.finish().to_le_bytes() != tail {
+        return Err(ProjectError::BadChecksum);
+    }
+    Ok((version, offset - CRC_SIZE))
+}
+
+struct Writer<W> {
+    write: W,
+    buf: [u8; 64],
+    len: usize,
+    crc: Crc32,
+}
+
+impl<E, W: FnMut(&[u8]) -> Result<(), E>> Writer<W> {
+    fn bytes(&mut self, data: &[u8]) -> Result<(), E> {
+        self.crc.update(data);
+        for &byte in data {
+            if self.len == self.buf.len() {
+                self.flush()?;
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), E> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), E> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn flush(&mut self) -> Result<(), E> {
+        if self.len > 0 {
+            (self.write)(&self.buf[..self.len])?;
+            self.len = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Buffered reader of the fields, up to the checksum
+struct Reader<R> {
+    read: R,
+    /// File offset after the buffer
+    offset: u32,
+    end: u32,
+    buf: [u8; 64],
+    pos: usize,
+    len: usize,
+}
+
+impl<E: Into<ProjectError>, R: FnMut(u32, &mut [u8]) -> Result<usize, E>> Reader<R> {
+    fn bytes(&mut self, out: &mut [u8]) -> Result<(), ProjectError> {
+        for byte in out {
+            if self.pos == self.len {
+                let len = (self.end.saturating_sub(self.offset) as usize).min(self.buf.len());
+                if len == 0 {
+                    return Err(ProjectError::Invalid);
+                }
+                self.len = (self.read)(self.offset, &mut self.buf[..len]).map_err(Into::into)?;
+                if self.len == 0 {
+                    return Err(ProjectError::Truncated);
+                }
+                self.offset += self.len as u32;
+                self.pos = 0;
+            }
+            *byte = self.buf[self.pos];
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, ProjectError> {
+        let mut buf = [0];
+        self.bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProjectError> {
+        let mut buf = [0; 2];
+        self.bytes(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{RamFlash, SECTOR_SIZE};
+
+    fn encode(project: &Project) -> std::vec::Vec<u8> {
+        let mut out = std::vec::Vec::new();
+        project
+            .encode(|data| {
+                out.extend_from_slice(data);
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+        out
+    }
+
+    fn example() -> Box<Project> {
+        let mut project = Box::new(Project::new());
+        project.name.push_str("Demo").unwrap();
+        project.tempo = 1275;
+        project.swing = 58;
+        project.tracks[1].pan = -20;
+        project.tracks[1].mute = true;
+        project.tracks[1]
+            .sound
+            .extend_from_slice(&[3, -400, 12000])
+            .unwrap();
+        project.patterns[2].tracks[0].length = 64;
+        project.patterns[2].tracks[0].steps[63] = Step {
+            active: true,
+            note: 36,
+            velocity: 127,
+            gate: 100,
+            offset: -3,
+            condition: Condition::Cycle { n: 2, m: 3 },
+            retrigs: 3,
+            decay: 25,
+        };
+        project.patterns[2].tracks[0].steps[0] = Step {
+            active: true,
+            condition: Condition::Probability(35),
+            ..Step::new()
+        };
+        project.patterns[5].tracks[7].steps[0].active = true;
+        project.patterns[5].set_lock(7, 0, 4, -1000).unwrap();
+        project.patterns[9].set_lock(0, 12, 31, 7).unwrap();
+        project
+            .song
+            .push(SongRow {
+                pattern: 2,
+                repeats: 4,
+                tempo: 900,
+                mutes: 0b1000_0001,
+            })
+            .unwrap();
+        project.song.push(SongRow::new(5)).unwrap();
+        project.song_loop = Some(SongLoop { start: 1, end: 1 });
+        project
+    }
+
+    #[test]
+    fn round_trip() {
+        let project = example();
+        let data = encode(&project);
+
+        let mut loaded = Box::new(Project::new());
+        loaded.patterns[0].tracks[0].steps[0].active = true;
+        loaded.decode_bytes(&data).unwrap();
+        assert_eq!(loaded, project);
+
+        // Empty patterns are not saved
+        assert_eq!(
+            encode(&Project::new()).len(),
+            8 + 1 + 3 + 1 + 8 * 5 + 1 + 1 + 2 + 4
+        );
+
+        let mut fs = Box::new(Fs::new(RamFlash::<{ 4 * SECTOR_SIZE as usize }>::new()).unwrap());
+        project.save(&mut fs, "/demo.prj").unwrap();
+        let mut loaded = Box::new(Project::new());
+        loaded.load(&mut fs, "/demo.prj").unwrap();
+        assert_eq!(loaded, project);
+
+        assert_eq!(path(5), "/projects/005");
+        assert_eq!(path(MAX_PROJECT), "/projects/127");
+        fs.create_dir(PROJECT_DIR).unwrap();
+        project.save(&mut fs, &path(5)).unwrap();
+        assert!(fs.exists("/projects/005"));
+    }
+
+    #[test]
+    fn older_versions() {
+        // Written by the first firmware
+        const VERSION_1: [u8; 85] = [
+            80, 71, 66, 80, 1, 0, 0, 0, 3, 79, 108, 100, 176, 4, 50, 8, 100, 0, 0, 0, 0, 100, 0, 0,
+            0, 0, 100, 0, 0, 0, 0, 100, 0, 0, 0, 0, 100, 0, 0, 0, 0, 100, 0, 0, 0, 0, 100, 0, 0, 0,
+            0, 100, 0, 0, 0, 0, 1, 2, 16, 0, 16, 1, 3, 60, 100, 50, 16, 0, 16, 0, 16, 0, 16, 0, 16,
+            0, 16, 0, 1, 2, 2, 200, 219, 88, 128,
+        ];
+        let mut expected = Box::new(Project::new());
+        expected.name.push_str("Old").unwrap();
+        expected.patterns[2].tracks[1].steps[3].active = true;
+        expected
+            .song
+            .push(SongRow {
+                repeats: 2,
+                ..SongRow::new(2)
+            })
+            .unwrap();
+
+        let mut project = Box::new(Project::new());
+        project.patterns[2].set_lock(1, 3, 0, 0).unwrap();
+        project.song_loop = Some(SongLoop { start: 0, end: 0 });
+        project.decode_bytes(&VERSION_1).unwrap();
+        assert_eq!(project, expected);
+    }
+
+    #[test]
+    fn locks() {
+        let mut pattern = Box::new(Pattern::new());
+        pattern.set_lock(1, 5, 3, 100).unwrap();
+        pattern.set_lock(1, 5, 4, -5).unwrap();
+        pattern.set_lock(1, 5, 3, 200).unwrap();
+        pattern.set_lock(2, 5, 3, 1).unwrap();
+        assert_eq!(pattern.lock(1, 5, 3), Some(200));
+        assert_eq!(pattern.step_locks(1, 5).count(), 2);
+        assert!(!pattern.is_locked(1, 6));
+        assert!(!pattern.is_empty());
+
+        pattern.clear_lock(1, 5, Some(3));
+        assert_eq!(pattern.lock(1, 5, 3), None);
+        assert!(pattern.is_locked(1, 5));
+        pattern.clear_lock(1, 5, None);
+        assert!(!pattern.is_locked(1, 5));
+        assert_eq!(pattern.free_locks(), MAX_LOCKS - 1);
+
+        // Fixed budget
+        for i in 0..MAX_LOCKS - 1 {
+            pattern.set_lock(i % TRACKS, i / TRACKS, 0, 0).unwrap();
+        }
+        assert_eq!(pattern.set_lock(7, 63, 0, 0), Err(LocksFull));
+        pattern.set_lock(2, 5, 3, 9).unwrap();
+        assert_eq!(pattern.lock(2, 5, 3), Some(9));
+    }
+
+    #[test]
+    fn song_rows() {
+        let mut project = Box::new(Project::new());
+        for pattern in 0..4 {
+            project.song.push(SongRow::new(pattern)).unwrap();
+        }
+        project.song_loop = Some(SongLoop { start: 1, end: 2 });
+        let patterns = |project: &Project| -> std::vec::Vec<u8> {
+            project.song.iter().map(|row| row.pattern).collect()
+        };
+
+        project.insert_row(2, SongRow::new(9)).unwrap();
+        project.insert_row(0, SongRow::new(8)).unwrap();
+        project.insert_row(99, SongRow::new(7)).unwrap();
+        assert_eq!(patterns(&project), [8, 0, 1, 9, 2, 3, 7]);
+        assert_eq!(project.song_loop, Some(SongLoop { start: 2, end: 4 }));
+
+        project.remove_row(2);
+        project.remove_row(3);
+        project.remove_row(99);
+        assert_eq!(patterns(&project), [8, 0, 9, 3, 7]);
+        assert_eq!(project.song_loop, Some(SongLoop { start: 2, end: 2 }));
+        project.remove_row(2);
+        assert_eq!(project.song_loop, None);
+
+        while project.song.len() < MAX_SONG_ROWS {
+            project.insert_row(0, SongRow::new(0)).unwrap();
+        }
+        assert_eq!(project.insert_row(0, SongRow::new(0)), Err(SongFull));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut project = Box::new(Project::new());
+        let data = encode(&example());
+
+        assert_eq!(
+            project.decode_bytes(&data[..data.len() - 1]),
+            Err(ProjectError::BadChecksum)
+        );
+        assert_eq!(
+            project.decode_bytes(&data[..6]),
+            Err(ProjectError::Truncated)
+        );
+
+        let mut corrupt = data.clone();
+        corrupt[20] ^= 1;
+        assert_eq!(
+            project.decode_bytes(&corrupt),
+            Err(ProjectError::BadChecksum)
+        );
+
+        let mut corrupt = data.clone();
+        corrupt[0] = b'X';
+        assert_eq!(project.decode_bytes(&corrupt), Err(ProjectError::BadMagic));
+
+        let mut newer = data.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            project.decode_bytes(&newer),
+            Err(ProjectError::UnsupportedVersion(VERSION + 1))
+        );
+
+        // The project is not changed by a file that fails the checks
+        assert_eq!(*project, Project::new());
+    }
+}
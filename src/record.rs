@@ -0,0 +1,514 @@
+//! Live recording
+//!
+//! A `Recorder` writes the notes played on the keys, or received over MIDI,
+//! into a track of the pattern played by a `sequencer::Sequencer`:
+//!
+//! ```ignore
+//! if keyboard.falling(Keys::REC) {
+//!     recorder.toggle(&mut seq);
+//! }
+//! recorder.update(&mut project, &seq, &mut history);
+//! seq.advance(&project, elapsed_us, |event| play(event));
+//!
+//! // Notes from the keys or MIDI
+//! recorder.note_on(&mut project, &seq, &mut history, note, velocity);
+//! recorder.note_off(&mut project, &seq, note);
+//! ```
+//!
+//! Notes go to the nearest step. With `Quantize::Off` the distance to the
+//! step is kept as the micro-timing `offset` of the step, `ThirtySecond`
+//! only keeps half steps. The gate of the step is the time the note is held.
+//!
+//! In `RecordMode::Overdub` the notes are added to the track, in `Replace`
+//! the steps of the track are cleared as the playhead reaches them, on
+//! every loop.
+//!
+//! Each recorded note, and each step cleared in `Replace` mode, is an edit
+//! of the `history::History`. The gate set by `note_off` belongs to the
+//! edit of the note.
+//!
+//! Recording while the sequencer is stopped starts it, after `count_in`
+//! bars. Notes played during the last half step of the count-in go to the
+//! first step.
+
+use heapless::Vec;
+
+use crate::history::History;
+use crate::project::{Project, Step, MAX_OFFSET, MAX_STEPS, TRACKS};
+use crate::sequencer::{swing_delay, Sequencer, TICKS_PER_STEP};
+
+/// Notes held at the same time whose gate is recorded
+pub const MAX_HELD_NOTES: usize = 8;
+
+/// Clear the steps this many ticks before they are due, so that early steps
+/// are cleared before they play
+const ERASE_AHEAD: i32 = TICKS_PER_STEP as i32 + MAX_OFFSET as i32;
+
+const STEP: i32 = TICKS_PER_STEP as i32;
+
+/// Tick of the step after a position, or of the first step
+fn next_step(position: i32) -> i32 {
+    ((position + STEP).div_euclid(STEP) * STEP).max(0)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Quantize {
+    /// Micro-timing is recorded
+    Off,
+    /// On the steps
+    Sixteenth,
+    /// On the steps and half steps
+    ThirtySecond,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RecordMode {
+    /// Keep the steps of the track
+    Overdub,
+    /// Clear the steps of the track as they are played
+    Replace,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Held {
+    note: u8,
+    /// Sequencer position of the note on
+    start: i32,
+    step: usize,
+}
+
+pub struct Recorder {
+    quantize: Quantize,
+    mode: RecordMode,
+    count_in: u8,
+    track: usize,
+    recording: bool,
+    /// Sequencer tick of the next step to clear in `Replace` mode
+    erase: i32,
+    held: Vec<Held, MAX_HELD_NOTES>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    pub const fn new() -> Self {
+        Recorder {
+            quantize: Quantize::Sixteenth,
+            mode: RecordMode::Overdub,
+            count_in: 1,
+            track: 0,
+            recording: false,
+            erase: 0,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn quantize(&self) -> Quantize {
+        self.quantize
+    }
+
+    pub fn set_quantize(&mut self, quantize: Quantize) {
+        self.quantize = quantize;
+    }
+
+    pub fn mode(&self) -> RecordMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: RecordMode) {
+        self.mode = mode;
+    }
+
+    /// Bars of count-in when recording starts the sequencer, 0 for none
+    pub fn count_in(&self) -> u8 {
+        self.count_in
+    }
+
+    pub fn set_count_in(&mut self, bars: u8) {
+        self.count_in = bars;
+    }
+
+    /// Track the notes are recorded into
+    pub fn track(&self) -> usize {
+        self.track
+    }
+
+    pub fn set_track(&mut self, track: usize) {
+        self.track = track.min(TRACKS - 1);
+        self.held.clear();
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Start recording, and the sequencer if it is stopped
+    pub fn start(&mut self, seq: &mut Sequencer) {
+        if !seq.is_playing() {
+            seq.count_in(self.count_in);
+        }
+        self.recording = true;
+        self.held.clear();
+        self.erase = next_step(seq.position().unwrap_or(-1));
+    }
+
+    /// Stop recording, the sequencer keeps playing
+    pub fn stop(&mut self) {
+        self.recording = false;
+        self.held.clear();
+    }
+
+    pub fn toggle(&mut self, seq: &mut Sequencer) {
+        if self.recording {
+            self.stop();
+        } else {
+            self.start(seq);
+        }
+    }
+
+    /// Clear the steps reached by the playhead in `Replace` mode, call
+    /// before each `Sequencer::advance`
+    pub fn update<const N: usize>(
+        &mut self,
+        project: &mut Project,
+        seq: &Sequencer,
+        history: &mut History<N>,
+    ) {
+        if !seq.is_playing() {
+            self.stop();
+        }
+        if let Some(position) = seq.position() {
+            // From the start of a new pattern
+            if self.erase > position + ERASE_AHEAD + STEP {
+                self.erase = next_step(position);
+            }
+            self.erase_until(project, seq, history, position + ERASE_AHEAD);
+        }
+    }
+
+    /// Clear the steps due up to `tick`
+    fn erase_until<const N: usize>(
+        &mut self,
+        project: &mut Project,
+        seq: &Sequencer,
+        history: &mut History<N>,
+        tick: i32,
+    ) {
+        if !self.recording || self.mode != RecordMode::Replace {
+            return;
+        }
+        let pattern = seq.pattern();
+        while self.erase <= tick {
+            let length = project.patterns[pattern].tracks[self.track].length;
+            let step = self.step_index(length, self.erase);
+            history.begin();
+            history.set_step(project, pattern, self.track, step, Step::new());
+            history.clear_locks(project, pattern, self.track, step);
+            history.end();
+            self.erase += STEP;
+        }
+    }
+
+    /// Step of the track at a sequencer tick
+    fn step_index(&self, length: u8, tick: i32) -> usize {
+        let length = (length as i32).clamp(1, MAX_STEPS as i32);
+        tick.div_euclid(STEP).rem_euclid(length) as usize
+    }
+
+    /// Record a note at the current position, returns the step
+    pub fn note_on<const N: usize>(
+        &mut self,
+        project: &mut Project,
+        seq: &Sequencer,
+        history: &mut History<N>,
+        note: u8,
+        velocity: u8,
+    ) -> Option<usize> {
+        let position = seq.position().filter(|_| self.recording)?;
+        if position < -(MAX_OFFSET as i32) {
+            // Too early in the count-in
+            return None;
+        }
+        let length = project.patterns[seq.pattern()].tracks[self.track].length;
+        let nearest = |tick: i32| (tick + STEP / 2).div_euclid(STEP) * STEP;
+        let (tick, offset) = match self.quantize {
+            Quantize::Sixteenth => (nearest(position), 0),
+            Quantize::ThirtySecond => {
+                let half = (position + STEP / 4).div_euclid(STEP / 2) * (STEP / 2);
+                (nearest(half), half - nearest(half))
+            }
+            Quantize::Off => {
+                // Nearest swung step
+                let swung = |tick: i32| {
+                    let step = self.step_index(length, tick);
+                    tick + swing_delay(project.swing, step) as i32
+                };
+                let before = position.div_euclid(STEP) * STEP;
+                let after = before + STEP;
+                let tick = if position - swung(before) <= swung(after) - position {
+                    before
+                } else {
+                    after
+                };
+                let tick = tick.max(0);
+                (tick, position - swung(tick))
+            }
+        };
+        let offset = offset.clamp(-(MAX_OFFSET as i32), MAX_OFFSET as i32) as i8;
+
+        // Clear the step first, so that it is not cleared again in this loop
+        self.erase_until(project, seq, history, tick);
+        let step = self.step_index(length, tick);
+        let recorded = Step {
+            active: true,
+            note,
+            velocity,
+            offset,
+            ..Step::new()
+        };
+        history.set_step(project, seq.pattern(), self.track, step, recorded);
+        self.held.retain(|held| held.note != note);
+        let _ = self.held.push(Held {
+            note,
+            start: position,
+            step,
+        });
+        Some(step)
+    }
+
+    /// End a recorded note, its step gets the length of the note as gate
+    pub fn note_off(&mut self, project: &mut Project, seq: &Sequencer, note: u8) {
+        let Some(index) = self.held.iter().position(|held| held.note == note) else {
+            return;
+        };
+        let held = self.held.swap_remove(index);
+        let Some(position) = seq.position() else {
+            return;
+        };
+        let length = (position - held.start).max(0);
+        let gate = (length * 100 / STEP).clamp(1, 100) as u8;
+        // Not recorded: undoing the note puts back the whole step, redoing
+        // it brings back the gate
+        let step = &mut project.patterns[seq.pattern()].tracks[self.track].steps[held.step];
+        if step.active && step.note == note {
+            step.gate = gate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Play ticks up to `position`
+    fn play_to(seq: &mut Sequencer, project: &Project, position: i32) {
+        while seq.position().unwrap() < position {
+            seq.tick(project, |_| {});
+        }
+    }
+
+    fn recorded(project: &Project, track: usize) -> std::vec::Vec<(usize, u8, i8)> {
+        let steps = &project.patterns[0].tracks[track].steps;
+        (0..MAX_STEPS)
+            .filter(|&i| steps[i].active)
+            .map(|i| (i, steps[i].note, steps[i].offset))
+            .collect()
+    }
+
+    #[test]
+    fn quantization() {
+        let cases = [
+            (Quantize::Sixteenth, [(0, 0), (1, 0), (2, 0), (3, 0)]),
+            (Quantize::ThirtySecond, [(0, 0), (1, -12), (2, 0), (3, -12)]),
+            (Quantize::Off, [(0, 5), (1, -9), (2, 3), (3, -8)]),
+        ];
+        for (quantize, expected) in cases {
+            let mut project = Box::new(Project::new());
+            let mut history = History::<1024>::new();
+            let mut seq = Sequencer::new();
+            let mut recorder = Recorder::new();
+            recorder.set_quantize(quantize);
+            recorder.set_count_in(0);
+            recorder.set_track(2);
+            recorder.start(&mut seq);
+
+            for (note, position) in [(60, 5), (61, 15), (62, 51), (63, 64)] {
+                play_to(&mut seq, &project, position);
+                recorder.note_on(&mut project, &seq, &mut history, note, 90);
+            }
+            let expected: std::vec::Vec<_> = expected
+                .iter()
+                .zip(60..)
+                .map(|(&(step, offset), note)| (step, note, offset))
+                .collect();
+            assert_eq!(recorded(&project, 2), expected, "{:?}", quantize);
+            assert_eq!(project.patterns[0].tracks[2].steps[0].velocity, 90);
+        }
+    }
+
+    #[test]
+    fn micro_timing_with_swing() {
+        let mut project = Box::new(Project::new());
+        let mut history = History::<1024>::new();
+        project.swing = 75;
+        let mut seq = Sequencer::new();
+        let mut recorder = Recorder::new();
+        recorder.set_quantize(Quantize::Off);
+        recorder.start(&mut seq);
+
+        // The second step is played 12 ticks late
+        play_to(&mut seq, &project, 34);
+        recorder.note_on(&mut project, &seq, &mut history, 60, 100);
+        assert_eq!(recorded(&project, 0), [(1, 60, -2)]);
+
+        // Recorded notes play where they were recorded
+        let mut seq = Sequencer::new();
+        seq.start();
+        let mut ticks = std::vec::Vec::new();
+        for _ in 0..TICKS_PER_STEP * 2 {
+            let tick = seq.ticks();
+            seq.tick(&project, |event| {
+                if let crate::sequencer::Event::NoteOn { .. } = event {
+                    ticks.push(tick);
+                }
+            });
+        }
+        assert_eq!(ticks, [34]);
+    }
+
+    #[test]
+    fn gates() {
+        let mut project = Box::new(Project::new());
+        let mut history = History::<1024>::new();
+        let mut seq = Sequencer::new();
+        let mut recorder = Recorder::new();
+        recorder.set_count_in(0);
+        recorder.start(&mut seq);
+
+        play_to(&mut seq, &project, 0);
+        recorder.note_on(&mut project, &seq, &mut history, 60, 100);
+        play_to(&mut seq, &project, 6);
+        recorder.note_off(&mut project, &seq, 60);
+
+        // The step was replaced by another note before the note off
+        play_to(&mut seq, &project, 30);
+        recorder.note_on(&mut project, &seq, &mut history, 64, 100);
+        recorder.note_on(&mut project, &seq, &mut history, 67, 100);
+        play_to(&mut seq, &project, 40);
+        recorder.note_off(&mut project, &seq, 64);
+        assert_eq!(project.patterns[0].tracks[0].steps[1].gate, 50);
+        play_to(&mut seq, &project, 200);
+        recorder.note_off(&mut project, &seq, 67);
+
+        let steps = &project.patterns[0].tracks[0].steps;
+        assert_eq!((steps[0].note, steps[0].gate), (60, 25));
+        assert_eq!((steps[1].note, steps[1].gate), (67, 100));
+
+        // Each note is an edit, with its gate
+        let edited = project.clone();
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[0].tracks[0].steps[1].note, 64);
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert_eq!(*project, Project::new());
+        while history.redo(&mut project) {}
+        assert_eq!(project, edited);
+    }
+
+    #[test]
+    fn overdub_and_replace() {
+        let mut project = Box::new(Project::new());
+        let mut history = History::<1024>::new();
+        for i in [0, 4, 8, 12] {
+            project.patterns[0].tracks[0].steps[i].active = true;
+        }
+        project.patterns[0].set_lock(0, 8, 1, 1).unwrap();
+        project.patterns[0].tracks[1].steps[8].active = true;
+
+        let mut seq = Sequencer::new();
+        let mut recorder = Recorder::new();
+        recorder.set_count_in(0);
+        recorder.start(&mut seq);
+        play_to(&mut seq, &project, 48);
+        recorder.note_on(&mut project, &seq, &mut history, 72, 100);
+        assert_eq!(
+            recorded(&project, 0),
+            [(0, 60, 0), (2, 72, 0), (4, 60, 0), (8, 60, 0), (12, 60, 0)]
+        );
+
+        // Clears the steps as they are reached, from the next one
+        let mut seq = Sequencer::new();
+        recorder.stop();
+        recorder.set_mode(RecordMode::Replace);
+        seq.start();
+        play_to(&mut seq, &project, 80);
+        recorder.start(&mut seq);
+        while seq.position().unwrap() < 14 * STEP {
+            recorder.update(&mut project, &seq, &mut history);
+            seq.tick(&project, |_| {});
+            if seq.position() == Some(9 * STEP - 2) {
+                recorder.note_on(&mut project, &seq, &mut history, 50, 100);
+            }
+        }
+        assert_eq!(recorded(&project, 0), [(0, 60, 0), (2, 72, 0), (9, 50, 0)]);
+        assert!(!project.patterns[0].is_locked(0, 8));
+        assert_eq!(recorded(&project, 1), [(8, 60, 0)]);
+
+        // Cleared steps are edits, as recorded notes
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert!(history.undo(&mut project));
+        assert_eq!(
+            recorded(&project, 0),
+            [(0, 60, 0), (2, 72, 0), (8, 60, 0), (12, 60, 0)]
+        );
+        assert!(project.patterns[0].is_locked(0, 8));
+        while history.redo(&mut project) {}
+
+        // Again on the next loop
+        while seq.position().unwrap() < 32 * STEP {
+            recorder.update(&mut project, &seq, &mut history);
+            seq.tick(&project, |_| {});
+        }
+        assert!(recorded(&project, 0).is_empty());
+
+        // Stopping the sequencer stops the recording
+        seq.stop(|_| {});
+        recorder.update(&mut project, &seq, &mut history);
+        assert!(!recorder.is_recording());
+    }
+
+    #[test]
+    fn count_in() {
+        let mut project = Box::new(Project::new());
+        let mut history = History::<1024>::new();
+        let mut seq = Sequencer::new();
+        let mut recorder = Recorder::new();
+        recorder.set_count_in(2);
+        recorder.start(&mut seq);
+        assert!(seq.is_counting_in());
+
+        // Too early, then early enough for the first step
+        play_to(&mut seq, &project, -40);
+        assert_eq!(
+            recorder.note_on(&mut project, &seq, &mut history, 60, 100),
+            None
+        );
+        play_to(&mut seq, &project, -5);
+        assert_eq!(
+            recorder.note_on(&mut project, &seq, &mut history, 62, 100),
+            Some(0)
+        );
+        assert_eq!(recorded(&project, 0), [(0, 62, 0)]);
+
+        recorder.stop();
+        assert_eq!(
+            recorder.note_on(&mut project, &seq, &mut history, 64, 100),
+            None
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Pseudo random numbers for the sequencer and the pattern generators
+//!
+//! A xorshift32 generator: the same seed always gives the same numbers, so
+//! the random parts of a pattern play the same way from the start and can
+//! be checked by the tests.
+
+/// Seed used when none is given
+pub const DEFAULT_SEED: u32 = 0x1234_5678;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEED)
+    }
+}
+
+impl Rng {
+    /// A seed of 0, that xorshift cannot use, is replaced by `DEFAULT_SEED`
+    pub const fn new(seed: u32) -> Self {
+        Rng {
+            state: if seed == 0 { DEFAULT_SEED } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// Uniform in `0..n`, 0 if `n` is 0
+    pub fn below(&mut self, n: u32) -> u32 {
+        ((self.next_u32() as u64 * n as u64) >> 32) as u32
+    }
+
+    /// True `percent` times out of 100
+    pub fn chance(&mut self, percent: u8) -> bool {
+        self.below(100) < percent as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+        assert_ne!(Rng::new(1).next_u32(), Rng::new(2).next_u32());
+        assert_eq!(Rng::new(0), Rng::default());
+    }
+
+    #[test]
+    fn distribution() {
+        let mut rng = Rng::new(7);
+        let mut counts = [0; 10];
+        for _ in 0..10_000 {
+            counts[rng.below(10) as usize] += 1;
+        }
+        assert!(counts.iter().all(|&count| (900..1100).contains(&count)));
+
+        let hits = (0..10_000).filter(|_| rng.chance(30)).count();
+        assert!((2800..3200).contains(&hits), "{}", hits);
+        assert!(!(0..1000).any(|_| rng.chance(0)));
+        assert!((0..1000).all(|_| rng.chance(100)));
+        assert_eq!(rng.below(0), 0);
+    }
+}
@@ -0,0 +1,707 @@
+//! Sample playback from the flash sample bank
+//!
+//! Samples are packed on the host with `pgb1-tool pack-samples` (see
+//! `tools/pgb1-tool`) into a bank image that is written to flash at
+//! `flash::SAMPLE_BANK_OFFSET`. The bank is read in place through the XIP
+//! mapping, nothing is copied to RAM.
+//!
+//! Bank layout, all integers are little-endian:
+//!
+//! | Offset | Size       | Content                              |
+//! |--------|------------|--------------------------------------|
+//! | 0      | 4          | Magic `PGBS`                         |
+//! | 4      | 2          | Format version (`BANK_VERSION`)      |
+//! | 6      | 2          | Number of samples                    |
+//! | 8      | 4          | Total size of the image in bytes     |
+//! | 12     | 4          | Reserved, 0                          |
+//! | 16     | 32 × count | Sample entries                       |
+//! | ...    |            | Sample data, 16-bit signed mono PCM  |
+//!
+//! Sample entry:
+//!
+//! | Offset | Size | Content                                        |
+//! |--------|------|------------------------------------------------|
+//! | 0      | 16   | Name, ASCII, NUL padded                        |
+//! | 16     | 4    | Offset of the data from the start of the bank  |
+//! | 20     | 4    | Length in frames                               |
+//! | 24     | 4    | Sample rate in Hz                              |
+//! | 28     | 1    | Root note (MIDI note number)                   |
+//! | 29     | 3    | Reserved, 0                                    |
+//!
+//! Sample data offsets are 4-byte aligned.
+
+use crate::audio::{self, StereoFrame, SAMPLE_RATE};
+use crate::voice::{Pitch, Voice};
+
+pub const BANK_MAGIC: [u8; 4] = *b"PGBS";
+pub const BANK_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 16;
+pub const ENTRY_SIZE: usize = 32;
+pub const NAME_LEN: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BankError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    /// The entry at this index points outside of the bank
+    BadEntry(u16),
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BankHeader {
+    pub version: u16,
+    pub count: u16,
+    pub size: u32,
+}
+
+impl BankHeader {
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut out = [0; HEADER_SIZE];
+        out[0..4].copy_from_slice(&BANK_MAGIC);
+        out[4..6].copy_from_slice(&self.version.to_le_bytes());
+        out[6..8].copy_from_slice(&self.count.to_le_bytes());
+        out[8..12].copy_from_slice(&self.size.to_le_bytes());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, BankError> {
+        if data.len() < HEADER_SIZE {
+            return Err(BankError::Truncated);
+        }
+        if data[0..4] != BANK_MAGIC {
+            return Err(BankError::BadMagic);
+        }
+        let version = read_u16(data, 4);
+        if version != BANK_VERSION {
+            return Err(BankError::UnsupportedVersion(version));
+        }
+        Ok(BankHeader {
+            version,
+            count: read_u16(data, 6),
+            size: read_u32(data, 8),
+        })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SampleEntry {
+    pub name: [u8; NAME_LEN],
+    pub offset: u32,
+    pub length: u32,
+    pub sample_rate: u32,
+    pub root_note: u8,
+}
+
+impl SampleEntry {
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut out = [0; ENTRY_SIZE];
+        out[0..16].copy_from_slice(&self.name);
+        out[16..20].copy_from_slice(&self.offset.to_le_bytes());
+        out[20..24].copy_from_slice(&self.length.to_le_bytes());
+        out[24..28].copy_from_slice(&self.sample_rate.to_le_bytes());
+        out[28] = self.root_note;
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, BankError> {
+        if data.len() < ENTRY_SIZE {
+            return Err(BankError::Truncated);
+        }
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&data[0..16]);
+        Ok(SampleEntry {
+            name,
+            offset: read_u32(data, 16),
+            length: read_u32(data, 20),
+            sample_rate: read_u32(data, 24),
+            root_note: data[28],
+        })
+    }
+
+    /// Return the name without NUL padding
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// A sample bank mapped in memory
+#[derive(Copy, Clone)]
+pub struct SampleBank {
+    data: &'static [u8],
+    count: u16,
+}
+
+impl SampleBank {
+    /// Parse and validate a bank image
+    pub fn new(data: &'static [u8]) -> Result<Self, BankError> {
+        let header = BankHeader::decode(data)?;
+        let size = header.size as usize;
+        let table_end = HEADER_SIZE + header.count as usize * ENTRY_SIZE;
+        if size > data.len() || table_end > size {
+            return Err(BankError::Truncated);
+        }
+
+        let data = &data[..size];
+        for index in 0..header.count {
+            let start = HEADER_SIZE + index as usize * ENTRY_SIZE;
+            let entry = SampleEntry::decode(&data[start..])?;
+            let end = entry.offset as u64 + entry.length as u64 * 2;
+            if (entry.offset as usize) < table_end || end > size as u64 {
+                return Err(BankError::BadEntry(index));
+            }
+        }
+
+        Ok(SampleBank {
+            data,
+            count: header.count,
+        })
+    }
+
+    /// Open the bank stored in the flash sample bank region
+    pub fn from_flash() -> Result<Self, BankError> {
+        // The sample bank region is only written by `disk::import`, which
+        // is documented to be called without a bank alive
+        let data = unsafe {
+            crate::flash::xip_slice(
+                crate::flash::SAMPLE_BANK_OFFSET,
+                crate::flash::SAMPLE_BANK_SIZE,
+            )
+        };
+        Self::new(data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn entry(&self, index: usize) -> Option<SampleEntry> {
+        if index >= self.len() {
+            return None;
+        }
+        SampleEntry::decode(&self.data[HEADER_SIZE + index * ENTRY_SIZE..]).ok()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Sample> {
+        let entry = self.entry(index)?;
+        let start = entry.offset as usize;
+        let end = start + entry.length as usize * 2;
+        Some(Sample::new(
+            &self.data[start..end],
+            entry.sample_rate,
+            entry.root_note,
+        ))
+    }
+
+    pub fn find(&self, name: &str) -> Option<Sample> {
+        (0..self.len())
+            .find(|&index| self.entry(index).is_some_and(|e| e.name() == name))
+            .and_then(|index| self.get(index))
+    }
+}
+
+/// 16-bit mono PCM data with its playback properties
+#[derive(Copy, Clone)]
+pub struct Sample {
+    pcm: &'static [u8],
+    pub sample_rate: u32,
+    pub root_note: u8,
+}
+
+impl Sample {
+    /// `pcm` is little-endian 16-bit signed data
+    pub fn new(pcm: &'static [u8], sample_rate: u32, root_note: u8) -> Self {
+        Sample {
+            pcm,
+            sample_rate,
+            root_note,
+        }
+    }
+
+    /// Length in frames
+    pub fn len(&self) -> u32 {
+        (self.pcm.len() / 2) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pcm.len() < 2
+    }
+
+    #[inline]
+    pub fn frame(&self, index: u32) -> i16 {
+        let i = index as usize * 2;
+        i16::from_le_bytes([self.pcm[i], self.pcm[i + 1]])
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LoopMode {
+    /// Play from start to end, ignore note-off
+    OneShot,
+    /// Play from start to end, stop on note-off
+    Gate,
+    /// Loop between the loop start and end points until note-off
+    Forward,
+    /// Alternate direction between the loop start and end points until
+    /// note-off
+    PingPong,
+}
+
+/// 2^(n/12) in 16.16 fixed point
+const SEMITONE_RATIOS: [u32; 13] = [
+    65536, 69433, 73562, 77936, 82570, 87480, 92682, 98193, 104032, 110218, 116772, 123715,
+    131072,
+];
+
+/// Frequency ratio in 16.16 fixed point for an interval in 1/256 semitones
+fn pitch_ratio(interval: i32) -> u32 {
+    let octave = interval.div_euclid(12 * 256);
+    let rem = interval.rem_euclid(12 * 256) as u32;
+    let semi = (rem / 256) as usize;
+    let fine = rem % 256;
+
+    let low = SEMITONE_RATIOS[semi];
+    let high = SEMITONE_RATIOS[semi + 1];
+    let ratio = low + (high - low) * fine / 256;
+
+    match octave {
+        o if o >= 15 => u32::MAX,
+        o if o >= 0 => ratio << o,
+        o if o > -32 => ratio >> -o,
+        _ => 0,
+    }
+}
+
+/// Number of frames of the fade out applied when a voice is stopped
+const FADE_FRAMES: u16 = 64;
+
+const FRAC_BITS: u32 = 16;
+
+/// A sample playback voice
+///
+/// The voice reads the sample with a 32.16 fixed point position, the step
+/// between two output frames depends on the pitch, the root note and the
+/// sample rate of the sample. Frames are linearly interpolated.
+pub struct SamplerVoice {
+    sample: Option<Sample>,
+    mode: LoopMode,
+    start: u32,
+    end: u32,
+    loop_start: u32,
+
+    /// Read position in 32.16 fixed point frames
+    position: u64,
+    step: u32,
+    forward: bool,
+
+    playing: bool,
+    fade: u16,
+
+    pitch: Pitch,
+    velocity: u8,
+    volume: u8,
+    pan: i8,
+}
+
+impl Default for SamplerVoice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SamplerVoice {
+    pub const fn new() -> Self {
+        SamplerVoice {
+            sample: None,
+            mode: LoopMode::OneShot,
+            start: 0,
+            end: 0,
+            loop_start: 0,
+            position: 0,
+            step: 1 << FRAC_BITS,
+            forward: true,
+            playing: false,
+            fade: 0,
+            pitch: 60 << 8,
+            velocity: 127,
+            volume: 255,
+            pan: 0,
+        }
+    }
+
+    /// Set the sample to play, start/end points are reset to cover the
+    /// whole sample. Stops the voice.
+    pub fn set_sample(&mut self, sample: Sample) {
+        self.start = 0;
+        self.end = sample.len();
+        self.loop_start = 0;
+        self.sample = Some(sample);
+        self.playing = false;
+        self.update_step();
+    }
+
+    pub fn sample(&self) -> Option<Sample> {
+        self.sample
+    }
+
+    /// Set the start and end points in frames, clamped to the sample length
+    pub fn set_range(&mut self, start: u32, end: u32) {
+        let len = self.sample.map_or(0, |s| s.len());
+        self.end = end.min(len);
+        self.start = start.min(self.end);
+        self.loop_start = self.loop_start.clamp(self.start, self.end);
+    }
+
+    /// Set the point where loops restart, clamped between the start and end
+    /// points
+    pub fn set_loop_start(&mut self, loop_start: u32) {
+        self.loop_start = loop_start.clamp(self.start, self.end);
+    }
+
+    pub fn set_loop_mode(&mut self, mode: LoopMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_volume(&mut self, volume: u8) {
+        self.volume = volume;
+    }
+
+    /// Pan position from -64 (left) to 63 (right)
+    pub fn set_pan(&mut self, pan: i8) {
+        self.pan = pan;
+    }
+
+    /// Stop immediately, without fade out
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    fn update_step(&mut self) {
+        let Some(sample) = self.sample else {
+            return;
+        };
+
+        let rate = ((sample.sample_rate as u64) << FRAC_BITS) / SAMPLE_RATE as u64;
+        let interval = self.pitch as i32 - ((sample.root_note as i32) << 8);
+        let step = (pitch_ratio(interval) as u64 * rate) >> FRAC_BITS;
+        self.step = step.min(u16::MAX as u64 * (1 << FRAC_BITS)) as u32;
+    }
+
+    fn gain(&self) -> u32 {
+        // 0..=0x8000
+        self.volume as u32 * self.velocity as u32 * 0x8000 / (255 * 127)
+    }
+
+    /// Render and mix the voice into `out`
+    pub fn render(&mut self, out: &mut [StereoFrame]) {
+        let Some(sample) = self.sample else {
+            return;
+        };
+
+        let gain = self.gain() as i32;
+        let (left, right) = audio::pan_gains(self.pan);
+        let left = (gain * left as i32) >> 15;
+        let right = (gain * right as i32) >> 15;
+
+        for frame in out.iter_mut() {
+            if !self.playing {
+                break;
+            }
+
+            let index = (self.position >> FRAC_BITS) as u32;
+            let frac = (self.position & 0xFFFF) as i32;
+            let s0 = sample.frame(index) as i32;
+            let s1 = if index + 1 < self.end {
+                sample.frame(index + 1) as i32
+            } else {
+                s0
+            };
+            let mut value = s0 + (((s1 - s0) * frac) >> FRAC_BITS);
+
+            if self.fade > 0 {
+                value = value * self.fade as i32 / FADE_FRAMES as i32;
+                self.fade -= 1;
+                if self.fade == 0 {
+                    self.playing = false;
+                }
+            }
+
+            audio::mix(
+                frame,
+                [
+                    audio::saturate((value * left) >> 15),
+                    audio::saturate((value * right) >> 15),
+                ],
+            );
+
+            self.advance();
+        }
+    }
+
+    fn advance(&mut self) {
+        let end = (self.end as u64) << FRAC_BITS;
+        let loop_start = (self.loop_start as u64) << FRAC_BITS;
+        let looping = matches!(self.mode, LoopMode::Forward | LoopMode::PingPong);
+
+        if self.forward {
+            self.position += self.step as u64;
+            if self.position < end {
+                return;
+            }
+
+            let over = self.position - end;
+            let loop_len = end - loop_start;
+            if !looping || loop_len == 0 {
+                self.playing = false;
+            } else if self.mode == LoopMode::Forward {
+                self.position = loop_start + over % loop_len;
+            } else {
+                self.forward = false;
+                self.position = end - 1 - (over % loop_len);
+            }
+        } else {
+            match self.position.checked_sub(self.step as u64) {
+                Some(position) if position >= loop_start => {
+                    self.position = position;
+                }
+                _ => {
+                    let under = loop_start + self.step as u64 - self.position;
+                    self.forward = true;
+                    self.position = loop_start + under % (end - loop_start);
+                }
+            }
+        }
+    }
+}
+
+impl Voice for SamplerVoice {
+    fn note_on(&mut self, _note: u8, velocity: u8) {
+        if self.sample.is_none() || self.start >= self.end {
+            return;
+        }
+        self.velocity = velocity;
+        self.position = (self.start as u64) << FRAC_BITS;
+        self.forward = true;
+        self.fade = 0;
+        self.playing = true;
+    }
+
+    fn note_off(&mut self) {
+        if self.playing && self.mode != LoopMode::OneShot && self.fade == 0 {
+            self.fade = FADE_FRAMES;
+        }
+    }
+
+    fn set_pitch(&mut self, pitch: Pitch) {
+        self.pitch = pitch;
+        self.update_step();
+    }
+
+    fn is_active(&self) -> bool {
+        self.playing
+    }
+
+    fn level(&self) -> u16 {
+        if self.playing {
+            self.gain() as u16
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn pcm(frames: &[i16]) -> Vec<u8> {
+        frames.iter().flat_map(|f| f.to_le_bytes()).collect()
+    }
+
+    fn bank(samples: &[(&str, &[i16])]) -> Vec<u8> {
+        let table_end = HEADER_SIZE + samples.len() * ENTRY_SIZE;
+        let mut entries = Vec::new();
+        let mut data = Vec::new();
+        for (name, frames) in samples {
+            let mut entry = SampleEntry {
+                name: [0; NAME_LEN],
+                offset: (table_end + data.len()) as u32,
+                length: frames.len() as u32,
+                sample_rate: 22_050,
+                root_note: 48,
+            };
+            entry.name[..name.len()].copy_from_slice(name.as_bytes());
+            entries.extend(entry.encode());
+            data.extend(pcm(frames));
+        }
+        let header = BankHeader {
+            version: BANK_VERSION,
+            count: samples.len() as u16,
+            size: (table_end + data.len()) as u32,
+        };
+        let mut image = header.encode().to_vec();
+        image.extend(entries);
+        image.extend(data);
+        image
+    }
+
+    fn leak(data: Vec<u8>) -> &'static [u8] {
+        Box::leak(data.into_boxed_slice())
+    }
+
+    #[test]
+    fn banks() {
+        let image = bank(&[("kick", &[1, 2, 3]), ("snare", &[-4, 5])]);
+        let bank = SampleBank::new(leak(image.clone())).unwrap();
+        assert_eq!(bank.len(), 2);
+        assert_eq!(bank.entry(0).unwrap().name(), "kick");
+        assert_eq!(bank.entry(1).unwrap().offset, 16 + 2 * 32 + 6);
+        assert!(bank.entry(2).is_none());
+        let snare = bank.find("snare").unwrap();
+        assert_eq!((snare.len(), snare.frame(0), snare.frame(1)), (2, -4, 5));
+        assert_eq!((snare.sample_rate, snare.root_note), (22_050, 48));
+        assert!(bank.find("hat").is_none());
+
+        // Trailing data after the image is ignored
+        let mut longer = image.clone();
+        longer.extend([0xFF; 8]);
+        assert_eq!(SampleBank::new(leak(longer)).unwrap().len(), 2);
+
+        let error = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut image = image.clone();
+            edit(&mut image);
+            SampleBank::new(leak(image)).err()
+        };
+        assert_eq!(error(&|i| i[0] = b'X'), Some(BankError::BadMagic));
+        assert_eq!(error(&|i| i[4] = 2), Some(BankError::UnsupportedVersion(2)));
+        assert_eq!(error(&|i| i.truncate(10)), Some(BankError::Truncated));
+        assert_eq!(
+            error(&|i| i.truncate(i.len() - 1)),
+            Some(BankError::Truncated)
+        );
+        // More entries than the image holds
+        assert_eq!(error(&|i| i[6] = 5), Some(BankError::Truncated));
+        // Data in the entry table
+        assert_eq!(error(&|i| i[16 + 16] = 8), Some(BankError::BadEntry(0)));
+        // Data beyond the image
+        assert_eq!(
+            error(&|i| i[16 + 32 + 20] = 3),
+            Some(BankError::BadEntry(1))
+        );
+    }
+
+    #[test]
+    fn pitch_ratios() {
+        assert_eq!(pitch_ratio(0), 65536);
+        assert_eq!(pitch_ratio(12 * 256), 131072);
+        assert_eq!(pitch_ratio(-12 * 256), 32768);
+        assert_eq!(pitch_ratio(7 * 256), 98193);
+        assert_eq!(pitch_ratio(-256), 123715 / 2);
+        // Half a semitone, interpolated
+        assert_eq!(pitch_ratio(128), 65536 + (69433 - 65536) / 2);
+        assert_eq!(pitch_ratio(15 * 12 * 256), u32::MAX);
+        assert_eq!(pitch_ratio(-40 * 12 * 256), 0);
+
+        // A 22.05 kHz sample with root note 48 played at note 60
+        let mut voice = SamplerVoice::new();
+        voice.set_sample(Sample::new(leak(pcm(&[0; 4])), 22_050, 48));
+        voice.set_pitch(60 << 8);
+        assert_eq!(voice.step, 1 << FRAC_BITS);
+    }
+
+    /// Voice at the root note of a sample at `audio::SAMPLE_RATE`, frame i
+    /// is 1000 × i
+    fn voice(frames: i16, mode: LoopMode) -> SamplerVoice {
+        let ramp: Vec<i16> = (0..frames).map(|i| i * 1000).collect();
+        let mut voice = SamplerVoice::new();
+        voice.set_sample(Sample::new(leak(pcm(&ramp)), SAMPLE_RATE, 60));
+        voice.set_loop_mode(mode);
+        voice.set_pitch(60 << 8);
+        voice.note_on(60, 127);
+        voice
+    }
+
+    /// Frames read by the next advances, until the voice stops
+    fn positions(voice: &mut SamplerVoice, count: usize) -> Vec<u32> {
+        let mut positions = Vec::new();
+        while voice.playing && positions.len() < count {
+            positions.push((voice.position >> FRAC_BITS) as u32);
+            voice.advance();
+        }
+        positions
+    }
+
+    #[test]
+    fn loops() {
+        let mut one_shot = voice(4, LoopMode::OneShot);
+        one_shot.note_off();
+        assert_eq!(positions(&mut one_shot, 10), [0, 1, 2, 3]);
+
+        let mut forward = voice(8, LoopMode::Forward);
+        forward.set_loop_start(5);
+        assert_eq!(
+            positions(&mut forward, 12),
+            [0, 1, 2, 3, 4, 5, 6, 7, 5, 6, 7, 5]
+        );
+
+        let mut ping_pong = voice(8, LoopMode::PingPong);
+        ping_pong.set_range(2, 6);
+        ping_pong.set_loop_start(4);
+        ping_pong.note_on(60, 127);
+        assert_eq!(
+            positions(&mut ping_pong, 14),
+            [2, 3, 4, 5, 5, 4, 4, 5, 5, 4, 4, 5, 5, 4]
+        );
+
+        // Twice as fast, the loop of 4 frames is read every other frame
+        let mut fast = voice(8, LoopMode::Forward);
+        fast.set_loop_start(4);
+        fast.set_pitch(72 << 8);
+        assert_eq!(positions(&mut fast, 8), [0, 2, 4, 6, 4, 6, 4, 6]);
+
+        // A loop stops after the fade out of a note off
+        let mut gate = voice(8, LoopMode::Forward);
+        let mut out = [[0; 2]; 16];
+        gate.render(&mut out);
+        gate.note_off();
+        let mut out = [[0; 2]; FADE_FRAMES as usize + 1];
+        gate.render(&mut out);
+        assert!(!gate.is_active());
+        assert_eq!(out[FADE_FRAMES as usize], [0, 0]);
+    }
+
+    #[test]
+    fn interpolation() {
+        // An octave down reads half frames, panned left at full volume the
+        // output is the sample
+        let mut voice = voice(4, LoopMode::OneShot);
+        voice.set_pan(-64);
+        voice.set_pitch(48 << 8);
+        let mut out = [[0; 2]; 10];
+        voice.render(&mut out);
+        let left: Vec<i16> = out.iter().map(|f| f[0]).collect();
+        assert_eq!(left, [0, 500, 1000, 1500, 2000, 2500, 3000, 3000, 0, 0]);
+        assert!(out.iter().all(|f| f[1] == 0));
+        assert!(!voice.is_active());
+    }
+}
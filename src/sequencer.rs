@@ -0,0 +1,1105 @@
+//! Step sequencer
+//!
+//! The `Sequencer` plays a pattern of a `project::Project`: each track
+//! loops over its own number of steps, so tracks of different lengths
+//! drift against each other. Active steps of the tracks that are not muted
+//! emit a note on, and a note off after the gate time of the step.
+//!
+//! Playback is driven by ticks, `TICKS_PER_STEP` per sixteenth note:
+//!
+//! - `advance` with the elapsed time, ticks are then played at the tempo of
+//!   the project.
+//! - `tick` to play a single tick, e.g. `TICKS_PER_CLOCK` times per
+//!   `midi::clock` tick to follow an external clock, or from the tests.
+//!
+//! Parameter locks of a step are sent before its note on, as `Event::Lock`.
+//! At the next step of the track the parameters that are not locked again
+//! get an `Event::Unlock`: the sound goes back to its own value.
+//!
+//! Swing delays the even steps (2, 4, ...) of every track, up to half a step
+//! at 75%. The micro-timing `offset` of a step moves it by up to half a step
+//! earlier or later, on top of the swing.
+//!
+//! `count_in` starts after a bar or more of `Event::Click`, to get ready for
+//! live recording, see `record`.
+//!
+//! The `Condition` of a step decides whether it plays each time it is
+//! reached. Probabilities are drawn from an `Rng` seeded with `set_seed` at
+//! every start, so a pattern plays the same way each time it is started
+//! with the same seed. The loops of the `Cycle`, `First` and `NotFirst`
+//! conditions are counted for each track from the start of the pattern.
+//! Retrigs divide the step in equal parts, each note softer by the `decay`
+//! of the step.
+//!
+//! Pattern changes are queued with `queue_pattern` and happen on the next
+//! bar, tracks then start from their first step. `start_song` plays the
+//! rows of `Project::song` instead: each row plays its pattern `repeats`
+//! times, a pass lasting as many bars as needed for the longest track. The
+//! tempo and mutes of the rows apply while they play. After the last row
+//! the song loops over `Project::song_loop`, or stops.
+
+use crate::midi::clock::{self, MAX_BPM, MIN_BPM};
+use crate::project::{
+    Condition, Pattern, Project, MAX_OFFSET, MAX_RETRIGS, MAX_STEPS, PAGE_STEPS, PATTERNS, TRACKS,
+};
+use crate::rng::{Rng, DEFAULT_SEED};
+
+/// Ticks per sixteenth note
+pub const TICKS_PER_STEP: u32 = 24;
+/// Ticks per quarter note
+pub const PPQN: u32 = 4 * TICKS_PER_STEP;
+/// Ticks per MIDI clock
+pub const TICKS_PER_CLOCK: u32 = PPQN / clock::PPQN;
+/// Ticks per bar of 4/4
+pub const TICKS_PER_BAR: u32 = 4 * PPQN;
+
+pub const MIN_SWING: u8 = 50;
+pub const MAX_SWING: u8 = 75;
+
+/// Tick periods are in microseconds with 8 fractional bits
+const PERIOD_SHIFT: u32 = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    NoteOn {
+        track: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        track: u8,
+        note: u8,
+    },
+    /// Value of a sound parameter for the next note
+    Lock {
+        track: u8,
+        param: u8,
+        value: i16,
+    },
+    /// End of a lock, back to the value of the track
+    Unlock {
+        track: u8,
+        param: u8,
+    },
+    /// Beat of the count-in, `accent` on the first beat of a bar
+    Click {
+        accent: bool,
+    },
+}
+
+/// Tick period of a tempo in tenth of BPM, in µs with `PERIOD_SHIFT`
+/// fractional bits
+fn tick_period(tempo: u16) -> u64 {
+    let tempo = tempo.clamp(MIN_BPM * 10, MAX_BPM * 10) as u64;
+    (600_000_000u64 << PERIOD_SHIFT) / (tempo * PPQN as u64)
+}
+
+/// Delay of a step by the swing, in ticks
+pub(crate) fn swing_delay(swing: u8, step: usize) -> u32 {
+    if step.is_multiple_of(2) {
+        return 0;
+    }
+    let swing = swing.clamp(MIN_SWING, MAX_SWING) as u32;
+    (2 * TICKS_PER_STEP * (swing - 50) + 50) / 100
+}
+
+/// Tick of a step from the start of the sequence, may be negative for the
+/// first step
+fn step_tick(swing: u8, step: usize, offset: i8) -> i32 {
+    let offset = offset.clamp(-MAX_OFFSET, MAX_OFFSET) as i32;
+    (step as u32 * TICKS_PER_STEP + swing_delay(swing, step)) as i32 + offset
+}
+
+/// Length of a note in ticks, for a gate in percent of the step
+fn gate_ticks(gate: u8) -> u32 {
+    (TICKS_PER_STEP * gate as u32 / 100).max(1)
+}
+
+/// Bars of a pass of a pattern in a song
+fn pattern_bars(pattern: &Pattern) -> u32 {
+    let steps = pattern.tracks.iter().map(|s| s.length as usize).max();
+    (steps.unwrap_or(PAGE_STEPS).div_ceil(PAGE_STEPS) as u32).max(1)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct SongPosition {
+    row: usize,
+    /// Passes of the pattern played in the row
+    repeat: u8,
+}
+
+/// Notes of a step still to play
+#[derive(Copy, Clone, Debug)]
+struct Retrig {
+    note: u8,
+    velocity: u8,
+    decay: u8,
+    left: u8,
+    /// Tick of the next note
+    next: u32,
+    interval: u32,
+    gate: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Note {
+    note: u8,
+    /// Tick of the note off
+    off: u32,
+}
+
+pub struct Sequencer {
+    playing: bool,
+    pattern: usize,
+    /// Pattern played from the next bar
+    queued: Option<usize>,
+    song: Option<SongPosition>,
+    /// Next tick to play, since the start
+    next: u32,
+    /// Tick the pattern started at
+    origin: u32,
+    /// Ticks of count-in left before the start
+    count_in: u32,
+    /// The next tick is due right away
+    starting: bool,
+    /// Time since the last tick, in µs with `PERIOD_SHIFT` fractional bits
+    time: u64,
+    notes: [Option<Note>; TRACKS],
+    retrigs: [Option<Retrig>; TRACKS],
+    /// Locked parameters of each track, one bit per parameter
+    locked: [u32; TRACKS],
+    fill: bool,
+    seed: u32,
+    rng: Rng,
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sequencer {
+    pub const fn new() -> Self {
+        Sequencer {
+            playing: false,
+            pattern: 0,
+            queued: None,
+            song: None,
+            next: 0,
+            origin: 0,
+            count_in: 0,
+            starting: false,
+            time: 0,
+            notes: [None; TRACKS],
+            retrigs: [None; TRACKS],
+            locked: [0; TRACKS],
+            fill: false,
+            seed: DEFAULT_SEED,
+            rng: Rng::new(DEFAULT_SEED),
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Play from the first step, the first tick is played by the next
+    /// `advance`
+    pub fn start(&mut self) {
+        self.playing = true;
+        self.next = 0;
+        self.origin = 0;
+        self.count_in = 0;
+        self.starting = true;
+        self.time = 0;
+        self.rng = Rng::new(self.seed);
+    }
+
+    /// Seed of the probabilities, used from the next start
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    /// Steps with the `Fill` condition play while the fill is on, the
+    /// ones with `NotFill` while it is off
+    pub fn set_fill(&mut self, fill: bool) {
+        self.fill = fill;
+    }
+
+    pub fn is_fill(&self) -> bool {
+        self.fill
+    }
+
+    /// Like `start`, after `bars` bars of `Event::Click` on each beat
+    pub fn count_in(&mut self, bars: u8) {
+        self.start();
+        self.count_in = bars as u32 * TICKS_PER_BAR;
+    }
+
+    pub fn is_counting_in(&self) -> bool {
+        self.playing && self.count_in > 0
+    }
+
+    /// Play the song from `row`, or the first row after the count-in
+    /// when `bars` is not 0
+    pub fn start_song(&mut self, project: &Project, row: usize, bars: u8) {
+        let Some(song_row) = project.song.get(row) else {
+            return;
+        };
+        self.count_in(bars);
+        self.set_pattern(song_row.pattern as usize);
+        self.queued = None;
+        self.song = Some(SongPosition { row, repeat: 0 });
+    }
+
+    /// Row of the song being played, `None` out of the song mode
+    pub fn song_row(&self) -> Option<usize> {
+        self.song.map(|song| song.row)
+    }
+
+    /// Stop, release the notes being played and end the locks. Leaves the
+    /// song mode.
+    pub fn stop(&mut self, mut out: impl FnMut(Event)) {
+        self.playing = false;
+        self.count_in = 0;
+        self.queued = None;
+        self.song = None;
+        self.retrigs = [None; TRACKS];
+        for (track, slot) in self.notes.iter_mut().enumerate() {
+            if let Some(note) = slot.take() {
+                out(Event::NoteOff {
+                    track: track as u8,
+                    note: note.note,
+                });
+            }
+        }
+        for track in 0..TRACKS {
+            self.unlock(track, 0, &mut out);
+        }
+    }
+
+    /// End the locks of a track that are not in `keep`
+    fn unlock(&mut self, track: usize, keep: u32, out: &mut impl FnMut(Event)) {
+        let mut ended = self.locked[track] & !keep;
+        while ended != 0 {
+            let param = ended.trailing_zeros();
+            ended &= ended - 1;
+            out(Event::Unlock {
+                track: track as u8,
+                param: param as u8,
+            });
+        }
+        self.locked[track] = keep;
+    }
+
+    pub fn pattern(&self) -> usize {
+        self.pattern
+    }
+
+    pub fn set_pattern(&mut self, pattern: usize) {
+        self.pattern = pattern.min(PATTERNS - 1);
+    }
+
+    /// Switch to `pattern` at the next bar, or right away when stopped.
+    /// Leaves the song mode.
+    pub fn queue_pattern(&mut self, pattern: usize) {
+        self.song = None;
+        if self.playing {
+            self.queued = Some(pattern.min(PATTERNS - 1));
+        } else {
+            self.set_pattern(pattern);
+        }
+    }
+
+    /// Pattern played from the next bar
+    pub fn queued(&self) -> Option<usize> {
+        self.queued
+    }
+
+    /// Tempo in tenth of BPM: the one of the last song row that sets it,
+    /// or of the project
+    pub fn tempo(&self, project: &Project) -> u16 {
+        let rows = match self.song {
+            Some(song) => project.song.get(..=song.row).unwrap_or(&[]),
+            None => &[],
+        };
+        rows.iter()
+            .rev()
+            .map(|row| row.tempo)
+            .find(|&tempo| tempo != 0)
+            .unwrap_or(project.tempo)
+    }
+
+    fn is_muted(&self, project: &Project, track: usize) -> bool {
+        let row = self.song.and_then(|song| project.song.get(song.row));
+        project.tracks[track].mute || row.is_some_and(|row| row.mutes & (1 << track) != 0)
+    }
+
+    /// Number of ticks played since the start
+    pub fn ticks(&self) -> u32 {
+        self.next
+    }
+
+    /// Last tick played since the start of the pattern, negative before
+    /// the start and during the count-in, `None` when stopped
+    pub fn position(&self) -> Option<i32> {
+        self.playing
+            .then(|| (self.next - self.origin) as i32 - self.count_in as i32 - 1)
+    }
+
+    /// Step of `track` being played, or next to play when stopped
+    pub fn step(&self, project: &Project, track: usize) -> usize {
+        let length = project.patterns[self.pattern].tracks[track].length;
+        let tick = self.next.saturating_sub(self.origin + 1);
+        (tick / TICKS_PER_STEP) as usize % (length as usize).clamp(1, MAX_STEPS)
+    }
+
+    /// Play the ticks due after `elapsed_us` microseconds
+    pub fn advance(&mut self, project: &Project, elapsed_us: u32, mut out: impl FnMut(Event)) {
+        if !self.playing {
+            return;
+        }
+        let period = tick_period(self.tempo(project));
+        self.time += (elapsed_us as u64) << PERIOD_SHIFT;
+        // The first tick is right at the start
+        if self.starting {
+            self.starting = false;
+            self.time += period;
+        }
+        while self.time >= period {
+            self.time -= period;
+            self.tick(project, &mut out);
+        }
+    }
+
+    /// Play one tick
+    pub fn tick(&mut self, project: &Project, mut out: impl FnMut(Event)) {
+        if !self.playing {
+            return;
+        }
+        if self.count_in > 0 {
+            if self.count_in.is_multiple_of(PPQN) {
+                out(Event::Click {
+                    accent: self.count_in.is_multiple_of(TICKS_PER_BAR),
+                });
+            }
+            self.count_in -= 1;
+            return;
+        }
+        let tick = self.next;
+        self.next += 1;
+
+        // Note offs first, so that the next step can play the same note
+        for (track, slot) in self.notes.iter_mut().enumerate() {
+            if let Some(note) = slot.filter(|note| note.off <= tick) {
+                out(Event::NoteOff {
+                    track: track as u8,
+                    note: note.note,
+                });
+                *slot = None;
+            }
+        }
+
+        let bar = tick > self.origin && (tick - self.origin).is_multiple_of(TICKS_PER_BAR);
+        if bar && !self.next_bar(project, &mut out) {
+            return;
+        }
+        for track in 0..TRACKS {
+            self.retrig(project, track, tick, &mut out);
+        }
+
+        let local = tick - self.origin;
+        for (track, sequence) in project.patterns[self.pattern].tracks.iter().enumerate() {
+            let length = (sequence.length as usize).clamp(1, MAX_STEPS);
+            let loop_ticks = (length as u32 * TICKS_PER_STEP) as i32;
+            let position = (local % loop_ticks as u32) as i32;
+            // Micro-timing and swing move steps at most to the neighbour
+            // steps
+            let index = position as usize / TICKS_PER_STEP as usize;
+            let neighbours = [(index + length - 1) % length, index, (index + 1) % length];
+            // Distinct unless the sequence is shorter
+            for step in neighbours.into_iter().take(length.min(3)) {
+                let at = step_tick(project.swing, step, sequence.steps[step].offset);
+                // Steps before the start of the sequence are played at the
+                // start the first time, steps after its end are not played
+                // before their time
+                let elapsed = local as i32 - at;
+                if elapsed >= 0 && (at.rem_euclid(loop_ticks) == position || (local == 0 && at < 0))
+                {
+                    let cycle = elapsed.div_euclid(loop_ticks) as u32;
+                    self.play_step(project, track, step, tick, cycle, &mut out);
+                }
+            }
+        }
+    }
+
+    /// Switch pattern or song row at the start of a bar, returns false at
+    /// the end of the song
+    fn next_bar(&mut self, project: &Project, out: &mut impl FnMut(Event)) -> bool {
+        let tick = self.next - 1;
+        let Some(mut song) = self.song else {
+            if let Some(pattern) = self.queued.take() {
+                self.pattern = pattern;
+                self.origin = tick;
+            }
+            return true;
+        };
+
+        let bars = (tick - self.origin) / TICKS_PER_BAR;
+        if !bars.is_multiple_of(pattern_bars(&project.patterns[self.pattern])) {
+            return true;
+        }
+        song.repeat = song.repeat.saturating_add(1);
+        let repeats = project.song.get(song.row).map_or(0, |row| row.repeats);
+        if song.repeat >= repeats {
+            song.repeat = 0;
+            song.row = match project.song_loop {
+                Some(song_loop) if song.row == song_loop.end as usize => song_loop.start as usize,
+                _ => song.row + 1,
+            };
+            let Some(row) = project.song.get(song.row) else {
+                self.stop(out);
+                return false;
+            };
+            self.pattern = (row.pattern as usize).min(PATTERNS - 1);
+            self.origin = tick;
+        }
+        self.song = Some(song);
+        true
+    }
+
+    /// Whether a step plays on loop `cycle` of its track
+    fn condition_met(&mut self, condition: Condition, cycle: u32) -> bool {
+        match condition {
+            Condition::Always => true,
+            Condition::Probability(percent) => self.rng.chance(percent),
+            Condition::Fill => self.fill,
+            Condition::NotFill => !self.fill,
+            Condition::Cycle { n, m } => cycle % m.max(1) as u32 + 1 == n as u32,
+            Condition::First => cycle == 0,
+            Condition::NotFirst => cycle != 0,
+        }
+    }
+
+    /// Play the next note of a retrig if it is due
+    fn retrig(&mut self, project: &Project, track: usize, tick: u32, out: &mut impl FnMut(Event)) {
+        let Some(mut retrig) = self.retrigs[track].filter(|r| r.next == tick) else {
+            return;
+        };
+        if self.is_muted(project, track) {
+            self.retrigs[track] = None;
+            return;
+        }
+        let velocity = retrig.velocity as u32 * (100 - retrig.decay.min(100) as u32) / 100;
+        retrig.velocity = velocity.max(1) as u8;
+        self.note_on(track, retrig.note, retrig.velocity, tick + retrig.gate, out);
+        retrig.left -= 1;
+        retrig.next += retrig.interval;
+        self.retrigs[track] = Some(retrig).filter(|r| r.left > 0);
+    }
+
+    fn note_on(
+        &mut self,
+        track: usize,
+        note: u8,
+        velocity: u8,
+        off: u32,
+        out: &mut impl FnMut(Event),
+    ) {
+        if let Some(note) = self.notes[track].take() {
+            out(Event::NoteOff {
+                track: track as u8,
+                note: note.note,
+            });
+        }
+        out(Event::NoteOn {
+            track: track as u8,
+            note,
+            velocity,
+        });
+        self.notes[track] = Some(Note { note, off });
+    }
+
+    fn play_step(
+        &mut self,
+        project: &Project,
+        track: usize,
+        index: usize,
+        tick: u32,
+        cycle: u32,
+        out: &mut impl FnMut(Event),
+    ) {
+        let pattern = &project.patterns[self.pattern];
+        let step = pattern.tracks[track].steps[index];
+        // Conditions are checked before the mute, so that muting a track
+        // does not change the random steps of the others
+        if !step.active
+            || !self.condition_met(step.condition, cycle)
+            || self.is_muted(project, track)
+        {
+            return;
+        }
+
+        let mut locked = 0;
+        for (param, value) in pattern.step_locks(track, index) {
+            locked |= 1 << param;
+            out(Event::Lock {
+                track: track as u8,
+                param,
+                value,
+            });
+        }
+        self.unlock(track, locked, out);
+
+        let retrigs = step.retrigs.min(MAX_RETRIGS);
+        let interval = TICKS_PER_STEP / (retrigs as u32 + 1);
+        let gate = (gate_ticks(step.gate) / (retrigs as u32 + 1)).max(1);
+        self.note_on(track, step.note, step.velocity, tick + gate, out);
+        self.retrigs[track] = (retrigs > 0).then_some(Retrig {
+            note: step.note,
+            velocity: step.velocity,
+            decay: step.decay,
+            left: retrigs,
+            next: tick + interval,
+            interval,
+            gate,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::{SongLoop, SongRow, Step};
+    use std::vec::Vec;
+
+    fn step(note: u8, gate: u8) -> Step {
+        Step {
+            active: true,
+            note,
+            velocity: 100,
+            gate,
+            ..Step::new()
+        }
+    }
+
+    /// Events of `count` ticks, with their tick
+    fn run(seq: &mut Sequencer, project: &Project, count: u32) -> Vec<(u32, Event)> {
+        let mut events = Vec::new();
+        for _ in 0..count {
+            let tick = seq.ticks();
+            seq.tick(project, |event| events.push((tick, event)));
+        }
+        events
+    }
+
+    fn note_ons(events: &[(u32, Event)], track: u8) -> Vec<u32> {
+        events
+            .iter()
+            .filter(|(_, e)| matches!(*e, Event::NoteOn { track: t, .. } if t == track))
+            .map(|&(tick, _)| tick)
+            .collect()
+    }
+
+    #[test]
+    fn steps_and_gates() {
+        let mut project = Box::new(Project::new());
+        let sequence = &mut project.patterns[0].tracks[0];
+        sequence.steps[0] = step(60, 50);
+        sequence.steps[4] = step(62, 100);
+        sequence.steps[5] = step(62, 1);
+
+        let mut seq = Sequencer::new();
+        assert!(run(&mut seq, &project, 10).is_empty());
+        seq.start();
+        let events = run(&mut seq, &project, 16 * TICKS_PER_STEP);
+        let note_on = |note| Event::NoteOn {
+            track: 0,
+            note,
+            velocity: 100,
+        };
+        let note_off = |note| Event::NoteOff { track: 0, note };
+        assert_eq!(
+            events,
+            [
+                (0, note_on(60)),
+                (12, note_off(60)),
+                (96, note_on(62)),
+                // Full gate, released right before the next step
+                (120, note_off(62)),
+                (120, note_on(62)),
+                (121, note_off(62)),
+            ]
+        );
+        assert_eq!(seq.step(&project, 0), 15);
+
+        // Loop
+        let events = run(&mut seq, &project, 1);
+        assert_eq!(events, [(384, note_on(60))]);
+        assert_eq!(seq.step(&project, 0), 0);
+
+        let mut events = Vec::new();
+        seq.stop(|e| events.push(e));
+        assert_eq!(events, [note_off(60)]);
+        assert!(run(&mut seq, &project, 100).is_empty());
+    }
+
+    #[test]
+    fn parameter_locks() {
+        let mut project = Box::new(Project::new());
+        let pattern = &mut project.patterns[0];
+        for i in 0..3 {
+            pattern.tracks[2].steps[i] = step(60, 50);
+        }
+        pattern.set_lock(2, 0, 5, -300).unwrap();
+        pattern.set_lock(2, 0, 31, 7).unwrap();
+        pattern.set_lock(2, 1, 5, 200).unwrap();
+
+        let mut seq = Sequencer::new();
+        seq.start();
+        let events: Vec<Event> = run(&mut seq, &project, 3 * TICKS_PER_STEP)
+            .into_iter()
+            .map(|(_, event)| event)
+            .filter(|event| !matches!(event, Event::NoteOff { .. }))
+            .collect();
+        let note_on = Event::NoteOn {
+            track: 2,
+            note: 60,
+            velocity: 100,
+        };
+        let lock = |param, value| Event::Lock {
+            track: 2,
+            param,
+            value,
+        };
+        let unlock = |param| Event::Unlock { track: 2, param };
+        assert_eq!(
+            events,
+            [
+                lock(5, -300),
+                lock(31, 7),
+                note_on,
+                lock(5, 200),
+                unlock(31),
+                note_on,
+                unlock(5),
+                note_on,
+            ]
+        );
+
+        project.patterns[0].set_lock(2, 3, 1, 1).unwrap();
+        project.patterns[0].tracks[2].steps[3] = step(60, 50);
+        let _ = run(&mut seq, &project, TICKS_PER_STEP);
+        let mut events = Vec::new();
+        seq.stop(|e| events.push(e));
+        assert_eq!(events, [unlock(1)]);
+    }
+
+    #[test]
+    fn track_lengths_and_mute() {
+        let mut project = Box::new(Project::new());
+        project.patterns[1].tracks[0].steps[0] = step(36, 50);
+        project.patterns[1].tracks[2].steps[0] = step(38, 50);
+        project.patterns[1].tracks[2].length = 3;
+        project.patterns[1].tracks[3].steps[0] = step(40, 50);
+        project.patterns[1].tracks[3].length = 64;
+        project.patterns[1].tracks[5].steps[0] = step(42, 50);
+        project.tracks[5].mute = true;
+
+        let mut seq = Sequencer::new();
+        seq.set_pattern(1);
+        seq.start();
+        let events = run(&mut seq, &project, 64 * TICKS_PER_STEP);
+        let steps = |track| -> Vec<u32> {
+            note_ons(&events, track)
+                .iter()
+                .map(|tick| tick / TICKS_PER_STEP)
+                .collect()
+        };
+        assert_eq!(steps(0), [0, 16, 32, 48]);
+        assert_eq!(steps(2), (0..64).step_by(3).collect::<Vec<_>>());
+        assert_eq!(steps(3), [0]);
+        assert!(steps(5).is_empty());
+    }
+
+    #[test]
+    fn swing() {
+        let mut project = Box::new(Project::new());
+        for i in 0..4 {
+            project.patterns[0].tracks[0].steps[i] = step(60, 10);
+        }
+        let mut seq = Sequencer::new();
+
+        for (swing, delay) in [(50, 0), (58, 4), (66, 8), (75, 12), (90, 12)] {
+            project.swing = swing;
+            seq.start();
+            let events = run(&mut seq, &project, 4 * TICKS_PER_STEP);
+            assert_eq!(note_ons(&events, 0), [0, 24 + delay, 48, 72 + delay]);
+        }
+    }
+
+    #[test]
+    fn micro_timing() {
+        let mut project = Box::new(Project::new());
+        let sequence = &mut project.patterns[0].tracks[0];
+        sequence.length = 4;
+        for (i, offset) in [(0, -3), (1, 12), (2, -12), (3, 5)] {
+            sequence.steps[i] = Step {
+                offset,
+                ..step(60 + i as u8, 10)
+            };
+        }
+        project.swing = 75;
+
+        let mut seq = Sequencer::new();
+        seq.start();
+        let events = run(&mut seq, &project, 8 * TICKS_PER_STEP);
+        // The first step is early, at the start the first time only
+        assert_eq!(
+            note_ons(&events, 0),
+            [0, 36, 48, 89, 93, 132, 144, 185, 189]
+        );
+
+        let mut project = Box::new(Project::new());
+        project.patterns[0].tracks[0].length = 1;
+        project.patterns[0].tracks[0].steps[0] = Step {
+            offset: 7,
+            ..step(60, 10)
+        };
+        seq.start();
+        let events = run(&mut seq, &project, 3 * TICKS_PER_STEP);
+        assert_eq!(note_ons(&events, 0), [7, 31, 55]);
+
+        // The last step is late, after the end of the sequence
+        let mut project = Box::new(Project::new());
+        let sequence = &mut project.patterns[0].tracks[0];
+        sequence.steps[15] = Step {
+            offset: MAX_OFFSET,
+            ..step(60, 10)
+        };
+        project.swing = 75;
+        seq.start();
+        let events = run(&mut seq, &project, 2 * 16 * TICKS_PER_STEP + 1);
+        assert_eq!(note_ons(&events, 0), [384, 768]);
+        project.patterns[0].tracks[0].steps[15].condition = Condition::First;
+        seq.start();
+        let events = run(&mut seq, &project, 2 * 16 * TICKS_PER_STEP + 1);
+        assert_eq!(note_ons(&events, 0), [384]);
+    }
+
+    #[test]
+    fn count_in() {
+        let mut project = Box::new(Project::new());
+        project.patterns[0].tracks[0].steps[0] = step(60, 50);
+
+        let mut seq = Sequencer::new();
+        seq.count_in(1);
+        assert!(seq.is_counting_in());
+        assert_eq!(seq.position(), Some(-(TICKS_PER_BAR as i32) - 1));
+        let events = run(&mut seq, &project, TICKS_PER_BAR);
+        let click = |accent| Event::Click { accent };
+        assert_eq!(
+            events,
+            [
+                (0, click(true)),
+                (0, click(false)),
+                (0, click(false)),
+                (0, click(false)),
+            ]
+        );
+        assert!(!seq.is_counting_in());
+        assert_eq!(seq.position(), Some(-1));
+
+        let events = run(&mut seq, &project, 1);
+        assert_eq!(note_ons(&events, 0), [0]);
+        assert_eq!(seq.position(), Some(0));
+        seq.stop(|_| {});
+        assert_eq!(seq.position(), None);
+    }
+
+    /// Note ons as (tick, note)
+    fn notes(events: &[(u32, Event)]) -> Vec<(u32, u8)> {
+        events
+            .iter()
+            .filter_map(|&(tick, event)| match event {
+                Event::NoteOn { note, .. } => Some((tick, note)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn queued_patterns() {
+        let mut project = Box::new(Project::new());
+        project.patterns[0].tracks[0].steps[0] = step(36, 50);
+        project.patterns[1].tracks[0].steps[0] = step(38, 50);
+        project.patterns[1].tracks[0].length = 3;
+
+        let mut seq = Sequencer::new();
+        seq.queue_pattern(1);
+        assert_eq!((seq.pattern(), seq.queued()), (1, None));
+        seq.queue_pattern(0);
+        seq.start();
+        let mut events = run(&mut seq, &project, 100);
+        seq.queue_pattern(1);
+        assert_eq!((seq.pattern(), seq.queued()), (0, Some(1)));
+
+        // On the next bar, from the first step
+        events.extend(run(&mut seq, &project, TICKS_PER_BAR + 100));
+        assert_eq!(notes(&events), [(0, 36), (384, 38), (456, 38), (528, 38)]);
+        assert_eq!((seq.pattern(), seq.queued()), (1, None));
+        assert_eq!(seq.position(), Some(100 + 100 - 1));
+        assert_eq!(seq.step(&project, 0), 2);
+    }
+
+    #[test]
+    fn song() {
+        let mut project = Box::new(Project::new());
+        project.patterns[0].tracks[0].steps[0] = step(36, 50);
+        project.patterns[1].tracks[0].steps[0] = step(38, 50);
+        project.patterns[1].tracks[0].length = 20;
+        project.patterns[1].tracks[1].steps[0] = step(40, 50);
+        let rows = [
+            SongRow {
+                repeats: 2,
+                ..SongRow::new(0)
+            },
+            SongRow {
+                tempo: 1500,
+                mutes: 0b10,
+                ..SongRow::new(1)
+            },
+            SongRow::new(0),
+        ];
+        project.song.extend_from_slice(&rows).unwrap();
+
+        let mut seq = Sequencer::new();
+        seq.start_song(&project, 0, 0);
+        let mut events = Vec::new();
+        let mut rows = Vec::new();
+        let mut tempos = Vec::new();
+        while seq.is_playing() {
+            let tick = seq.ticks();
+            seq.tick(&project, |event| events.push((tick, event)));
+            if tick.is_multiple_of(TICKS_PER_BAR) {
+                rows.push(seq.song_row());
+                tempos.push(seq.tempo(&project));
+            }
+        }
+        // The second row lasts 2 bars for the track of 20 steps, and its
+        // second track is muted
+        let bar = TICKS_PER_BAR;
+        assert_eq!(
+            notes(&events),
+            [
+                (0, 36),
+                (bar, 36),
+                (2 * bar, 38),
+                (3 * bar + 96, 38),
+                (4 * bar, 36)
+            ]
+        );
+        assert_eq!(seq.ticks(), 5 * bar + 1);
+        assert_eq!(rows, [Some(0), Some(0), Some(1), Some(1), Some(2), None]);
+        assert_eq!(tempos, [1200, 1200, 1500, 1500, 1500, 1200]);
+        assert_eq!(seq.song_row(), None);
+
+        // Loop over the last rows, after a count-in
+        project.song_loop = Some(SongLoop { start: 1, end: 2 });
+        seq.start_song(&project, 1, 1);
+        let events = run(&mut seq, &project, 7 * bar);
+        assert_eq!(
+            notes(&events),
+            [
+                (0, 38),
+                (bar + 96, 38),
+                (2 * bar, 36),
+                (3 * bar, 38),
+                (4 * bar + 96, 38),
+                (5 * bar, 36)
+            ]
+        );
+        assert_eq!(seq.song_row(), Some(2));
+    }
+
+    #[test]
+    fn conditions() {
+        let mut project = Box::new(Project::new());
+        let conditions = [
+            Condition::Probability(30),
+            Condition::Cycle { n: 2, m: 3 },
+            Condition::First,
+            Condition::NotFirst,
+            Condition::Fill,
+            Condition::NotFill,
+            Condition::Probability(100),
+            Condition::Probability(0),
+        ];
+        for (track, condition) in conditions.into_iter().enumerate() {
+            let sequence = &mut project.patterns[0].tracks[track];
+            sequence.length = 1;
+            sequence.steps[0] = Step {
+                condition,
+                ..step(60, 50)
+            };
+        }
+        let loops = 1000;
+        let play = |seq: &mut Sequencer, project: &Project| {
+            seq.start();
+            let half = run(seq, project, loops / 2 * TICKS_PER_STEP);
+            seq.set_fill(true);
+            let mut events = run(seq, project, loops / 2 * TICKS_PER_STEP);
+            seq.set_fill(false);
+            events.splice(0..0, half);
+            // Loop of each note
+            (0..TRACKS as u8)
+                .map(|track| {
+                    note_ons(&events, track)
+                        .iter()
+                        .map(|tick| tick / TICKS_PER_STEP)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut seq = Sequencer::new();
+        seq.set_seed(1234);
+        let loops_played = play(&mut seq, &project);
+        let probable = loops_played[0].len() as u32;
+        assert!((250..350).contains(&probable), "{}", probable);
+        let cycles: Vec<u32> = (0..loops).filter(|l| l % 3 == 1).collect();
+        assert_eq!(loops_played[1], cycles);
+        assert_eq!(loops_played[2], [0]);
+        assert_eq!(loops_played[3], (1..loops).collect::<Vec<_>>());
+        assert_eq!(loops_played[4], (loops / 2..loops).collect::<Vec<_>>());
+        assert_eq!(loops_played[5], (0..loops / 2).collect::<Vec<_>>());
+        assert_eq!(loops_played[6].len() as u32, loops);
+        assert!(loops_played[7].is_empty());
+
+        // Same seed, same steps, muting a track does not change the others
+        project.tracks[1].mute = true;
+        assert_eq!(play(&mut seq, &project)[0], loops_played[0]);
+        seq.set_seed(99);
+        assert_ne!(play(&mut seq, &project)[0], loops_played[0]);
+    }
+
+    #[test]
+    fn cycles_of_longer_tracks() {
+        let mut project = Box::new(Project::new());
+        let sequence = &mut project.patterns[0].tracks[0];
+        sequence.length = 4;
+        sequence.steps[0] = Step {
+            condition: Condition::NotFirst,
+            offset: -MAX_OFFSET,
+            ..step(60, 10)
+        };
+        sequence.steps[2] = Step {
+            condition: Condition::Cycle { n: 1, m: 2 },
+            ..step(62, 10)
+        };
+
+        let mut seq = Sequencer::new();
+        seq.start();
+        let events = run(&mut seq, &project, 4 * 4 * TICKS_PER_STEP);
+        // The early first step belongs to the next loop
+        assert_eq!(
+            notes(&events),
+            [
+                (48, 62),
+                (84, 60),
+                (180, 60),
+                (240, 62),
+                (276, 60),
+                (372, 60)
+            ]
+        );
+    }
+
+    #[test]
+    fn retrigs() {
+        let mut project = Box::new(Project::new());
+        project.patterns[0].tracks[0].steps[0] = Step {
+            retrigs: 3,
+            decay: 50,
+            ..step(60, 100)
+        };
+        project.patterns[0].tracks[0].steps[1] = Step {
+            retrigs: 3,
+            ..step(62, 50)
+        };
+        // Cut by the next step
+        project.patterns[0].tracks[0].steps[3] = Step {
+            retrigs: 1,
+            ..step(64, 50)
+        };
+        project.patterns[0].tracks[0].steps[4] = step(65, 50);
+        project.patterns[0].tracks[0].length = 5;
+
+        let mut seq = Sequencer::new();
+        seq.start();
+        let events = run(&mut seq, &project, 5 * TICKS_PER_STEP);
+        let note_on = |note, velocity| Event::NoteOn {
+            track: 0,
+            note,
+            velocity,
+        };
+        let note_off = |note| Event::NoteOff { track: 0, note };
+        let ons: Vec<(u32, Event)> = events
+            .iter()
+            .copied()
+            .filter(|(_, e)| matches!(e, Event::NoteOn { .. }))
+            .collect();
+        assert_eq!(
+            ons,
+            [
+                (0, note_on(60, 100)),
+                (6, note_on(60, 50)),
+                (12, note_on(60, 25)),
+                (18, note_on(60, 12)),
+                (24, note_on(62, 100)),
+                (30, note_on(62, 100)),
+                (36, note_on(62, 100)),
+                (42, note_on(62, 100)),
+                (72, note_on(64, 100)),
+                (84, note_on(64, 100)),
+                (96, note_on(65, 100)),
+            ]
+        );
+        // Gates are divided too
+        assert!(events.contains(&(6, note_off(60))));
+        assert!(events.contains(&(27, note_off(62))));
+        assert!(events.contains(&(78, note_off(64))));
+    }
+
+    #[test]
+    fn tempo() {
+        let mut project = Box::new(Project::new());
+        project.patterns[0].tracks[0].steps[0] = step(60, 50);
+        project.patterns[0].tracks[0].length = 1;
+        project.tempo = 1200;
+
+        // 125 ms per step at 120 BPM, in 1 ms updates
+        let mut seq = Sequencer::new();
+        seq.start();
+        let mut times = Vec::new();
+        for ms in 0..1000 {
+            let elapsed = if ms == 0 { 0 } else { 1000 };
+            seq.advance(&project, elapsed, |event| {
+                if let Event::NoteOn { .. } = event {
+                    times.push(ms)
+                }
+            });
+        }
+        assert_eq!(times, [0, 125, 250, 375, 500, 625, 750, 875]);
+        assert_eq!(seq.ticks(), 8 * TICKS_PER_STEP);
+
+        // Same ticks with irregular updates
+        let mut other = Sequencer::new();
+        other.start();
+        for elapsed in [0, 333_333, 1, 665_666] {
+            other.advance(&project, elapsed, |_| {});
+        }
+        assert_eq!(other.ticks(), seq.ticks());
+    }
+}
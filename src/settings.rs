@@ -0,0 +1,652 @@
+//! Key/value settings store in flash
+//!
+//! Settings are small named values (volume, brightness, MIDI channel, ...)
+//! kept in a reserved region of the flash (`flash::SETTINGS_OFFSET`) and
+//! read back at power-on:
+//!
+//! ```ignore
+//! let volume: u8 = periph.settings.get("volume")?.unwrap_or(100);
+//! periph.settings.set("volume", &volume)?;
+//! ```
+//!
+//! The store is a log: each change appends a record to the active erase
+//! sector, the last record of a key wins. When the sector is full, the
+//! live records are copied to the next sector, which becomes the active
+//! one. Sectors are used in turn, spreading the erases over the whole
+//! region.
+//!
+//! Records are checked with a CRC-32 and a sector only becomes active once
+//! its header is written, after the copy. A power loss in the middle of a
+//! write leaves an invalid record at the end of the log, which is ignored,
+//! or an incomplete sector, which is not used: the previous value of the
+//! setting is kept.
+//!
+//! Sector layout, little endian:
+//!
+//! | Offset | Size | Field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | `MAGIC`, written last                         |
+//! | 4      | 4    | sequence number, incremented on each copy     |
+//! | 8      |      | records                                       |
+//!
+//! Record layout, padded with 0xFF to a multiple of 4 bytes:
+//!
+//! | Offset | Size | Field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | CRC-32 of the rest of the record              |
+//! | 4      | 1    | key length                                    |
+//! | 5      | 1    | value length                                  |
+//! | 6      | 1    | 1: value, 0: removed                          |
+//! | 7      | 1    | 0xFF                                          |
+//! | 8      |      | key (UTF-8), then value                       |
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+use heapless::String;
+
+use crate::crc::crc32;
+use crate::flash::FlashRegion;
+
+pub const MAGIC: [u8; 4] = *b"SET1";
+
+pub const MAX_KEY_SIZE: usize = 32;
+pub const MAX_VALUE_SIZE: usize = 96;
+
+const SECTOR_HEADER_SIZE: u32 = 8;
+const RECORD_HEADER_SIZE: usize = 8;
+const MAX_RECORD_SIZE: usize = RECORD_HEADER_SIZE + MAX_KEY_SIZE + MAX_VALUE_SIZE;
+
+const KIND_REMOVED: u8 = 0;
+const KIND_VALUE: u8 = 1;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SettingsError {
+    Flash(NorFlashErrorKind),
+    /// Empty key, or longer than `MAX_KEY_SIZE`
+    InvalidKey,
+    /// Value longer than `MAX_VALUE_SIZE`
+    ValueTooLong,
+    /// The live settings do not fit in one sector
+    Full,
+}
+
+impl<E: NorFlashError> From<E> for SettingsError {
+    fn from(err: E) -> Self {
+        SettingsError::Flash(err.kind())
+    }
+}
+
+/// A type that can be stored as a setting
+pub trait Value: Sized {
+    /// Write the value in `buf`, at least `MAX_VALUE_SIZE` bytes long,
+    /// return the encoded length
+    fn encode(&self, buf: &mut [u8]) -> usize;
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_value {
+    ($($t:ty),*) => {
+        $(
+            impl Value for $t {
+                fn encode(&self, buf: &mut [u8]) -> usize {
+                    let bytes = self.to_le_bytes();
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    bytes.len()
+                }
+
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    Some(Self::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_value!(u8, i8, u16, i16, u32, i32, f32);
+
+impl Value for bool {
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        buf[0] = *self as u8;
+        1
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl<const N: usize> Value for String<N> {
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        let len = self.len().min(MAX_VALUE_SIZE);
+        buf[..len].copy_from_slice(&self.as_bytes()[..len]);
+        len
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut s = String::new();
+        s.push_str(core::str::from_utf8(bytes).ok()?).ok()?;
+        Some(s)
+    }
+}
+
+struct Record {
+    key_len: usize,
+    value_len: usize,
+    removed: bool,
+}
+
+impl Record {
+    /// Size in flash, with the padding
+    fn size(&self) -> u32 {
+        (RECORD_HEADER_SIZE + self.key_len + self.value_len).next_multiple_of(4) as u32
+    }
+}
+
+enum Scan {
+    Record(Record),
+    /// Erased flash, the end of the log
+    End,
+    /// Invalid record, e.g. interrupted by a power loss
+    Corrupt,
+}
+
+type RecordBuffer = [u8; MAX_RECORD_SIZE];
+
+/// Settings store on a `NorFlash` of at least two erase sectors
+pub struct Settings<F> {
+    flash: F,
+    sectors: u32,
+    active: u32,
+    sequence: u32,
+    /// Offset of the free space in the active sector
+    end: u32,
+}
+
+/// The settings in the on-board flash
+pub type FlashSettings = Settings<FlashRegion>;
+
+impl<F: NorFlash> Settings<F> {
+    /// Open the store, formatting the flash if it does not hold one
+    pub fn new(flash: F) -> Result<Self, SettingsError> {
+        let mut settings = Self::closed(flash);
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..settings.sectors {
+            if let Some(sequence) = settings.sector_sequence(sector)? {
+                if newest.is_none_or(|(_, n)| sequence.wrapping_sub(n) as i32 > 0) {
+                    newest = Some((sector, sequence));
+                }
+            }
+        }
+
+        match newest {
+            Some((sector, sequence)) => {
+                settings.active = sector;
+                settings.sequence = sequence;
+                settings.end = settings.find_end()?;
+            }
+            None => {
+                settings.flash.erase(0, F::ERASE_SIZE as u32)?;
+                settings.write_sector_header(0, 0)?;
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Erase the flash and open an empty store, to start again when `new`
+    /// fails
+    pub fn formatted(flash: F) -> Result<Self, SettingsError> {
+        let mut settings = Self::closed(flash);
+        for sector in 0..settings.sectors {
+            let start = settings.address(sector, 0);
+            settings
+                .flash
+                .erase(start, start + settings.sector_size())?;
+        }
+        settings.write_sector_header(0, 0)?;
+        Ok(settings)
+    }
+
+    fn closed(flash: F) -> Self {
+        assert!(4 % F::WRITE_SIZE == 0);
+        let sectors = (flash.capacity() / F::ERASE_SIZE) as u32;
+        assert!(sectors >= 2);
+
+        Settings {
+            flash,
+            sectors,
+            active: 0,
+            sequence: 0,
+            end: SECTOR_HEADER_SIZE,
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Read a setting, `None` if it is not set or not of type `T`
+    pub fn get<T: Value>(&mut self, key: &str) -> Result<Option<T>, SettingsError> {
+        let mut buf = [0; MAX_VALUE_SIZE];
+        Ok(self
+            .get_bytes(key, &mut buf)?
+            .and_then(|len| T::decode(&buf[..len])))
+    }
+
+    pub fn set<T: Value>(&mut self, key: &str, value: &T) -> Result<(), SettingsError> {
+        let mut buf = [0; MAX_VALUE_SIZE];
+        let len = value.encode(&mut buf);
+        self.set_bytes(key, &buf[..len])
+    }
+
+    /// Copy the value of a setting in `value`, truncated if `value` is too
+    /// short, and return its length
+    pub fn get_bytes(
+        &mut self,
+        key: &str,
+        value: &mut [u8],
+    ) -> Result<Option<usize>, SettingsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let Some(record) = self.find(key, &mut buf)? else {
+            return Ok(None);
+        };
+        let start = RECORD_HEADER_SIZE + record.key_len;
+        let len = record.value_len.min(value.len());
+        value[..len].copy_from_slice(&buf[start..start + len]);
+        Ok(Some(record.value_len))
+    }
+
+    /// Change a setting, nothing is written if the value is the same
+    pub fn set_bytes(&mut self, key: &str, value: &[u8]) -> Result<(), SettingsError> {
+        check_key(key)?;
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(SettingsError::ValueTooLong);
+        }
+        let mut current = [0; MAX_VALUE_SIZE];
+        if self.get_bytes(key, &mut current)? == Some(value.len())
+            && current[..value.len()] == *value
+        {
+            return Ok(());
+        }
+        self.append(key, value, KIND_VALUE)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), SettingsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        if self.find(key, &mut buf)?.is_none() {
+            return Ok(());
+        }
+        self.append(key, &[], KIND_REMOVED)
+    }
+
+    /// Call `f` with the key and value of each setting
+    pub fn for_each(&mut self, mut f: impl FnMut(&str, &[u8])) -> Result<(), SettingsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let mut offset = SECTOR_HEADER_SIZE;
+        while offset < self.end {
+            let Scan::Record(record) = self.read_record(self.active, offset, &mut buf)? else {
+                break;
+            };
+            offset += record.size();
+            if record.removed || !self.is_last(self.active, offset, &buf)? {
+                continue;
+            }
+            let key = &buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + record.key_len];
+            if let Ok(key) = core::str::from_utf8(key) {
+                let start = RECORD_HEADER_SIZE + record.key_len;
+                f(key, &buf[start..start + record.value_len]);
+            }
+        }
+        Ok(())
+    }
+
+    fn sector_size(&self) -> u32 {
+        F::ERASE_SIZE as u32
+    }
+
+    fn address(&self, sector: u32, offset: u32) -> u32 {
+        sector * self.sector_size() + offset
+    }
+
+    /// Sequence number of a sector with a valid header
+    fn sector_sequence(&mut self, sector: u32) -> Result<Option<u32>, SettingsError> {
+        let mut header = [0; SECTOR_HEADER_SIZE as usize];
+        self.flash.read(self.address(sector, 0), &mut header)?;
+        if header[..4] != MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ])))
+    }
+
+    /// The magic is written after the sequence number, so that a sector
+    /// is only valid once its header is complete
+    fn write_sector_header(&mut self, sector: u32, sequence: u32) -> Result<(), SettingsError> {
+        self.flash
+            .write(self.address(sector, 4), &sequence.to_le_bytes())?;
+        self.flash.write(self.address(sector, 0), &MAGIC)?;
+        self.active = sector;
+        self.sequence = sequence;
+        self.end = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    /// Read the record at `offset` of `sector` in `buf`
+    fn read_record(
+        &mut self,
+        sector: u32,
+        offset: u32,
+        buf: &mut RecordBuffer,
+    ) -> Result<Scan, SettingsError> {
+        if offset + RECORD_HEADER_SIZE as u32 > self.sector_size() {
+            return Ok(Scan::End);
+        }
+        let address = self.address(sector, offset);
+        self.flash.read(address, &mut buf[..RECORD_HEADER_SIZE])?;
+        if buf[..RECORD_HEADER_SIZE].iter().all(|&b| b == 0xFF) {
+            return Ok(Scan::End);
+        }
+
+        let record = Record {
+            key_len: buf[4] as usize,
+            value_len: buf[5] as usize,
+            removed: buf[6] == KIND_REMOVED,
+        };
+        if record.key_len == 0
+            || record.key_len > MAX_KEY_SIZE
+            || record.value_len > MAX_VALUE_SIZE
+            || buf[6] > KIND_VALUE
+            || offset + record.size() > self.sector_size()
+        {
+            return Ok(Scan::Corrupt);
+        }
+
+        let len = RECORD_HEADER_SIZE + record.key_len + record.value_len;
+        self.flash.read(
+            address + RECORD_HEADER_SIZE as u32,
+            &mut buf[RECORD_HEADER_SIZE..len],
+        )?;
+        if crc32(&buf[4..len]).to_le_bytes() != buf[..4] {
+            return Ok(Scan::Corrupt);
+        }
+        buf[len..record.size() as usize].fill(0xFF);
+        Ok(Scan::Record(record))
+    }
+
+    /// Offset of the free space in the active sector. After an invalid
+    /// record the sector is considered full, the next write moves the
+    /// valid records to a new sector.
+    fn find_end(&mut self) -> Result<u32, SettingsError> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let mut offset = SECTOR_HEADER_SIZE;
+        loop {
+            match self.read_record(self.active, offset, &mut buf)? {
+                Scan::Record(record) => offset += record.size(),
+                Scan::Corrupt => return Ok(self.sector_size()),
+                Scan::End => break,
+            }
+        }
+
+        // An interrupted write may have programmed bytes after the end
+        let mut rest = offset;
+        while rest < self.sector_size() {
+            let len = (self.sector_size() - rest).min(MAX_RECORD_SIZE as u32);
+            self.flash
+                .read(self.address(self.active, rest), &mut buf[..len as usize])?;
+            if buf[..len as usize].iter().any(|&b| b != 0xFF) {
+                return Ok(self.sector_size());
+            }
+            rest += len;
+        }
+        Ok(offset)
+    }
+
+    /// True if the key of the record in `buf` is not set again between
+    /// `offset` and the end of the log
+    fn is_last(
+        &mut self,
+        sector: u32,
+        mut offset: u32,
+        buf: &RecordBuffer,
+    ) -> Result<bool, SettingsError> {
+        let key = &buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + buf[4] as usize];
+        let mut next = [0; MAX_RECORD_SIZE];
+        while offset < self.end {
+            let Scan::Record(record) = self.read_record(sector, offset, &mut next)? else {
+                break;
+            };
+            if next[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + record.key_len] == *key {
+                return Ok(false);
+            }
+            offset += record.size();
+        }
+        Ok(true)
+    }
+
+    /// Last record of `key` in `buf`, `None` if not set
+    fn find(&mut self, key: &str, buf: &mut RecordBuffer) -> Result<Option<Record>, SettingsError> {
+        let mut found = None;
+        let mut offset = SECTOR_HEADER_SIZE;
+        while offset < self.end {
+            let Scan::Record(record) = self.read_record(self.active, offset, buf)? else {
+                break;
+            };
+            if buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + record.key_len] == *key.as_bytes() {
+                found = Some(offset);
+            }
+            offset += record.size();
+        }
+
+        let Some(offset) = found else {
+            return Ok(None);
+        };
+        match self.read_record(self.active, offset, buf)? {
+            Scan::Record(record) if !record.removed => Ok(Some(record)),
+            _ => Ok(None),
+        }
+    }
+
+    fn append(&mut self, key: &str, value: &[u8], kind: u8) -> Result<(), SettingsError> {
+        let record = Record {
+            key_len: key.len(),
+            value_len: value.len(),
+            removed: kind == KIND_REMOVED,
+        };
+        if self.end + record.size() > self.sector_size() {
+            self.compact()?;
+            if self.end + record.size() > self.sector_size() {
+                return Err(SettingsError::Full);
+            }
+        }
+
+        let mut buf = [0xFF; MAX_RECORD_SIZE];
+        let len = RECORD_HEADER_SIZE + key.len() + value.len();
+        buf[4] = key.len() as u8;
+        buf[5] = value.len() as u8;
+        buf[6] = kind;
+        buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + key.len()].copy_from_slice(key.as_bytes());
+        buf[RECORD_HEADER_SIZE + key.len()..len].copy_from_slice(value);
+        let crc = crc32(&buf[4..len]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+
+        let size = record.size();
+        self.flash
+            .write(self.address(self.active, self.end), &buf[..size as usize])?;
+        self.end += size;
+        Ok(())
+    }
+
+    /// Copy the live records to the next sector and make it active
+    fn compact(&mut self) -> Result<(), SettingsError> {
+        let old = self.active;
+        let next = (old + 1) % self.sectors;
+        let start = self.address(next, 0);
+        self.flash.erase(start, start + self.sector_size())?;
+
+        let mut buf = [0; MAX_RECORD_SIZE];
+        let mut offset = SECTOR_HEADER_SIZE;
+        let mut to = SECTOR_HEADER_SIZE;
+        while offset < self.end {
+            let Scan::Record(record) = self.read_record(old, offset, &mut buf)? else {
+                break;
+            };
+            offset += record.size();
+            if record.removed || !self.is_last(old, offset, &buf)? {
+                continue;
+            }
+            let size = record.size();
+            self.flash
+                .write(self.address(next, to), &buf[..size as usize])?;
+            to += size;
+        }
+
+        self.write_sector_header(next, self.sequence.wrapping_add(1))?;
+        self.end = to;
+        Ok(())
+    }
+}
+
+fn check_key(key: &str) -> Result<(), SettingsError> {
+    if key.is_empty() || key.len() > MAX_KEY_SIZE {
+        Err(SettingsError::InvalidKey)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{RamFlash, SECTOR_SIZE};
+
+    const SIZE: usize = 4 * SECTOR_SIZE as usize;
+
+    fn reopen(settings: Settings<RamFlash<SIZE>>) -> Settings<RamFlash<SIZE>> {
+        let mut flash = settings.into_inner();
+        flash.power_loss_after(None);
+        Settings::new(flash).unwrap()
+    }
+
+    #[test]
+    fn typed_values() {
+        let mut settings = Settings::new(RamFlash::<SIZE>::new()).unwrap();
+        assert_eq!(settings.get::<u8>("volume").unwrap(), None);
+
+        settings.set("volume", &100u8).unwrap();
+        settings.set("tempo", &120.5f32).unwrap();
+        settings.set("transpose", &-12i16).unwrap();
+        settings.set("thru", &true).unwrap();
+        settings
+            .set("name", &String::<16>::try_from("PGB-1").unwrap())
+            .unwrap();
+        settings.set("volume", &90u8).unwrap();
+
+        let mut settings = reopen(settings);
+        assert_eq!(settings.get("volume").unwrap(), Some(90u8));
+        assert_eq!(settings.get("tempo").unwrap(), Some(120.5f32));
+        assert_eq!(settings.get("transpose").unwrap(), Some(-12i16));
+        assert_eq!(settings.get("thru").unwrap(), Some(true));
+        assert_eq!(
+            settings.get("name").unwrap(),
+            Some(String::<16>::try_from("PGB-1").unwrap())
+        );
+        // Wrong type
+        assert_eq!(settings.get::<u32>("volume").unwrap(), None);
+
+        settings.remove("thru").unwrap();
+        let mut keys = std::vec::Vec::new();
+        settings
+            .for_each(|key, _| keys.push(std::string::String::from(key)))
+            .unwrap();
+        assert_eq!(keys, ["tempo", "transpose", "name", "volume"]);
+
+        assert_eq!(settings.set("", &0u8), Err(SettingsError::InvalidKey));
+        assert_eq!(
+            settings.set_bytes("big", &[0; MAX_VALUE_SIZE + 1]),
+            Err(SettingsError::ValueTooLong)
+        );
+    }
+
+    #[test]
+    fn wear_leveling() {
+        let mut settings = Settings::new(RamFlash::<SIZE>::new()).unwrap();
+        settings.set("channel", &3u8).unwrap();
+        for i in 0..10_000u32 {
+            settings.set("counter", &i).unwrap();
+            // Same value, nothing written
+            settings.set("channel", &3u8).unwrap();
+        }
+
+        let mut settings = reopen(settings);
+        assert_eq!(settings.get("counter").unwrap(), Some(9999u32));
+        assert_eq!(settings.get("channel").unwrap(), Some(3u8));
+
+        // 10000 records of 20 bytes, about 200 per sector, spread evenly
+        let flash = settings.into_inner();
+        assert!(
+            (45..=55).contains(&flash.erase_count()),
+            "{}",
+            flash.erase_count()
+        );
+    }
+
+    #[test]
+    fn formatted() {
+        let mut settings = Settings::new(RamFlash::<SIZE>::new()).unwrap();
+        settings.set("channel", &3u8).unwrap();
+        // Move to the newer sectors
+        for i in 0..500u32 {
+            settings.set("counter", &i).unwrap();
+        }
+
+        let mut settings = Settings::formatted(settings.into_inner()).unwrap();
+        assert_eq!(settings.get::<u8>("channel").unwrap(), None);
+        settings.set("volume", &100u8).unwrap();
+
+        let mut settings = reopen(settings);
+        assert_eq!(settings.get::<u8>("channel").unwrap(), None);
+        assert_eq!(settings.get::<u32>("counter").unwrap(), None);
+        assert_eq!(settings.get("volume").unwrap(), Some(100u8));
+    }
+
+    #[test]
+    fn power_loss() {
+        // Fill most of the active sector, so that some writes move the
+        // settings to the next sector
+        let mut base = Settings::new(RamFlash::<SIZE>::new()).unwrap();
+        base.set("brightness", &20u8).unwrap();
+        for i in 0..203u32 {
+            base.set("counter", &i).unwrap();
+        }
+        let image = base.into_inner().as_bytes().to_vec();
+
+        for count in 0..150 {
+            let mut flash = RamFlash::<SIZE>::new();
+            flash.write(0, &image).unwrap();
+            flash.power_loss_after(Some(count));
+            let mut settings = Settings::new(flash).unwrap();
+
+            let _ = settings.set("counter", &1000u32);
+            let _ = settings.set("counter", &1001u32);
+
+            let mut settings = reopen(settings);
+            let counter: u32 = settings.get("counter").unwrap().unwrap();
+            assert!(
+                [202, 1000, 1001].contains(&counter),
+                "{}: {}",
+                count,
+                counter
+            );
+            assert_eq!(settings.get("brightness").unwrap(), Some(20u8));
+
+            // The store still works after the power loss
+            settings.set("counter", &2000u32).unwrap();
+            let mut settings = reopen(settings);
+            assert_eq!(settings.get("counter").unwrap(), Some(2000u32));
+            assert_eq!(settings.get("brightness").unwrap(), Some(20u8));
+        }
+    }
+}
@@ -0,0 +1,763 @@
+//! Line oriented command shell
+//!
+//! A small console for the USB serial port, usable from any terminal
+//! (`screen /dev/ttyACM0`, PuTTY, ...). Bytes received from the port go
+//! through `Shell::input`, which echoes them, edits the line and runs the
+//! command on return:
+//!
+//! ```ignore
+//! let mut buf = [0; 64];
+//! let len = periph.usb.serial.read(&mut buf);
+//! shell.input(&buf[..len], &mut app, &mut periph.usb.serial);
+//! shell.monitor_keys(&periph.keyboard, &mut periph.usb.serial);
+//! ```
+//!
+//! Built-in commands call the `Handler` methods, applications add their
+//! own commands with `Handler::commands` and `Handler::command`.
+//!
+//! | Command                          | Handler method  |
+//! |----------------------------------|-----------------|
+//! | `help`                           |                 |
+//! | `reboot`                         | `reboot`        |
+//! | `bootloader`                     | `bootloader`    |
+//! | `led test`                       | `led_test`      |
+//! | `keys on\|off`                   |                 |
+//! | `settings list`                  | `settings_list` |
+//! | `settings get <name>`            | `setting_get`   |
+//! | `settings set <name> <value>`    | `setting_set`   |
+//! | `storage ls [path]`              | `storage_list`  |
+//!
+//! Words are separated by spaces, double quotes keep spaces in a word.
+//!
+//! `DeviceHandler` implements the built-in commands over the settings, the
+//! filesystem and the LEDs:
+//!
+//! ```ignore
+//! let mut handler = DeviceHandler::new(&mut periph.settings, &mut periph.fs)
+//!     .leds(&mut periph.leds, &mut periph.delay);
+//! shell.input(&buf[..len], &mut handler, &mut periph.usb.serial);
+//! ```
+
+use core::fmt::{self, Write};
+
+use embedded_hal::delay::DelayNs;
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+use smart_leds::{SmartLedsWrite, RGB8};
+
+use crate::fs::{Fs, FsError};
+use crate::settings::{Settings, SettingsError, MAX_VALUE_SIZE};
+use crate::ui::LED_COUNT;
+use crate::{KeyboardMatrix, Keys};
+
+/// Longest command line, longer lines are truncated
+pub const LINE_SIZE: usize = 128;
+/// Most words in a command line, command name included
+pub const MAX_ARGS: usize = 8;
+
+pub const PROMPT: &str = "> ";
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    UnknownCommand,
+    /// Wrong arguments, the usage of the command is printed
+    Usage,
+    Unsupported,
+    NotFound,
+    InvalidValue,
+    /// Unbalanced quotes or too many words
+    Syntax,
+    Failed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::UnknownCommand => "unknown command, try help",
+            Error::Usage => "wrong arguments",
+            Error::Unsupported => "not supported",
+            Error::NotFound => "not found",
+            Error::InvalidValue => "invalid value",
+            Error::Syntax => "syntax error",
+            Error::Failed => "failed",
+        })
+    }
+}
+
+/// Description of a command for `help` and usage errors
+#[derive(Copy, Clone, Debug)]
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments, e.g. `<name> [value]`
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+impl Command {
+    pub const fn new(name: &'static str, usage: &'static str, help: &'static str) -> Self {
+        Command { name, usage, help }
+    }
+}
+
+const BUILTINS: [Command; 7] = [
+    Command::new("help", "", "list the commands"),
+    Command::new("reboot", "", "restart the PGB-1"),
+    Command::new("bootloader", "", "restart in the USB bootloader"),
+    Command::new("led", "test", "light up the LEDs one by one"),
+    Command::new("keys", "on|off", "print key presses and releases"),
+    Command::new(
+        "settings",
+        "list | get <name> | set <name> <value>",
+        "read and change the settings",
+    ),
+    Command::new("storage", "ls [path]", "list the files"),
+];
+
+/// Words of a command line, the command name is `args[0]`
+pub type Args<'a> = Vec<&'a str, MAX_ARGS>;
+
+/// Split a command line in words
+pub fn split(line: &str) -> Result<Args<'_>, Error> {
+    let mut args = Args::new();
+    let mut rest = line.trim_start_matches(' ');
+    while !rest.is_empty() {
+        let (word, next) = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or(Error::Syntax)?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = rest.find([' ', '"']).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        args.push(word).map_err(|_| Error::Syntax)?;
+        rest = next.trim_start_matches(' ');
+    }
+    Ok(args)
+}
+
+/// Application side of the shell, every method has a default so only the
+/// supported features need to be implemented
+pub trait Handler {
+    fn reboot(&mut self) -> Result<(), Error> {
+        crate::reboot()
+    }
+
+    /// See `crate::reboot_to_bootloader`
+    fn bootloader(&mut self) -> Result<(), Error> {
+        crate::reboot_to_bootloader()
+    }
+
+    fn led_test(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Print the settings, one `name = value` per line
+    fn settings_list(&mut self, _out: &mut dyn Write) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Print the value of a setting
+    fn setting_get(&mut self, _name: &str, _out: &mut dyn Write) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn setting_set(&mut self, _name: &str, _value: &str) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Print the content of a directory, one entry per line
+    fn storage_list(&mut self, _path: &str, _out: &mut dyn Write) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Application commands, listed by `help`
+    fn commands(&self) -> &[Command] {
+        &[]
+    }
+
+    /// Run an application command, `args[0]` is the command name
+    fn command(&mut self, _args: &[&str], _out: &mut dyn Write) -> Result<(), Error> {
+        Err(Error::UnknownCommand)
+    }
+}
+
+/// LEDs lit by the `led test` of `DeviceHandler`, implemented for the
+/// `smart_leds` drivers
+pub trait Leds {
+    fn show(&mut self, colors: &[RGB8; LED_COUNT]);
+}
+
+impl<T: SmartLedsWrite<Color = RGB8>> Leds for T {
+    fn show(&mut self, colors: &[RGB8; LED_COUNT]) {
+        let _ = self.write(colors.iter().copied());
+    }
+}
+
+/// Time each LED stays lit during the `led test`
+pub const LED_TEST_MS: u32 = 100;
+
+/// Stock `Handler` of the PGB-1
+///
+/// Settings values of 1, 2 or 4 bytes are shown and set as little endian
+/// integers, unsigned on 1 byte and signed otherwise, the other values as
+/// text. A new setting is an integer if the
+/// value is a number. Settings of other types, e.g. `f32`, need an
+/// application handler.
+///
+/// The `led test` is unsupported unless LEDs are given.
+pub struct DeviceHandler<'a, S, F> {
+    settings: &'a mut Settings<S>,
+    fs: &'a mut Fs<F>,
+    leds: Option<(&'a mut dyn Leds, &'a mut dyn DelayNs)>,
+}
+
+impl<'a, S: NorFlash, F: NorFlash> DeviceHandler<'a, S, F> {
+    pub fn new(settings: &'a mut Settings<S>, fs: &'a mut Fs<F>) -> Self {
+        DeviceHandler {
+            settings,
+            fs,
+            leds: None,
+        }
+    }
+
+    pub fn leds(mut self, leds: &'a mut dyn Leds, delay: &'a mut dyn DelayNs) -> Self {
+        self.leds = Some((leds, delay));
+        self
+    }
+}
+
+impl From<SettingsError> for Error {
+    fn from(err: SettingsError) -> Self {
+        match err {
+            SettingsError::InvalidKey | SettingsError::ValueTooLong => Error::InvalidValue,
+            _ => Error::Failed,
+        }
+    }
+}
+
+impl From<FsError> for Error {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound | FsError::NotADirectory => Error::NotFound,
+            FsError::InvalidPath => Error::InvalidValue,
+            _ => Error::Failed,
+        }
+    }
+}
+
+/// Print a setting value, see `DeviceHandler`
+fn write_setting(value: &[u8], out: &mut dyn Write) {
+    let _ = match *value {
+        [a] => write!(out, "{a}"),
+        [a, b] => write!(out, "{}", i16::from_le_bytes([a, b])),
+        [a, b, c, d] => write!(out, "{}", i32::from_le_bytes([a, b, c, d])),
+        _ => match core::str::from_utf8(value) {
+            Ok(text) => write!(out, "\"{text}\""),
+            Err(_) => value.iter().try_for_each(|byte| write!(out, "{byte:02x}")),
+        },
+    };
+}
+
+impl<S: NorFlash, F: NorFlash> Handler for DeviceHandler<'_, S, F> {
+    fn led_test(&mut self) -> Result<(), Error> {
+        let (leds, delay) = self.leds.as_mut().ok_or(Error::Unsupported)?;
+        let mut colors = [RGB8::default(); LED_COUNT];
+        for i in 0..LED_COUNT {
+            colors[i] = RGB8::new(64, 64, 64);
+            leds.show(&colors);
+            delay.delay_ms(LED_TEST_MS);
+            colors[i] = RGB8::default();
+        }
+        leds.show(&colors);
+        Ok(())
+    }
+
+    fn settings_list(&mut self, out: &mut dyn Write) -> Result<(), Error> {
+        self.settings.for_each(|name, value| {
+            let _ = write!(out, "{name} = ");
+            write_setting(value, out);
+            let _ = writeln!(out);
+        })?;
+        Ok(())
+    }
+
+    fn setting_get(&mut self, name: &str, out: &mut dyn Write) -> Result<(), Error> {
+        let mut value = [0; MAX_VALUE_SIZE];
+        let len = self.settings.get_bytes(name, &mut value)?;
+        write_setting(&value[..len.ok_or(Error::NotFound)?], out);
+        Ok(())
+    }
+
+    fn setting_set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let number = value.parse::<i32>();
+        let len = match self.settings.get_bytes(name, &mut [])? {
+            Some(len @ (1 | 2 | 4)) => len,
+            None if number.is_ok() => 4,
+            _ => return Ok(self.settings.set_bytes(name, value.as_bytes())?),
+        };
+        let number = number.map_err(|_| Error::InvalidValue)?;
+        let fits = match len {
+            1 => i8::try_from(number).is_ok() || u8::try_from(number).is_ok(),
+            2 => i16::try_from(number).is_ok() || u16::try_from(number).is_ok(),
+            _ => true,
+        };
+        if !fits {
+            return Err(Error::InvalidValue);
+        }
+        self.settings
+            .set_bytes(name, &number.to_le_bytes()[..len])?;
+        Ok(())
+    }
+
+    fn storage_list(&mut self, path: &str, out: &mut dyn Write) -> Result<(), Error> {
+        self.fs.read_dir(path, |entry| {
+            let _ = if entry.is_dir {
+                writeln!(out, "{}/", entry.name)
+            } else {
+                writeln!(out, "{} {}", entry.name, entry.size)
+            };
+        })?;
+        Ok(())
+    }
+}
+
+/// Terminals expect CR LF line endings
+struct Crlf<'a>(&'a mut dyn Write);
+
+impl Write for Crlf<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+        if let Some(first) = lines.next() {
+            self.0.write_str(first)?;
+        }
+        for line in lines {
+            self.0.write_str("\r\n")?;
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Shell {
+    line: String<LINE_SIZE>,
+    /// Ignore the LF of a CR LF pair
+    after_cr: bool,
+    echo: bool,
+    key_monitor: bool,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Shell {
+            line: String::new(),
+            after_cr: false,
+            echo: true,
+            key_monitor: false,
+        }
+    }
+
+    /// Echo the characters typed, on by default
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    pub fn key_monitor(&self) -> bool {
+        self.key_monitor
+    }
+
+    /// Handle received bytes, run the commands and write their output to
+    /// `out`
+    pub fn input(&mut self, bytes: &[u8], handler: &mut dyn Handler, out: &mut dyn Write) {
+        let mut out = Crlf(out);
+        for &byte in bytes {
+            let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    if self.echo {
+                        let _ = out.write_str("\n");
+                    }
+                    let line = core::mem::take(&mut self.line);
+                    self.execute_line(&line, handler, &mut out);
+                    let _ = out.write_str(PROMPT);
+                }
+                BACKSPACE | DELETE => {
+                    let erased = self.line.pop().is_some();
+                    if erased && self.echo {
+                        let _ = out.write_str("\x08 \x08");
+                    }
+                }
+                CTRL_C => {
+                    self.line.clear();
+                    self.key_monitor = false;
+                    let _ = out.write_str("^C\n");
+                    let _ = out.write_str(PROMPT);
+                }
+                b' '..=b'~' => {
+                    // Characters past the end of a full line are dropped
+                    let added = self.line.push(byte as char).is_ok();
+                    if added && self.echo {
+                        let _ = out.write_char(byte as char);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Run a command line
+    pub fn execute(&mut self, line: &str, handler: &mut dyn Handler, out: &mut dyn Write) {
+        self.execute_line(line, handler, &mut Crlf(out));
+    }
+
+    fn execute_line(&mut self, line: &str, handler: &mut dyn Handler, out: &mut Crlf) {
+        let args = match split(line) {
+            Ok(args) if args.is_empty() => return,
+            Ok(args) => args,
+            Err(err) => {
+                let _ = writeln!(out, "error: {err}");
+                return;
+            }
+        };
+
+        match self.run(&args, handler, out) {
+            Ok(()) => {}
+            Err(Error::Usage) => {
+                let usage = BUILTINS
+                    .iter()
+                    .chain(handler.commands())
+                    .find(|cmd| cmd.name == args[0])
+                    .map_or("", |cmd| cmd.usage);
+                let _ = writeln!(out, "usage: {} {usage}", args[0]);
+            }
+            Err(err) => {
+                let _ = writeln!(out, "error: {err}");
+            }
+        }
+    }
+
+    fn run(
+        &mut self,
+        args: &[&str],
+        handler: &mut dyn Handler,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        match args {
+            ["help"] => {
+                for cmd in BUILTINS.iter().chain(handler.commands()) {
+                    let _ = writeln!(out, "{} {}\n    {}", cmd.name, cmd.usage, cmd.help);
+                }
+                Ok(())
+            }
+            ["reboot"] => handler.reboot(),
+            ["bootloader"] => handler.bootloader(),
+            ["led", "test"] => handler.led_test(),
+            ["keys", "on"] => {
+                self.key_monitor = true;
+                Ok(())
+            }
+            ["keys", "off"] => {
+                self.key_monitor = false;
+                Ok(())
+            }
+            ["settings", "list"] => handler.settings_list(out),
+            ["settings", "get", name] => {
+                handler.setting_get(name, out)?;
+                let _ = writeln!(out);
+                Ok(())
+            }
+            ["settings", "set", name, value] => handler.setting_set(name, value),
+            ["storage", "ls"] => handler.storage_list("/", out),
+            ["storage", "ls", path] => handler.storage_list(path, out),
+            [name, ..] if BUILTINS.iter().any(|cmd| cmd.name == *name) => Err(Error::Usage),
+            _ => handler.command(args, out),
+        }
+    }
+
+    /// Print the key presses and releases of the last scan while the
+    /// monitor is on
+    pub fn monitor_keys(&self, keyboard: &KeyboardMatrix, out: &mut dyn Write) {
+        if !self.key_monitor {
+            return;
+        }
+        let mut out = Crlf(out);
+        for key in Keys::LIST {
+            if keyboard.falling(key) {
+                let _ = writeln!(out, "key {} down", key.name());
+            } else if keyboard.raising(key) {
+                let _ = writeln!(out, "key {} up", key.name());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct App {
+        volume: i32,
+        rebooted: bool,
+        bootloader: bool,
+        led_test: bool,
+    }
+
+    const COMMANDS: [Command; 1] = [Command::new("add", "<a> <b>", "add two numbers")];
+
+    impl Handler for App {
+        fn reboot(&mut self) -> Result<(), Error> {
+            self.rebooted = true;
+            Ok(())
+        }
+
+        fn bootloader(&mut self) -> Result<(), Error> {
+            self.bootloader = true;
+            Ok(())
+        }
+
+        fn led_test(&mut self) -> Result<(), Error> {
+            self.led_test = true;
+            Ok(())
+        }
+
+        fn settings_list(&mut self, out: &mut dyn Write) -> Result<(), Error> {
+            let _ = writeln!(out, "volume = {}", self.volume);
+            Ok(())
+        }
+
+        fn setting_get(&mut self, name: &str, out: &mut dyn Write) -> Result<(), Error> {
+            match name {
+                "volume" => {
+                    let _ = write!(out, "{}", self.volume);
+                    Ok(())
+                }
+                _ => Err(Error::NotFound),
+            }
+        }
+
+        fn setting_set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+            match name {
+                "volume" => {
+                    self.volume = value.parse().map_err(|_| Error::InvalidValue)?;
+                    Ok(())
+                }
+                _ => Err(Error::NotFound),
+            }
+        }
+
+        fn commands(&self) -> &[Command] {
+            &COMMANDS
+        }
+
+        fn command(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+            match args {
+                ["add", a, b] => {
+                    let a: i32 = a.parse().map_err(|_| Error::InvalidValue)?;
+                    let b: i32 = b.parse().map_err(|_| Error::InvalidValue)?;
+                    let _ = writeln!(out, "{}", a + b);
+                    Ok(())
+                }
+                ["add", ..] => Err(Error::Usage),
+                _ => Err(Error::UnknownCommand),
+            }
+        }
+    }
+
+    fn run(shell: &mut Shell, app: &mut dyn Handler, line: &str) -> String {
+        let mut out = String::new();
+        shell.execute(line, app, &mut out);
+        out
+    }
+
+    #[test]
+    fn split_words() {
+        assert_eq!(split("").unwrap(), [] as [&str; 0]);
+        assert_eq!(split("  help  ").unwrap(), ["help"]);
+        assert_eq!(
+            split("settings set  name \"two words\"").unwrap(),
+            ["settings", "set", "name", "two words"]
+        );
+        assert_eq!(split("a\"b c\"d").unwrap(), ["a", "b c", "d"]);
+        assert_eq!(split("\"\"").unwrap(), [""]);
+        assert_eq!(split("say \"hello"), Err(Error::Syntax));
+        assert_eq!(split("1 2 3 4 5 6 7 8 9"), Err(Error::Syntax));
+    }
+
+    #[test]
+    fn line_editing() {
+        let mut shell = Shell::new();
+        let mut app = App::default();
+        let mut out = String::new();
+
+        shell.input(b"settings set volume 12x\x08\r\n", &mut app, &mut out);
+        assert_eq!(app.volume, 12);
+        assert_eq!(out, "settings set volume 12x\x08 \x08\r\n> ");
+
+        // LF alone, and CR LF split between two reads
+        out.clear();
+        shell.input(b"settings set volume 3\r", &mut app, &mut out);
+        shell.input(b"\nsettings get volume\n", &mut app, &mut out);
+        assert_eq!(app.volume, 3);
+        assert!(out.ends_with("settings get volume\r\n3\r\n> "));
+        assert_eq!(out.matches(PROMPT).count(), 2);
+
+        // Ctrl-C drops the line
+        out.clear();
+        shell.input(b"reboot\x03\r", &mut app, &mut out);
+        assert!(!app.rebooted);
+        assert_eq!(out, "reboot^C\r\n> \r\n> ");
+
+        // Control characters are ignored, long lines truncated
+        out.clear();
+        shell.set_echo(false);
+        shell.input(b"\x01re\x1bboot\r", &mut app, &mut out);
+        assert!(app.rebooted);
+        assert_eq!(out, "> ");
+        let long = [b'x'; LINE_SIZE + 10];
+        shell.input(&long, &mut app, &mut out);
+        assert_eq!(shell.line.len(), LINE_SIZE);
+    }
+
+    #[test]
+    fn builtins() {
+        let mut shell = Shell::new();
+        let mut app = App::default();
+
+        assert_eq!(run(&mut shell, &mut app, ""), "");
+        assert!(run(&mut shell, &mut app, "help").contains("storage ls [path]\r\n"));
+        assert_eq!(run(&mut shell, &mut app, "bootloader"), "");
+        assert!(app.bootloader);
+        assert_eq!(run(&mut shell, &mut app, "led test"), "");
+        assert!(app.led_test);
+
+        assert!(!shell.key_monitor());
+        run(&mut shell, &mut app, "keys on");
+        assert!(shell.key_monitor());
+        run(&mut shell, &mut app, "keys off");
+        assert!(!shell.key_monitor());
+
+        assert_eq!(run(&mut shell, &mut app, "settings set volume 42"), "");
+        assert_eq!(run(&mut shell, &mut app, "settings get volume"), "42\r\n");
+        assert_eq!(
+            run(&mut shell, &mut app, "settings list"),
+            "volume = 42\r\n"
+        );
+        assert_eq!(
+            run(&mut shell, &mut app, "settings set volume loud"),
+            "error: invalid value\r\n"
+        );
+        assert_eq!(
+            run(&mut shell, &mut app, "settings get tempo"),
+            "error: not found\r\n"
+        );
+        assert_eq!(
+            run(&mut shell, &mut app, "settings set volume"),
+            "usage: settings list | get <name> | set <name> <value>\r\n"
+        );
+        assert_eq!(
+            run(&mut shell, &mut app, "storage ls"),
+            "error: not supported\r\n"
+        );
+        assert_eq!(
+            run(&mut shell, &mut app, "say \"hi"),
+            "error: syntax error\r\n"
+        );
+    }
+
+    #[test]
+    fn app_commands() {
+        let mut shell = Shell::new();
+        let mut app = App::default();
+
+        assert!(
+            run(&mut shell, &mut app, "help").contains("add <a> <b>\r\n    add two numbers\r\n")
+        );
+        assert_eq!(run(&mut shell, &mut app, "add 2 40"), "42\r\n");
+        assert_eq!(run(&mut shell, &mut app, "add 2"), "usage: add <a> <b>\r\n");
+        assert_eq!(
+            run(&mut shell, &mut app, "sub 2 1"),
+            "error: unknown command, try help\r\n"
+        );
+    }
+    struct TestLeds(Vec<usize>);
+
+    impl Leds for TestLeds {
+        fn show(&mut self, colors: &[RGB8; LED_COUNT]) {
+            self.0
+                .extend(colors.iter().position(|&c| c != RGB8::default()));
+        }
+    }
+
+    struct TestDelay(u32);
+
+    impl DelayNs for TestDelay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.0 += ns / 1_000_000;
+        }
+    }
+
+    #[test]
+    fn device_handler() {
+        use crate::flash::{RamFlash, SECTOR_SIZE};
+
+        let mut settings = Settings::new(RamFlash::<{ 2 * SECTOR_SIZE as usize }>::new()).unwrap();
+        let mut fs = Fs::new(RamFlash::<{ 4 * SECTOR_SIZE as usize }>::new()).unwrap();
+        settings.set("channel", &200u8).unwrap();
+        settings.set("transpose", &-12i16).unwrap();
+        settings.set_bytes("name", b"PGB-1").unwrap();
+        settings.set_bytes("blob", &[0xFF, 0, 1]).unwrap();
+        fs.create_dir("/projects").unwrap();
+        fs.write_file("/projects/song", &[0; 10]).unwrap();
+        fs.write_file("/kit", &[]).unwrap();
+
+        let mut shell = Shell::new();
+        let mut handler = DeviceHandler::new(&mut settings, &mut fs);
+        assert_eq!(
+            run(&mut shell, &mut handler, "settings list"),
+            "channel = 200\r\ntranspose = -12\r\nname = \"PGB-1\"\r\nblob = ff0001\r\n"
+        );
+        let commands = [
+            ("settings set channel 3", ""),
+            ("settings get channel", "3\r\n"),
+            ("settings set channel 256", "error: invalid value\r\n"),
+            ("settings set channel low", "error: invalid value\r\n"),
+            ("settings set transpose -32768", ""),
+            ("settings get transpose", "-32768\r\n"),
+            ("settings set name \"PGB-1 #2\"", ""),
+            ("settings get name", "\"PGB-1 #2\"\r\n"),
+            ("settings set tempo 120", ""),
+            ("settings get tempo", "120\r\n"),
+            ("settings set mode studio", ""),
+            ("settings get mode", "\"studio\"\r\n"),
+            ("settings get volume", "error: not found\r\n"),
+            ("storage ls", "projects/\r\nkit 0\r\n"),
+            ("storage ls /projects", "song 10\r\n"),
+            ("storage ls /samples", "error: not found\r\n"),
+            ("led test", "error: not supported\r\n"),
+        ];
+        for (line, output) in commands {
+            assert_eq!(run(&mut shell, &mut handler, line), output, "{line}");
+        }
+        assert_eq!(settings.get("channel").unwrap(), Some(3u8));
+        assert_eq!(settings.get("tempo").unwrap(), Some(120i32));
+
+        let mut leds = TestLeds(Vec::new());
+        let mut delay = TestDelay(0);
+        let mut handler = DeviceHandler::new(&mut settings, &mut fs).leds(&mut leds, &mut delay);
+        assert_eq!(run(&mut shell, &mut handler, "led test"), "");
+        assert_eq!(leds.0, (0..LED_COUNT).collect::<Vec<_>>());
+        assert_eq!(delay.0, LED_COUNT as u32 * LED_TEST_MS);
+    }
+}
@@ -0,0 +1,263 @@
+//! Lock-free single producer single consumer queue
+//!
+//! The queue only relies on atomic loads and stores, which the Cortex-M0+
+//! supports, so it can be used between an interrupt handler and the main
+//! loop or between the two cores.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fixed capacity queue of `N` elements, `N` must be a power of two
+pub struct Queue<T, const N: usize> {
+    /// Index of the next element to read, only written by the consumer
+    head: AtomicUsize,
+    /// Index of the next element to write, only written by the producer
+    tail: AtomicUsize,
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two());
+        Queue {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Split the queue in its producer and consumer ends
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (
+            Producer {
+                queue: self,
+                _not_sync: PhantomData,
+            },
+            Consumer {
+                queue: self,
+                _not_sync: PhantomData,
+            },
+        )
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        // Elements are only accessed through raw pointers, the producer and
+        // consumer never touch the same slot at the same time.
+        unsafe { (self.buffer.get() as *mut T).add(index % N) }
+    }
+
+    /// # Safety
+    ///
+    /// Only one producer may call this at a time.
+    unsafe fn enqueue(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= N {
+            return Err(value);
+        }
+
+        self.slot(tail).write(value);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// Only one consumer may call this at a time.
+    unsafe fn dequeue(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let value = self.slot(head).read();
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        // Drop the elements left in the queue
+        while unsafe { self.dequeue() }.is_some() {}
+    }
+}
+
+/// Writing end of a `Queue`
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Producer<'_, T, N> {}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Add an element, or give it back if the queue is full
+    pub fn enqueue(&mut self, value: T) -> Result<(), T> {
+        // We are the only producer
+        unsafe { self.queue.enqueue(value) }
+    }
+
+    pub fn ready(&self) -> bool {
+        self.queue.len() < N
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Reading end of a `Queue`
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Consumer<'_, T, N> {}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    pub fn dequeue(&mut self) -> Option<T> {
+        // We are the only consumer
+        unsafe { self.queue.dequeue() }
+    }
+
+    pub fn ready(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl<T, const N: usize> Iterator for Consumer<'_, T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.dequeue()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    #[test]
+    fn full_and_empty() {
+        let mut queue: Queue<u32, 4> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        assert!(consumer.dequeue().is_none());
+        assert!(!consumer.ready());
+        for i in 0..4 {
+            assert!(producer.ready());
+            producer.enqueue(i).unwrap();
+        }
+        assert!(!producer.ready());
+        assert_eq!(producer.enqueue(4), Err(4));
+        assert_eq!(consumer.len(), 4);
+
+        assert_eq!(consumer.dequeue(), Some(0));
+        producer.enqueue(4).unwrap();
+        assert_eq!(consumer.by_ref().collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert!(producer.is_empty());
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut queue: Queue<usize, 8> = Queue::new();
+        // Indexes close to overflowing
+        queue.head = AtomicUsize::new(usize::MAX - 5);
+        queue.tail = AtomicUsize::new(usize::MAX - 5);
+        let (mut producer, mut consumer) = queue.split();
+        let mut next = 0;
+        for round in 0..100 {
+            for i in 0..round % 9 {
+                producer.enqueue(round * 10 + i).unwrap();
+            }
+            assert_eq!(consumer.len(), round % 9);
+            for i in 0..round % 9 {
+                assert_eq!(consumer.dequeue(), Some(round * 10 + i));
+                next += 1;
+            }
+            assert!(consumer.is_empty());
+        }
+        assert_eq!(next, (0..100).map(|r| r % 9).sum::<usize>());
+    }
+
+    #[test]
+    fn drop_remaining() {
+        let item = Rc::new(());
+        {
+            let mut queue: Queue<Rc<()>, 4> = Queue::new();
+            let (mut producer, mut consumer) = queue.split();
+            for _ in 0..3 {
+                producer.enqueue(item.clone()).unwrap();
+            }
+            drop(consumer.dequeue());
+            assert_eq!(Rc::strong_count(&item), 3);
+        }
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn threads() {
+        let mut queue: Queue<u32, 16> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for i in 0..100_000 {
+                    let mut value = i;
+                    while let Err(v) = producer.enqueue(value) {
+                        value = v;
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0;
+            while expected < 100_000 {
+                match consumer.dequeue() {
+                    Some(value) => {
+                        assert_eq!(value, expected);
+                        expected += 1;
+                    }
+                    None => std::thread::yield_now(),
+                }
+            }
+        });
+    }
+}
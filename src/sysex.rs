@@ -0,0 +1,1285 @@
+//! SysEx protocol for backup, restore and remote control
+//!
+//! The PGB-1 answers SysEx requests on all its MIDI ports, the `pgb1-tool`
+//! host utility speaks the other side. All messages share the same header:
+//!
+//! ```text
+//! F0 7D 50 47 <command> <arguments...> F7
+//! ```
+//!
+//! `7D` is the non-commercial manufacturer ID, `50 47` is "PG". Numbers are
+//! sent in 7-bit groups, least significant group first: `u14` on 2 bytes,
+//! `u28` on 4 bytes and `u32` on 5 bytes. Binary data is packed 7 bytes in
+//! 8: a byte with the top bits of the following 7 bytes (bit 0 for the
+//! first one), then their lower 7 bits.
+//!
+//! Items (`Kind`) are read and written in chunks of `CHUNK_SIZE` bytes.
+//! Each request gets exactly one reply.
+//!
+//! | Request             | Arguments                                  | Reply        |
+//! |---------------------|--------------------------------------------|--------------|
+//! | `01` version        |                                            | `41` version |
+//! | `02` dump           | kind, index, chunk `u14`                   | `42` data    |
+//! | `03` restore begin  | kind, index, size `u28`                    | `40` ack     |
+//! | `04` restore data   | kind, index, chunk `u14`, packed data      | `40` ack     |
+//! | `05` restore end    | kind, index, CRC-32 of the data `u32`      | `40` ack     |
+//! | `06` key            | key index in `Keys::LIST`, action          | `40` ack     |
+//! | `07` screen         | page 0..=7                                 | `47` screen  |
+//! | `08` bootloader     |                                            | `40` ack     |
+//!
+//! | Reply        | Arguments                                                 |
+//! |--------------|-----------------------------------------------------------|
+//! | `40` ack     | request command                                           |
+//! | `41` version | ASCII firmware name and version                           |
+//! | `42` data    | kind, index, chunk `u14`, item size `u28`, packed data    |
+//! | `47` screen  | page, 128 packed bytes (one per column, top pixel in bit 0) |
+//! | `7F` nak     | request command, `Error` code                             |
+//!
+//! Chunks of a restore must be sent in order, the item is only committed by
+//! the restore end request once the CRC matches. After acknowledging a
+//! bootloader request the device restarts in the USB bootloader.
+//!
+//! `DeviceHandler` implements the requests over the settings, the
+//! filesystem, the keyboard and the screen. It only borrows them, so it can
+//! be made for each message; the state of a restore is kept by the `Server`:
+//!
+//! ```ignore
+//! let mut handler = DeviceHandler::new(&mut periph.settings, &mut periph.fs)
+//!     .keyboard(&mut periph.keyboard)
+//!     .screen(&frame);
+//! let len = server.handle(data, &mut handler, &mut reply);
+//! ```
+
+use core::fmt::Write;
+
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+
+use crate::crc::Crc32;
+use crate::fs::{FileWriter, Fs, FsError};
+use crate::settings::{Settings, SettingsError, MAX_KEY_SIZE, MAX_VALUE_SIZE};
+use crate::ui::FrameBuffer;
+use crate::{KeyboardMatrix, Keys};
+
+pub const MANUFACTURER_ID: u8 = 0x7D;
+pub const HEADER: [u8; 3] = [MANUFACTURER_ID, b'P', b'G'];
+
+/// Data bytes in a dump or restore chunk
+pub const CHUNK_SIZE: usize = 128;
+
+/// Bytes per column page of the screen
+pub const SCREEN_PAGE_SIZE: usize = 128;
+pub const SCREEN_PAGES: u8 = 8;
+
+/// Longest message of the protocol, without the F0/F7 delimiters
+pub const MAX_MESSAGE_SIZE: usize = HEADER.len() + 1 + 1 + 1 + 2 + 4 + packed_len(CHUNK_SIZE);
+
+// Messages must fit in the receive buffer of the MIDI ports
+const _: () = assert!(MAX_MESSAGE_SIZE <= crate::midi::SYSEX_BUFFER_SIZE);
+
+pub const VERSION_SIZE: usize = 32;
+
+const CMD_VERSION: u8 = 0x01;
+const CMD_DUMP: u8 = 0x02;
+const CMD_RESTORE_BEGIN: u8 = 0x03;
+const CMD_RESTORE_DATA: u8 = 0x04;
+const CMD_RESTORE_END: u8 = 0x05;
+const CMD_KEY: u8 = 0x06;
+const CMD_SCREEN: u8 = 0x07;
+const CMD_BOOTLOADER: u8 = 0x08;
+
+const REPLY_ACK: u8 = 0x40;
+const REPLY_VERSION: u8 = 0x41;
+const REPLY_DATA: u8 = 0x42;
+const REPLY_SCREEN: u8 = 0x47;
+const REPLY_NAK: u8 = 0x7F;
+
+/// Size of `len` bytes once packed
+pub const fn packed_len(len: usize) -> usize {
+    len + len.div_ceil(7)
+}
+
+/// Pack 8-bit `data` in `out`, return the packed length
+pub fn pack(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    let out = out.get_mut(..packed_len(data.len()))?;
+    for (group, packed) in data.chunks(7).zip(out.chunks_mut(8)) {
+        packed[0] = 0;
+        for (i, &byte) in group.iter().enumerate() {
+            packed[0] |= (byte >> 7) << i;
+            packed[i + 1] = byte & 0x7F;
+        }
+    }
+    Some(out.len())
+}
+
+/// Unpack 7-bit `data` in `out`, return the unpacked length
+pub fn unpack(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    if data.iter().any(|&b| b >= 0x80) {
+        return None;
+    }
+    let mut len = 0;
+    for group in data.chunks(8) {
+        let (&msb, rest) = group.split_first()?;
+        for (i, &byte) in rest.iter().enumerate() {
+            *out.get_mut(len)? = byte | ((msb >> i) & 1) << 7;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Kind {
+    Settings = 0,
+    Project = 1,
+    Pattern = 2,
+}
+
+impl Kind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Kind::Settings),
+            1 => Some(Kind::Project),
+            2 => Some(Kind::Pattern),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeyAction {
+    Release = 0,
+    Press = 1,
+    /// Pressed for a single keyboard scan
+    Tap = 2,
+}
+
+impl KeyAction {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(KeyAction::Release),
+            1 => Some(KeyAction::Press),
+            2 => Some(KeyAction::Tap),
+            _ => None,
+        }
+    }
+}
+
+/// Error codes of the nak reply
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    UnknownCommand = 1,
+    Malformed = 2,
+    NotFound = 3,
+    /// Restore data without restore begin, or chunk out of order
+    OutOfOrder = 4,
+    Checksum = 5,
+    Storage = 6,
+    Unsupported = 7,
+}
+
+impl Error {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Error::UnknownCommand,
+            3 => Error::NotFound,
+            4 => Error::OutOfOrder,
+            5 => Error::Checksum,
+            6 => Error::Storage,
+            7 => Error::Unsupported,
+            _ => Error::Malformed,
+        }
+    }
+}
+
+pub type Chunk = Vec<u8, CHUNK_SIZE>;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Request {
+    Version,
+    Dump {
+        kind: Kind,
+        index: u8,
+        chunk: u16,
+    },
+    RestoreBegin {
+        kind: Kind,
+        index: u8,
+        size: u32,
+    },
+    RestoreData {
+        kind: Kind,
+        index: u8,
+        chunk: u16,
+        data: Chunk,
+    },
+    RestoreEnd {
+        kind: Kind,
+        index: u8,
+        crc: u32,
+    },
+    Key {
+        key: u8,
+        action: KeyAction,
+    },
+    Screen {
+        page: u8,
+    },
+    Bootloader,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Reply {
+    Ack(u8),
+    Version(String<VERSION_SIZE>),
+    Data {
+        kind: Kind,
+        index: u8,
+        chunk: u16,
+        size: u32,
+        data: Chunk,
+    },
+    Screen {
+        page: u8,
+        data: [u8; SCREEN_PAGE_SIZE],
+    },
+    Nak(u8, Error),
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buffer: &'a mut [u8], command: u8) -> Option<Self> {
+        let mut writer = Writer { buffer, len: 0 };
+        for byte in HEADER {
+            writer.byte(byte)?;
+        }
+        writer.byte(command)?;
+        Some(writer)
+    }
+
+    fn byte(&mut self, byte: u8) -> Option<()> {
+        *self.buffer.get_mut(self.len)? = byte & 0x7F;
+        self.len += 1;
+        Some(())
+    }
+
+    fn number(&mut self, value: u32, bytes: usize) -> Option<()> {
+        for i in 0..bytes {
+            self.byte((value >> (7 * i)) as u8)?;
+        }
+        Some(())
+    }
+
+    fn packed(&mut self, data: &[u8]) -> Option<()> {
+        self.len += pack(data, self.buffer.get_mut(self.len..)?)?;
+        Some(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.data.split_first()?;
+        self.data = rest;
+        (byte < 0x80).then_some(byte)
+    }
+
+    fn number(&mut self, bytes: usize) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..bytes {
+            value |= (self.byte()? as u32) << (7 * i);
+        }
+        Some(value)
+    }
+
+    fn kind(&mut self) -> Option<Kind> {
+        Kind::from_u8(self.byte()?)
+    }
+
+    /// Unpack the rest of the message
+    fn packed(self, out: &mut [u8]) -> Option<usize> {
+        unpack(self.data, out)
+    }
+
+    fn chunk(self) -> Option<Chunk> {
+        let mut data = [0; CHUNK_SIZE];
+        let len = self.packed(&mut data)?;
+        Vec::from_slice(&data[..len]).ok()
+    }
+
+    fn end(self) -> Option<()> {
+        self.data.is_empty().then_some(())
+    }
+}
+
+/// Split a message of the protocol in command and arguments
+fn split(message: &[u8]) -> Option<(u8, Reader<'_>)> {
+    let rest = message.strip_prefix(&HEADER)?;
+    let (&command, data) = rest.split_first()?;
+    Some((command, Reader { data }))
+}
+
+/// True if a SysEx message (without F0/F7) is for this protocol
+pub fn is_protocol_message(message: &[u8]) -> bool {
+    message.starts_with(&HEADER)
+}
+
+impl Request {
+    fn command(&self) -> u8 {
+        match self {
+            Request::Version => CMD_VERSION,
+            Request::Dump { .. } => CMD_DUMP,
+            Request::RestoreBegin { .. } => CMD_RESTORE_BEGIN,
+            Request::RestoreData { .. } => CMD_RESTORE_DATA,
+            Request::RestoreEnd { .. } => CMD_RESTORE_END,
+            Request::Key { .. } => CMD_KEY,
+            Request::Screen { .. } => CMD_SCREEN,
+            Request::Bootloader => CMD_BOOTLOADER,
+        }
+    }
+
+    /// Write the message, without the F0/F7 delimiters, return its length
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut w = Writer::new(out, self.command())?;
+        match self {
+            Request::Version | Request::Bootloader => {}
+            Request::Dump { kind, index, chunk } => {
+                w.byte(*kind as u8)?;
+                w.byte(*index)?;
+                w.number(*chunk as u32, 2)?;
+            }
+            Request::RestoreBegin { kind, index, size } => {
+                w.byte(*kind as u8)?;
+                w.byte(*index)?;
+                w.number(*size, 4)?;
+            }
+            Request::RestoreData {
+                kind,
+                index,
+                chunk,
+                data,
+            } => {
+                w.byte(*kind as u8)?;
+                w.byte(*index)?;
+                w.number(*chunk as u32, 2)?;
+                w.packed(data)?;
+            }
+            Request::RestoreEnd { kind, index, crc } => {
+                w.byte(*kind as u8)?;
+                w.byte(*index)?;
+                w.number(*crc, 5)?;
+            }
+            Request::Key { key, action } => {
+                w.byte(*key)?;
+                w.byte(*action as u8)?;
+            }
+            Request::Screen { page } => w.byte(*page)?,
+        }
+        Some(w.len)
+    }
+
+    /// Decode a message, without the F0/F7 delimiters
+    pub fn decode(message: &[u8]) -> Result<Self, Error> {
+        let (command, mut r) = split(message).ok_or(Error::Malformed)?;
+        let request = match command {
+            CMD_VERSION => r.end().map(|_| Request::Version),
+            CMD_DUMP => (|| {
+                let request = Request::Dump {
+                    kind: r.kind()?,
+                    index: r.byte()?,
+                    chunk: r.number(2)? as u16,
+                };
+                r.end().map(|_| request)
+            })(),
+            CMD_RESTORE_BEGIN => (|| {
+                let request = Request::RestoreBegin {
+                    kind: r.kind()?,
+                    index: r.byte()?,
+                    size: r.number(4)?,
+                };
+                r.end().map(|_| request)
+            })(),
+            CMD_RESTORE_DATA => (|| {
+                Some(Request::RestoreData {
+                    kind: r.kind()?,
+                    index: r.byte()?,
+                    chunk: r.number(2)? as u16,
+                    data: r.chunk()?,
+                })
+            })(),
+            CMD_RESTORE_END => (|| {
+                let request = Request::RestoreEnd {
+                    kind: r.kind()?,
+                    index: r.byte()?,
+                    crc: r.number(5)?,
+                };
+                r.end().map(|_| request)
+            })(),
+            CMD_KEY => (|| {
+                let request = Request::Key {
+                    key: r.byte()?,
+                    action: KeyAction::from_u8(r.byte()?)?,
+                };
+                r.end().map(|_| request)
+            })(),
+            CMD_SCREEN => (|| {
+                let request = Request::Screen { page: r.byte()? };
+                r.end().map(|_| request)
+            })(),
+            CMD_BOOTLOADER => r.end().map(|_| Request::Bootloader),
+            _ => return Err(Error::UnknownCommand),
+        };
+        request.ok_or(Error::Malformed)
+    }
+}
+
+impl Reply {
+    /// Write the message, without the F0/F7 delimiters, return its length
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let command = match self {
+            Reply::Ack(_) => REPLY_ACK,
+            Reply::Version(_) => REPLY_VERSION,
+            Reply::Data { .. } => REPLY_DATA,
+            Reply::Screen { .. } => REPLY_SCREEN,
+            Reply::Nak(..) => REPLY_NAK,
+        };
+        let mut w = Writer::new(out, command)?;
+        match self {
+            Reply::Ack(request) => w.byte(*request)?,
+            Reply::Version(version) => {
+                for byte in version.bytes() {
+                    w.byte(byte)?;
+                }
+            }
+            Reply::Data {
+                kind,
+                index,
+                chunk,
+                size,
+                data,
+            } => {
+                w.byte(*kind as u8)?;
+                w.byte(*index)?;
+                w.number(*chunk as u32, 2)?;
+                w.number(*size, 4)?;
+                w.packed(data)?;
+            }
+            Reply::Screen { page, data } => {
+                w.byte(*page)?;
+                w.packed(data)?;
+            }
+            Reply::Nak(request, error) => {
+                w.byte(*request)?;
+                w.byte(*error as u8)?;
+            }
+        }
+        Some(w.len)
+    }
+
+    /// Decode a message, without the F0/F7 delimiters
+    pub fn decode(message: &[u8]) -> Option<Self> {
+        let (command, mut r) = split(message)?;
+        match command {
+            REPLY_ACK => {
+                let request = r.byte()?;
+                r.end().map(|_| Reply::Ack(request))
+            }
+            REPLY_VERSION => {
+                let mut version = String::new();
+                while let Some(byte) = r.byte() {
+                    version.push(byte as char).ok()?;
+                }
+                r.end().map(|_| Reply::Version(version))
+            }
+            REPLY_DATA => Some(Reply::Data {
+                kind: r.kind()?,
+                index: r.byte()?,
+                chunk: r.number(2)? as u16,
+                size: r.number(4)?,
+                data: r.chunk()?,
+            }),
+            REPLY_SCREEN => {
+                let page = r.byte()?;
+                let mut data = [0; SCREEN_PAGE_SIZE];
+                let len = r.packed(&mut data)?;
+                (len == SCREEN_PAGE_SIZE).then_some(Reply::Screen { page, data })
+            }
+            REPLY_NAK => {
+                let request = r.byte()?;
+                let error = Error::from_u8(r.byte()?);
+                r.end().map(|_| Reply::Nak(request, error))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What the application provides to the protocol `Server`
+///
+/// All operations are unsupported by default, `DeviceHandler` implements
+/// them for the PGB-1.
+pub trait Handler {
+    /// Firmware name and version
+    fn version(&self) -> &str {
+        concat!("pgb1 ", env!("CARGO_PKG_VERSION"))
+    }
+
+    /// Size of an item in bytes, `None` if it does not exist
+    fn size(&mut self, _kind: Kind, _index: u8) -> Option<u32> {
+        None
+    }
+
+    /// Read `buffer.len()` bytes of an item from `offset`
+    fn read(
+        &mut self,
+        _kind: Kind,
+        _index: u8,
+        _offset: u32,
+        _buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Prepare to receive an item of `size` bytes. The file returned, if
+    /// any, is kept by the `Server` until the end of the restore and given
+    /// to `write` and `end_restore`.
+    fn begin_restore(
+        &mut self,
+        _kind: Kind,
+        _index: u8,
+        _size: u32,
+    ) -> Result<Option<FileWriter>, Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Data of the item being restored, in order
+    fn write(
+        &mut self,
+        _kind: Kind,
+        _index: u8,
+        _offset: u32,
+        _data: &[u8],
+        _file: Option<&mut FileWriter>,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// End of a restore, the item must only replace the existing one if
+    /// `commit` is true
+    fn end_restore(
+        &mut self,
+        _kind: Kind,
+        _index: u8,
+        _file: Option<FileWriter>,
+        _commit: bool,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Remote key press, see `KeyboardMatrix::set_remote`
+    fn key(&mut self, _key: Keys, _action: KeyAction) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Copy a page of the screen, see `ui::FrameBuffer::page`
+    fn screen(&mut self, _page: u8, _buffer: &mut [u8; SCREEN_PAGE_SIZE]) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Bootloader request, the application restarts with
+    /// `crate::reboot_to_bootloader` once the ack is sent
+    fn bootloader(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+}
+
+/// Projects are the files of `project::path`, e.g. `/projects/005`
+pub use crate::project::PROJECT_DIR;
+/// Patterns are named like the projects, e.g. `/patterns/005`
+pub const PATTERN_DIR: &str = "/patterns";
+
+/// Settings being restored, applied once complete
+const SETTINGS_RESTORE_PATH: &str = "/.settings";
+
+/// Stock `Handler` of the PGB-1
+///
+/// Settings are backed up as a list of `key length, key, value length,
+/// value` records. Restoring them sets the keys of the backup and keeps the
+/// other settings. Projects and patterns are the files of `PROJECT_DIR` and
+/// `PATTERN_DIR`.
+///
+/// Remote keys and screen captures are unsupported unless a keyboard and a
+/// screen are given.
+pub struct DeviceHandler<'a, S, F> {
+    settings: &'a mut Settings<S>,
+    fs: &'a mut Fs<F>,
+    keyboard: Option<&'a mut KeyboardMatrix>,
+    screen: Option<&'a FrameBuffer>,
+}
+
+impl<'a, S: NorFlash, F: NorFlash> DeviceHandler<'a, S, F> {
+    pub fn new(settings: &'a mut Settings<S>, fs: &'a mut Fs<F>) -> Self {
+        DeviceHandler {
+            settings,
+            fs,
+            keyboard: None,
+            screen: None,
+        }
+    }
+
+    /// Apply the remote key requests to `keyboard`
+    pub fn keyboard(mut self, keyboard: &'a mut KeyboardMatrix) -> Self {
+        self.keyboard = Some(keyboard);
+        self
+    }
+
+    /// Send `screen` for the screen capture requests
+    pub fn screen(mut self, screen: &'a FrameBuffer) -> Self {
+        self.screen = Some(screen);
+        self
+    }
+
+    fn path(kind: Kind, index: u8) -> String<16> {
+        match kind {
+            Kind::Settings => String::try_from(SETTINGS_RESTORE_PATH).unwrap(),
+            Kind::Project => crate::project::path(index),
+            Kind::Pattern => {
+                let mut path = String::new();
+                let _ = write!(path, "{PATTERN_DIR}/{index:03}");
+                path
+            }
+        }
+    }
+
+    /// Set the settings of the backup in `SETTINGS_RESTORE_PATH`
+    fn apply_settings(&mut self) -> Result<(), Error> {
+        let mut offset = 0;
+        let mut read = |buffer: &mut [u8]| -> Result<bool, Error> {
+            let len = self.fs.read(SETTINGS_RESTORE_PATH, offset, buffer)?;
+            offset += len as u32;
+            match len {
+                0 if buffer.len() == 1 => Ok(false),
+                len if len == buffer.len() => Ok(true),
+                _ => Err(Error::Malformed),
+            }
+        };
+
+        let mut key = [0; MAX_KEY_SIZE];
+        let mut value = [0; MAX_VALUE_SIZE];
+        let mut len = [0];
+        while read(&mut len)? {
+            let key = key.get_mut(..len[0] as usize).ok_or(Error::Malformed)?;
+            read(key)?;
+            read(&mut len)?;
+            let value = value.get_mut(..len[0] as usize).ok_or(Error::Malformed)?;
+            read(value)?;
+            let key = core::str::from_utf8(key).map_err(|_| Error::Malformed)?;
+            self.settings.set_bytes(key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<FsError> for Error {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Error::NotFound,
+            _ => Error::Storage,
+        }
+    }
+}
+
+impl From<SettingsError> for Error {
+    fn from(err: SettingsError) -> Self {
+        match err {
+            SettingsError::InvalidKey | SettingsError::ValueTooLong => Error::Malformed,
+            _ => Error::Storage,
+        }
+    }
+}
+
+/// Copy the part of `data`, at `position` in an item, that overlaps
+/// `buffer`, at `offset` in the item
+fn copy_overlap(data: &[u8], position: u32, offset: u32, buffer: &mut [u8]) {
+    let from = position.max(offset);
+    let to = (position + data.len() as u32).min(offset + buffer.len() as u32);
+    if from < to {
+        buffer[(from - offset) as usize..(to - offset) as usize]
+            .copy_from_slice(&data[(from - position) as usize..(to - position) as usize]);
+    }
+}
+
+impl<S: NorFlash, F: NorFlash> Handler for DeviceHandler<'_, S, F> {
+    fn size(&mut self, kind: Kind, index: u8) -> Option<u32> {
+        match kind {
+            Kind::Settings if index == 0 => {
+                let mut size = 0;
+                self.settings
+                    .for_each(|key, value| size += 2 + key.len() as u32 + value.len() as u32)
+                    .ok()?;
+                Some(size)
+            }
+            Kind::Settings => None,
+            _ => self
+                .fs
+                .metadata(&Self::path(kind, index))
+                .ok()
+                .filter(|entry| !entry.is_dir)
+                .map(|entry| entry.size),
+        }
+    }
+
+    fn read(&mut self, kind: Kind, index: u8, offset: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        if kind != Kind::Settings {
+            let len = self.fs.read(&Self::path(kind, index), offset, buffer)?;
+            return if len == buffer.len() {
+                Ok(())
+            } else {
+                Err(Error::Malformed)
+            };
+        }
+
+        let mut position = 0;
+        self.settings.for_each(|key, value| {
+            for part in [
+                &[key.len() as u8][..],
+                key.as_bytes(),
+                &[value.len() as u8],
+                value,
+            ] {
+                copy_overlap(part, position, offset, buffer);
+                position += part.len() as u32;
+            }
+        })?;
+        Ok(())
+    }
+
+    fn begin_restore(
+        &mut self,
+        kind: Kind,
+        index: u8,
+        _size: u32,
+    ) -> Result<Option<FileWriter>, Error> {
+        if kind == Kind::Settings && index != 0 {
+            return Err(Error::NotFound);
+        }
+        let dir = match kind {
+            Kind::Settings => None,
+            Kind::Project => Some(PROJECT_DIR),
+            Kind::Pattern => Some(PATTERN_DIR),
+        };
+        if let Some(dir) = dir.filter(|dir| !self.fs.exists(dir)) {
+            self.fs.create_dir(dir)?;
+        }
+        Ok(Some(self.fs.create(&Self::path(kind, index))?))
+    }
+
+    fn write(
+        &mut self,
+        _kind: Kind,
+        _index: u8,
+        _offset: u32,
+        data: &[u8],
+        file: Option<&mut FileWriter>,
+    ) -> Result<(), Error> {
+        let writer = file.ok_or(Error::OutOfOrder)?;
+        self.fs.write(writer, data)?;
+        Ok(())
+    }
+
+    fn end_restore(
+        &mut self,
+        kind: Kind,
+        _index: u8,
+        file: Option<FileWriter>,
+        commit: bool,
+    ) -> Result<(), Error> {
+        let writer = file.ok_or(Error::OutOfOrder)?;
+        if !commit {
+            return Ok(());
+        }
+        self.fs.commit(writer)?;
+        if kind == Kind::Settings {
+            let result = self.apply_settings();
+            self.fs.remove(SETTINGS_RESTORE_PATH)?;
+            result?;
+        }
+        Ok(())
+    }
+
+    fn key(&mut self, key: Keys, action: KeyAction) -> Result<(), Error> {
+        let keyboard = self.keyboard.as_mut().ok_or(Error::Unsupported)?;
+        match action {
+            KeyAction::Release => keyboard.set_remote(key, false),
+            KeyAction::Press => keyboard.set_remote(key, true),
+            KeyAction::Tap => keyboard.tap_remote(key),
+        }
+        Ok(())
+    }
+
+    fn screen(&mut self, page: u8, buffer: &mut [u8; SCREEN_PAGE_SIZE]) -> Result<(), Error> {
+        let screen = self.screen.ok_or(Error::Unsupported)?;
+        buffer.copy_from_slice(screen.page(page as usize).ok_or(Error::Malformed)?);
+        Ok(())
+    }
+
+    fn bootloader(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Restore {
+    kind: Kind,
+    index: u8,
+    size: u32,
+    received: u32,
+    next_chunk: u16,
+    crc: Crc32,
+    /// From `Handler::begin_restore`
+    file: Option<FileWriter>,
+}
+
+/// Device side of the protocol
+///
+/// The server keeps the state of a restore between messages, the handler
+/// can be made again for each message:
+///
+/// ```ignore
+/// routing.process(&mut ports, |port, event| {
+///     if let Event::SysEx(data) = event {
+///         let mut reply = [0; sysex::MAX_MESSAGE_SIZE];
+///         if let Some(len) = server.handle(data, &mut handler, &mut reply) {
+///             // send reply[..len] on `port`
+///         }
+///     }
+/// });
+/// ```
+#[derive(Default)]
+pub struct Server {
+    restore: Option<Restore>,
+}
+
+impl Server {
+    pub const fn new() -> Self {
+        Server { restore: None }
+    }
+
+    /// Handle a SysEx message (without F0/F7), return the length of the
+    /// reply written in `reply`, or `None` if the message is not for us
+    pub fn handle<H: Handler>(
+        &mut self,
+        message: &[u8],
+        handler: &mut H,
+        reply: &mut [u8; MAX_MESSAGE_SIZE],
+    ) -> Option<usize> {
+        let (command, _) = split(message)?;
+        let response = match Request::decode(message) {
+            Ok(request) => self.execute(request, handler),
+            Err(error) => Err(error),
+        };
+        let response = response.unwrap_or_else(|error| Reply::Nak(command, error));
+        response.encode(reply)
+    }
+
+    fn execute<H: Handler>(&mut self, request: Request, handler: &mut H) -> Result<Reply, Error> {
+        let command = request.command();
+        match request {
+            Request::Version => {
+                let mut version = String::new();
+                for c in handler.version().chars().filter(char::is_ascii) {
+                    if version.push(c).is_err() {
+                        break;
+                    }
+                }
+                Ok(Reply::Version(version))
+            }
+
+            Request::Dump { kind, index, chunk } => {
+                let size = handler.size(kind, index).ok_or(Error::NotFound)?;
+                let offset = chunk as u32 * CHUNK_SIZE as u32;
+                if offset > size || (offset == size && chunk != 0) {
+                    return Err(Error::Malformed);
+                }
+                let len = (size - offset).min(CHUNK_SIZE as u32) as usize;
+                let mut data = Chunk::new();
+                let _ = data.resize(len, 0);
+                handler.read(kind, index, offset, &mut data)?;
+                Ok(Reply::Data {
+                    kind,
+                    index,
+                    chunk,
+                    size,
+                    data,
+                })
+            }
+
+            Request::RestoreBegin { kind, index, size } => {
+                if let Some(previous) = self.restore.take() {
+                    let _ =
+                        handler.end_restore(previous.kind, previous.index, previous.file, false);
+                }
+                let file = handler.begin_restore(kind, index, size)?;
+                self.restore = Some(Restore {
+                    kind,
+                    index,
+                    size,
+                    received: 0,
+                    next_chunk: 0,
+                    crc: Crc32::new(),
+                    file,
+                });
+                Ok(Reply::Ack(command))
+            }
+
+            Request::RestoreData {
+                kind,
+                index,
+                chunk,
+                data,
+            } => {
+                let restore = self
+                    .restore
+                    .as_mut()
+                    .filter(|r| r.kind == kind && r.index == index && r.next_chunk == chunk)
+                    .ok_or(Error::OutOfOrder)?;
+                if restore.received + data.len() as u32 > restore.size {
+                    return Err(Error::Malformed);
+                }
+                handler.write(kind, index, restore.received, &data, restore.file.as_mut())?;
+                restore.received += data.len() as u32;
+                restore.next_chunk += 1;
+                restore.crc.update(&data);
+                Ok(Reply::Ack(command))
+            }
+
+            Request::RestoreEnd { kind, index, crc } => {
+                let restore = self
+                    .restore
+                    .take_if(|r| r.kind == kind && r.index == index)
+                    .ok_or(Error::OutOfOrder)?;
+                let complete = restore.received == restore.size && restore.crc.finish() == crc;
+                handler.end_restore(kind, index, restore.file, complete)?;
+                if complete {
+                    Ok(Reply::Ack(command))
+                } else {
+                    Err(Error::Checksum)
+                }
+            }
+
+            Request::Key { key, action } => {
+                let key = *Keys::LIST.get(key as usize).ok_or(Error::Malformed)?;
+                handler.key(key, action)?;
+                Ok(Reply::Ack(command))
+            }
+
+            Request::Screen { page } => {
+                if page >= SCREEN_PAGES {
+                    return Err(Error::Malformed);
+                }
+                let mut data = [0; SCREEN_PAGE_SIZE];
+                handler.screen(page, &mut data)?;
+                Ok(Reply::Screen { page, data })
+            }
+
+            Request::Bootloader => {
+                handler.bootloader()?;
+                Ok(Reply::Ack(command))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{RamFlash, SECTOR_SIZE};
+    use std::vec::Vec;
+
+    #[test]
+    fn pack_unpack() {
+        let data: Vec<u8> = (0..=255).collect();
+        for len in [0, 1, 6, 7, 8, 100, 256] {
+            let mut packed = [0; packed_len(256)];
+            let packed_size = pack(&data[..len], &mut packed).unwrap();
+            assert_eq!(packed_size, packed_len(len));
+            assert!(packed[..packed_size].iter().all(|&b| b < 0x80));
+
+            let mut out = [0; 256];
+            assert_eq!(unpack(&packed[..packed_size], &mut out), Some(len));
+            assert_eq!(&out[..len], &data[..len]);
+        }
+    }
+
+    #[test]
+    fn message_sizes() {
+        let request = Request::RestoreData {
+            kind: Kind::Project,
+            index: 3,
+            chunk: 1000,
+            data: Chunk::from_slice(&[0xFF; CHUNK_SIZE]).unwrap(),
+        };
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = request.encode(&mut buffer).unwrap();
+        assert_eq!(Request::decode(&buffer[..len]), Ok(request));
+
+        let reply = Reply::Data {
+            kind: Kind::Pattern,
+            index: 127,
+            chunk: 0x3FFF,
+            size: 0x0FFF_FFFF,
+            data: Chunk::from_slice(&[0x80; CHUNK_SIZE]).unwrap(),
+        };
+        let len = reply.encode(&mut buffer).unwrap();
+        assert_eq!(len, MAX_MESSAGE_SIZE);
+        assert_eq!(Reply::decode(&buffer[..len]), Some(reply));
+    }
+
+    type TestHandler<'a> = DeviceHandler<
+        'a,
+        RamFlash<{ 2 * SECTOR_SIZE as usize }>,
+        RamFlash<{ 8 * SECTOR_SIZE as usize }>,
+    >;
+
+    fn request(server: &mut Server, handler: &mut TestHandler, request: Request) -> Reply {
+        let mut message = [0; MAX_MESSAGE_SIZE];
+        let len = request.encode(&mut message).unwrap();
+        let mut reply = [0; MAX_MESSAGE_SIZE];
+        let len = server.handle(&message[..len], handler, &mut reply).unwrap();
+        Reply::decode(&reply[..len]).unwrap()
+    }
+
+    /// Dump an item chunk by chunk
+    fn dump(
+        server: &mut Server,
+        handler: &mut TestHandler,
+        kind: Kind,
+        index: u8,
+    ) -> Option<Vec<u8>> {
+        let mut item = Vec::new();
+        for chunk in 0.. {
+            match request(server, handler, Request::Dump { kind, index, chunk }) {
+                Reply::Data { size, data, .. } => {
+                    item.extend_from_slice(&data);
+                    if item.len() == size as usize {
+                        return Some(item);
+                    }
+                }
+                Reply::Nak(_, Error::NotFound) => return None,
+                reply => panic!("{reply:?}"),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Restore an item, with a wrong CRC if `corrupt`
+    fn restore(
+        server: &mut Server,
+        handler: &mut TestHandler,
+        kind: Kind,
+        index: u8,
+        item: &[u8],
+        corrupt: bool,
+    ) -> Reply {
+        let size = item.len() as u32;
+        let reply = request(server, handler, Request::RestoreBegin { kind, index, size });
+        assert_eq!(reply, Reply::Ack(CMD_RESTORE_BEGIN));
+        for (chunk, data) in item.chunks(CHUNK_SIZE).enumerate() {
+            let data = Chunk::from_slice(data).unwrap();
+            let chunk = chunk as u16;
+            let reply = request(
+                server,
+                handler,
+                Request::RestoreData {
+                    kind,
+                    index,
+                    chunk,
+                    data,
+                },
+            );
+            assert_eq!(reply, Reply::Ack(CMD_RESTORE_DATA));
+        }
+        let crc = crate::crc::crc32(item) ^ corrupt as u32;
+        request(server, handler, Request::RestoreEnd { kind, index, crc })
+    }
+
+    #[test]
+    fn device_handler() {
+        let mut settings = Settings::new(RamFlash::new()).unwrap();
+        let mut fs = Fs::new(RamFlash::new()).unwrap();
+        settings.set_bytes("volume", &[100]).unwrap();
+        settings.set_bytes("name", b"PGB").unwrap();
+        let mut handler = DeviceHandler::new(&mut settings, &mut fs);
+        let mut server = Server::new();
+
+        let backup = dump(&mut server, &mut handler, Kind::Settings, 0).unwrap();
+        assert_eq!(backup, b"\x06volume\x01\x64\x04name\x03PGB");
+        assert_eq!(dump(&mut server, &mut handler, Kind::Settings, 1), None);
+        let mut part = [0; 4];
+        handler.read(Kind::Settings, 0, 7, &mut part).unwrap();
+        assert_eq!(&part, b"\x01\x64\x04n");
+
+        // Projects and patterns, over several chunks
+        let project: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        assert_eq!(dump(&mut server, &mut handler, Kind::Project, 5), None);
+        let reply = restore(&mut server, &mut handler, Kind::Project, 5, &project, false);
+        assert_eq!(reply, Reply::Ack(CMD_RESTORE_END));
+        assert_eq!(
+            dump(&mut server, &mut handler, Kind::Project, 5),
+            Some(project.clone())
+        );
+        let reply = restore(&mut server, &mut handler, Kind::Pattern, 127, &[], false);
+        assert_eq!(reply, Reply::Ack(CMD_RESTORE_END));
+        assert_eq!(
+            dump(&mut server, &mut handler, Kind::Pattern, 127),
+            Some(Vec::new())
+        );
+        assert_eq!(fs.metadata("/projects/005").unwrap().size, 300);
+        assert!(fs.exists("/patterns/127"));
+
+        // A failed restore changes nothing
+        let mut handler = DeviceHandler::new(&mut settings, &mut fs);
+        let reply = restore(
+            &mut server,
+            &mut handler,
+            Kind::Project,
+            5,
+            &[1, 2, 3],
+            true,
+        );
+        assert_eq!(reply, Reply::Nak(CMD_RESTORE_END, Error::Checksum));
+        assert_eq!(
+            dump(&mut server, &mut handler, Kind::Project, 5),
+            Some(project)
+        );
+        let reply = restore(
+            &mut server,
+            &mut handler,
+            Kind::Settings,
+            0,
+            &[1, b'a'],
+            true,
+        );
+        assert_eq!(reply, Reply::Nak(CMD_RESTORE_END, Error::Checksum));
+        assert_eq!(
+            dump(&mut server, &mut handler, Kind::Settings, 0),
+            Some(backup.clone())
+        );
+
+        // Restored settings are set, the others kept
+        let reply = restore(
+            &mut server,
+            &mut handler,
+            Kind::Settings,
+            0,
+            b"\x06volume\x01\x50\x04mode\x00",
+            false,
+        );
+        assert_eq!(reply, Reply::Ack(CMD_RESTORE_END));
+        assert_eq!(settings.get::<u8>("volume").unwrap(), Some(0x50));
+        assert_eq!(settings.get_bytes("mode", &mut []).unwrap(), Some(0));
+        assert_eq!(settings.get_bytes("name", &mut []).unwrap(), Some(3));
+        assert!(!fs.exists(SETTINGS_RESTORE_PATH));
+
+        // Truncated record
+        let mut handler = DeviceHandler::new(&mut settings, &mut fs);
+        let reply = restore(
+            &mut server,
+            &mut handler,
+            Kind::Settings,
+            0,
+            b"\x06vol",
+            false,
+        );
+        assert_eq!(reply, Reply::Nak(CMD_RESTORE_END, Error::Malformed));
+        let key = Request::Key {
+            key: 0,
+            action: KeyAction::Tap,
+        };
+        assert_eq!(
+            request(&mut server, &mut handler, key),
+            Reply::Nak(CMD_KEY, Error::Unsupported)
+        );
+        assert_eq!(
+            request(&mut server, &mut handler, Request::Bootloader),
+            Reply::Ack(CMD_BOOTLOADER)
+        );
+    }
+
+    #[test]
+    fn handler_per_message() {
+        let mut settings = Settings::new(RamFlash::new()).unwrap();
+        let mut fs = Fs::new(RamFlash::new()).unwrap();
+        let mut server = Server::new();
+        let mut send = |server: &mut Server, message: Request| {
+            let mut handler = DeviceHandler::new(&mut settings, &mut fs);
+            request(server, &mut handler, message)
+        };
+
+        let (kind, index) = (Kind::Project, 9);
+        let project: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let size = project.len() as u32;
+        let reply = send(&mut server, Request::RestoreBegin { kind, index, size });
+        assert_eq!(reply, Reply::Ack(CMD_RESTORE_BEGIN));
+        for (chunk, data) in project.chunks(CHUNK_SIZE).enumerate() {
+            let data = Chunk::from_slice(data).unwrap();
+            let chunk = chunk as u16;
+            let reply = send(
+                &mut server,
+                Request::RestoreData {
+                    kind,
+                    index,
+                    chunk,
+                    data,
+                },
+            );
+            assert_eq!(reply, Reply::Ack(CMD_RESTORE_DATA));
+        }
+        let crc = crate::crc::crc32(&project);
+        let reply = send(&mut server, Request::RestoreEnd { kind, index, crc });
+        assert_eq!(reply, Reply::Ack(CMD_RESTORE_END));
+
+        let mut restored = std::vec![0; 1001];
+        let path = crate::project::path(index);
+        assert_eq!(fs.read(&path, 0, &mut restored), Ok(1000));
+        assert_eq!(restored[..1000], project);
+    }
+
+    #[test]
+    fn screen_capture() {
+        use embedded_graphics::pixelcolor::BinaryColor;
+        use embedded_graphics::prelude::*;
+
+        let mut settings = Settings::new(RamFlash::new()).unwrap();
+        let mut fs = Fs::new(RamFlash::new()).unwrap();
+        let mut frame = FrameBuffer::new();
+        Pixel(Point::new(3, 9), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        assert!(frame.pixel(3, 9));
+        // Out of the screen, not the next page
+        assert!(!frame.pixel(3 + 128, 1));
+        assert!(!frame.pixel(3, 64 + 9));
+
+        let mut server = Server::new();
+        let mut handler = DeviceHandler::new(&mut settings, &mut fs);
+        let page = Request::Screen { page: 1 };
+        assert_eq!(
+            request(&mut server, &mut handler, page.clone()),
+            Reply::Nak(CMD_SCREEN, Error::Unsupported)
+        );
+        let mut handler = handler.screen(&frame);
+        let mut data = [0; SCREEN_PAGE_SIZE];
+        data[3] = 1 << 1;
+        assert_eq!(
+            request(&mut server, &mut handler, page),
+            Reply::Screen { page: 1, data }
+        );
+    }
+}
@@ -0,0 +1,1111 @@
+//! User interface widgets for the OLED screen
+//!
+//! Widgets draw on any `DrawTarget` with `BinaryColor` pixels, such as
+//! `Peripherals::display`, and take their input from a `KeyState`, the
+//! state of the last scan of the `KeyboardMatrix`:
+//!
+//! ```ignore
+//! periph.keyboard.scan(&mut periph.delay);
+//! let keys = periph.keyboard.keys();
+//! if let Some(event) = menu.handle_keys(&keys, &mut params) {
+//!     // ...
+//! }
+//! ```
+
+use core::fmt::Write;
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::String;
+use ssd1306::{mode::BufferedGraphicsMode, prelude::*, Ssd1306};
+
+use smart_leds::RGB8;
+
+use crate::generate::{self, Generator, PARAM_COUNT};
+use crate::history::History;
+use crate::info::FirmwareInfo;
+use crate::midi::clock::{MAX_BPM, MIN_BPM};
+use crate::param::{ParamId, ParamSet};
+use crate::project::{Pattern, Project, Sequence, SongLoop, SongRow, PAGE_STEPS, PATTERNS, TRACKS};
+use crate::{KeyState, Keys};
+
+pub const SCREEN_WIDTH: u32 = 128;
+pub const SCREEN_HEIGHT: u32 = 64;
+
+/// Height of a line of text with the menu font
+pub const LINE_HEIGHT: u32 = 10;
+
+/// Draw a line of text, inverted when `selected`
+pub fn draw_line<D>(
+    target: &mut D,
+    row: u32,
+    left: &str,
+    right: &str,
+    selected: bool,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let y = (row * LINE_HEIGHT) as i32;
+    let (bg, fg) = if selected {
+        (BinaryColor::On, BinaryColor::Off)
+    } else {
+        (BinaryColor::Off, BinaryColor::On)
+    };
+
+    Rectangle::new(Point::new(0, y), Size::new(SCREEN_WIDTH, LINE_HEIGHT))
+        .into_styled(PrimitiveStyle::with_fill(bg))
+        .draw(target)?;
+
+    let style = MonoTextStyle::new(&FONT_6X10, fg);
+    Text::with_baseline(left, Point::new(1, y), style, Baseline::Top).draw(target)?;
+
+    let right_aligned = TextStyleBuilder::new()
+        .alignment(Alignment::Right)
+        .baseline(Baseline::Top)
+        .build();
+    Text::with_text_style(
+        right,
+        Point::new(SCREEN_WIDTH as i32 - 1, y),
+        style,
+        right_aligned,
+    )
+    .draw(target)?;
+    Ok(())
+}
+
+/// Draw the application name, version and build information, to show at
+/// startup
+pub fn draw_boot_screen<D>(target: &mut D, info: &FirmwareInfo) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    target.clear(BinaryColor::Off)?;
+
+    let centered = TextStyleBuilder::new()
+        .alignment(Alignment::Center)
+        .baseline(Baseline::Top)
+        .build();
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let center = SCREEN_WIDTH as i32 / 2;
+
+    let mut board: String<16> = String::new();
+    let _ = write!(board, "PGB-1 rev {}", info.board_revision());
+
+    let lines = [
+        info.name(),
+        info.version(),
+        "",
+        info.git_hash(),
+        info.build_date(),
+        board.as_str(),
+    ];
+    for (row, line) in lines.iter().enumerate() {
+        let y = 2 + (row as u32 * LINE_HEIGHT) as i32;
+        Text::with_text_style(line, Point::new(center, y), style, centered).draw(target)?;
+    }
+    Ok(())
+}
+
+/// Number of 8 pixel high pages of the screen
+pub const SCREEN_PAGES: usize = SCREEN_HEIGHT as usize / 8;
+
+/// A copy of the screen that can be read back, for instance to send a
+/// screenshot over SysEx
+///
+/// The layout is the one of the SSD1306: 8 pages of 128 columns, one byte
+/// per column with the top pixel in bit 0.
+pub struct FrameBuffer {
+    buffer: [u8; SCREEN_WIDTH as usize * SCREEN_PAGES],
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameBuffer {
+    pub const fn new() -> Self {
+        FrameBuffer {
+            buffer: [0; SCREEN_WIDTH as usize * SCREEN_PAGES],
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Columns of a page, `None` if `page` is out of the screen
+    pub fn page(&self, page: usize) -> Option<&[u8]> {
+        let width = SCREEN_WIDTH as usize;
+        self.buffer.get(page * width..(page + 1) * width)
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+            return false;
+        }
+        let index = (y / 8 * SCREEN_WIDTH + x) as usize;
+        self.buffer
+            .get(index)
+            .is_some_and(|byte| byte & (1 << (y % 8)) != 0)
+    }
+
+    /// Copy to the display buffer, `flush` then sends it to the screen
+    pub fn show<DI>(
+        &self,
+        display: &mut Ssd1306<DI, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>,
+    ) where
+        DI: WriteOnlyDataCommand,
+    {
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                display.set_pixel(x, y, self.pixel(x, y));
+            }
+        }
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        Size::new(SCREEN_WIDTH, SCREEN_HEIGHT)
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) else {
+                continue;
+            };
+            if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+                continue;
+            }
+            let byte = &mut self.buffer[(y / 8 * SCREEN_WIDTH + x) as usize];
+            let bit = 1 << (y % 8);
+            if color.is_on() {
+                *byte |= bit;
+            } else {
+                *byte &= !bit;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.buffer.fill(if color.is_on() { 0xFF } else { 0x00 });
+        Ok(())
+    }
+}
+
+/// Result of a key press in a menu
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MenuEvent {
+    /// The selected parameter changed
+    Selected(ParamId),
+    /// A parameter value changed
+    Changed(ParamId, i16),
+}
+
+/// A scrolling list of parameters: UP/DOWN select a parameter, LEFT/RIGHT
+/// change its value, holding ALT changes it ten times faster.
+pub struct ParamMenu {
+    /// Parameters displayed by the menu
+    items: &'static [ParamId],
+    selected: usize,
+    scroll: usize,
+}
+
+impl ParamMenu {
+    /// Number of lines visible on screen
+    pub const VISIBLE_LINES: usize = (SCREEN_HEIGHT / LINE_HEIGHT) as usize;
+
+    pub const fn new(items: &'static [ParamId]) -> Self {
+        ParamMenu {
+            items,
+            selected: 0,
+            scroll: 0,
+        }
+    }
+
+    /// Currently selected parameter
+    pub fn selected(&self) -> Option<ParamId> {
+        self.items.get(self.selected).copied()
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if index < self.items.len() {
+            self.selected = index;
+            if self.selected < self.scroll {
+                self.scroll = self.selected;
+            } else if self.selected >= self.scroll + Self::VISIBLE_LINES {
+                self.scroll = self.selected + 1 - Self::VISIBLE_LINES;
+            }
+        }
+    }
+
+    /// Handle the keys pressed since the last keyboard scan
+    pub fn handle_keys<const N: usize>(
+        &mut self,
+        keys: &KeyState,
+        params: &mut ParamSet<N>,
+    ) -> Option<MenuEvent> {
+        if keys.falling(Keys::UP) && self.selected > 0 {
+            self.select(self.selected - 1);
+            return self.selected().map(MenuEvent::Selected);
+        }
+        if keys.falling(Keys::DOWN) && self.selected + 1 < self.items.len() {
+            self.select(self.selected + 1);
+            return self.selected().map(MenuEvent::Selected);
+        }
+
+        let step = if keys.pressed(Keys::ALT) { 10 } else { 1 };
+        let delta = if keys.falling(Keys::RIGHT) {
+            step
+        } else if keys.falling(Keys::LEFT) {
+            -step
+        } else {
+            return None;
+        };
+
+        let id = self.selected()?;
+        params.step(id, delta);
+        Some(MenuEvent::Changed(id, params.get(id)))
+    }
+
+    pub fn draw<D, const N: usize>(
+        &self,
+        target: &mut D,
+        params: &ParamSet<N>,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let visible = self
+            .items
+            .iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(Self::VISIBLE_LINES);
+
+        for (row, (index, &id)) in visible.enumerate() {
+            let name = params.info(id).map_or("", |info| info.name);
+            let mut value: String<16> = String::new();
+            // Values that do not fit in the buffer are cut short
+            let _ = params.write_value(id, &mut value);
+            draw_line(target, row as u32, name, &value, index == self.selected)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of a key press in the lock editor
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LockEvent {
+    Changed {
+        step: usize,
+        param: ParamId,
+        value: i16,
+    },
+    /// The locks of the step were removed
+    Cleared { step: usize },
+    /// No lock left in the pattern, see `project::MAX_LOCKS`
+    Full,
+}
+
+/// Parameter locks of a track: hold a step key K1..K16 and press UP/DOWN
+/// to change the selected parameter for that step only, holding ALT changes
+/// it ten times faster. B removes the locks of the held step. A new lock
+/// starts from the value of the track. The changes are recorded in a
+/// `History`.
+///
+/// Call `handle_keys` before `ParamMenu::handle_keys`, and leave the menu
+/// alone while a step is `held`.
+pub struct LockEditor {
+    track: usize,
+    page: usize,
+    held: Option<usize>,
+}
+
+impl Default for LockEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockEditor {
+    pub const fn new() -> Self {
+        LockEditor {
+            track: 0,
+            page: 0,
+            held: None,
+        }
+    }
+
+    pub fn track(&self) -> usize {
+        self.track
+    }
+
+    pub fn set_track(&mut self, track: usize) {
+        self.track = track;
+    }
+
+    /// Page of 16 steps shown on the step keys
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    pub fn set_page(&mut self, page: usize) {
+        self.page = page;
+    }
+
+    /// Step of the held key
+    pub fn held(&self) -> Option<usize> {
+        self.held
+    }
+
+    /// Handle the keys pressed since the last keyboard scan for a pattern of
+    /// the project, `param` is the parameter selected in the menu and
+    /// `params` the values of the track
+    pub fn handle_keys<const N: usize, const H: usize>(
+        &mut self,
+        keys: &KeyState,
+        project: &mut Project,
+        pattern: usize,
+        history: &mut History<H>,
+        params: &ParamSet<N>,
+        param: ParamId,
+    ) -> Option<LockEvent> {
+        self.held = Keys::STEPS
+            .iter()
+            .position(|&k| keys.pressed(k))
+            .map(|i| self.page * PAGE_STEPS + i);
+        let step = self.held?;
+
+        if keys.falling(Keys::B) {
+            history.clear_locks(project, pattern, self.track, step);
+            return Some(LockEvent::Cleared { step });
+        }
+
+        let size = if keys.pressed(Keys::ALT) { 10 } else { 1 };
+        let delta = if keys.falling(Keys::UP) {
+            size
+        } else if keys.falling(Keys::DOWN) {
+            -size
+        } else {
+            return None;
+        };
+
+        let info = params.info(param)?;
+        let lock = u8::try_from(param).ok()?;
+        let current = project.patterns[pattern]
+            .lock(self.track, step, lock)
+            .unwrap_or(params.get(param));
+        let value = info.clamp(current as i32 + delta);
+        match history.set_lock(project, pattern, self.track, step, lock, Some(value)) {
+            Ok(()) => Some(LockEvent::Changed { step, param, value }),
+            Err(_) => Some(LockEvent::Full),
+        }
+    }
+}
+
+/// Number of LEDs, see `Keys::led_index`
+pub const LED_COUNT: usize = 24;
+
+pub const STEP_COLOR: RGB8 = RGB8::new(0, 0, 48);
+pub const LOCKED_STEP_COLOR: RGB8 = RGB8::new(48, 16, 0);
+pub const PLAYING_STEP_COLOR: RGB8 = RGB8::new(64, 64, 64);
+
+/// Light the step keys for a page of a track: active steps, steps with
+/// parameter locks, and the step being played
+pub fn step_leds(
+    leds: &mut [RGB8; LED_COUNT],
+    pattern: &Pattern,
+    track: usize,
+    page: usize,
+    playing: Option<usize>,
+) {
+    let sequence = &pattern.tracks[track];
+    for (i, key) in Keys::STEPS.iter().enumerate() {
+        let step = page * PAGE_STEPS + i;
+        leds[key.led_index()] = if playing == Some(step) {
+            PLAYING_STEP_COLOR
+        } else if step >= sequence.length as usize {
+            RGB8::default()
+        } else if pattern.is_locked(track, step) {
+            LOCKED_STEP_COLOR
+        } else if sequence.steps[step].active {
+            STEP_COLOR
+        } else {
+            RGB8::default()
+        };
+    }
+}
+
+/// Pattern of a step key pressed while PATT is held, to queue with
+/// `Sequencer::queue_pattern`
+pub fn pattern_key(keys: &KeyState) -> Option<usize> {
+    if !keys.pressed(Keys::PATT) {
+        return None;
+    }
+    Keys::STEPS
+        .iter()
+        .position(|&k| keys.falling(k))
+        .filter(|&pattern| pattern < PATTERNS)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HistoryKey {
+    Undo,
+    Redo,
+}
+
+/// ALT+TRACK to undo the last edit, ALT+STEP to redo it, see
+/// `history::History`
+pub fn history_key(keys: &KeyState) -> Option<HistoryKey> {
+    if !keys.pressed(Keys::ALT) {
+        None
+    } else if keys.falling(Keys::TRACK) {
+        Some(HistoryKey::Undo)
+    } else if keys.falling(Keys::STEP) {
+        Some(HistoryKey::Redo)
+    } else {
+        None
+    }
+}
+
+/// Result of a key press in the song editor
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SongEvent {
+    Selected(usize),
+    /// Pattern, repeats, tempo or mutes of a row changed
+    Changed(usize),
+    Inserted(usize),
+    Removed(usize),
+    LoopChanged(Option<SongLoop>),
+    /// No row left, see `project::MAX_SONG_ROWS`
+    Full,
+}
+
+/// Editor of the song arrangement:
+///
+/// - UP/DOWN select a row, K1..K16 set its pattern and LEFT/RIGHT its
+///   number of repeats.
+/// - ALT+LEFT/RIGHT change the tempo of the row by 1 BPM, below the
+///   minimum the row keeps the tempo of the previous rows.
+/// - TRACK+K1..K8 mute or unmute a track during the row.
+/// - A inserts a copy of the row after it, B removes it.
+/// - ALT+A starts the loop at the row, ALT+B ends it there. ALT+A on the
+///   first row of the loop removes the loop.
+///
+/// The song is not part of a `History`, its edits cannot be undone.
+pub struct SongEditor {
+    selected: usize,
+    scroll: usize,
+}
+
+impl Default for SongEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SongEditor {
+    /// Number of rows visible on screen, the last line shows the mutes
+    pub const VISIBLE_ROWS: usize = (SCREEN_HEIGHT / LINE_HEIGHT) as usize - 1;
+
+    pub const fn new() -> Self {
+        SongEditor {
+            selected: 0,
+            scroll: 0,
+        }
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn select(&mut self, row: usize) {
+        self.selected = row;
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + Self::VISIBLE_ROWS {
+            self.scroll = self.selected + 1 - Self::VISIBLE_ROWS;
+        }
+    }
+
+    /// Handle the keys pressed since the last keyboard scan
+    pub fn handle_keys(&mut self, keys: &KeyState, project: &mut Project) -> Option<SongEvent> {
+        let alt = keys.pressed(Keys::ALT);
+        let rows = project.song.len();
+        if rows > 0 && self.selected >= rows {
+            self.select(rows - 1);
+        }
+        let index = self.selected;
+
+        if keys.falling(Keys::UP) && index > 0 {
+            self.select(index - 1);
+            return Some(SongEvent::Selected(self.selected));
+        }
+        if keys.falling(Keys::DOWN) && index + 1 < rows {
+            self.select(index + 1);
+            return Some(SongEvent::Selected(self.selected));
+        }
+
+        if keys.falling(Keys::A) {
+            if alt {
+                return self.set_loop(project, true);
+            }
+            let row = project.song.get(index).copied().unwrap_or(SongRow::new(0));
+            let at = if rows == 0 { 0 } else { index + 1 };
+            if project.insert_row(at, row).is_err() {
+                return Some(SongEvent::Full);
+            }
+            self.select(at);
+            return Some(SongEvent::Inserted(at));
+        }
+        if keys.falling(Keys::B) {
+            if alt {
+                return self.set_loop(project, false);
+            }
+            if index >= rows {
+                return None;
+            }
+            project.remove_row(index);
+            self.select(index.min(rows.saturating_sub(2)));
+            return Some(SongEvent::Removed(index));
+        }
+
+        let row = project.song.get_mut(index)?;
+        let key = Keys::STEPS.iter().position(|&k| keys.falling(k));
+        if let Some(key) = key {
+            if keys.pressed(Keys::TRACK) {
+                if key >= TRACKS {
+                    return None;
+                }
+                row.mutes ^= 1 << key;
+            } else {
+                row.pattern = key as u8;
+            }
+            return Some(SongEvent::Changed(index));
+        }
+
+        let delta = if keys.falling(Keys::RIGHT) {
+            1
+        } else if keys.falling(Keys::LEFT) {
+            -1
+        } else {
+            return None;
+        };
+        if alt {
+            let (min, max) = (MIN_BPM * 10, MAX_BPM * 10);
+            row.tempo = match (row.tempo, delta) {
+                (0, 1) => project.tempo.clamp(min, max),
+                (0, _) => 0,
+                (tempo, -1) if tempo <= min => 0,
+                (tempo, _) => (tempo as i32 + delta * 10).clamp(min as i32, max as i32) as u16,
+            };
+        } else {
+            row.repeats = (row.repeats as i32 + delta).clamp(1, 99) as u8;
+        }
+        Some(SongEvent::Changed(index))
+    }
+
+    fn set_loop(&self, project: &mut Project, start: bool) -> Option<SongEvent> {
+        let row = self.selected as u8;
+        if self.selected >= project.song.len() {
+            return None;
+        }
+        project.song_loop = match (project.song_loop, start) {
+            (Some(song_loop), true) if song_loop.start == row => None,
+            (Some(song_loop), true) => Some(SongLoop {
+                start: row,
+                end: song_loop.end.max(row),
+            }),
+            (Some(song_loop), false) => Some(SongLoop {
+                start: song_loop.start.min(row),
+                end: row,
+            }),
+            (None, _) => Some(SongLoop {
+                start: row,
+                end: row,
+            }),
+        };
+        Some(SongEvent::LoopChanged(project.song_loop))
+    }
+
+    /// Draw the rows, with the loop, and the row being played, `playing`
+    /// from `Sequencer::song_row`
+    pub fn draw<D>(
+        &self,
+        target: &mut D,
+        project: &Project,
+        playing: Option<usize>,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        target.clear(BinaryColor::Off)?;
+        if project.song.is_empty() {
+            return draw_line(target, 0, "Empty song", "A: add", false);
+        }
+
+        let visible = project
+            .song
+            .iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(Self::VISIBLE_ROWS);
+        for (line, (index, row)) in visible.enumerate() {
+            let marker = match project.song_loop {
+                Some(l) if index == l.start as usize => '[',
+                Some(l) if index == l.end as usize => ']',
+                Some(l) if (l.start as usize..=l.end as usize).contains(&index) => '|',
+                _ => ' ',
+            };
+            let play = if playing == Some(index) { '>' } else { ' ' };
+            let mut left: String<16> = String::new();
+            let _ = write!(
+                left,
+                "{}{:02}{}P{:02} x{}",
+                marker,
+                index + 1,
+                play,
+                row.pattern + 1,
+                row.repeats
+            );
+            let mut right: String<8> = String::new();
+            if row.tempo != 0 {
+                let _ = write!(right, "{}.{}", row.tempo / 10, row.tempo % 10);
+            }
+            draw_line(target, line as u32, &left, &right, index == self.selected)?;
+        }
+
+        let mutes = project.song.get(self.selected).map_or(0, |row| row.mutes);
+        let mut tracks: String<TRACKS> = String::new();
+        for track in 0..TRACKS {
+            let _ = tracks.push(if mutes & (1 << track) != 0 { 'M' } else { '-' });
+        }
+        draw_line(target, Self::VISIBLE_ROWS as u32, "Mutes", &tracks, false)
+    }
+}
+
+/// Result of a key press in the generator menu
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GeneratorEvent {
+    Selected(ParamId),
+    /// A setting changed and the preview was generated again
+    Changed(ParamId, i16),
+    /// The preview replaced the steps of the track
+    Committed,
+    Cancelled,
+}
+
+pub const PREVIEW_COLOR: RGB8 = RGB8::new(0, 40, 16);
+
+/// Menu of the pattern generators, see `generate`: UP/DOWN select a setting
+/// and LEFT/RIGHT change it as in a `ParamMenu`. Each change generates the
+/// steps again from those of the track, `preview_leds` shows them on the
+/// step keys until A replaces the track with them, as one edit of a
+/// `History`, or B cancels.
+pub struct GeneratorMenu {
+    menu: ParamMenu,
+    params: ParamSet<PARAM_COUNT>,
+    pattern: usize,
+    track: usize,
+    preview: Sequence,
+}
+
+impl Default for GeneratorMenu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GeneratorMenu {
+    pub fn new() -> Self {
+        let params = ParamSet::new(&generate::PARAMS);
+        GeneratorMenu {
+            menu: ParamMenu::new(Generator::from_params(&params).items()),
+            params,
+            pattern: 0,
+            track: 0,
+            preview: Sequence::new(),
+        }
+    }
+
+    /// Start generating steps for a track of a pattern of the project, with
+    /// the last settings
+    pub fn open(&mut self, project: &Project, pattern: usize, track: usize) {
+        self.pattern = pattern;
+        self.track = track;
+        self.generate(project);
+    }
+
+    pub fn pattern(&self) -> usize {
+        self.pattern
+    }
+
+    pub fn track(&self) -> usize {
+        self.track
+    }
+
+    pub fn generator(&self) -> Generator {
+        Generator::from_params(&self.params)
+    }
+
+    /// Steps that would replace those of the track
+    pub fn preview(&self) -> &Sequence {
+        &self.preview
+    }
+
+    fn generate(&mut self, project: &Project) {
+        self.preview = project.patterns[self.pattern].tracks[self.track].clone();
+        self.generator().fill(&mut self.preview);
+    }
+
+    /// Handle the keys pressed since the last keyboard scan
+    pub fn handle_keys<const H: usize>(
+        &mut self,
+        keys: &KeyState,
+        project: &mut Project,
+        history: &mut History<H>,
+    ) -> Option<GeneratorEvent> {
+        if keys.falling(Keys::A) {
+            history.set_sequence(project, self.pattern, self.track, &self.preview);
+            return Some(GeneratorEvent::Committed);
+        }
+        if keys.falling(Keys::B) {
+            return Some(GeneratorEvent::Cancelled);
+        }
+
+        match self.menu.handle_keys(keys, &mut self.params)? {
+            MenuEvent::Selected(id) => Some(GeneratorEvent::Selected(id)),
+            MenuEvent::Changed(id, value) => {
+                if id == generate::KIND {
+                    // The settings of the new kind, with the kind selected
+                    self.menu = ParamMenu::new(self.generator().items());
+                }
+                self.generate(project);
+                Some(GeneratorEvent::Changed(id, value))
+            }
+        }
+    }
+
+    pub fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        target.clear(BinaryColor::Off)?;
+        self.menu.draw(target, &self.params)
+    }
+
+    /// Light the step keys with a page of the preview
+    pub fn preview_leds(&self, leds: &mut [RGB8; LED_COUNT], page: usize) {
+        for (i, key) in Keys::STEPS.iter().enumerate() {
+            let step = page * PAGE_STEPS + i;
+            let active = step < self.preview.length as usize && self.preview.steps[step].active;
+            leds[key.led_index()] = if active {
+                PREVIEW_COLOR
+            } else {
+                RGB8::default()
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::param::ParamInfo;
+    use crate::project::{MAX_LOCKS, MAX_SONG_ROWS, MAX_STEPS};
+
+    const CUTOFF: ParamId = 0;
+    const RESONANCE: ParamId = 1;
+    static PARAMS: [ParamInfo; 2] = [
+        ParamInfo::new("Cutoff", 0, 100, 50),
+        ParamInfo::new("Reso", 0, 10, 0),
+    ];
+
+    /// Press `keys` together, none held before
+    fn tap(keys: &[Keys]) -> KeyState {
+        KeyState::default().scan(keys)
+    }
+
+    #[test]
+    fn lock_editor() {
+        let params = ParamSet::new(&PARAMS);
+        let mut project = Box::new(Project::new());
+        let mut history = History::<256>::new();
+        let mut editor = LockEditor::new();
+        editor.set_track(2);
+
+        // Nothing held
+        let keys = KeyState::default().scan(&[Keys::UP]);
+        assert_eq!(
+            editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF),
+            None
+        );
+        assert_eq!(editor.held(), None);
+
+        // Hold K3, UP twice: from the track value
+        let keys = keys.scan(&[]).scan(&[Keys::K3]);
+        assert_eq!(
+            editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF),
+            None
+        );
+        assert_eq!(editor.held(), Some(2));
+        let keys = keys.scan(&[Keys::K3, Keys::UP]);
+        assert_eq!(
+            editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF),
+            Some(LockEvent::Changed {
+                step: 2,
+                param: CUTOFF,
+                value: 51
+            })
+        );
+        // UP still held, not a new press
+        let keys = keys.scan(&[Keys::K3, Keys::UP]);
+        assert_eq!(
+            editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF),
+            None
+        );
+        let keys = keys.scan(&[Keys::K3]).scan(&[Keys::K3, Keys::UP]);
+        editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF);
+        assert_eq!(project.patterns[0].lock(2, 2, CUTOFF as u8), Some(52));
+        assert_eq!(project.patterns[0].lock(1, 2, CUTOFF as u8), None);
+
+        // ALT+DOWN, clamped to the range of the parameter
+        let keys = keys.scan(&[Keys::K3, Keys::ALT, Keys::DOWN]);
+        editor.handle_keys(&keys, &mut project, 0, &mut history, &params, RESONANCE);
+        assert_eq!(project.patterns[0].lock(2, 2, RESONANCE as u8), Some(0));
+        assert_eq!(project.patterns[0].lock(2, 2, CUTOFF as u8), Some(52));
+
+        // Steps of the second page
+        editor.set_page(1);
+        let keys = keys
+            .scan(&[Keys::K1])
+            .scan(&[Keys::K1, Keys::ALT, Keys::UP]);
+        assert_eq!(
+            editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF),
+            Some(LockEvent::Changed {
+                step: 16,
+                param: CUTOFF,
+                value: 60
+            })
+        );
+        assert!(project.patterns[0].is_locked(2, 16));
+
+        // B clears the locks of the held step only
+        editor.set_page(0);
+        let keys = keys.scan(&[Keys::K3]).scan(&[Keys::K3, Keys::B]);
+        assert_eq!(
+            editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF),
+            Some(LockEvent::Cleared { step: 2 })
+        );
+        assert!(!project.patterns[0].is_locked(2, 2));
+        assert!(project.patterns[0].is_locked(2, 16));
+
+        // Undone at once
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[0].lock(2, 2, CUTOFF as u8), Some(52));
+        assert_eq!(project.patterns[0].lock(2, 2, RESONANCE as u8), Some(0));
+        assert!(history.undo(&mut project));
+        assert!(!project.patterns[0].is_locked(2, 16));
+        while history.redo(&mut project) {}
+        assert!(!project.patterns[0].is_locked(2, 2));
+
+        // No room for a new lock
+        for step in 0..MAX_LOCKS - 1 {
+            let track = step / MAX_STEPS;
+            project.patterns[0]
+                .set_lock(track, step % MAX_STEPS, 1, 0)
+                .unwrap();
+        }
+        let keys = keys.scan(&[Keys::K3]).scan(&[Keys::K3, Keys::UP]);
+        assert_eq!(
+            editor.handle_keys(&keys, &mut project, 0, &mut history, &params, CUTOFF),
+            Some(LockEvent::Full)
+        );
+    }
+
+    #[test]
+    fn shortcut_keys() {
+        let keys = KeyState::default().scan(&[Keys::PATT]);
+        assert_eq!(pattern_key(&keys), None);
+        assert_eq!(pattern_key(&keys.scan(&[Keys::PATT, Keys::K5])), Some(4));
+        // The key alone does not switch patterns
+        assert_eq!(pattern_key(&keys.scan(&[Keys::K5])), None);
+
+        let keys = KeyState::default().scan(&[Keys::ALT]);
+        assert_eq!(
+            history_key(&keys.scan(&[Keys::ALT, Keys::TRACK])),
+            Some(HistoryKey::Undo)
+        );
+        assert_eq!(
+            history_key(&keys.scan(&[Keys::ALT, Keys::STEP])),
+            Some(HistoryKey::Redo)
+        );
+        assert_eq!(history_key(&KeyState::default().scan(&[Keys::TRACK])), None);
+    }
+
+    #[test]
+    fn song_editor() {
+        let mut project = Box::new(Project::new());
+        project.tempo = 1200;
+        let mut editor = SongEditor::new();
+        let mut press =
+            |keys: &[Keys], project: &mut Project| editor.handle_keys(&tap(keys), project);
+
+        // An empty song only takes A
+        assert_eq!(press(&[Keys::K5], &mut project), None);
+        assert_eq!(press(&[Keys::B], &mut project), None);
+        assert_eq!(
+            press(&[Keys::A], &mut project),
+            Some(SongEvent::Inserted(0))
+        );
+        assert_eq!(project.song[..], [SongRow::new(0)]);
+
+        assert_eq!(
+            press(&[Keys::K5], &mut project),
+            Some(SongEvent::Changed(0))
+        );
+        press(&[Keys::RIGHT], &mut project);
+        press(&[Keys::RIGHT], &mut project);
+        assert_eq!((project.song[0].pattern, project.song[0].repeats), (4, 3));
+        for _ in 0..5 {
+            press(&[Keys::LEFT], &mut project);
+        }
+        assert_eq!(project.song[0].repeats, 1);
+
+        // Tempo: from the project one, back to none below the minimum
+        press(&[Keys::ALT, Keys::RIGHT], &mut project);
+        assert_eq!(project.song[0].tempo, 1200);
+        press(&[Keys::ALT, Keys::RIGHT], &mut project);
+        assert_eq!(project.song[0].tempo, 1210);
+        project.song[0].tempo = MIN_BPM * 10;
+        press(&[Keys::ALT, Keys::LEFT], &mut project);
+        assert_eq!(project.song[0].tempo, 0);
+        project.song[0].tempo = 1200;
+
+        assert_eq!(
+            press(&[Keys::TRACK, Keys::K2], &mut project),
+            Some(SongEvent::Changed(0))
+        );
+        assert_eq!(press(&[Keys::TRACK, Keys::K9], &mut project), None);
+        assert_eq!(project.song[0].mutes, 0b10);
+
+        // A inserts a copy after the row
+        assert_eq!(
+            press(&[Keys::A], &mut project),
+            Some(SongEvent::Inserted(1))
+        );
+        assert_eq!(project.song[1], project.song[0]);
+        project.song[1].pattern = 7;
+        assert_eq!(press(&[Keys::DOWN], &mut project), None);
+        assert_eq!(
+            press(&[Keys::UP], &mut project),
+            Some(SongEvent::Selected(0))
+        );
+        assert_eq!(press(&[Keys::UP], &mut project), None);
+
+        // Loop
+        let loop_of = |start, end| Some(SongEvent::LoopChanged(Some(SongLoop { start, end })));
+        assert_eq!(press(&[Keys::ALT, Keys::B], &mut project), loop_of(0, 0));
+        assert_eq!(
+            press(&[Keys::DOWN], &mut project),
+            Some(SongEvent::Selected(1))
+        );
+        assert_eq!(press(&[Keys::ALT, Keys::B], &mut project), loop_of(0, 1));
+        assert_eq!(press(&[Keys::ALT, Keys::A], &mut project), loop_of(1, 1));
+        assert_eq!(
+            press(&[Keys::ALT, Keys::A], &mut project),
+            Some(SongEvent::LoopChanged(None))
+        );
+        press(&[Keys::ALT, Keys::A], &mut project);
+
+        // Removing the only row of the loop removes the loop
+        assert_eq!(press(&[Keys::B], &mut project), Some(SongEvent::Removed(1)));
+        assert_eq!(project.song.len(), 1);
+        assert_eq!(project.song[0].pattern, 4);
+        assert_eq!(project.song_loop, None);
+
+        while project.song.len() < MAX_SONG_ROWS {
+            project.song.push(SongRow::new(0)).unwrap();
+        }
+        assert_eq!(press(&[Keys::A], &mut project), Some(SongEvent::Full));
+    }
+
+    #[test]
+    fn generator_menu() {
+        let active = |sequence: &Sequence| sequence.steps.iter().filter(|s| s.active).count();
+        let mut project = Box::new(Project::new());
+        let mut history = History::<1024>::new();
+        project.patterns[2].tracks[1].steps[1].active = true;
+        let original = project.patterns[2].tracks[1].clone();
+
+        // Euclid, 4 hits over 16 steps
+        let mut menu = GeneratorMenu::new();
+        menu.open(&project, 2, 1);
+        assert_eq!((menu.pattern(), menu.track()), (2, 1));
+        assert_eq!(menu.preview().length, 16);
+        assert_eq!(active(menu.preview()), 4);
+
+        // Changes only regenerate the preview
+        assert_eq!(
+            menu.handle_keys(&tap(&[Keys::DOWN]), &mut project, &mut history),
+            Some(GeneratorEvent::Selected(generate::HITS))
+        );
+        assert_eq!(
+            menu.handle_keys(&tap(&[Keys::RIGHT]), &mut project, &mut history),
+            Some(GeneratorEvent::Changed(generate::HITS, 5))
+        );
+        assert_eq!(active(menu.preview()), 5);
+        assert_eq!(project.patterns[2].tracks[1], original);
+
+        assert_eq!(
+            menu.handle_keys(&tap(&[Keys::B]), &mut project, &mut history),
+            Some(GeneratorEvent::Cancelled)
+        );
+        assert_eq!(project.patterns[2].tracks[1], original);
+
+        // The settings are kept for the next opening, the menu follows the
+        // kind of generator
+        menu.open(&project, 2, 1);
+        assert_eq!(active(menu.preview()), 5);
+        menu.handle_keys(&tap(&[Keys::UP]), &mut project, &mut history);
+        assert_eq!(
+            menu.handle_keys(&tap(&[Keys::RIGHT]), &mut project, &mut history),
+            Some(GeneratorEvent::Changed(generate::KIND, 1))
+        );
+        assert!(matches!(menu.generator(), Generator::Random { .. }));
+        assert_eq!(
+            menu.handle_keys(&tap(&[Keys::DOWN]), &mut project, &mut history),
+            Some(GeneratorEvent::Selected(generate::DENSITY))
+        );
+
+        let preview = menu.preview().clone();
+        assert_ne!(preview, original);
+        assert_eq!(
+            menu.handle_keys(&tap(&[Keys::A]), &mut project, &mut history),
+            Some(GeneratorEvent::Committed)
+        );
+        assert_eq!(project.patterns[2].tracks[1], preview);
+        assert_eq!(project.patterns[2].tracks[0], Sequence::new());
+        assert!(history.undo(&mut project));
+        assert_eq!(project.patterns[2].tracks[1], original);
+        assert!(!history.can_undo());
+    }
+}
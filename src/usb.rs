@@ -0,0 +1,145 @@
+//! USB device
+//!
+//! The PGB-1 enumerates as a composite device: a class compliant MIDI
+//! device (`midi`), a serial port (`serial`) and a drive (`msc`, see
+//! `crate::disk`). The device is serviced
+//! from the main loop: call `Usb::poll` at least every few milliseconds,
+//! then read and write through the classes.
+//!
+//! Like most RP2040 boards, the PGB-1 restarts in the USB bootloader when
+//! the host opens and closes the serial port at 1200 baud, e.g. with
+//! `stty -F /dev/ttyACM0 1200`. See `Usb::set_bootloader_touch`.
+//!
+//! ```ignore
+//! loop {
+//!     periph.usb.poll();
+//!     while let Some(event) = periph.usb.midi.poll() {
+//!         // ...
+//!     }
+//!     let mut buf = [0; 64];
+//!     let len = periph.usb.serial.read(&mut buf);
+//!     shell.input(&buf[..len], &mut app, &mut periph.usb.serial);
+//! }
+//! ```
+
+use core::ptr::addr_of_mut;
+
+pub mod msc;
+pub mod serial;
+
+use rp2040_hal::clocks::UsbClock;
+use rp2040_hal::usb::UsbBus;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+
+use crate::disk::Disk;
+use crate::flash::{self, UNIQUE_ID_SIZE};
+use crate::midi::usb::MidiClass;
+use crate::pac;
+use msc::MscClass;
+use serial::SerialClass;
+
+/// pid.codes test VID/PID, only for development: products shipped to users
+/// must replace it with their own allocated pair, e.g. from pid.codes.
+pub const VID_PID: UsbVidPid = UsbVidPid(0x1209, 0x0001);
+
+pub const MANUFACTURER: &str = "Wee Noise Makers";
+pub const PRODUCT: &str = "PGB-1";
+
+static mut USB_BUS: Option<UsbBusAllocator<UsbBus>> = None;
+
+/// Serial number string, the flash unique ID in hexadecimal
+static mut SERIAL_NUMBER: [u8; 2 * UNIQUE_ID_SIZE] = [0; 2 * UNIQUE_ID_SIZE];
+
+/// Write `id` as uppercase hexadecimal
+fn serial_number(id: &[u8; UNIQUE_ID_SIZE], out: &mut [u8; 2 * UNIQUE_ID_SIZE]) {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    for (byte, pair) in id.iter().zip(out.chunks_exact_mut(2)) {
+        pair[0] = DIGITS[(byte >> 4) as usize];
+        pair[1] = DIGITS[(byte & 0xF) as usize];
+    }
+}
+
+pub struct Usb {
+    device: UsbDevice<'static, UsbBus>,
+    pub midi: MidiClass<'static, UsbBus>,
+    pub serial: SerialClass<'static, UsbBus>,
+    pub msc: MscClass<'static, UsbBus, Disk>,
+    bootloader_touch: bool,
+}
+
+impl Usb {
+    /// Only called once, from `Peripherals::steal`
+    pub(crate) fn new(
+        regs: pac::USBCTRL_REGS,
+        dpram: pac::USBCTRL_DPRAM,
+        clock: UsbClock,
+        resets: &mut pac::RESETS,
+    ) -> Self {
+        let bus = UsbBusAllocator::new(UsbBus::new(regs, dpram, clock, true, resets));
+        // The allocator must outlive the device and classes
+        let bus: &'static UsbBusAllocator<UsbBus> =
+            unsafe { (*addr_of_mut!(USB_BUS)).insert(bus) };
+
+        let midi = MidiClass::new(bus);
+        let serial = SerialClass::new(bus);
+        let msc = MscClass::new(bus);
+
+        // Each unit has its own serial number, so that the host keeps its
+        // settings (e.g. the serial port name) per device
+        let hex = unsafe { &mut *addr_of_mut!(SERIAL_NUMBER) };
+        serial_number(&flash::unique_id(), hex);
+        let serial_number = core::str::from_utf8(hex).unwrap();
+
+        let device = UsbDeviceBuilder::new(bus, VID_PID)
+            .composite_with_iads()
+            .strings(&[StringDescriptors::default()
+                .manufacturer(MANUFACTURER)
+                .product(PRODUCT)
+                .serial_number(serial_number)])
+            .unwrap()
+            .build();
+
+        Usb {
+            device,
+            midi,
+            serial,
+            msc,
+            bootloader_touch: true,
+        }
+    }
+
+    /// Service the USB device, return true when one of the classes may have
+    /// data to read
+    pub fn poll(&mut self) -> bool {
+        let ready = self
+            .device
+            .poll(&mut [&mut self.midi, &mut self.serial, &mut self.msc]);
+        if self.bootloader_touch && self.serial.touched() {
+            crate::reboot_to_bootloader();
+        }
+        ready
+    }
+
+    /// Enable the 1200 baud touch to the bootloader, on by default
+    pub fn set_bootloader_touch(&mut self, enabled: bool) {
+        self.bootloader_touch = enabled;
+    }
+
+    /// True once the host has configured the device
+    pub fn is_configured(&self) -> bool {
+        self.device.state() == UsbDeviceState::Configured
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_numbers() {
+        let mut out = [0; 2 * UNIQUE_ID_SIZE];
+        serial_number(&[0xE6, 0x60, 0x58, 0x38, 0x83, 0x1F, 0x2A, 0x05], &mut out);
+        assert_eq!(&out, b"E6605838831F2A05");
+    }
+}
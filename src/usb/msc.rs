@@ -0,0 +1,699 @@
+//! USB mass storage class
+//!
+//! SCSI over Bulk-Only Transport, the protocol of thumb drives. The class
+//! is always present, like a card reader: the host sees an empty drive
+//! until a disk is `attach`ed, typically when the application enters a
+//! "USB disk" mode.
+//!
+//! When the user ejects the drive the host flushes its writes and sends a
+//! START STOP UNIT command, `ejected` then returns true and the disk can be
+//! `detach`ed and used by the firmware again. Detaching before the eject
+//! is possible, but the host may not have written everything yet.
+//!
+//! Disk writes happen during `Usb::poll`, a flash sector erase keeps it
+//! busy for tens of milliseconds.
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+use crate::disk::{BlockDevice, BLOCK_SIZE};
+
+const USB_CLASS_MSC: u8 = 0x08;
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+const MSC_PROTOCOL_BBB: u8 = 0x50;
+
+const REQ_GET_MAX_LUN: u8 = 0xFE;
+const REQ_BULK_ONLY_RESET: u8 = 0xFF;
+
+pub const MAX_PACKET_SIZE: u16 = 64;
+const PACKET_SIZE: usize = MAX_PACKET_SIZE as usize;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_SIZE: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const VERIFY_10: u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5A;
+
+/// Sense key, additional sense code and qualifier of the last error
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Sense(u8, u8, u8);
+
+const NO_SENSE: Sense = Sense(0x00, 0x00, 0x00);
+const MEDIUM_NOT_PRESENT: Sense = Sense(0x02, 0x3A, 0x00);
+const MEDIUM_ERROR: Sense = Sense(0x03, 0x11, 0x00);
+const INVALID_COMMAND: Sense = Sense(0x05, 0x20, 0x00);
+const LBA_OUT_OF_RANGE: Sense = Sense(0x05, 0x21, 0x00);
+const INVALID_FIELD: Sense = Sense(0x05, 0x24, 0x00);
+const MEDIUM_CHANGED: Sense = Sense(0x06, 0x28, 0x00);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    /// Waiting for a command block wrapper
+    Command,
+    /// Sending `buffer[pos..len]`, then the next blocks of a READ
+    DataIn,
+    /// Receiving the blocks of a WRITE
+    DataOut,
+    /// Sending the command status wrapper
+    Status,
+}
+
+pub struct MscClass<'a, B: UsbBus, D: BlockDevice> {
+    interface: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+
+    disk: Option<D>,
+    ejected: bool,
+    /// Report a medium change on the next TEST UNIT READY
+    changed: bool,
+    sense: Sense,
+
+    state: State,
+    tag: u32,
+    /// Bytes announced by the host for the data stage
+    expected: u32,
+    transferred: u32,
+    status: u8,
+
+    buffer: [u8; BLOCK_SIZE],
+    len: usize,
+    pos: usize,
+    /// Next block of a READ or WRITE, and blocks left
+    lba: u32,
+    blocks: u32,
+    /// WRITE data is received but not written
+    discard: bool,
+}
+
+impl<'a, B: UsbBus, D: BlockDevice> MscClass<'a, B, D> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        MscClass {
+            interface: alloc.interface(),
+            ep_out: alloc.bulk(MAX_PACKET_SIZE),
+            ep_in: alloc.bulk(MAX_PACKET_SIZE),
+            disk: None,
+            ejected: false,
+            changed: false,
+            sense: NO_SENSE,
+            state: State::Command,
+            tag: 0,
+            expected: 0,
+            transferred: 0,
+            status: CSW_PASSED,
+            buffer: [0; BLOCK_SIZE],
+            len: 0,
+            pos: 0,
+            lba: 0,
+            blocks: 0,
+            discard: false,
+        }
+    }
+
+    /// Make the disk available to the host, return the disk previously
+    /// attached
+    pub fn attach(&mut self, disk: D) -> Option<D> {
+        let previous = self.detach();
+        self.disk = Some(disk);
+        self.changed = true;
+        previous
+    }
+
+    /// Take the disk back, flushed
+    pub fn detach(&mut self) -> Option<D> {
+        self.ejected = false;
+        let mut disk = self.disk.take()?;
+        let _ = disk.flush();
+        Some(disk)
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    /// True once the host has ejected the attached disk
+    pub fn ejected(&self) -> bool {
+        self.ejected
+    }
+
+    fn has_medium(&self) -> bool {
+        self.disk.is_some() && !self.ejected
+    }
+
+    /// The disk, if attached and not ejected
+    fn medium(&mut self) -> Option<&mut D> {
+        if self.ejected {
+            None
+        } else {
+            self.disk.as_mut()
+        }
+    }
+
+    fn respond(&mut self, data: &[u8]) {
+        let len = data.len().min(self.expected as usize);
+        self.buffer[..len].copy_from_slice(&data[..len]);
+        self.len = len;
+        self.state = State::DataIn;
+    }
+
+    fn fail(&mut self, sense: Sense) {
+        self.sense = sense;
+        self.status = CSW_FAILED;
+    }
+
+    fn command(&mut self, cbw: &[u8]) {
+        self.tag = u32::from_le_bytes([cbw[4], cbw[5], cbw[6], cbw[7]]);
+        self.expected = u32::from_le_bytes([cbw[8], cbw[9], cbw[10], cbw[11]]);
+        let data_in = cbw[12] & 0x80 != 0;
+        let cb = &cbw[15..31];
+
+        self.transferred = 0;
+        self.status = CSW_PASSED;
+        self.len = 0;
+        self.pos = 0;
+        self.blocks = 0;
+        self.discard = false;
+        self.state = match (self.expected, data_in) {
+            (0, _) => State::Status,
+            (_, true) => State::DataIn,
+            (_, false) => State::DataOut,
+        };
+
+        let opcode = cb[0];
+        if opcode != REQUEST_SENSE {
+            self.sense = NO_SENSE;
+        }
+
+        let block_count = self.disk.as_ref().map_or(0, |disk| disk.block_count());
+        let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+        let count = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+
+        match opcode {
+            INQUIRY if cb[1] & 1 != 0 => self.fail(INVALID_FIELD),
+            INQUIRY => {
+                let mut data = [0u8; 36];
+                data[1] = 0x80; // Removable
+                data[2] = 0x04; // SPC-2
+                data[3] = 0x02;
+                data[4] = 31;
+                data[8..16].copy_from_slice(b"WeeNoise");
+                data[16..32].copy_from_slice(b"PGB-1 Disk      ");
+                data[32..36].copy_from_slice(b"1.0 ");
+                self.respond(&data);
+            }
+            REQUEST_SENSE => {
+                let Sense(key, asc, ascq) = core::mem::replace(&mut self.sense, NO_SENSE);
+                let mut data = [0u8; 18];
+                data[0] = 0x70; // Current error, fixed format
+                data[2] = key;
+                data[7] = 10;
+                data[12] = asc;
+                data[13] = ascq;
+                self.respond(&data);
+            }
+            MODE_SENSE_6 => self.respond(&[3, 0, 0, 0]),
+            MODE_SENSE_10 => self.respond(&[0, 6, 0, 0, 0, 0, 0, 0]),
+            PREVENT_ALLOW_MEDIUM_REMOVAL => {}
+            _ if !self.has_medium() => self.fail(MEDIUM_NOT_PRESENT),
+            TEST_UNIT_READY if self.changed => {
+                self.changed = false;
+                self.fail(MEDIUM_CHANGED);
+            }
+            TEST_UNIT_READY | VERIFY_10 => {}
+            READ_CAPACITY_10 => {
+                let mut data = [0u8; 8];
+                data[..4].copy_from_slice(&(block_count - 1).to_be_bytes());
+                data[4..].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.respond(&data);
+            }
+            READ_FORMAT_CAPACITIES => {
+                let mut data = [0u8; 12];
+                data[3] = 8;
+                data[4..8].copy_from_slice(&block_count.to_be_bytes());
+                data[8] = 0x02; // Formatted media
+                data[9..].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+                self.respond(&data);
+            }
+            START_STOP_UNIT => {
+                let load_eject = cb[4] & 0x02 != 0;
+                let start = cb[4] & 0x01 != 0;
+                if load_eject && !start {
+                    if let Some(disk) = self.medium() {
+                        let _ = disk.flush();
+                    }
+                    self.ejected = true;
+                }
+            }
+            SYNCHRONIZE_CACHE_10 => {
+                if let Some(Err(_)) = self.medium().map(|disk| disk.flush()) {
+                    self.fail(MEDIUM_ERROR);
+                }
+            }
+            READ_10 | WRITE_10 if lba.checked_add(count).is_none_or(|end| end > block_count) => {
+                self.fail(LBA_OUT_OF_RANGE)
+            }
+            READ_10 | WRITE_10 if self.expected != count * BLOCK_SIZE as u32 => {
+                self.fail(INVALID_FIELD)
+            }
+            READ_10 | WRITE_10 => {
+                self.lba = lba;
+                self.blocks = count;
+            }
+            _ => self.fail(INVALID_COMMAND),
+        }
+
+        // Data of failed commands is not transferred: a short packet ends
+        // an IN stage, OUT data is received and dropped.
+        if self.status == CSW_FAILED {
+            self.len = 0;
+            self.blocks = 0;
+            self.discard = true;
+        }
+    }
+
+    /// Move the transfers forward as far as the endpoints allow
+    fn process(&mut self) {
+        loop {
+            match self.state {
+                State::Command => {
+                    let mut packet = [0; PACKET_SIZE];
+                    match self.ep_out.read(&mut packet) {
+                        Ok(CBW_SIZE) if packet[..4] == CBW_SIGNATURE.to_le_bytes() => {
+                            self.command(&packet[..CBW_SIZE]);
+                        }
+                        // Not a valid command, ignored
+                        Ok(_) => {}
+                        Err(_) => return,
+                    }
+                }
+                State::DataIn => {
+                    if self.pos == self.len && self.blocks > 0 {
+                        let lba = self.lba;
+                        let mut block = [0; BLOCK_SIZE];
+                        let read = self.medium().map(|disk| disk.read_block(lba, &mut block));
+                        if let Some(Ok(())) = read {
+                            self.buffer = block;
+                        } else {
+                            self.fail(MEDIUM_ERROR);
+                            self.state = State::Status;
+                            continue;
+                        }
+                        self.lba += 1;
+                        self.blocks -= 1;
+                        self.pos = 0;
+                        self.len = BLOCK_SIZE;
+                    }
+
+                    if self.pos == self.len {
+                        // A short packet ends a data stage shorter than
+                        // announced
+                        let short = self.transferred < self.expected
+                            && self.transferred.is_multiple_of(MAX_PACKET_SIZE as u32);
+                        if short && self.ep_in.write(&[]).is_err() {
+                            return;
+                        }
+                        self.state = State::Status;
+                        continue;
+                    }
+
+                    let len = (self.len - self.pos).min(PACKET_SIZE);
+                    match self.ep_in.write(&self.buffer[self.pos..self.pos + len]) {
+                        Ok(_) => {
+                            self.pos += len;
+                            self.transferred += len as u32;
+                        }
+                        Err(_) => return,
+                    }
+                }
+                State::DataOut => {
+                    let mut packet = [0; PACKET_SIZE];
+                    let Ok(count) = self.ep_out.read(&mut packet) else {
+                        return;
+                    };
+                    let count = count.min(BLOCK_SIZE - self.len);
+                    self.buffer[self.len..self.len + count].copy_from_slice(&packet[..count]);
+                    self.len += count;
+                    self.transferred += count as u32;
+
+                    if self.len == BLOCK_SIZE || self.transferred >= self.expected {
+                        if !self.discard {
+                            let (lba, block) = (self.lba, self.buffer);
+                            let written = self.medium().map(|disk| disk.write_block(lba, &block));
+                            if written != Some(Ok(())) {
+                                self.fail(MEDIUM_ERROR);
+                                self.discard = true;
+                            }
+                            self.lba += 1;
+                        }
+                        self.len = 0;
+                    }
+                    if self.transferred >= self.expected {
+                        self.state = State::Status;
+                    }
+                }
+                State::Status => {
+                    let mut csw = [0; 13];
+                    csw[..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+                    csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+                    let residue = self.expected.saturating_sub(self.transferred);
+                    csw[8..12].copy_from_slice(&residue.to_le_bytes());
+                    csw[12] = self.status;
+                    if self.ep_in.write(&csw).is_err() {
+                        return;
+                    }
+                    self.state = State::Command;
+                }
+            }
+        }
+    }
+}
+
+impl<B: UsbBus, D: BlockDevice> UsbClass<B> for MscClass<'_, B, D> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            USB_CLASS_MSC,
+            MSC_SUBCLASS_SCSI,
+            MSC_PROTOCOL_BBB,
+        )?;
+        writer.endpoint(&self.ep_out)?;
+        writer.endpoint(&self.ep_in)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Command;
+        self.sense = NO_SENSE;
+        self.changed = self.disk.is_some();
+    }
+
+    fn poll(&mut self) {
+        self.process();
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.process();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+            && req.request == REQ_GET_MAX_LUN
+        {
+            // A single logical unit
+            let _ = xfer.accept_with(&[0]);
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+            && req.request == REQ_BULK_ONLY_RESET
+        {
+            self.state = State::Command;
+            let _ = xfer.accept();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::FlashDisk;
+    use crate::flash::{RamFlash, SECTOR_SIZE};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+    use usb_device::bus::PollResult;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+    use usb_device::{UsbDirection, UsbError};
+
+    type TestDisk = FlashDisk<RamFlash<{ 4 * SECTOR_SIZE as usize }>>;
+
+    /// Packets from the host on the OUT endpoints, packets sent on the IN
+    /// endpoints
+    #[derive(Default)]
+    struct Packets {
+        out: VecDeque<Vec<u8>>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    struct TestBus {
+        next: u8,
+        packets: &'static Mutex<Packets>,
+    }
+
+    impl UsbBus for TestBus {
+        fn alloc_ep(
+            &mut self,
+            dir: UsbDirection,
+            addr: Option<EndpointAddress>,
+            _: EndpointType,
+            _: u16,
+            _: u8,
+        ) -> Result<EndpointAddress> {
+            Ok(addr.unwrap_or_else(|| {
+                self.next += 1;
+                EndpointAddress::from_parts(self.next as usize, dir)
+            }))
+        }
+
+        fn enable(&mut self) {}
+        fn reset(&self) {}
+        fn set_device_address(&self, _: u8) {}
+
+        fn write(&self, _: EndpointAddress, buf: &[u8]) -> Result<usize> {
+            self.packets.lock().unwrap().sent.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn read(&self, _: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+            let packet = self.packets.lock().unwrap().out.pop_front();
+            let packet = packet.ok_or(UsbError::WouldBlock)?;
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+
+        fn set_stalled(&self, _: EndpointAddress, _: bool) {}
+        fn is_stalled(&self, _: EndpointAddress) -> bool {
+            false
+        }
+        fn suspend(&self) {}
+        fn resume(&self) {}
+        fn poll(&self) -> PollResult {
+            PollResult::None
+        }
+    }
+
+    fn cbw(tag: u32, expected: u32, data_in: bool, cb: &[u8]) -> Vec<u8> {
+        let mut cbw = std::vec![0; CBW_SIZE];
+        cbw[..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&expected.to_le_bytes());
+        cbw[12] = if data_in { 0x80 } else { 0 };
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        cbw
+    }
+
+    fn rw10(opcode: u8, lba: u32, count: u16) -> [u8; 10] {
+        let mut cb = [0; 10];
+        cb[0] = opcode;
+        cb[2..6].copy_from_slice(&lba.to_be_bytes());
+        cb[7..9].copy_from_slice(&count.to_be_bytes());
+        cb
+    }
+
+    /// Status wrapper: tag, residue, status
+    fn csw(packet: &[u8]) -> (u32, u32, u8) {
+        assert_eq!(packet.len(), 13);
+        assert_eq!(packet[..4], CSW_SIGNATURE.to_le_bytes());
+        let tag = u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let residue = u32::from_le_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        (tag, residue, packet[12])
+    }
+
+    struct Host<'a> {
+        packets: &'static Mutex<Packets>,
+        msc: MscClass<'a, TestBus, TestDisk>,
+        tag: u32,
+    }
+
+    impl Host<'_> {
+        fn packets(&self) -> std::sync::MutexGuard<'_, Packets> {
+            self.packets.lock().unwrap()
+        }
+
+        /// Run a command, return the data received and the status wrapper
+        fn run(
+            &mut self,
+            expected: u32,
+            data_in: bool,
+            cb: &[u8],
+            data: &[u8],
+        ) -> (Vec<u8>, u32, u8) {
+            self.tag += 1;
+            {
+                let mut packets = self.packets();
+                packets.out.push_back(cbw(self.tag, expected, data_in, cb));
+                for packet in data.chunks(PACKET_SIZE) {
+                    packets.out.push_back(packet.to_vec());
+                }
+            }
+            self.msc.poll();
+
+            let sent: Vec<Vec<u8>> = self.packets().sent.drain(..).collect();
+            let (status, data) = sent.split_last().unwrap();
+            assert!(data.iter().all(|packet| packet.len() <= PACKET_SIZE));
+            let (tag, residue, status) = csw(status);
+            assert_eq!(tag, self.tag);
+            (data.concat(), residue, status)
+        }
+
+        fn sense(&mut self) -> Sense {
+            let (data, residue, status) = self.run(18, true, &[REQUEST_SENSE, 0, 0, 0, 18, 0], &[]);
+            assert_eq!((data.len(), residue, status), (18, 0, CSW_PASSED));
+            Sense(data[2], data[12], data[13])
+        }
+    }
+
+    fn with_host(f: impl FnOnce(&mut Host)) {
+        let packets = Box::leak(Box::default());
+        let alloc = UsbBusAllocator::new(TestBus { next: 0, packets });
+        let msc = MscClass::new(&alloc);
+        // Building the device freezes the allocator, the endpoints work
+        // from then on
+        let _device = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        f(&mut Host {
+            packets,
+            msc,
+            tag: 0,
+        });
+    }
+
+    #[test]
+    fn commands() {
+        with_host(|host| {
+            // No disk attached
+            let (_, residue, status) = host.run(0, false, &[TEST_UNIT_READY; 6], &[]);
+            assert_eq!((residue, status), (0, CSW_FAILED));
+            assert_eq!(host.sense(), MEDIUM_NOT_PRESENT);
+
+            let (data, residue, status) = host.run(36, true, &[INQUIRY, 0, 0, 0, 36, 0], &[]);
+            assert_eq!((residue, status), (0, CSW_PASSED));
+            assert_eq!(&data[16..32], b"PGB-1 Disk      ");
+
+            // Shorter data than announced
+            let (data, residue, status) = host.run(255, true, &[INQUIRY, 0, 0, 0, 255, 0], &[]);
+            assert_eq!((data.len(), residue, status), (36, 255 - 36, CSW_PASSED));
+
+            assert!(host.msc.attach(FlashDisk::new(RamFlash::new())).is_none());
+            let (_, _, status) = host.run(0, false, &[TEST_UNIT_READY; 6], &[]);
+            assert_eq!(status, CSW_FAILED);
+            assert_eq!(host.sense(), MEDIUM_CHANGED);
+            let (_, _, status) = host.run(0, false, &[0; 6], &[]);
+            assert_eq!(status, CSW_PASSED);
+
+            let mut cb = [0; 10];
+            cb[0] = READ_CAPACITY_10;
+            let (data, _, status) = host.run(8, true, &cb, &[]);
+            assert_eq!(status, CSW_PASSED);
+            assert_eq!(data[..4], 31u32.to_be_bytes());
+            assert_eq!(data[4..], (BLOCK_SIZE as u32).to_be_bytes());
+
+            let (_, residue, status) = host.run(0, false, &[0x99, 0, 0, 0, 0, 0], &[]);
+            assert_eq!((residue, status), (0, CSW_FAILED));
+            assert_eq!(host.sense(), INVALID_COMMAND);
+            assert_eq!(host.sense(), NO_SENSE);
+        });
+    }
+
+    #[test]
+    fn read_and_write() {
+        with_host(|host| {
+            host.msc.attach(FlashDisk::new(RamFlash::new()));
+            host.run(0, false, &[TEST_UNIT_READY; 6], &[]);
+
+            let data: Vec<u8> = (0..3 * BLOCK_SIZE as u32).map(|i| (i * 13) as u8).collect();
+            let (received, residue, status) = host.run(1536, false, &rw10(WRITE_10, 6, 3), &data);
+            assert_eq!((received.len(), residue, status), (0, 0, CSW_PASSED));
+
+            let (received, residue, status) = host.run(1536, true, &rw10(READ_10, 6, 3), &[]);
+            assert_eq!((residue, status), (0, CSW_PASSED));
+            assert_eq!(received, data);
+
+            // Blocks past the end: no data, the whole length is left over
+            let (received, residue, status) = host.run(1024, true, &rw10(READ_10, 31, 2), &[]);
+            assert_eq!((received.len(), residue, status), (0, 1024, CSW_FAILED));
+            assert_eq!(host.sense(), LBA_OUT_OF_RANGE);
+
+            // Failed WRITE data is received and dropped
+            let (_, residue, status) = host.run(1024, false, &rw10(WRITE_10, 31, 2), &data[..1024]);
+            assert_eq!((residue, status), (0, CSW_FAILED));
+            assert_eq!(host.sense(), LBA_OUT_OF_RANGE);
+
+            // Length that does not match the block count
+            let (_, residue, status) = host.run(100, true, &rw10(READ_10, 0, 1), &[]);
+            assert_eq!((residue, status), (100, CSW_FAILED));
+            assert_eq!(host.sense(), INVALID_FIELD);
+
+            // Written to the flash once detached
+            let mut disk = host.msc.detach().unwrap();
+            let mut block = [0; BLOCK_SIZE];
+            disk.read_block(7, &mut block).unwrap();
+            assert_eq!(&block[..], &data[BLOCK_SIZE..2 * BLOCK_SIZE]);
+            let flash = disk.into_inner().unwrap();
+            assert_eq!(&flash.as_bytes()[6 * BLOCK_SIZE..9 * BLOCK_SIZE], &data[..]);
+        });
+    }
+
+    #[test]
+    fn eject() {
+        with_host(|host| {
+            host.msc.attach(FlashDisk::new(RamFlash::new()));
+            host.run(0, false, &[TEST_UNIT_READY; 6], &[]);
+
+            let data = [0x5A; BLOCK_SIZE];
+            host.run(512, false, &rw10(WRITE_10, 0, 1), &data);
+            assert!(!host.msc.ejected());
+
+            // Start bit clear, load/eject bit set
+            let (_, _, status) = host.run(0, false, &[START_STOP_UNIT, 0, 0, 0, 0x02, 0], &[]);
+            assert_eq!(status, CSW_PASSED);
+            assert!(host.msc.ejected());
+            assert!(host.msc.is_attached());
+
+            let (_, _, status) = host.run(0, false, &[TEST_UNIT_READY; 6], &[]);
+            assert_eq!(status, CSW_FAILED);
+            assert_eq!(host.sense(), MEDIUM_NOT_PRESENT);
+
+            // The eject flushed the write cache
+            let disk = host.msc.detach().unwrap();
+            assert!(!host.msc.ejected());
+            assert!(!host.msc.is_attached());
+            let flash = disk.into_inner().unwrap();
+            assert_eq!(&flash.as_bytes()[..BLOCK_SIZE], &data[..]);
+        });
+    }
+}
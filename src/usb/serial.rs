@@ -0,0 +1,388 @@
+//! USB CDC-ACM serial port
+//!
+//! Shows up as `/dev/ttyACM*` or a COM port without a driver. Like the
+//! other classes it never blocks: received bytes wait in the endpoint
+//! buffer until `read`, written bytes are queued and sent when the host
+//! polls the IN endpoint. Bytes that do not fit in the queue are dropped,
+//! so a terminal that is not open does not stall the application.
+
+use core::fmt;
+
+use heapless::Deque;
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0A;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+pub const MAX_PACKET_SIZE: u16 = 64;
+
+/// Opening and closing the port at this rate asks for the bootloader
+pub const TOUCH_BAUD_RATE: u32 = 1200;
+
+/// Bytes waiting to be sent
+pub const TX_BUFFER_SIZE: usize = 1024;
+
+/// Serial settings chosen by the host. They have no effect on the
+/// transfers, but some hosts use them as signals, e.g. the 1200 baud touch.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LineCoding {
+    pub baud_rate: u32,
+    /// 0: 1 stop bit, 1: 1.5 stop bits, 2: 2 stop bits
+    pub stop_bits: u8,
+    /// 0: none, 1: odd, 2: even, 3: mark, 4: space
+    pub parity: u8,
+    pub data_bits: u8,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        LineCoding {
+            baud_rate: 115_200,
+            stop_bits: 0,
+            parity: 0,
+            data_bits: 8,
+        }
+    }
+}
+
+impl LineCoding {
+    fn encode(&self) -> [u8; 7] {
+        let [b0, b1, b2, b3] = self.baud_rate.to_le_bytes();
+        [b0, b1, b2, b3, self.stop_bits, self.parity, self.data_bits]
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let [b0, b1, b2, b3, stop_bits, parity, data_bits, ..] = *data else {
+            return None;
+        };
+        Some(LineCoding {
+            baud_rate: u32::from_le_bytes([b0, b1, b2, b3]),
+            stop_bits,
+            parity,
+            data_bits,
+        })
+    }
+}
+
+/// Line coding and control lines, set by the host
+#[derive(Copy, Clone, Default, Debug)]
+struct LineState {
+    coding: LineCoding,
+    dtr: bool,
+    rts: bool,
+    touched: bool,
+}
+
+impl LineState {
+    /// SET_CONTROL_LINE_STATE, DTR in bit 0 and RTS in bit 1
+    fn set_control(&mut self, value: u16) {
+        let dtr = value & 1 != 0;
+        // Only closing a port already at the touch rate: hosts may set
+        // 1200 baud before raising DTR to open a port at that rate
+        self.touched |= self.dtr && !dtr && self.coding.baud_rate == TOUCH_BAUD_RATE;
+        self.dtr = dtr;
+        self.rts = value & 2 != 0;
+    }
+}
+
+pub struct SerialClass<'a, B: UsbBus> {
+    comm_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, B>,
+    data_if: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+
+    line: LineState,
+
+    rx: [u8; MAX_PACKET_SIZE as usize],
+    rx_len: usize,
+    rx_pos: usize,
+
+    tx: Deque<u8, TX_BUFFER_SIZE>,
+    /// The last packet sent was full, the host waits for a short one
+    zlp_pending: bool,
+}
+
+impl<'a, B: UsbBus> SerialClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        SerialClass {
+            comm_if: alloc.interface(),
+            comm_ep: alloc.interrupt(8, 255),
+            data_if: alloc.interface(),
+            ep_out: alloc.bulk(MAX_PACKET_SIZE),
+            ep_in: alloc.bulk(MAX_PACKET_SIZE),
+            line: LineState::default(),
+            rx: [0; MAX_PACKET_SIZE as usize],
+            rx_len: 0,
+            rx_pos: 0,
+            tx: Deque::new(),
+            zlp_pending: false,
+        }
+    }
+
+    pub fn line_coding(&self) -> LineCoding {
+        self.line.coding
+    }
+
+    /// Data Terminal Ready, set by most terminals while the port is open
+    pub fn dtr(&self) -> bool {
+        self.line.dtr
+    }
+
+    pub fn rts(&self) -> bool {
+        self.line.rts
+    }
+
+    /// True once the host has closed the port (DTR falling) while at
+    /// `TOUCH_BAUD_RATE`, the convention to restart a device in its
+    /// bootloader
+    pub fn touched(&self) -> bool {
+        self.line.touched
+    }
+
+    /// Read received bytes into `buffer`, return the number of bytes read
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buffer.len() {
+            if self.rx_pos >= self.rx_len {
+                self.rx_pos = 0;
+                self.rx_len = self.ep_out.read(&mut self.rx).unwrap_or(0);
+                if self.rx_len == 0 {
+                    break;
+                }
+            }
+            let len = (self.rx_len - self.rx_pos).min(buffer.len() - count);
+            buffer[count..count + len].copy_from_slice(&self.rx[self.rx_pos..self.rx_pos + len]);
+            self.rx_pos += len;
+            count += len;
+        }
+        count
+    }
+
+    /// Free space in the transmit queue, in bytes
+    pub fn tx_space(&self) -> usize {
+        self.tx.capacity() - self.tx.len()
+    }
+
+    /// Queue bytes to send, return the number of bytes queued
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(self.tx_space());
+        for &byte in &data[..len] {
+            let _ = self.tx.push_back(byte);
+        }
+        self.flush();
+        len
+    }
+
+    /// Hand the queued bytes to the IN endpoint
+    fn flush(&mut self) {
+        if self.tx.is_empty() && !self.zlp_pending {
+            return;
+        }
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE as usize];
+        let mut len = 0;
+        for (dst, &src) in buffer.iter_mut().zip(self.tx.iter()) {
+            *dst = src;
+            len += 1;
+        }
+
+        // The endpoint is busy until the host reads the previous packet
+        if self.ep_in.write(&buffer[..len]).is_ok() {
+            for _ in 0..len {
+                self.tx.pop_front();
+            }
+            self.zlp_pending = len == buffer.len();
+        }
+    }
+}
+
+/// Formatted output, fails when the transmit queue is full
+impl<B: UsbBus> fmt::Write for SerialClass<'_, B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.write(s.as_bytes()) == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for SerialClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.iad(
+            self.comm_if,
+            2,
+            USB_CLASS_CDC,
+            CDC_SUBCLASS_ACM,
+            CDC_PROTOCOL_NONE,
+            None,
+        )?;
+
+        writer.interface(
+            self.comm_if,
+            USB_CLASS_CDC,
+            CDC_SUBCLASS_ACM,
+            CDC_PROTOCOL_NONE,
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_HEADER,
+                0x10, // bcdCDC 1.10
+                0x01,
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_CALL_MANAGEMENT,
+                0x00, // bmCapabilities: no call management
+                self.data_if.into(),
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_ACM,
+                0x02, // bmCapabilities: line coding and control line state
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[CDC_TYPE_UNION, self.comm_if.into(), self.data_if.into()],
+        )?;
+        writer.endpoint(&self.comm_ep)?;
+
+        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+        writer.endpoint(&self.ep_out)?;
+        writer.endpoint(&self.ep_in)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.line = LineState::default();
+        self.rx_len = 0;
+        self.rx_pos = 0;
+        self.tx.clear();
+        self.zlp_pending = false;
+    }
+
+    fn poll(&mut self) {
+        self.flush();
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.flush();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type != control::RequestType::Class
+            || req.recipient != control::Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return;
+        }
+
+        match req.request {
+            REQ_GET_LINE_CODING => {
+                let _ = xfer.accept_with(&self.line.coding.encode());
+            }
+            _ => {
+                let _ = xfer.reject();
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type != control::RequestType::Class
+            || req.recipient != control::Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return;
+        }
+
+        match req.request {
+            REQ_SET_LINE_CODING => match LineCoding::decode(xfer.data()) {
+                Some(coding) => {
+                    self.line.coding = coding;
+                    let _ = xfer.accept();
+                }
+                None => {
+                    let _ = xfer.reject();
+                }
+            },
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.line.set_control(req.value);
+                let _ = xfer.accept();
+            }
+            _ => {
+                let _ = xfer.reject();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(baud_rate: u32) -> LineCoding {
+        LineCoding {
+            baud_rate,
+            ..LineCoding::default()
+        }
+    }
+
+    #[test]
+    fn touch() {
+        // Open and close at the touch rate
+        let mut line = LineState {
+            coding: at(TOUCH_BAUD_RATE),
+            ..LineState::default()
+        };
+        line.set_control(0b11);
+        assert!(line.dtr && line.rts && !line.touched);
+        line.set_control(0);
+        assert!(!line.dtr && line.touched);
+
+        // Rate set before DTR is raised, then a normal close
+        let mut line = LineState::default();
+        line.set_control(0);
+        line.coding = at(TOUCH_BAUD_RATE);
+        line.set_control(0);
+        assert!(!line.touched);
+        line.set_control(1);
+        line.coding = at(115_200);
+        line.set_control(0);
+        assert!(!line.touched);
+
+        // Closed at another rate
+        let mut line = LineState::default();
+        line.set_control(1);
+        line.set_control(0);
+        assert!(!line.touched);
+
+        // DTR already low when the rate changes
+        line.coding = at(TOUCH_BAUD_RATE);
+        line.set_control(0b10);
+        assert!(!line.touched);
+    }
+}
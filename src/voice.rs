@@ -0,0 +1,504 @@
+//! Polyphonic voice allocation
+//!
+//! `VoiceAllocator` maps note-on/note-off events (from the keyboard or MIDI)
+//! to a fixed pool of voices. It knows nothing about how sound is produced:
+//! synth engines plug in by implementing the `Voice` trait.
+
+use heapless::Vec;
+
+/// Pitch in 8.8 fixed point semitones (MIDI note number in the high byte)
+pub type Pitch = u16;
+
+/// Convert a MIDI note number to a `Pitch`
+pub const fn note_pitch(note: u8) -> Pitch {
+    (note as Pitch) << 8
+}
+
+/// A single sound generator driven by the `VoiceAllocator`
+pub trait Voice {
+    /// Start (or restart) the envelopes for a new note. `set_pitch` is
+    /// always called right after this.
+    fn note_on(&mut self, note: u8, velocity: u8);
+
+    /// Release the current note
+    fn note_off(&mut self);
+
+    /// Set the current pitch of the voice. Called on every note-on and
+    /// repeatedly while gliding.
+    fn set_pitch(&mut self, pitch: Pitch);
+
+    /// Return true while the voice is producing sound, including its
+    /// release phase.
+    fn is_active(&self) -> bool;
+
+    /// Current output level of the voice, used by `StealPolicy::Quietest`
+    fn level(&self) -> u16 {
+        0
+    }
+}
+
+/// How to choose a voice when all of them are in use
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StealPolicy {
+    /// Steal the voice that was triggered first
+    Oldest,
+    /// Steal the voice with the lowest `Voice::level`
+    Quietest,
+    /// Retrigger the voice already playing the same note, otherwise steal
+    /// the oldest one
+    SameNote,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// One voice per note
+    Poly,
+    /// A single voice, envelopes restart on every note
+    Mono,
+    /// A single voice, envelopes only restart when no other note is held
+    Legato,
+}
+
+/// Maximum number of held notes remembered in mono and legato modes
+pub const MAX_HELD_NOTES: usize = 16;
+
+#[derive(Copy, Clone)]
+struct Slot {
+    /// Note currently assigned to the voice, if its key is still held
+    note: Option<u8>,
+    /// Last note played by the voice
+    last_note: u8,
+    age: u32,
+
+    glide_from: Pitch,
+    glide_to: Pitch,
+    glide_elapsed: u32,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Slot {
+            note: None,
+            last_note: 0,
+            age: 0,
+            glide_from: 0,
+            glide_to: 0,
+            glide_elapsed: 0,
+        }
+    }
+
+    fn pitch(&self, glide_time: u32) -> Pitch {
+        if glide_time == 0 || self.glide_elapsed >= glide_time {
+            return self.glide_to;
+        }
+
+        let from = self.glide_from as i32;
+        let to = self.glide_to as i32;
+        let delta = (to - from) as i64 * self.glide_elapsed as i64 / glide_time as i64;
+        (from + delta as i32) as Pitch
+    }
+}
+
+pub struct VoiceAllocator<V: Voice, const N: usize> {
+    voices: [V; N],
+    slots: [Slot; N],
+    mode: Mode,
+    policy: StealPolicy,
+
+    /// Glide time in microseconds, 0 disables glide
+    glide_time: u32,
+    last_pitch: Option<Pitch>,
+
+    /// Notes held in mono/legato modes, most recent last
+    held: Vec<(u8, u8), MAX_HELD_NOTES>,
+    counter: u32,
+}
+
+impl<V: Voice, const N: usize> VoiceAllocator<V, N> {
+    pub fn new(voices: [V; N]) -> Self {
+        VoiceAllocator {
+            voices,
+            slots: [Slot::new(); N],
+            mode: Mode::Poly,
+            policy: StealPolicy::Oldest,
+            glide_time: 0,
+            last_pitch: None,
+            held: Vec::new(),
+            counter: 0,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Change the allocation mode. All notes are released.
+    pub fn set_mode(&mut self, mode: Mode) {
+        if mode != self.mode {
+            self.all_notes_off();
+            self.mode = mode;
+        }
+    }
+
+    pub fn steal_policy(&self) -> StealPolicy {
+        self.policy
+    }
+
+    pub fn set_steal_policy(&mut self, policy: StealPolicy) {
+        self.policy = policy;
+    }
+
+    /// Set the glide (portamento) time in milliseconds, 0 to disable
+    pub fn set_glide(&mut self, time_ms: u32) {
+        self.glide_time = time_ms.saturating_mul(1000);
+    }
+
+    pub fn voices(&self) -> &[V; N] {
+        &self.voices
+    }
+
+    /// Access the voices, typically to render them
+    pub fn voices_mut(&mut self) -> &mut [V; N] {
+        &mut self.voices
+    }
+
+    /// Return the note currently held on the given voice
+    pub fn voice_note(&self, index: usize) -> Option<u8> {
+        self.slots[index].note
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        if velocity == 0 {
+            self.note_off(note);
+            return;
+        }
+
+        match self.mode {
+            Mode::Poly => {
+                let index = self.allocate(note);
+                self.trigger(index, note, velocity, true);
+            }
+            Mode::Mono | Mode::Legato => {
+                let legato = self.mode == Mode::Legato && !self.held.is_empty();
+
+                self.held.retain(|(n, _)| *n != note);
+                if self.held.is_full() {
+                    self.held.remove(0);
+                }
+                // Cannot fail, we just made room
+                let _ = self.held.push((note, velocity));
+
+                self.trigger(0, note, velocity, !legato);
+            }
+        }
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        match self.mode {
+            Mode::Poly => {
+                for (slot, voice) in self.slots.iter_mut().zip(self.voices.iter_mut()) {
+                    if slot.note == Some(note) {
+                        slot.note = None;
+                        voice.note_off();
+                    }
+                }
+            }
+            Mode::Mono | Mode::Legato => {
+                let was_playing = self.held.last().map(|(n, _)| *n) == Some(note);
+                self.held.retain(|(n, _)| *n != note);
+
+                if !was_playing {
+                    return;
+                }
+
+                match self.held.last().copied() {
+                    // Go back to the previous held note
+                    Some((prev, velocity)) => {
+                        let retrigger = self.mode == Mode::Mono;
+                        self.trigger(0, prev, velocity, retrigger);
+                    }
+                    None => {
+                        self.slots[0].note = None;
+                        self.voices[0].note_off();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Release all held notes
+    pub fn all_notes_off(&mut self) {
+        self.held.clear();
+        for (slot, voice) in self.slots.iter_mut().zip(self.voices.iter_mut()) {
+            if slot.note.take().is_some() {
+                voice.note_off();
+            }
+        }
+    }
+
+    /// Advance glides by `elapsed_us` microseconds
+    pub fn update(&mut self, elapsed_us: u32) {
+        if self.glide_time == 0 {
+            return;
+        }
+
+        for (slot, voice) in self.slots.iter_mut().zip(self.voices.iter_mut()) {
+            if slot.glide_elapsed < self.glide_time {
+                slot.glide_elapsed = slot.glide_elapsed.saturating_add(elapsed_us);
+                voice.set_pitch(slot.pitch(self.glide_time));
+            }
+        }
+    }
+
+    fn trigger(&mut self, index: usize, note: u8, velocity: u8, retrigger: bool) {
+        self.counter = self.counter.wrapping_add(1);
+
+        let target = note_pitch(note);
+        let slot = &mut self.slots[index];
+        let current = slot.pitch(self.glide_time);
+
+        slot.glide_from = if self.glide_time == 0 {
+            target
+        } else if self.mode == Mode::Poly {
+            // Glide from the last note played on any voice
+            self.last_pitch.unwrap_or(target)
+        } else if slot.note.is_some() || self.voices[index].is_active() {
+            current
+        } else {
+            self.last_pitch.unwrap_or(target)
+        };
+        slot.glide_to = target;
+        slot.glide_elapsed = 0;
+        slot.note = Some(note);
+        slot.last_note = note;
+        slot.age = self.counter;
+
+        let voice = &mut self.voices[index];
+        if retrigger {
+            voice.note_on(note, velocity);
+        }
+        voice.set_pitch(slot.pitch(self.glide_time));
+
+        self.last_pitch = Some(target);
+    }
+
+    fn allocate(&self, note: u8) -> usize {
+        if self.policy == StealPolicy::SameNote {
+            let same = (0..N).find(|&i| {
+                self.slots[i].last_note == note
+                    && (self.slots[i].note.is_some() || self.voices[i].is_active())
+            });
+            if let Some(index) = same {
+                return index;
+            }
+        }
+
+        // Prefer a silent voice, then a released one, the oldest first
+        let idle = (0..N)
+            .filter(|&i| !self.voices[i].is_active())
+            .min_by_key(|&i| self.age(i));
+        if let Some(index) = idle {
+            return index;
+        }
+
+        let released = (0..N)
+            .filter(|&i| self.slots[i].note.is_none())
+            .min_by_key(|&i| self.age(i));
+        if let Some(index) = released {
+            return index;
+        }
+
+        match self.policy {
+            StealPolicy::Quietest => (0..N)
+                .min_by_key(|&i| (self.voices[i].level(), self.age(i)))
+                .unwrap_or(0),
+            StealPolicy::Oldest | StealPolicy::SameNote => {
+                (0..N).min_by_key(|&i| self.age(i)).unwrap_or(0)
+            }
+        }
+    }
+
+    /// Sort key placing the voice triggered the longest time ago first
+    fn age(&self, index: usize) -> core::cmp::Reverse<u32> {
+        core::cmp::Reverse(self.counter.wrapping_sub(self.slots[index].age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, Default, Debug)]
+    struct TestVoice {
+        note: u8,
+        triggers: u32,
+        gate: bool,
+        /// Stays set after `note_off`, as during a release
+        active: bool,
+        pitch: Pitch,
+        level: u16,
+    }
+
+    impl Voice for TestVoice {
+        fn note_on(&mut self, note: u8, _velocity: u8) {
+            self.note = note;
+            self.triggers += 1;
+            self.gate = true;
+            self.active = true;
+        }
+
+        fn note_off(&mut self) {
+            self.gate = false;
+        }
+
+        fn set_pitch(&mut self, pitch: Pitch) {
+            self.pitch = pitch;
+        }
+
+        fn is_active(&self) -> bool {
+            self.active
+        }
+
+        fn level(&self) -> u16 {
+            self.level
+        }
+    }
+
+    fn allocator<const N: usize>() -> VoiceAllocator<TestVoice, N> {
+        VoiceAllocator::new([TestVoice::default(); N])
+    }
+
+    fn notes<const N: usize>(alloc: &VoiceAllocator<TestVoice, N>) -> [Option<u8>; N] {
+        core::array::from_fn(|i| alloc.voice_note(i))
+    }
+
+    #[test]
+    fn stealing() {
+        let mut alloc = allocator::<3>();
+        alloc.note_on(60, 100);
+        alloc.note_on(62, 100);
+        alloc.note_on(64, 100);
+        assert_eq!(notes(&alloc), [Some(60), Some(62), Some(64)]);
+
+        // Oldest
+        alloc.note_on(65, 100);
+        alloc.note_on(67, 100);
+        assert_eq!(notes(&alloc), [Some(65), Some(67), Some(64)]);
+
+        // Released voices before held ones, silent voices first
+        alloc.note_off(67);
+        alloc.note_off(64);
+        alloc.voices_mut()[1].active = false;
+        alloc.note_on(69, 100);
+        assert_eq!(notes(&alloc), [Some(65), Some(69), None]);
+        alloc.note_on(71, 100);
+        assert_eq!(notes(&alloc), [Some(65), Some(69), Some(71)]);
+
+        // Velocity 0 is a note off
+        alloc.note_on(69, 0);
+        assert_eq!(notes(&alloc), [Some(65), None, Some(71)]);
+        assert!(!alloc.voices()[1].gate);
+
+        alloc.all_notes_off();
+        assert_eq!(notes(&alloc), [None; 3]);
+        assert!(alloc.voices().iter().all(|v| !v.gate));
+    }
+
+    #[test]
+    fn steal_policies() {
+        let mut alloc = allocator::<3>();
+        alloc.set_steal_policy(StealPolicy::Quietest);
+        for note in [60, 62, 64] {
+            alloc.note_on(note, 100);
+        }
+        alloc.voices_mut()[0].level = 900;
+        alloc.voices_mut()[1].level = 100;
+        alloc.voices_mut()[2].level = 500;
+        alloc.note_on(65, 100);
+        assert_eq!(notes(&alloc), [Some(60), Some(65), Some(64)]);
+
+        let mut alloc = allocator::<3>();
+        alloc.set_steal_policy(StealPolicy::SameNote);
+        for note in [60, 62, 64] {
+            alloc.note_on(note, 100);
+        }
+        alloc.note_off(62);
+        alloc.note_on(62, 100);
+        assert_eq!(alloc.voices()[1].triggers, 2);
+        alloc.note_on(64, 100);
+        assert_eq!(alloc.voices()[2].triggers, 2);
+        alloc.note_on(67, 100);
+        assert_eq!(notes(&alloc), [Some(67), Some(62), Some(64)]);
+    }
+
+    #[test]
+    fn mono_and_legato() {
+        let mut alloc = allocator::<2>();
+        alloc.set_mode(Mode::Mono);
+        alloc.note_on(60, 100);
+        alloc.note_on(64, 100);
+        alloc.note_on(67, 100);
+        assert_eq!(notes(&alloc), [Some(67), None]);
+        assert_eq!(alloc.voices()[0].triggers, 3);
+
+        // Back to the previous held note, releasing a note not played
+        // changes nothing
+        alloc.note_off(64);
+        assert_eq!(alloc.voices()[0].triggers, 3);
+        alloc.note_off(67);
+        assert_eq!(notes(&alloc), [Some(60), None]);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(60));
+        assert_eq!(alloc.voices()[0].triggers, 4);
+        alloc.note_off(60);
+        assert_eq!(notes(&alloc), [None; 2]);
+        assert!(!alloc.voices()[0].gate);
+
+        alloc.set_mode(Mode::Legato);
+        alloc.voices_mut()[0].triggers = 0;
+        alloc.note_on(60, 100);
+        alloc.note_on(64, 100);
+        alloc.note_off(64);
+        assert_eq!(alloc.voices()[0].triggers, 1);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(60));
+        alloc.note_off(60);
+        alloc.note_on(62, 100);
+        assert_eq!(alloc.voices()[0].triggers, 2);
+
+        // Changing the mode releases the notes
+        alloc.set_mode(Mode::Poly);
+        assert_eq!(notes(&alloc), [None; 2]);
+    }
+
+    #[test]
+    fn glide() {
+        let mut alloc = allocator::<1>();
+        alloc.set_mode(Mode::Mono);
+        alloc.set_glide(100);
+        alloc.note_on(60, 100);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(60));
+
+        alloc.note_on(72, 100);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(60));
+        alloc.update(25_000);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(63));
+        alloc.update(50_000);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(69));
+        alloc.update(50_000);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(72));
+
+        // Downwards, from the current pitch of the glide
+        alloc.note_on(48, 100);
+        alloc.update(50_000);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(60));
+
+        let mut alloc = allocator::<2>();
+        alloc.note_on(60, 100);
+        alloc.note_on(62, 100);
+        assert_eq!(alloc.voices()[1].pitch, note_pitch(62));
+        alloc.set_glide(10);
+        alloc.note_on(64, 100);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(62));
+        alloc.update(5_000);
+        assert_eq!(alloc.voices()[0].pitch, note_pitch(63));
+    }
+}
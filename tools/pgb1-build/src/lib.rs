@@ -0,0 +1,106 @@
+//! Build script helpers for PGB-1 applications
+//!
+//! The build script of an application only calls `build`:
+//!
+//! ```ignore
+//! fn main() {
+//!     pgb1_build::build();
+//! }
+//! ```
+//!
+//! with in `Cargo.toml`:
+//!
+//! ```toml
+//! [build-dependencies]
+//! pgb1-build = { path = "../../tools/pgb1-build" }
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Run all the steps below
+pub fn build() {
+    memory_x();
+    firmware_info();
+}
+
+/// Copy the `memory.x` file of the application into a directory where the
+/// linker always finds it, and rebuild when it changes
+///
+/// The linker searches the directory of `Cargo.toml` too, but not when the
+/// application is built from a workspace.
+pub fn memory_x() {
+    let manifest = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy(manifest.join("memory.x"), out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+}
+
+/// Set the `PGB1_GIT_HASH` and `PGB1_BUILD_DATE` environment variables
+/// read by `pgb1::firmware_info!`
+///
+/// The date is the one of `SOURCE_DATE_EPOCH` if set, for reproducible
+/// builds. Outside of a git repository the hash is empty.
+pub fn firmware_info() {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let git_hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_default();
+    println!("cargo:rustc-env=PGB1_GIT_HASH={}", git_hash);
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/refs/heads", git_dir);
+    }
+
+    let seconds = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().unwrap(),
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+    };
+    println!("cargo:rustc-env=PGB1_BUILD_DATE={}", date(seconds));
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+/// `YYYY-MM-DD` of a Unix time
+pub fn date(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Date from a number of days since 1970-01-01, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(86399), "1970-01-01");
+        assert_eq!(date(951_782_400), "2000-02-29");
+        assert_eq!(date(951_868_800), "2000-03-01");
+        assert_eq!(date(1_798_675_200), "2026-12-31");
+        assert_eq!(date(4_107_542_400), "2100-03-01");
+    }
+}
@@ -0,0 +1,549 @@
+//! Talk to a PGB-1 with the SysEx protocol of `pgb1::sysex`
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pgb1::crc::crc32;
+use pgb1::midi::{Event, Parser};
+use pgb1::sysex::{
+    is_protocol_message, Chunk, Error, KeyAction, Kind, Reply, Request, CHUNK_SIZE,
+    MAX_MESSAGE_SIZE, SCREEN_PAGES, SCREEN_PAGE_SIZE,
+};
+
+/// Number of projects and patterns tried by a backup
+pub const MAX_INDEX: u8 = 127;
+
+/// Transport of SysEx messages, without the F0/F7 delimiters
+pub trait Port {
+    fn send(&mut self, message: &[u8]) -> Result<(), String>;
+
+    /// Next SysEx message received, `None` after `timeout`
+    fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, String>;
+}
+
+/// Raw MIDI device, such as `/dev/snd/midiC1D0` on Linux
+pub struct RawMidi {
+    output: File,
+    messages: Receiver<Vec<u8>>,
+}
+
+impl RawMidi {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let output = OpenOptions::new().write(true).open(path).map_err(error)?;
+        let mut input = File::open(path).map_err(error)?;
+
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut parser: Parser<1024> = Parser::new();
+            let mut buffer = [0; 256];
+            while let Ok(len @ 1..) = input.read(&mut buffer) {
+                for &byte in &buffer[..len] {
+                    if let Some(Event::SysEx(data)) = parser.feed(byte) {
+                        if sender.send(data.to_vec()).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(RawMidi { output, messages })
+    }
+}
+
+impl Port for RawMidi {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(message.len() + 2);
+        bytes.push(0xF0);
+        bytes.extend_from_slice(message);
+        bytes.push(0xF7);
+        self.output.write_all(&bytes).map_err(|e| e.to_string())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+        match self.messages.recv_timeout(timeout) {
+            Ok(message) => Ok(Some(message)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err("MIDI input closed".into()),
+        }
+    }
+}
+
+pub struct Client<P: Port> {
+    port: P,
+    timeout: Duration,
+}
+
+impl<P: Port> Client<P> {
+    pub fn new(port: P) -> Self {
+        Client {
+            port,
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Send a request and wait for its reply, nak replies are returned as
+    /// protocol errors
+    fn exchange(&mut self, request: &Request) -> Result<Result<Reply, Error>, String> {
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = request
+            .encode(&mut buffer)
+            .ok_or("request does not fit in a message")?;
+        self.port.send(&buffer[..len])?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let message = self
+                .port
+                .receive(remaining)?
+                .ok_or("no reply from the PGB-1")?;
+            if !is_protocol_message(&message) {
+                continue;
+            }
+            return match Reply::decode(&message) {
+                Some(Reply::Nak(_, error)) => Ok(Err(error)),
+                Some(reply) => Ok(Ok(reply)),
+                None => Err("invalid reply".into()),
+            };
+        }
+    }
+
+    /// Send a request and wait for its reply
+    pub fn request(&mut self, request: &Request) -> Result<Reply, String> {
+        self.exchange(request)?
+            .map_err(|error| format!("{:?} request failed: {:?}", request, error))
+    }
+
+    fn expect_ack(&mut self, request: &Request) -> Result<(), String> {
+        match self.request(request)? {
+            Reply::Ack(_) => Ok(()),
+            reply => Err(format!("unexpected reply {:?}", reply)),
+        }
+    }
+
+    pub fn version(&mut self) -> Result<String, String> {
+        match self.request(&Request::Version)? {
+            Reply::Version(version) => Ok(version.to_string()),
+            reply => Err(format!("unexpected reply {:?}", reply)),
+        }
+    }
+
+    /// Read an item, `None` if it does not exist
+    pub fn dump(&mut self, kind: Kind, index: u8) -> Result<Option<Vec<u8>>, String> {
+        let mut data = Vec::new();
+        let mut chunk = 0;
+        loop {
+            let request = Request::Dump { kind, index, chunk };
+            let reply = match self.exchange(&request)? {
+                Ok(reply) => reply,
+                Err(Error::NotFound) if chunk == 0 => return Ok(None),
+                Err(error) => return Err(format!("{:?} request failed: {:?}", request, error)),
+            };
+
+            let Reply::Data {
+                size,
+                data: chunk_data,
+                chunk: reply_chunk,
+                ..
+            } = reply
+            else {
+                return Err(format!("unexpected reply {:?}", reply));
+            };
+            if reply_chunk != chunk {
+                return Err(format!("expected chunk {}, got {}", chunk, reply_chunk));
+            }
+
+            data.extend_from_slice(&chunk_data);
+            if data.len() >= size as usize {
+                data.truncate(size as usize);
+                return Ok(Some(data));
+            }
+            if chunk_data.is_empty() {
+                return Err("empty chunk".into());
+            }
+            chunk += 1;
+        }
+    }
+
+    /// Replace an item
+    pub fn restore(&mut self, kind: Kind, index: u8, data: &[u8]) -> Result<(), String> {
+        let size = data.len() as u32;
+        self.expect_ack(&Request::RestoreBegin { kind, index, size })?;
+        for (chunk, chunk_data) in data.chunks(CHUNK_SIZE).enumerate() {
+            self.expect_ack(&Request::RestoreData {
+                kind,
+                index,
+                chunk: chunk as u16,
+                data: Chunk::from_slice(chunk_data).unwrap(),
+            })?;
+        }
+        let crc = crc32(data);
+        self.expect_ack(&Request::RestoreEnd { kind, index, crc })
+    }
+
+    pub fn key(&mut self, key: pgb1::Keys, action: KeyAction) -> Result<(), String> {
+        let index = pgb1::Keys::LIST
+            .iter()
+            .position(|k| k.mask() == key.mask())
+            .unwrap();
+        self.expect_ack(&Request::Key {
+            key: index as u8,
+            action,
+        })
+    }
+
+    /// Restart the device in the USB bootloader
+    pub fn bootloader(&mut self) -> Result<(), String> {
+        self.expect_ack(&Request::Bootloader)
+    }
+
+    /// Screen content, in the layout of `pgb1::ui::FrameBuffer`
+    pub fn screen(&mut self) -> Result<Vec<u8>, String> {
+        let mut screen = Vec::new();
+        for page in 0..SCREEN_PAGES {
+            match self.request(&Request::Screen { page })? {
+                Reply::Screen {
+                    page: reply_page,
+                    data,
+                } if reply_page == page => screen.extend_from_slice(&data),
+                reply => return Err(format!("unexpected reply {:?}", reply)),
+            }
+        }
+        Ok(screen)
+    }
+}
+
+/// Screen pixel from a screen dump
+pub fn pixel(screen: &[u8], x: usize, y: usize) -> bool {
+    screen[y / 8 * SCREEN_PAGE_SIZE + x] & (1 << (y % 8)) != 0
+}
+
+pub const SCREEN_WIDTH: usize = SCREEN_PAGE_SIZE;
+pub const SCREEN_HEIGHT: usize = SCREEN_PAGES as usize * 8;
+
+/// Screen dump as a binary PBM image
+pub fn to_pbm(screen: &[u8]) -> Vec<u8> {
+    let mut image = format!("P4\n{} {}\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    for y in 0..SCREEN_HEIGHT {
+        for x in (0..SCREEN_WIDTH).step_by(8) {
+            let byte = (0..8).fold(0, |byte, bit| byte << 1 | pixel(screen, x + bit, y) as u8);
+            image.push(byte);
+        }
+    }
+    image
+}
+
+/// Screen dump as text, two rows of pixels per line
+pub fn to_text(screen: &[u8]) -> String {
+    let mut text = String::new();
+    for y in (0..SCREEN_HEIGHT).step_by(2) {
+        for x in 0..SCREEN_WIDTH {
+            text.push(match (pixel(screen, x, y), pixel(screen, x, y + 1)) {
+                (false, false) => ' ',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (true, true) => '█',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// File name of an item in a backup directory
+pub fn backup_name(kind: Kind, index: u8) -> String {
+    match kind {
+        Kind::Settings => "settings.bin".into(),
+        Kind::Project => format!("project-{:03}.bin", index),
+        Kind::Pattern => format!("pattern-{:03}.bin", index),
+    }
+}
+
+/// Items that can be in a backup
+pub fn backup_items() -> impl Iterator<Item = (Kind, u8)> {
+    let indexed = |kind| (0..=MAX_INDEX).map(move |index| (kind, index));
+    std::iter::once((Kind::Settings, 0))
+        .chain(indexed(Kind::Project))
+        .chain(indexed(Kind::Pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, VecDeque};
+
+    use pgb1::fs::FileWriter;
+    use pgb1::sysex::{Handler, Server};
+    use pgb1::Keys;
+
+    /// Items, keys and screen of a pretend PGB-1
+    #[derive(Default)]
+    struct Device {
+        items: HashMap<(Kind, u8), Vec<u8>>,
+        restoring: Option<Vec<u8>>,
+        keys: Vec<(&'static str, KeyAction)>,
+        bootloader: bool,
+    }
+
+    impl Handler for Device {
+        fn size(&mut self, kind: Kind, index: u8) -> Option<u32> {
+            self.items.get(&(kind, index)).map(|item| item.len() as u32)
+        }
+
+        fn read(
+            &mut self,
+            kind: Kind,
+            index: u8,
+            offset: u32,
+            buffer: &mut [u8],
+        ) -> Result<(), Error> {
+            let item = self.items.get(&(kind, index)).ok_or(Error::NotFound)?;
+            let offset = offset as usize;
+            buffer.copy_from_slice(&item[offset..offset + buffer.len()]);
+            Ok(())
+        }
+
+        fn begin_restore(
+            &mut self,
+            _kind: Kind,
+            _index: u8,
+            size: u32,
+        ) -> Result<Option<FileWriter>, Error> {
+            self.restoring = Some(Vec::with_capacity(size as usize));
+            Ok(None)
+        }
+
+        fn write(
+            &mut self,
+            _kind: Kind,
+            _index: u8,
+            offset: u32,
+            data: &[u8],
+            _file: Option<&mut FileWriter>,
+        ) -> Result<(), Error> {
+            let item = self.restoring.as_mut().ok_or(Error::OutOfOrder)?;
+            assert_eq!(item.len(), offset as usize);
+            item.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn end_restore(
+            &mut self,
+            kind: Kind,
+            index: u8,
+            _file: Option<FileWriter>,
+            commit: bool,
+        ) -> Result<(), Error> {
+            let item = self.restoring.take().ok_or(Error::OutOfOrder)?;
+            if commit {
+                self.items.insert((kind, index), item);
+            }
+            Ok(())
+        }
+
+        fn key(&mut self, key: Keys, action: KeyAction) -> Result<(), Error> {
+            self.keys.push((key.name(), action));
+            Ok(())
+        }
+
+        fn bootloader(&mut self) -> Result<(), Error> {
+            self.bootloader = true;
+            Ok(())
+        }
+
+        fn screen(&mut self, page: u8, buffer: &mut [u8; SCREEN_PAGE_SIZE]) -> Result<(), Error> {
+            // A diagonal line
+            for (x, column) in buffer.iter_mut().enumerate() {
+                if x / 8 == page as usize * 2 {
+                    *column = 1 << (x % 8);
+                } else {
+                    *column = 0;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Stand-in for a MIDI port connected to a PGB-1: messages go through
+    /// MIDI parsers on both sides like on a real link.
+    struct Loopback {
+        device: Device,
+        server: Server,
+        device_parser: Parser,
+        host_parser: Parser<1024>,
+        replies: VecDeque<Vec<u8>>,
+        /// Corrupt the next message sent
+        corrupt: bool,
+    }
+
+    impl Loopback {
+        fn new(device: Device) -> Self {
+            Loopback {
+                device,
+                server: Server::new(),
+                device_parser: Parser::new(),
+                host_parser: Parser::new(),
+                replies: VecDeque::new(),
+                corrupt: false,
+            }
+        }
+    }
+
+    impl Port for Loopback {
+        fn send(&mut self, message: &[u8]) -> Result<(), String> {
+            let mut bytes = vec![0xF0];
+            bytes.extend_from_slice(message);
+            bytes.push(0xF7);
+            if std::mem::take(&mut self.corrupt) {
+                let last = bytes.len() - 2;
+                bytes[last] ^= 0x01;
+            }
+
+            for byte in bytes {
+                let Some(Event::SysEx(data)) = self.device_parser.feed(byte) else {
+                    continue;
+                };
+                let mut reply = [0; MAX_MESSAGE_SIZE];
+                let Some(len) = self.server.handle(data, &mut self.device, &mut reply) else {
+                    continue;
+                };
+
+                // Unrelated traffic the client has to skip
+                let mut wire = vec![0xF8, 0xF0, 0x43, 0x10, 0xF7, 0xF0];
+                wire.extend_from_slice(&reply[..len]);
+                wire.push(0xF7);
+                for byte in wire {
+                    if let Some(Event::SysEx(data)) = self.host_parser.feed(byte) {
+                        self.replies.push_back(data.to_vec());
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn receive(&mut self, _timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+            Ok(self.replies.pop_front())
+        }
+    }
+
+    fn client() -> Client<Loopback> {
+        Client::new(Loopback::new(Device::default()))
+    }
+
+    #[test]
+    fn version() {
+        let mut client = client();
+        assert_eq!(
+            client.version().unwrap(),
+            format!("pgb1 {}", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn restore_then_dump() {
+        let mut client = client();
+        let project: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 256) as u8).collect();
+        let settings = vec![0xFF; CHUNK_SIZE];
+
+        client.restore(Kind::Project, 5, &project).unwrap();
+        client.restore(Kind::Settings, 0, &settings).unwrap();
+        client.restore(Kind::Pattern, 1, &[]).unwrap();
+
+        assert_eq!(client.dump(Kind::Project, 5).unwrap(), Some(project));
+        assert_eq!(client.dump(Kind::Settings, 0).unwrap(), Some(settings));
+        assert_eq!(client.dump(Kind::Pattern, 1).unwrap(), Some(vec![]));
+        assert_eq!(client.dump(Kind::Project, 4).unwrap(), None);
+    }
+
+    #[test]
+    fn corrupted_restore_is_not_committed() {
+        let mut client = client();
+        client.restore(Kind::Project, 0, &[1, 2, 3]).unwrap();
+
+        client.port.corrupt = true;
+        let err = client.restore(Kind::Project, 0, &[4, 5, 6]).unwrap_err();
+        // The corrupted chunk is either refused or caught by the CRC
+        assert!(
+            err.contains("Checksum") || err.contains("Malformed"),
+            "{err}"
+        );
+        assert_eq!(client.dump(Kind::Project, 0).unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn restore_out_of_order() {
+        let mut client = client();
+        let request = Request::RestoreData {
+            kind: Kind::Project,
+            index: 0,
+            chunk: 0,
+            data: Chunk::from_slice(&[1]).unwrap(),
+        };
+        let err = client.request(&request).unwrap_err();
+        assert!(err.contains("OutOfOrder"), "{err}");
+    }
+
+    #[test]
+    fn keys() {
+        let mut client = client();
+        client.key(Keys::PLAY, KeyAction::Tap).unwrap();
+        client.key(Keys::K16, KeyAction::Press).unwrap();
+        client.key(Keys::K16, KeyAction::Release).unwrap();
+        assert_eq!(
+            client.port.device.keys,
+            [
+                ("PLAY", KeyAction::Tap),
+                ("K16", KeyAction::Press),
+                ("K16", KeyAction::Release)
+            ]
+        );
+    }
+
+    #[test]
+    fn screen() {
+        let mut client = client();
+        let screen = client.screen().unwrap();
+        assert_eq!(screen.len(), SCREEN_WIDTH * SCREEN_HEIGHT / 8);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                assert_eq!(pixel(&screen, x, y), x == y / 8 * 16 + y % 8, "{x} {y}");
+            }
+        }
+        assert_eq!(to_pbm(&screen).len(), 10 + SCREEN_WIDTH * SCREEN_HEIGHT / 8);
+        assert!(to_text(&screen).starts_with('▀'));
+    }
+
+    #[test]
+    fn bootloader() {
+        let mut client = client();
+        client.bootloader().unwrap();
+        assert!(client.port.device.bootloader);
+    }
+
+    #[test]
+    fn unsupported() {
+        struct Nothing;
+        impl Handler for Nothing {}
+
+        let mut server = Server::new();
+        let mut request = [0; MAX_MESSAGE_SIZE];
+        let mut reply = [0; MAX_MESSAGE_SIZE];
+        let len = Request::Screen { page: 0 }.encode(&mut request).unwrap();
+        let len = server
+            .handle(&request[..len], &mut Nothing, &mut reply)
+            .unwrap();
+        assert_eq!(
+            Reply::decode(&reply[..len]),
+            Some(Reply::Nak(0x07, Error::Unsupported))
+        );
+
+        // Not for us
+        assert_eq!(server.handle(&[0x43, 0x10], &mut Nothing, &mut reply), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! Loadable segments of an application ELF file, as built by `cargo build`
+
+const PT_LOAD: u32 = 1;
+const EM_ARM: u16 = 40;
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Segments stored in flash, as (physical address, content): the code,
+/// read-only data and initial values of the RAM variables
+pub fn segments(data: &[u8]) -> Result<Vec<(u32, &[u8])>, String> {
+    // 32 bit, little endian ARM
+    if data.get(..6) != Some(b"\x7fELF\x01\x01") || u16_at(data, 18) != Some(EM_ARM) {
+        return Err("not an ARM ELF file".into());
+    }
+    let invalid = || String::from("invalid ELF file");
+
+    let phoff = u32_at(data, 28).ok_or_else(invalid)? as usize;
+    let phentsize = u16_at(data, 42).ok_or_else(invalid)? as usize;
+    let phnum = u16_at(data, 44).ok_or_else(invalid)? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        let kind = u32_at(data, header).ok_or_else(invalid)?;
+        let offset = u32_at(data, header + 4).ok_or_else(invalid)? as usize;
+        let address = u32_at(data, header + 12).ok_or_else(invalid)?;
+        let size = u32_at(data, header + 16).ok_or_else(invalid)? as usize;
+        if kind != PT_LOAD || size == 0 {
+            continue;
+        }
+        let content = data.get(offset..offset + size).ok_or_else(invalid)?;
+        segments.push((address, content));
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_segments() {
+        let mut elf = vec![0u8; 52 + 3 * 32];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        elf[18..20].copy_from_slice(&EM_ARM.to_le_bytes());
+        elf[28..32].copy_from_slice(&52u32.to_le_bytes());
+        elf[42..44].copy_from_slice(&32u16.to_le_bytes());
+        elf[44..46].copy_from_slice(&3u16.to_le_bytes());
+
+        let mut header = |index: usize, kind: u32, offset: u32, address: u32, size: u32| {
+            let h = 52 + index * 32;
+            elf[h..h + 4].copy_from_slice(&kind.to_le_bytes());
+            elf[h + 4..h + 8].copy_from_slice(&offset.to_le_bytes());
+            elf[h + 12..h + 16].copy_from_slice(&address.to_le_bytes());
+            elf[h + 16..h + 20].copy_from_slice(&size.to_le_bytes());
+        };
+        header(0, PT_LOAD, 0, 0x1000_0000, 4);
+        // .bss, nothing to load
+        header(1, PT_LOAD, 0, 0x2000_0000, 0);
+        header(2, 6, 0, 0x1000_0000, 4);
+
+        assert_eq!(
+            segments(&elf).unwrap(),
+            vec![(0x1000_0000, &b"\x7fELF"[..])]
+        );
+
+        elf.truncate(100);
+        assert!(segments(&elf).is_err());
+        assert!(segments(b"PGB1").is_err());
+    }
+}
@@ -0,0 +1,373 @@
+//! Host-side utilities for the PGB-1
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use pgb1::info::FirmwareInfo;
+use pgb1::sysex::{KeyAction, Kind};
+
+mod device;
+mod elf;
+mod pack;
+mod project;
+mod uf2;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert WAV files into a sample bank flash image
+    PackSamples {
+        /// Output binary image
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Also write a UF2 file that can be copied to the PGB-1 in
+        /// bootloader mode
+        #[arg(long)]
+        uf2: Option<PathBuf>,
+
+        /// Root note (MIDI note number) of the samples
+        #[arg(long, default_value_t = 60)]
+        root_note: u8,
+
+        /// WAV files, the sample names are taken from the file names
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+
+    /// Convert an application ELF file, as built by cargo, to UF2
+    Uf2 {
+        elf: PathBuf,
+
+        /// Output UF2 file, next to the ELF file by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print the firmware information of an ELF, UF2 or binary file
+    Info { input: PathBuf },
+
+    /// Convert project files to text and back
+    Project {
+        #[command(subcommand)]
+        command: ProjectCommand,
+    },
+
+    /// Talk to a PGB-1 over MIDI
+    Device {
+        /// Raw MIDI device of the PGB-1, such as /dev/snd/midiC1D0
+        #[arg(short, long)]
+        port: PathBuf,
+
+        #[command(subcommand)]
+        command: DeviceCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProjectCommand {
+    /// Print a project file as text
+    ToText {
+        input: PathBuf,
+
+        /// Output text file, standard output by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write the project file of a text file
+    FromText {
+        input: PathBuf,
+
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum DeviceCommand {
+    /// Print the firmware version
+    Version,
+
+    /// Save an item to a file
+    Dump {
+        #[arg(value_enum)]
+        kind: KindArg,
+
+        /// Project or pattern number
+        #[arg(default_value_t = 0)]
+        index: u8,
+
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Replace an item with the content of a file
+    Restore {
+        #[arg(value_enum)]
+        kind: KindArg,
+
+        /// Project or pattern number
+        index: u8,
+
+        input: PathBuf,
+    },
+
+    /// Save the settings and all the projects and patterns to a directory
+    Backup { directory: PathBuf },
+
+    /// Restore the items of a backup directory
+    RestoreBackup { directory: PathBuf },
+
+    /// Press a key, TRACK, PLAY, K1..K16, ...
+    Key {
+        key: String,
+
+        #[arg(value_enum, default_value_t = ActionArg::Tap)]
+        action: ActionArg,
+    },
+
+    /// Print the screen, or save it as a PBM image
+    Screen {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Restart in the USB bootloader, to install a UF2 file
+    Bootloader,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum KindArg {
+    Settings,
+    Project,
+    Pattern,
+}
+
+impl From<KindArg> for Kind {
+    fn from(kind: KindArg) -> Kind {
+        match kind {
+            KindArg::Settings => Kind::Settings,
+            KindArg::Project => Kind::Project,
+            KindArg::Pattern => Kind::Pattern,
+        }
+    }
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum ActionArg {
+    Tap,
+    Press,
+    Release,
+}
+
+impl From<ActionArg> for KeyAction {
+    fn from(action: ActionArg) -> KeyAction {
+        match action {
+            ActionArg::Tap => KeyAction::Tap,
+            ActionArg::Press => KeyAction::Press,
+            ActionArg::Release => KeyAction::Release,
+        }
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Flash content of an ELF file
+fn load_elf(data: &[u8]) -> Result<uf2::Pages, String> {
+    use pgb1::flash::{FLASH_SIZE, XIP_BASE};
+
+    let mut pages = uf2::Pages::new();
+    for (address, content) in elf::segments(data)? {
+        let end = address as u64 + content.len() as u64;
+        if address < XIP_BASE || end > (XIP_BASE + FLASH_SIZE) as u64 {
+            return Err(format!("segment at {:#010x} is not in flash", address));
+        }
+        uf2::add(&mut pages, address, content);
+    }
+    Ok(pages)
+}
+
+fn print_info(info: &FirmwareInfo) {
+    println!("name:           {}", info.name());
+    println!("version:        {}", info.version());
+    println!("git hash:       {}", info.git_hash());
+    println!("build date:     {}", info.build_date());
+    println!("board revision: {}", info.board_revision());
+}
+
+fn run_project(command: ProjectCommand) -> Result<(), String> {
+    match command {
+        ProjectCommand::ToText { input, output } => {
+            let mut project = Box::new(pgb1::project::Project::new());
+            project
+                .decode_bytes(&read_file(&input)?)
+                .map_err(|e| format!("{}: {:?}", input.display(), e))?;
+            let text = project::to_text(&project);
+            match output {
+                Some(path) => write_file(&path, text.as_bytes())?,
+                None => print!("{}", text),
+            }
+        }
+
+        ProjectCommand::FromText { input, output } => {
+            let text = String::from_utf8(read_file(&input)?)
+                .map_err(|_| format!("{}: not a text file", input.display()))?;
+            let project =
+                project::from_text(&text).map_err(|e| format!("{}: {}", input.display(), e))?;
+            let mut data = Vec::new();
+            project
+                .encode(|part| {
+                    data.extend_from_slice(part);
+                    Ok::<_, std::convert::Infallible>(())
+                })
+                .unwrap();
+            write_file(&output, &data)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_device(port: &Path, command: DeviceCommand) -> Result<(), String> {
+    let mut client = device::Client::new(device::RawMidi::open(port)?);
+    match command {
+        DeviceCommand::Version => println!("{}", client.version()?),
+
+        DeviceCommand::Dump {
+            kind,
+            index,
+            output,
+        } => {
+            let data = client
+                .dump(kind.into(), index)?
+                .ok_or("no such item on the PGB-1")?;
+            write_file(&output, &data)?;
+        }
+
+        DeviceCommand::Restore { kind, index, input } => {
+            client.restore(kind.into(), index, &read_file(&input)?)?;
+        }
+
+        DeviceCommand::Backup { directory } => {
+            std::fs::create_dir_all(&directory)
+                .map_err(|e| format!("{}: {}", directory.display(), e))?;
+            for (kind, index) in device::backup_items() {
+                if let Some(data) = client.dump(kind, index)? {
+                    let name = device::backup_name(kind, index);
+                    write_file(&directory.join(&name), &data)?;
+                    println!("{} ({} bytes)", name, data.len());
+                }
+            }
+        }
+
+        DeviceCommand::RestoreBackup { directory } => {
+            for (kind, index) in device::backup_items() {
+                let name = device::backup_name(kind, index);
+                let path = directory.join(&name);
+                if path.exists() {
+                    client.restore(kind, index, &read_file(&path)?)?;
+                    println!("{}", name);
+                }
+            }
+        }
+
+        DeviceCommand::Key { key, action } => {
+            let key = pgb1::Keys::from_name(&key).ok_or(format!("unknown key {}", key))?;
+            client.key(key, action.into())?;
+        }
+
+        DeviceCommand::Screen { output } => {
+            let screen = client.screen()?;
+            match output {
+                Some(path) => write_file(&path, &device::to_pbm(&screen))?,
+                None => print!("{}", device::to_text(&screen)),
+            }
+        }
+
+        DeviceCommand::Bootloader => client.bootloader()?,
+    }
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<(), String> {
+    match cli.command {
+        Command::PackSamples {
+            output,
+            uf2,
+            root_note,
+            inputs,
+        } => {
+            let image = pack::pack(&inputs, root_note)?;
+            std::fs::write(&output, &image).map_err(|e| format!("{}: {}", output.display(), e))?;
+
+            if let Some(path) = uf2 {
+                let address = pgb1::flash::XIP_BASE + pgb1::flash::SAMPLE_BANK_OFFSET;
+                std::fs::write(&path, uf2::encode(&image, address))
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+
+            println!(
+                "{} samples, {} / {} bytes",
+                inputs.len(),
+                image.len(),
+                pgb1::flash::SAMPLE_BANK_SIZE
+            );
+            Ok(())
+        }
+
+        Command::Uf2 { elf, output } => {
+            let pages = load_elf(&read_file(&elf)?)?;
+            let output = output.unwrap_or_else(|| elf.with_extension("uf2"));
+            write_file(&output, &uf2::encode_pages(&pages))?;
+
+            let (_, image) = uf2::flatten(&pages);
+            match FirmwareInfo::find(&image) {
+                Some(info) => print_info(&info),
+                None => println!("no firmware information block"),
+            }
+            println!("{}: {} bytes", output.display(), image.len());
+            Ok(())
+        }
+
+        Command::Info { input } => {
+            let data = read_file(&input)?;
+            let image = if uf2::is_uf2(&data) {
+                uf2::flatten(&uf2::decode(&data)?).1
+            } else if data.starts_with(b"\x7fELF") {
+                uf2::flatten(&load_elf(&data)?).1
+            } else {
+                data
+            };
+            let info = FirmwareInfo::find(&image).ok_or("no firmware information block")?;
+            print_info(&info);
+            Ok(())
+        }
+
+        Command::Project { command } => run_project(command),
+
+        Command::Device { port, command } => run_device(&port, command),
+    }
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("error: {}", msg);
+            ExitCode::FAILURE
+        }
+    }
+}
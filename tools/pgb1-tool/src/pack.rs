@@ -0,0 +1,160 @@
+//! Sample bank packing, see `pgb1::sampler` for the image layout
+
+use std::path::Path;
+
+use pgb1::flash::SAMPLE_BANK_SIZE;
+use pgb1::sampler::{BankHeader, SampleEntry, BANK_VERSION, ENTRY_SIZE, HEADER_SIZE, NAME_LEN};
+
+/// Read a WAV file and convert it to 16-bit mono
+fn read_wav(path: &Path) -> Result<(Vec<i16>, u32), String> {
+    let err = |e: hound::Error| format!("{}: {}", path.display(), e);
+
+    let mut reader = hound::WavReader::open(path).map_err(err)?;
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+
+    let interleaved: Vec<i32> = match spec.sample_format {
+        hound::SampleFormat::Int => {
+            let shift = spec.bits_per_sample as i32 - 16;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| if shift >= 0 { s >> shift } else { s << -shift }))
+                .collect::<Result<_, _>>()
+                .map_err(err)?
+        }
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .map(|s| s.map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i32))
+            .collect::<Result<_, _>>()
+            .map_err(err)?,
+    };
+
+    let mono = interleaved
+        .chunks(channels)
+        .map(|frame| (frame.iter().sum::<i32>() / channels as i32) as i16)
+        .collect();
+
+    Ok((mono, spec.sample_rate))
+}
+
+fn sample_name(path: &Path) -> [u8; NAME_LEN] {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+
+    let mut name = [0; NAME_LEN];
+    for (dst, c) in name.iter_mut().zip(stem.chars().filter(|c| c.is_ascii())) {
+        *dst = c as u8;
+    }
+    name
+}
+
+/// Build a sample bank image from a list of WAV files
+pub fn pack(inputs: &[impl AsRef<Path>], root_note: u8) -> Result<Vec<u8>, String> {
+    let count = u16::try_from(inputs.len()).map_err(|_| "too many samples".to_string())?;
+
+    let mut entries = Vec::new();
+    let mut data = Vec::new();
+    let data_start = HEADER_SIZE + inputs.len() * ENTRY_SIZE;
+
+    for path in inputs {
+        let path = path.as_ref();
+        let (frames, sample_rate) = read_wav(path)?;
+
+        while !(data_start + data.len()).is_multiple_of(4) {
+            data.push(0);
+        }
+
+        entries.push(SampleEntry {
+            name: sample_name(path),
+            offset: (data_start + data.len()) as u32,
+            length: frames.len() as u32,
+            sample_rate,
+            root_note,
+        });
+        data.extend(frames.iter().flat_map(|s| s.to_le_bytes()));
+    }
+
+    let size = data_start + data.len();
+    if size > SAMPLE_BANK_SIZE as usize {
+        return Err(format!(
+            "samples do not fit in the bank: {} bytes, maximum is {}",
+            size, SAMPLE_BANK_SIZE
+        ));
+    }
+
+    let header = BankHeader {
+        version: BANK_VERSION,
+        count,
+        size: size as u32,
+    };
+
+    let mut image = Vec::with_capacity(size);
+    image.extend(header.encode());
+    for entry in &entries {
+        image.extend(entry.encode());
+    }
+    image.extend(data);
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pgb1::sampler::SampleBank;
+
+    fn write_wav(path: &Path, spec: hound::WavSpec, samples: &[i32]) {
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for &s in samples {
+            writer.write_sample(s).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn pack_and_parse() {
+        let dir = std::env::temp_dir().join(format!("pgb1-pack-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kick = dir.join("kick.wav");
+        let snare = dir.join("a-very-long-snare-name.wav");
+        let mono = hound::WavSpec {
+            channels: 1,
+            sample_rate: 22_050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        write_wav(&kick, mono, &[100, -200, 300]);
+        // 24-bit stereo, mixed down to 16-bit mono
+        let stereo = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 24,
+            ..mono
+        };
+        write_wav(&snare, stereo, &[256 * 10, 256 * 30, -256 * 100, 0]);
+
+        let image = pack(&[&kick, &snare], 36).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let bank = SampleBank::new(Box::leak(image.into_boxed_slice())).unwrap();
+        assert_eq!(bank.len(), 2);
+        let entry = bank.entry(1).unwrap();
+        assert_eq!(entry.name(), "a-very-long-snar");
+        // After the 3 frames of the kick, aligned
+        assert_eq!(entry.offset, (HEADER_SIZE + 2 * ENTRY_SIZE + 8) as u32);
+
+        let kick = bank.find("kick").unwrap();
+        assert_eq!(
+            (kick.sample_rate, kick.root_note, kick.len()),
+            (22_050, 36, 3)
+        );
+        assert_eq!(
+            [kick.frame(0), kick.frame(1), kick.frame(2)],
+            [100, -200, 300]
+        );
+        let snare = bank.get(1).unwrap();
+        assert_eq!((snare.sample_rate, snare.len()), (48_000, 2));
+        assert_eq!([snare.frame(0), snare.frame(1)], [20, -50]);
+    }
+}
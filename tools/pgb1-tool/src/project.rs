@@ -0,0 +1,497 @@
+//! Text format of the project files, see `pgb1::project`
+//!
+//! One setting per line, patterns, tracks, steps and MIDI channels are
+//! numbered from 1. Only the active steps and the lengths other than 16 are
+//! listed. The `offset` of a step is its micro-timing in ticks. It is given
+//! when it is not 0, like the trig `condition` (`30%`, `fill`, `!fill`,
+//! `2:4`, `first`, `!first`), the `retrig` count and the `decay`. Parameter
+//! locks are given as `parameter=value`, parameters are numbered from 0
+//! like `pgb1::param::ParamId`:
+//!
+//! ```text
+//! # PGB-1 project
+//! format 5
+//! name Demo
+//! tempo 127.5
+//! swing 58
+//! track 2 volume=100 pan=-20 mute=on channel=1
+//! sound 2 3 -400 12000
+//! length 3.1 64
+//! step 3.1.62 note=C2 velocity=127 gate=100
+//! step 3.1.63 note=C2 velocity=90 gate=50 offset=-4
+//! step 3.1.64 note=C2 velocity=90 gate=50 condition=1:4 retrig=3 decay=20
+//! lock 3.1.64 4=-1000 7=30
+//! song 3x4 6 6
+//! row 2 tempo=90.0 mute=1,8
+//! loop 2-3
+//! ```
+//!
+//! `song` lists the patterns of the rows and their repeats, `row` gives the
+//! tempo and the muted tracks of the rows that have them.
+//!
+//! The text is always written in the latest format, older project files
+//! are migrated when they are converted.
+
+use std::fmt::Write;
+
+use pgb1::project::{
+    Condition, Project, SongLoop, SongRow, Step, MAX_LOCKS, MAX_OFFSET, MAX_RETRIGS,
+    MAX_SOUND_PARAMS, MAX_STEPS, PAGE_STEPS, PATTERNS, TRACKS, VERSION,
+};
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// Note name such as C4 or F#-1, or a MIDI note number
+fn parse_note(text: &str) -> Option<u8> {
+    if let Ok(note) = text.parse::<u8>() {
+        return (note < 128).then_some(note);
+    }
+    let split = text.find(|c: char| c == '-' || c.is_ascii_digit())?;
+    let (name, octave) = text.split_at(split);
+    let index = NOTE_NAMES.iter().position(|&n| n == name)? as i32;
+    let note = (octave.parse::<i32>().ok()? + 1) * 12 + index;
+    u8::try_from(note).ok().filter(|&note| note < 128)
+}
+
+fn condition_text(condition: Condition) -> String {
+    match condition {
+        Condition::Always => "always".to_string(),
+        Condition::Probability(percent) => format!("{}%", percent),
+        Condition::Fill => "fill".to_string(),
+        Condition::NotFill => "!fill".to_string(),
+        Condition::Cycle { n, m } => format!("{}:{}", n, m),
+        Condition::First => "first".to_string(),
+        Condition::NotFirst => "!first".to_string(),
+    }
+}
+
+fn parse_condition(text: &str) -> Option<Condition> {
+    let condition = match text {
+        "always" => Condition::Always,
+        "fill" => Condition::Fill,
+        "!fill" => Condition::NotFill,
+        "first" => Condition::First,
+        "!first" => Condition::NotFirst,
+        _ => {
+            if let Some(percent) = text.strip_suffix('%') {
+                Condition::Probability(percent.parse().ok()?)
+            } else {
+                let (n, m) = text.split_once(':')?;
+                Condition::Cycle {
+                    n: n.parse().ok()?,
+                    m: m.parse().ok()?,
+                }
+            }
+        }
+    };
+    Some(condition)
+}
+
+pub fn to_text(project: &Project) -> String {
+    let mut out = String::new();
+    writeln!(out, "# PGB-1 project").unwrap();
+    writeln!(out, "format {}", VERSION).unwrap();
+    writeln!(out, "name {}", project.name).unwrap();
+    writeln!(out, "tempo {}.{}", project.tempo / 10, project.tempo % 10).unwrap();
+    writeln!(out, "swing {}", project.swing).unwrap();
+
+    for (index, track) in project.tracks.iter().enumerate() {
+        writeln!(
+            out,
+            "track {} volume={} pan={} mute={} channel={}",
+            index + 1,
+            track.volume,
+            track.pan,
+            if track.mute { "on" } else { "off" },
+            track.midi_channel + 1
+        )
+        .unwrap();
+        if !track.sound.is_empty() {
+            write!(out, "sound {}", index + 1).unwrap();
+            for value in &track.sound {
+                write!(out, " {}", value).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+    }
+
+    for (p, pattern) in project.patterns.iter().enumerate() {
+        for (t, sequence) in pattern.tracks.iter().enumerate() {
+            if sequence.length as usize != PAGE_STEPS {
+                writeln!(out, "length {}.{} {}", p + 1, t + 1, sequence.length).unwrap();
+            }
+            for (s, step) in sequence.steps.iter().enumerate() {
+                if step.active {
+                    write!(
+                        out,
+                        "step {}.{}.{} note={} velocity={} gate={}",
+                        p + 1,
+                        t + 1,
+                        s + 1,
+                        note_name(step.note),
+                        step.velocity,
+                        step.gate
+                    )
+                    .unwrap();
+                    if step.offset != 0 {
+                        write!(out, " offset={}", step.offset).unwrap();
+                    }
+                    if step.condition != Condition::Always {
+                        write!(out, " condition={}", condition_text(step.condition)).unwrap();
+                    }
+                    if step.retrigs != 0 {
+                        write!(out, " retrig={}", step.retrigs).unwrap();
+                    }
+                    if step.decay != 0 {
+                        write!(out, " decay={}", step.decay).unwrap();
+                    }
+                    writeln!(out).unwrap();
+                }
+                if pattern.is_locked(t, s) {
+                    write!(out, "lock {}.{}.{}", p + 1, t + 1, s + 1).unwrap();
+                    for (param, value) in pattern.step_locks(t, s) {
+                        write!(out, " {}={}", param, value).unwrap();
+                    }
+                    writeln!(out).unwrap();
+                }
+            }
+        }
+    }
+
+    if !project.song.is_empty() {
+        write!(out, "song").unwrap();
+        for row in &project.song {
+            match row.repeats {
+                1 => write!(out, " {}", row.pattern + 1).unwrap(),
+                n => write!(out, " {}x{}", row.pattern + 1, n).unwrap(),
+            }
+        }
+        writeln!(out).unwrap();
+    }
+    for (index, row) in project.song.iter().enumerate() {
+        if row.tempo == 0 && row.mutes == 0 {
+            continue;
+        }
+        write!(out, "row {}", index + 1).unwrap();
+        if row.tempo != 0 {
+            write!(out, " tempo={}.{}", row.tempo / 10, row.tempo % 10).unwrap();
+        }
+        if row.mutes != 0 {
+            let tracks: Vec<String> = (0..TRACKS)
+                .filter(|t| row.mutes & (1 << t) != 0)
+                .map(|t| (t + 1).to_string())
+                .collect();
+            write!(out, " mute={}", tracks.join(",")).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    if let Some(song_loop) = project.song_loop {
+        writeln!(out, "loop {}-{}", song_loop.start + 1, song_loop.end + 1).unwrap();
+    }
+    out
+}
+
+fn number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("invalid number '{}'", text))
+}
+
+/// BPM with a decimal, in tenth of BPM
+fn parse_tempo(text: &str) -> Result<u16, String> {
+    Ok((number::<f32>(text)? * 10.0).round() as u16)
+}
+
+/// 1-based number in `1..=max`, returned 0-based
+fn index(text: &str, max: usize) -> Result<usize, String> {
+    match number::<usize>(text)? {
+        n @ 1.. if n <= max => Ok(n - 1),
+        _ => Err(format!("'{}' is not in 1..={}", text, max)),
+    }
+}
+
+/// Dotted 1-based indexes, such as `3.1.64`
+fn indexes<const N: usize>(text: &str, max: [usize; N]) -> Result<[usize; N], String> {
+    let parts: Vec<&str> = text.split('.').collect();
+    if parts.len() != N {
+        return Err(format!("invalid position '{}'", text));
+    }
+    let mut out = [0; N];
+    for i in 0..N {
+        out[i] = index(parts[i], max[i])?;
+    }
+    Ok(out)
+}
+
+/// Settings of a line, as `key=value`
+fn settings<'a>(words: &[&'a str]) -> Result<Vec<(&'a str, &'a str)>, String> {
+    words
+        .iter()
+        .map(|word| {
+            word.split_once('=')
+                .ok_or(format!("expected key=value, got '{}'", word))
+        })
+        .collect()
+}
+
+fn parse_line(project: &mut Project, line: &str) -> Result<(), String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (&keyword, args) = words.split_first().unwrap();
+    let arg = |i: usize| {
+        args.get(i)
+            .copied()
+            .ok_or(format!("missing argument of {}", keyword))
+    };
+
+    match keyword {
+        "format" => {
+            let version = number::<u16>(arg(0)?)?;
+            if version == 0 || version > VERSION {
+                return Err(format!("unsupported format {}", version));
+            }
+        }
+        "name" => {
+            let name = line["name".len()..].trim();
+            project.name = name
+                .try_into()
+                .map_err(|_| "name is too long".to_string())?;
+        }
+        "tempo" => project.tempo = parse_tempo(arg(0)?)?,
+        "swing" => project.swing = number(arg(0)?)?,
+        "track" => {
+            let track = &mut project.tracks[index(arg(0)?, TRACKS)?];
+            for (key, value) in settings(&args[1..])? {
+                match key {
+                    "volume" => track.volume = number(value)?,
+                    "pan" => track.pan = number(value)?,
+                    "mute" => track.mute = value == "on",
+                    "channel" => track.midi_channel = index(value, 16)? as u8,
+                    _ => return Err(format!("unknown track setting '{}'", key)),
+                }
+            }
+        }
+        "sound" => {
+            let track = &mut project.tracks[index(arg(0)?, TRACKS)?];
+            track.sound.clear();
+            for value in &args[1..] {
+                track
+                    .sound
+                    .push(number(value)?)
+                    .map_err(|_| "too many sound parameters".to_string())?;
+            }
+        }
+        "length" => {
+            let [p, t] = indexes(arg(0)?, [PATTERNS, TRACKS])?;
+            let length = index(arg(1)?, MAX_STEPS)? + 1;
+            project.patterns[p].tracks[t].length = length as u8;
+        }
+        "step" => {
+            let [p, t, s] = indexes(arg(0)?, [PATTERNS, TRACKS, MAX_STEPS])?;
+            let mut step = Step {
+                active: true,
+                ..Step::new()
+            };
+            for (key, value) in settings(&args[1..])? {
+                match key {
+                    "note" => {
+                        step.note = parse_note(value).ok_or(format!("invalid note '{}'", value))?
+                    }
+                    "velocity" => step.velocity = number(value)?,
+                    "gate" => step.gate = number(value)?,
+                    "offset" => {
+                        step.offset = number(value)?;
+                        if !(-MAX_OFFSET..=MAX_OFFSET).contains(&step.offset) {
+                            return Err(format!("offset {} is not in ±{}", value, MAX_OFFSET));
+                        }
+                    }
+                    "condition" => {
+                        step.condition = parse_condition(value)
+                            .filter(|c| c.is_valid())
+                            .ok_or(format!("invalid condition '{}'", value))?
+                    }
+                    "retrig" => {
+                        step.retrigs = number(value)?;
+                        if step.retrigs > MAX_RETRIGS {
+                            return Err(format!("more than {} retrigs", MAX_RETRIGS));
+                        }
+                    }
+                    "decay" => step.decay = number::<u8>(value)?.min(100),
+                    _ => return Err(format!("unknown step setting '{}'", key)),
+                }
+            }
+            project.patterns[p].tracks[t].steps[s] = step;
+        }
+        "lock" => {
+            let [p, t, s] = indexes(arg(0)?, [PATTERNS, TRACKS, MAX_STEPS])?;
+            for (param, value) in settings(&args[1..])? {
+                let param = number::<u8>(param)?;
+                if param as usize >= MAX_SOUND_PARAMS {
+                    return Err(format!(
+                        "parameter {} is not below {}",
+                        param, MAX_SOUND_PARAMS
+                    ));
+                }
+                project.patterns[p]
+                    .set_lock(t, s, param, number(value)?)
+                    .map_err(|_| format!("more than {} locks in pattern {}", MAX_LOCKS, p + 1))?;
+            }
+        }
+        "song" => {
+            project.song.clear();
+            for row in args {
+                let (pattern, repeats) = row.split_once('x').unwrap_or((row, "1"));
+                let row = SongRow {
+                    repeats: index(repeats, 255)? as u8 + 1,
+                    ..SongRow::new(index(pattern, PATTERNS)? as u8)
+                };
+                project
+                    .song
+                    .push(row)
+                    .map_err(|_| "too many song rows".to_string())?;
+            }
+        }
+        "row" => {
+            let rows = project.song.len();
+            let row = &mut project.song[index(arg(0)?, rows)?];
+            for (key, value) in settings(&args[1..])? {
+                match key {
+                    "tempo" => row.tempo = parse_tempo(value)?,
+                    "mute" => {
+                        for track in value.split(',') {
+                            row.mutes |= 1 << index(track, TRACKS)?;
+                        }
+                    }
+                    _ => return Err(format!("unknown row setting '{}'", key)),
+                }
+            }
+        }
+        "loop" => {
+            let (start, end) = arg(0)?
+                .split_once('-')
+                .ok_or(format!("expected first-last rows, got '{}'", arg(0)?))?;
+            let rows = project.song.len();
+            let (start, end) = (index(start, rows)?, index(end, rows)?);
+            if start > end {
+                return Err(format!("loop row {} is after {}", start + 1, end + 1));
+            }
+            project.song_loop = Some(SongLoop {
+                start: start as u8,
+                end: end as u8,
+            });
+        }
+        _ => return Err(format!("unknown setting '{}'", keyword)),
+    }
+    Ok(())
+}
+
+pub fn from_text(text: &str) -> Result<Box<Project>, String> {
+    let mut project = Box::new(Project::new());
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        parse_line(&mut project, line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+    }
+    Ok(project)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let mut project = Box::new(Project::new());
+        project.name.push_str("My song").unwrap();
+        project.tempo = 1275;
+        project.tracks[1].pan = -20;
+        project.tracks[1].mute = true;
+        project.tracks[1]
+            .sound
+            .extend_from_slice(&[3, -400])
+            .unwrap();
+        project.patterns[2].tracks[0].length = 64;
+        project.patterns[2].tracks[0].steps[63] = Step {
+            active: true,
+            note: 1,
+            velocity: 127,
+            gate: 100,
+            offset: 0,
+            condition: Condition::Cycle { n: 3, m: 8 },
+            retrigs: 7,
+            decay: 15,
+        };
+        project.patterns[2].tracks[3].steps[4].active = true;
+        project.patterns[2].tracks[3].steps[5] = Step {
+            active: true,
+            offset: -12,
+            ..Step::new()
+        };
+        project.patterns[2].tracks[3].steps[6] = Step {
+            active: true,
+            condition: Condition::NotFill,
+            ..Step::new()
+        };
+        project.patterns[2].set_lock(3, 4, 9, -7).unwrap();
+        project.patterns[2].set_lock(3, 4, 2, 1000).unwrap();
+        project.patterns[15].set_lock(7, 0, 0, 0).unwrap();
+        project
+            .song
+            .push(SongRow {
+                pattern: 2,
+                repeats: 4,
+                tempo: 0,
+                mutes: 0,
+            })
+            .unwrap();
+        project
+            .song
+            .push(SongRow {
+                tempo: 905,
+                mutes: 0b1000_0010,
+                ..SongRow::new(0)
+            })
+            .unwrap();
+        project.song_loop = Some(SongLoop { start: 0, end: 1 });
+
+        let text = to_text(&project);
+        assert!(text.contains("\nname My song\n"));
+        assert!(text.contains("\ntempo 127.5\n"));
+        assert!(text.contains("\nlength 3.1 64\n"));
+        assert!(text.contains(
+            "\nstep 3.1.64 note=C#-1 velocity=127 gate=100 condition=3:8 retrig=7 decay=15\n"
+        ));
+        assert!(text.contains("\nstep 3.4.7 note=C4 velocity=100 gate=50 condition=!fill\n"));
+        assert!(text.contains("\nstep 3.4.6 note=C4 velocity=100 gate=50 offset=-12\n"));
+        assert!(text.contains("\nlock 3.4.5 2=1000 9=-7\n"));
+        assert!(text.contains("\nsong 3x4 1\nrow 2 tempo=90.5 mute=2,8\nloop 1-2\n"));
+        assert_eq!(from_text(&text).unwrap(), project);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_note("C4"), Some(60));
+        assert_eq!(parse_note("G9"), Some(127));
+        assert_eq!(parse_note("G#9"), None);
+        assert_eq!(parse_note("H2"), None);
+        assert_eq!(parse_note("36"), Some(36));
+
+        let err = from_text("tempo 120\nstep 1.9.1 note=C4\n").unwrap_err();
+        assert_eq!(err, "line 2: '9' is not in 1..=8");
+        assert!(from_text("format 99").is_err());
+        assert!(from_text("track 1 volume").is_err());
+        assert!(from_text("bpm 120").is_err());
+        assert!(from_text("step 1.1.1 offset=13").is_err());
+        assert!(from_text("step 1.1.1 condition=3:2").is_err());
+        assert!(from_text("step 1.1.1 condition=101%").is_err());
+        assert!(from_text("step 1.1.1 retrig=8").is_err());
+        assert!(from_text("song 1 2\nloop 2-1").is_err());
+        assert!(from_text("song 1 2\nrow 3 tempo=100").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! UF2 file encoding, see https://github.com/microsoft/uf2
+
+use std::collections::BTreeMap;
+
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+const RP2040_FAMILY_ID: u32 = 0xE48B_FF56;
+
+const BLOCK_SIZE: usize = 512;
+const PAYLOAD_SIZE: usize = 256;
+const MAX_PAYLOAD_SIZE: usize = 476;
+
+/// Flash content by 256 bytes page, indexed by address
+pub type Pages = BTreeMap<u32, [u8; PAYLOAD_SIZE]>;
+
+/// Copy `data` at `address`, bytes of new pages that are not written are
+/// zeros
+pub fn add(pages: &mut Pages, address: u32, data: &[u8]) {
+    for (i, &byte) in data.iter().enumerate() {
+        let address = address + i as u32;
+        let page = address - address % PAYLOAD_SIZE as u32;
+        let page = pages.entry(page).or_insert([0; PAYLOAD_SIZE]);
+        page[address as usize % PAYLOAD_SIZE] = byte;
+    }
+}
+
+/// Encode `data` to be written at flash `address` (in the XIP address space)
+pub fn encode(data: &[u8], address: u32) -> Vec<u8> {
+    let mut pages = Pages::new();
+    add(&mut pages, address, data);
+    encode_pages(&pages)
+}
+
+pub fn encode_pages(pages: &Pages) -> Vec<u8> {
+    let mut out = Vec::with_capacity(pages.len() * BLOCK_SIZE);
+
+    for (index, (&address, payload)) in pages.iter().enumerate() {
+        let mut block = [0u8; BLOCK_SIZE];
+        let words = [
+            MAGIC_START0,
+            MAGIC_START1,
+            FLAG_FAMILY_ID_PRESENT,
+            address,
+            PAYLOAD_SIZE as u32,
+            index as u32,
+            pages.len() as u32,
+            RP2040_FAMILY_ID,
+        ];
+        for (i, word) in words.iter().enumerate() {
+            block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        block[32..32 + PAYLOAD_SIZE].copy_from_slice(payload);
+        block[BLOCK_SIZE - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+        out.extend_from_slice(&block);
+    }
+    out
+}
+
+fn word(block: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(block[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+pub fn is_uf2(file: &[u8]) -> bool {
+    file.len() >= BLOCK_SIZE && word(file, 0) == MAGIC_START0 && word(file, 1) == MAGIC_START1
+}
+
+/// Decode the flash content of a UF2 file
+pub fn decode(file: &[u8]) -> Result<Pages, String> {
+    if !file.len().is_multiple_of(BLOCK_SIZE) {
+        return Err("UF2 file size is not a multiple of 512".into());
+    }
+
+    let mut pages = Pages::new();
+    for (index, block) in file.chunks(BLOCK_SIZE).enumerate() {
+        if word(block, 0) != MAGIC_START0
+            || word(block, 1) != MAGIC_START1
+            || word(block, 127) != MAGIC_END
+        {
+            return Err(format!("invalid UF2 block {}", index));
+        }
+        if word(block, 2) & FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+        let size = word(block, 4) as usize;
+        if size > MAX_PAYLOAD_SIZE {
+            return Err(format!("invalid payload size in UF2 block {}", index));
+        }
+        add(&mut pages, word(block, 3), &block[32..32 + size]);
+    }
+    Ok(pages)
+}
+
+/// Contiguous image from the first to the last page and its address, the
+/// gaps are erased flash
+pub fn flatten(pages: &Pages) -> (u32, Vec<u8>) {
+    let (Some((&first, _)), Some((&last, _))) = (pages.first_key_value(), pages.last_key_value())
+    else {
+        return (0, Vec::new());
+    };
+    let mut image = vec![0xFF; (last - first) as usize + PAYLOAD_SIZE];
+    for (&address, payload) in pages {
+        let offset = (address - first) as usize;
+        image[offset..offset + PAYLOAD_SIZE].copy_from_slice(payload);
+    }
+    (first, image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut pages = Pages::new();
+        add(&mut pages, 0x1000_0000, &[1; 300]);
+        add(&mut pages, 0x1000_0400, &[2; 4]);
+
+        let file = encode_pages(&pages);
+        assert_eq!(file.len(), 3 * BLOCK_SIZE);
+        assert!(is_uf2(&file));
+        assert_eq!(word(&file[BLOCK_SIZE..], 3), 0x1000_0100);
+        assert_eq!(word(&file[BLOCK_SIZE..], 5), 1);
+        assert_eq!(word(&file[BLOCK_SIZE..], 6), 3);
+        assert_eq!(decode(&file).unwrap(), pages);
+
+        let (address, image) = flatten(&pages);
+        assert_eq!(address, 0x1000_0000);
+        assert_eq!(image.len(), 0x500);
+        assert_eq!(&image[298..302], &[1, 1, 0, 0]);
+        assert_eq!(image[0x200], 0xFF);
+        assert_eq!(&image[0x400..0x405], &[2, 2, 2, 2, 0]);
+
+        assert!(decode(&file[1..]).is_err());
+    }
+}